@@ -0,0 +1,30 @@
+-- Tables of the "d1" storage backend, one per bucket, with the encoded object
+-- in `body` as the other backends store it.
+-- `meta` is a JSON object, `expire_at` is in seconds since the epoch.
+
+CREATE TABLE auth (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX auth_expire_at ON auth (expire_at);
+
+CREATE TABLE room (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX room_expire_at ON room (expire_at);
+
+CREATE TABLE lobby (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX lobby_expire_at ON lobby (expire_at);
@@ -0,0 +1,8 @@
+CREATE TABLE tomb (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX tomb_expire_at ON tomb (expire_at);
@@ -0,0 +1,8 @@
+CREATE TABLE audit (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX audit_expire_at ON audit (expire_at);
@@ -0,0 +1,17 @@
+CREATE TABLE ban (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX ban_expire_at ON ban (expire_at);
+
+CREATE TABLE report (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX report_expire_at ON report (expire_at);
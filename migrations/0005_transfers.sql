@@ -0,0 +1,8 @@
+CREATE TABLE xfer (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX xfer_expire_at ON xfer (expire_at);
@@ -0,0 +1,8 @@
+CREATE TABLE pool (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX pool_expire_at ON pool (expire_at);
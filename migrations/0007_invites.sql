@@ -0,0 +1,8 @@
+CREATE TABLE invite (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX invite_expire_at ON invite (expire_at);
@@ -0,0 +1,8 @@
+CREATE TABLE dead (
+    key TEXT PRIMARY KEY,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    expire_at INTEGER
+);
+CREATE INDEX dead_expire_at ON dead (expire_at);
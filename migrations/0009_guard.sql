@@ -0,0 +1,7 @@
+-- Single row `put_all` sets to the rows changed by each of its writes, so a
+-- batch that lost a write fails the check and is rolled back as a whole.
+
+CREATE TABLE guard (
+    ok INTEGER NOT NULL CHECK (ok = 1)
+);
+INSERT INTO guard (ok) VALUES (1);
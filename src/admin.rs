@@ -0,0 +1,213 @@
+use hmac::Mac;
+use serde::Deserialize;
+use worker::{Env, Method, Request, Response, Result};
+
+use crate::{
+    audit::{audit, recent, Action},
+    auth::{Auth, AuthInfo},
+    ban::{ban, bans, lift, reports},
+    clock::SystemClock,
+    config::Config,
+    db::{storage, BucketInfo, Storage},
+    deadletter::dead_letters,
+    error::ApiError,
+    lobby::unlist,
+    log::token_prefix,
+    poll::leave,
+    room::{bury, room_key, Room, RoomInfo},
+    signing::states,
+    token,
+};
+
+/// Checks the request carries the `ADMIN_TOKEN` secret as a bearer token.
+///
+/// Admin routes are disabled when the secret isn't set.
+pub fn is_admin(req: &Request, env: &Env) -> Result<bool> {
+    let secret = match env.secret("ADMIN_TOKEN") {
+        Ok(secret) => secret.to_string(),
+        Err(_) => return Ok(false),
+    };
+    let header = match req.headers().get("Authorization")? {
+        Some(header) => header,
+        None => return Ok(false),
+    };
+
+    let token = match header.strip_prefix("Bearer ") {
+        Some(token) => token,
+        None => return Ok(false),
+    };
+    // MACs of both under the secret are compared in constant time, whatever
+    // the length of the token
+    let expected = token::mac(&secret, &secret).finalize().into_bytes();
+    Ok(token::mac(&secret, token).verify_slice(&expected).is_ok())
+}
+
+async fn sessions(storage: &dyn Storage, config: &Config) -> Result<Response> {
+    let sessions: Vec<Auth> = storage
+        .list(AuthInfo::PREFIX)
+        .await?
+        .into_iter()
+        .filter_map(|entry| Auth::read(entry).ok())
+        .collect();
+
+    let summaries: Vec<_> = sessions
+        .iter()
+        .map(|s| s.summary(&SystemClock, config))
+        .collect();
+    Response::from_json(&summaries)
+}
+
+async fn rooms(storage: &dyn Storage) -> Result<Response> {
+    let mut rooms = vec![];
+    for entry in storage.list(RoomInfo::PREFIX).await?.into_iter() {
+        // Listings don't carry the body
+        let key = match Room::read(entry) {
+            Ok(room) => room.key,
+            Err(_) => continue,
+        };
+        if let Some(room) = Room::load(storage, &key).await? {
+            rooms.push(room);
+        }
+    }
+
+    let summaries: Vec<_> = rooms.iter().map(|r| r.summary()).collect();
+    Response::from_json(&summaries)
+}
+
+async fn expire_session(env: &Env, storage: &dyn Storage, token: &str) -> Result<Response> {
+    let user = match Auth::load(storage, token).await? {
+        Some(user) => user,
+        None => return ApiError::InvalidToken.into_response(),
+    };
+
+    if let Err(e) = leave(env, &SystemClock, storage, user).await? {
+        return e.into_response();
+    }
+    let config = Config::from_env(env);
+    audit(
+        storage,
+        &config,
+        Action::SessionKilled,
+        "admin",
+        &token_prefix(token),
+    )
+    .await;
+    Ok(Response::empty()?.with_status(204))
+}
+
+async fn expire_room(env: &Env, storage: &dyn Storage, key: &str) -> Result<Response> {
+    let room = match Room::load(storage, key).await? {
+        Some(room) => room,
+        None => return ApiError::RoomUnknown.into_response(),
+    };
+
+    let config = Config::from_env(env);
+    let members = room.get_members();
+    if members.is_empty() || room.is_reserved(&SystemClock) {
+        room.delete(storage).await?;
+        unlist(storage, key).await?;
+        bury(storage, &config, key).await?;
+    }
+    // Otherwise the last one to leave deletes the room
+    for key in members.iter() {
+        if let Some(user) = Auth::load(storage, key).await? {
+            if let Err(e) = leave(env, &SystemClock, storage, user).await? {
+                return e.into_response();
+            }
+        }
+    }
+    audit(storage, &config, Action::RoomExpired, "admin", key).await;
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// Replaces SDP bodies with their length, wherever they're queued.
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    serde_json::Value::String(sdp) if key == "SetSDP" => {
+                        *sdp = format!("<{} bytes>", sdp.len());
+                    }
+                    value => redact(value),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[derive(Deserialize)]
+struct DebugQuery {
+    #[serde(default)]
+    redact: bool,
+}
+
+/// Both sides of a negotiation as stored, the session of `token` and then its peers.
+///
+/// `?redact=true` leaves out the SDPs.
+async fn debug_session(req: &Request, env: &Env, token: &str) -> Result<Response> {
+    let redacted = req.query::<DebugQuery>().is_ok_and(|q| q.redact);
+    let config = Config::from_env(env);
+    let storage = storage(env)?;
+    let user = match token::session(env, &*storage, &config, token).await? {
+        Some(user) => user,
+        None => return ApiError::InvalidToken.into_response(),
+    };
+    let peers = user.load_peers(&*storage).await?;
+
+    let mut transcript = serde_json::json!({
+        "session": user.transcript(&SystemClock, &config),
+        "peers": peers.iter().map(|p| p.transcript(&SystemClock, &config)).collect::<Vec<_>>(),
+    });
+    if redacted {
+        redact(&mut transcript);
+    }
+    Response::from_json(&transcript)
+}
+
+/// `/debug/` routes, they need the admin token too.
+pub async fn debug(req: Request, env: Env) -> Result<Response> {
+    if !is_admin(&req, &env)? {
+        return ApiError::Unauthorized.into_response();
+    }
+
+    let path = req.path();
+    let parts: Vec<&str> = path.trim_start_matches("/debug/").split('/').collect();
+    match (req.method(), parts.as_slice()) {
+        (Method::Get, ["session", token]) => debug_session(&req, &env, token).await,
+        _ => ApiError::NotFound.into_response(),
+    }
+}
+
+/// Routes under `/admin/`, all of them need the admin token.
+pub async fn admin(req: Request, env: Env) -> Result<Response> {
+    if !is_admin(&req, &env)? {
+        return ApiError::Unauthorized.into_response();
+    }
+
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let path = req.path();
+    let parts: Vec<&str> = path.trim_start_matches("/admin/").split('/').collect();
+
+    match (req.method(), parts.as_slice()) {
+        (Method::Get, ["sessions"]) => sessions(&*storage, &config).await,
+        (Method::Get, ["rooms"]) => rooms(&*storage).await,
+        (Method::Get, ["audit"]) => recent(&req, &*storage).await,
+        (Method::Get, ["keys"]) => Response::from_json(&states(&env).await?),
+        (Method::Get, ["reports"]) => reports(&req, &*storage).await,
+        (Method::Get, ["dead-letters"]) => dead_letters(&req, &*storage).await,
+        (Method::Get, ["bans"]) => bans(&req, &*storage).await,
+        (Method::Post, ["bans"]) => ban(req, &*storage, &config).await,
+        (Method::Delete, ["bans", service, kind, value]) => {
+            lift(&*storage, &config, service, kind, value).await
+        }
+        (Method::Delete, ["sessions", token]) => expire_session(&env, &*storage, token).await,
+        (Method::Delete, ["rooms", service, code]) => {
+            expire_room(&env, &*storage, &room_key(service, code)).await
+        }
+        _ => ApiError::NotFound.into_response(),
+    }
+}
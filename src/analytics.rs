@@ -0,0 +1,82 @@
+use worker::{
+    console_log, js_sys,
+    wasm_bindgen::{JsCast, JsValue},
+    Env,
+};
+
+use crate::auth::Auth;
+
+const BINDING: &str = "ANALYTICS";
+
+/// Milestones of a session, the share of idents reaching `Connect` is the success rate.
+pub enum Event {
+    Ident,
+    Join,
+    FirstSdp,
+    /// Valued with the seconds from the first SDP to `connect_at`
+    Connect,
+    Done,
+    Expired,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Ident => "ident",
+            Self::Join => "join",
+            Self::FirstSdp => "first_sdp",
+            Self::Connect => "connect",
+            Self::Done => "done",
+            Self::Expired => "expired",
+        }
+    }
+}
+
+/// What data points are broken down by, taken before the auth is written.
+pub struct Dimensions {
+    service: String,
+    country: String,
+}
+
+impl Dimensions {
+    pub fn of(user: &Auth) -> Self {
+        Dimensions {
+            service: user.get_service().cloned().unwrap_or_default(),
+            country: user.get_country().cloned().unwrap_or_default(),
+        }
+    }
+}
+
+fn write(dataset: &JsValue, blobs: [&str; 3], value: f64) -> Result<(), JsValue> {
+    let point = js_sys::Object::new();
+    let strings = blobs.map(JsValue::from);
+    let blobs = js_sys::Array::of3(&strings[0], &strings[1], &strings[2]);
+    js_sys::Reflect::set(&point, &"blobs".into(), &blobs)?;
+    js_sys::Reflect::set(
+        &point,
+        &"doubles".into(),
+        &js_sys::Array::of1(&value.into()),
+    )?;
+    js_sys::Reflect::set(&point, &"indexes".into(), &js_sys::Array::of1(&strings[1]))?;
+
+    let write: js_sys::Function =
+        js_sys::Reflect::get(dataset, &"writeDataPoint".into())?.dyn_into()?;
+    write.call1(dataset, &point)?;
+    Ok(())
+}
+
+/// Writes a data point for the session as `[event, service, country]` blobs and
+/// a `value` double, indexed by service.
+///
+/// Skipped when the Analytics Engine binding isn't configured.
+pub fn record(env: &Env, event: Event, dimensions: &Dimensions, value: f64) {
+    let dataset = match js_sys::Reflect::get(env, &BINDING.into()) {
+        Ok(dataset) if !dataset.is_undefined() => dataset,
+        _ => return,
+    };
+
+    let blobs = [event.name(), &dimensions.service, &dimensions.country];
+    if let Err(e) = write(&dataset, blobs, value) {
+        console_log!("couldn't record {}: {:?}", event.name(), e);
+    }
+}
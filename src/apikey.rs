@@ -0,0 +1,34 @@
+use worker::{Env, Result};
+
+pub const HEADER: &str = "X-Api-Key";
+/// `;` separated `service=key` pairs.
+const SECRET: &str = "API_KEYS";
+/// KV namespace of keys, each pointing to its service.
+const BINDING: &str = "API_KEYS";
+
+/// Whether `/ident` needs an API key, true once keys are configured either way.
+///
+/// Services then come from the key the token was minted with, never from the
+/// client, so `Signal::SetService` is refused.
+pub fn is_required(env: &Env) -> bool {
+    env.secret(SECRET).is_ok() || env.kv(BINDING).is_ok()
+}
+
+/// Service `key` was issued for, `None` for unknown keys.
+pub async fn service(env: &Env, key: &str) -> Result<Option<String>> {
+    if let Ok(keys) = env.secret(SECRET) {
+        let found = keys
+            .to_string()
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(_, k)| !k.is_empty() && *k == key)
+            .map(|(service, _)| service.to_owned());
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    match env.kv(BINDING) {
+        Ok(store) => Ok(store.get(key).text().await?),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{console_log, Request, Response, Result};
+
+use crate::{
+    config::Config,
+    db::{BucketInfo, Corrupted, Data, Metadata, Storage},
+};
+
+/// Entry of the audit log, keyed `{millis}:{random}` so listings come out in
+/// time order, everything is in the metadata.
+pub type Record = Data<(), RecordMetadata, RecordInfo>;
+
+pub struct RecordInfo {}
+impl BucketInfo for RecordInfo {
+    const PREFIX: &'static str = "audit";
+    const KEY_LENGTH: u8 = 8;
+}
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// What got recorded, either done by an admin or hit by a client.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    SessionKilled,
+    RoomExpired,
+    Kicked,
+    RateLimited,
+    QuotaExceeded,
+    Banned,
+    Unbanned,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::SessionKilled => "session_killed",
+            Self::RoomExpired => "room_expired",
+            Self::Kicked => "kicked",
+            Self::RateLimited => "rate_limited",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::Banned => "banned",
+            Self::Unbanned => "unbanned",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            Self::SessionKilled,
+            Self::RoomExpired,
+            Self::Kicked,
+            Self::RateLimited,
+            Self::QuotaExceeded,
+            Self::Banned,
+            Self::Unbanned,
+        ]
+        .into_iter()
+        .find(|action| action.name() == name)
+    }
+}
+
+pub struct RecordMetadata {
+    action: Option<Action>,
+    // who did it, `admin`, an IP or a token prefix
+    actor: String,
+    // on what, a token prefix, room key or limit
+    target: String,
+    at: SystemTime,
+    expire_at: SystemTime,
+}
+impl Default for RecordMetadata {
+    fn default() -> Self {
+        RecordMetadata {
+            action: None,
+            actor: String::new(),
+            target: String::new(),
+            at: SystemTime::now(),
+            expire_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Metadata for RecordMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        Some(self.expire_at)
+    }
+}
+impl TryFrom<HashMap<String, String>> for RecordMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let time = |name: &str| {
+            value
+                .get(name)
+                .and_then(|v| v.parse().ok())
+                .map(|v| UNIX_EPOCH + Duration::from_secs(v))
+                .unwrap_or(UNIX_EPOCH)
+        };
+
+        Ok(RecordMetadata {
+            action: value.get("action").and_then(|v| Action::from_name(v)),
+            actor: value.get("actor").cloned().unwrap_or_default(),
+            target: value.get("target").cloned().unwrap_or_default(),
+            at: time("at"),
+            expire_at: time("expire_at"),
+        })
+    }
+}
+impl From<RecordMetadata> for HashMap<String, String> {
+    fn from(value: RecordMetadata) -> Self {
+        let time = |v: SystemTime| {
+            v.duration_since(UNIX_EPOCH)
+                .expect("time travel?")
+                .as_secs()
+                .to_string()
+        };
+
+        let mut map = HashMap::new();
+        if let Some(action) = value.action {
+            map.insert("action".to_owned(), action.name().to_owned());
+        }
+        map.insert("actor".to_owned(), value.actor);
+        map.insert("target".to_owned(), value.target);
+        map.insert("at".to_owned(), time(value.at));
+        map.insert("expire_at".to_owned(), time(value.expire_at));
+        map
+    }
+}
+
+impl Record {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.meta.expire_at
+    }
+}
+
+/// Entry of `/admin/audit`.
+#[derive(Serialize)]
+struct RecordSummary<'a> {
+    action: Option<Action>,
+    actor: &'a str,
+    target: &'a str,
+    at: SystemTime,
+}
+
+/// Appends to the audit log, kept for `AUDIT_TTL`.
+///
+/// The log is best effort, a failed write only shows up in the worker's logs.
+pub async fn audit(
+    storage: &dyn Storage,
+    config: &Config,
+    action: Action,
+    actor: &str,
+    target: &str,
+) {
+    let now = SystemTime::now();
+    let millis = now
+        .duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_millis();
+    let mut record = match Record::create_in(&format!("{:013}", millis)) {
+        Ok(record) => record,
+        Err(e) => return console_log!("couldn't audit {}: {}", action.name(), e),
+    };
+    record.meta = RecordMetadata {
+        action: Some(action),
+        actor: actor.to_owned(),
+        target: target.to_owned(),
+        at: now,
+        expire_at: now + Duration::from_secs(config.audit_ttl),
+    };
+    if let Err(e) = record.write(storage).await {
+        console_log!("couldn't audit {}: {}", action.name(), e);
+    }
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    action: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Recent audit records, newest first, `?action=` picking one kind and `?limit=` how many.
+pub async fn recent(req: &Request, storage: &dyn Storage) -> Result<Response> {
+    let query = req.query::<AuditQuery>().unwrap_or(AuditQuery {
+        action: None,
+        limit: None,
+    });
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let action = query.action.as_deref().map(Action::from_name);
+
+    let records: Vec<Record> = storage
+        .list(RecordInfo::PREFIX)
+        .await?
+        .into_iter()
+        .filter_map(|entry| Record::read(entry).ok())
+        .filter(|record| !record.is_expired())
+        .filter(|record| action.is_none_or(|action| record.meta.action == action))
+        .collect();
+    let summaries: Vec<_> = records
+        .iter()
+        .rev()
+        .take(limit)
+        .map(|record| RecordSummary {
+            action: record.meta.action,
+            actor: &record.meta.actor,
+            target: &record.meta.target,
+            at: record.meta.at,
+        })
+        .collect();
+    Response::from_json(&summaries)
+}
@@ -1,85 +1,478 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use serde::{Deserialize, Serialize};
 use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::Result;
 
 use crate::{
-    db::{BucketInfo, Data, Metadata},
-    poll::Signal,
-    room::Room,
+    clock::Clock,
+    config::Config,
+    db::{BucketInfo, Corrupted, Data, Metadata, Storage},
+    filter::{self, Verdict},
+    keys::ALPHANUMERIC,
+    proto::{Backoff, Capabilities, IceCandidate, LinkState, Role, SessionState, Signal},
+    room::{is_spectator_slot, room_code, room_key, Room},
 };
 
-const GRACE_PERIOD: u64 = 20;
-pub const MAX_CONNECTION: u64 = 3600;
-const FIRST_POLL: u64 = 1;
-const POLL: u64 = 10;
-const CONNECT: u64 = 5;
-const FAST_POLL: u64 = 1;
-
 pub type Auth = Data<AuthData, AuthMetadata, AuthInfo>;
 
+/// Responses kept around for retried polls.
+const MAX_REPLIES: usize = 8;
+/// Signals kept for redelivery until acked, the oldest go first.
+const MAX_UNACKED: usize = 1024;
+/// Width of the expiry buckets auth keys start with, in seconds.
+const EXPIRY_BUCKET: u64 = 3600;
+
+/// Zero padded so buckets sort in time order.
+pub fn expiry_bucket(at: SystemTime) -> String {
+    let secs = at
+        .duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_secs();
+    format!("{:010}", secs / EXPIRY_BUCKET)
+}
+
+/// Prefixes of the auth keys from before buckets that sort past the bucket
+/// after `now`, where cleanup stops listing.
+///
+/// Buckets are all digits, so a higher letter than the next bucket's at any
+/// position leads away from them, a higher digit only once past the bucket of
+/// `horizon`. Keys under the digits left out are reached as time gets there.
+pub fn legacy_prefixes(now: SystemTime, horizon: SystemTime) -> Vec<String> {
+    let next = expiry_bucket(now + Duration::from_secs(EXPIRY_BUCKET));
+    let last = expiry_bucket(horizon);
+    let mut prefixes = vec![];
+    for (i, at) in next.char_indices() {
+        for c in ALPHANUMERIC.chars().filter(|c| *c > at) {
+            let prefix = format!("{}{}", &next[..i], c);
+            // Lowest bucket under it
+            let first = format!("{:0<10}", prefix);
+            if !c.is_ascii_digit() || first > last {
+                prefixes.push(prefix);
+            }
+        }
+    }
+    prefixes
+}
+
 pub struct AuthInfo {}
 impl BucketInfo for AuthInfo {
     const PREFIX: &'static str = "auth";
     const KEY_LENGTH: u8 = 32;
 }
 
+/// Negotiation state between this auth and one of its peers.
 #[derive(Serialize, Deserialize, Default)]
-pub struct AuthData {
+pub struct Link {
+    slot: u8,
+    // negotiation round, bumped by `Signal::Renegotiate`
+    generation: u32,
     sent_sdp: bool,
     ice_done: bool,
     queue: Vec<Signal>,
     read: usize,
     connect_at: Option<SystemTime>,
-    sent_join: bool,
     read_connect: bool,
+    // relayed messages keep the session open, see `is_done`
+    relay: bool,
+    sent_role: bool,
+    sent_gone: bool,
+    // send the `LinkState` on the next pull
+    resync: bool,
+    // `Signal::Restart`s so far, the peer's queue is only read once both agree
+    restarts: u32,
+    // when each queued signal was received, handed out as `Signal::ReceivedAt`
+    queued_at: Vec<SystemTime>,
+    // the room's cached offer, handed out ahead of the peer's queue
+    offer: Option<Signal>,
+    // where this poll's candidates start in the queue, not stored
+    #[serde(skip)]
+    batch_start: Option<usize>,
+}
+
+impl Link {
+    fn state(&self) -> LinkState {
+        LinkState {
+            generation: self.generation,
+            sent_sdp: self.sent_sdp,
+            ice_done: self.ice_done,
+            connect_at: self.connect_at,
+        }
+    }
+
+    /// Where negotiation with the peer stands at `now`, given the peer's side of the link.
+    fn session_state(&self, peer: Option<&Link>, now: SystemTime) -> SessionState {
+        match self.connect_at {
+            Some(at) if now >= at => SessionState::Connected,
+            Some(_) => SessionState::Scheduled,
+            None if self.sent_sdp || peer.is_some_and(|p| p.sent_sdp) => SessionState::Negotiating,
+            None => SessionState::Joined,
+        }
+    }
+
+    /// Starts over for the given restart, forgetting everything sent and read.
+    fn restart(&mut self, restarts: u32) {
+        self.restarts = restarts;
+        self.sent_sdp = false;
+        self.ice_done = false;
+        self.queue.clear();
+        self.queued_at.clear();
+        self.read = 0;
+        self.connect_at = None;
+        self.read_connect = false;
+        self.batch_start = None;
+    }
+
+    fn candidates(&self) -> usize {
+        self.queue
+            .iter()
+            .map(|s| match s {
+                Signal::AddCandidate(_) => 1,
+                Signal::AddCandidates(batch) => batch.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+/// Longest `Signal::PublicKey`, in bytes.
+const MAX_KEY_SIZE: usize = 256;
+/// Longest candidate line, browsers stay well under it.
+const MAX_CANDIDATE_SIZE: usize = 512;
+const MAX_MID_LENGTH: usize = 32;
+/// Candidates point at one of the first `m=` lines of the SDP.
+const MAX_MLINE_INDEX: u16 = 64;
+const CANDIDATE_TYPES: [&str; 4] = ["host", "srflx", "prflx", "relay"];
+
+/// Limit a poll ran into, nothing it sent is kept.
+#[derive(Debug)]
+pub enum SendError {
+    SdpTooLarge,
+    KeyTooLarge,
+    TooManyCandidates,
+    QueueFull,
+    /// Names the part of the candidate that's wrong
+    InvalidCandidate(&'static str),
+    /// Spectators only watch
+    Spectating,
+    /// Names the filter that rejected a signal
+    Filtered(&'static str),
+}
+
+/// Checks a candidate follows RFC 8839's `candidate-attribute`, with or without
+/// the `candidate:` prefix. The empty one ending the candidates is fine too.
+fn check_candidate(ice: &IceCandidate) -> std::result::Result<(), &'static str> {
+    let (line, mid, mline) = ice;
+    let printable = |s: &str| s.chars().all(|c| c.is_ascii_graphic() || c == ' ');
+    let digits = |s: &str, max: usize| {
+        !s.is_empty() && s.len() <= max && s.chars().all(|c| c.is_ascii_digit())
+    };
+
+    if mid
+        .as_ref()
+        .is_some_and(|mid| mid.len() > MAX_MID_LENGTH || !printable(mid))
+    {
+        return Err("sdpMid");
+    }
+    if mline.is_some_and(|index| index >= MAX_MLINE_INDEX) {
+        return Err("sdpMLineIndex");
+    }
+    if line.is_empty() {
+        return Ok(());
+    }
+    if line.len() > MAX_CANDIDATE_SIZE || !printable(line) {
+        return Err("candidate");
+    }
+
+    let line = line.strip_prefix("candidate:").unwrap_or(line);
+    let fields: Vec<&str> = line.split(' ').collect();
+    let [foundation, component, transport, priority, address, port, typ, kind, ..] = fields[..]
+    else {
+        return Err("candidate");
+    };
+    let foundation_chars = |c: char| c.is_ascii_alphanumeric() || c == '+' || c == '/';
+    if foundation.is_empty() || foundation.len() > 32 || !foundation.chars().all(foundation_chars) {
+        return Err("foundation");
+    }
+    if !digits(component, 5) {
+        return Err("component");
+    }
+    if transport.is_empty() || !transport.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err("transport");
+    }
+    if !digits(priority, 10) {
+        return Err("priority");
+    }
+    let address_chars = |c: char| c.is_ascii_alphanumeric() || ".:-".contains(c);
+    if address.is_empty() || address.len() > 255 || !address.chars().all(address_chars) {
+        return Err("address");
+    }
+    if port.parse::<u16>().is_err() {
+        return Err("port");
+    }
+    if typ != "typ" || !CANDIDATE_TYPES.contains(&kind) {
+        return Err("type");
+    }
+    // Extensions, e.g. `raddr`, `rport` or `generation`, come in name value pairs
+    if !fields[8..].len().is_multiple_of(2) || fields[8..].iter().any(|f| f.is_empty()) {
+        return Err("extensions");
+    }
+    Ok(())
+}
+
+/// Same candidate string and `sdpMid`.
+fn same_candidate(a: &IceCandidate, b: &IceCandidate) -> bool {
+    a.0 == b.0 && a.1 == b.1
+}
+
+/// What's left of `MAX_PULL` as a pull reads the peers' queues.
+struct Budget {
+    left: usize,
+    // some queue wasn't read to the end
+    cut: bool,
+}
+
+/// This auth's place in one of its rooms.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Membership {
+    // own slot, the lower slot of a link is the offerer
+    slot: u8,
+    sent_join: bool,
+    // keyed by peer token
+    links: BTreeMap<String, Link>,
+    // server generated signals waiting for the next pull
+    notices: Vec<Signal>,
+    // told with `Signal::RoomOwner`
+    owner: bool,
+    // `Signal::PublicKey` queued for every new peer too
+    public_key: Option<Vec<u8>>,
+    // `Signal::JoinRoom` waits for an SDP, see `Signal::HoldCode`
+    hold_code: bool,
+    // SDP and candidates sent before anyone joined, queued for the first peer
+    offer: Vec<Signal>,
+}
+
+impl Membership {
+    /// Whether any of the peers is a member rather than a spectator.
+    fn has_members(&self) -> bool {
+        self.links
+            .values()
+            .any(|link| !is_spectator_slot(link.slot))
+    }
+
+    fn has_offer(&self) -> bool {
+        self.offer
+            .iter()
+            .any(|s| matches!(s, Signal::SetSDP(_) | Signal::Sealed { .. }))
+    }
+
+    /// The `Signal::JoinRoom` is due on the next pull.
+    fn join_pending(&self) -> bool {
+        let held = self.hold_code && !self.has_offer() && !self.has_members();
+        !self.sent_join && !held
+    }
+
+    /// Keeps what's sent while holding the code and there's nobody to send it to.
+    fn hold(&mut self, signal: &Signal, config: &Config) -> bool {
+        if !self.hold_code || self.has_members() {
+            return false;
+        }
+        let keep = match signal {
+            Signal::SetSDP(_) | Signal::Sealed { .. } => !self.has_offer(),
+            Signal::AddCandidate(_) => self.offer.len() <= config.max_candidates,
+            _ => false,
+        };
+        if keep {
+            self.offer.push(signal.clone());
+        }
+        keep
+    }
+
+    /// Link queue of the held offer, with whether it has the SDP and the end of candidates.
+    fn take_offer(&mut self) -> (Vec<Signal>, bool, bool) {
+        let mut queue = vec![];
+        let mut candidates = vec![];
+        for signal in std::mem::take(&mut self.offer) {
+            match signal {
+                Signal::AddCandidate(ice) => candidates.push(ice),
+                signal => queue.push(signal),
+            }
+        }
+        let sent_sdp = !queue.is_empty();
+        let ice_done = candidates.iter().any(|ice| ice.0.is_empty());
+        if !candidates.is_empty() {
+            queue.push(Signal::AddCandidates(candidates));
+        }
+        (queue, sent_sdp, ice_done)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct AuthData {
+    // keyed by room key
+    rooms: BTreeMap<String, Membership>,
+    // bytes sent through `Signal::Relay`
+    relayed: usize,
+    // last responses by idempotency key, oldest first
+    replies: Vec<(String, Vec<Signal>)>,
+    // first `Signal::SetSDP`, negotiation time is measured from it
+    sdp_at: Option<SystemTime>,
+    // last `Signal::Seq` handed out, and what was pulled since the last ack
+    // by the seq it first came with
+    seq: u64,
+    unacked: Vec<(u64, Vec<Signal>)>,
+    // the client sent a `Signal::Ack`
+    acks: bool,
+    // last `Signal::Stats` passed on
+    stats_at: Option<SystemTime>,
+    // why this poll's interval was stretched, not stored
+    #[serde(skip)]
+    backoff: Option<Backoff>,
+}
+
+/// What admins get to see of a session.
+#[derive(Serialize)]
+pub struct SessionSummary<'a> {
+    token: &'a str,
+    service: Option<&'a String>,
+    rooms: &'a [String],
+    peers: &'a [String],
+    kill_at: SystemTime,
+    next_poll: SystemTime,
+    alive: bool,
+}
+
+/// Everything stored for a session, for `/debug/session`.
+#[derive(Serialize)]
+pub struct Transcript<'a> {
+    #[serde(flatten)]
+    summary: SessionSummary<'a>,
+    started_at: SystemTime,
+    country: Option<&'a String>,
+    // queues, read cursors and negotiation state per room and peer
+    data: Option<&'a AuthData>,
 }
 
 pub struct AuthMetadata {
     kill_at: SystemTime,
+    // the ident, refreshes can't go past `max_session` from it
+    started_at: SystemTime,
     next_poll: SystemTime,
     service: Option<String>,
-    room: Option<String>,
-    peer: Option<String>,
+    // keys of the rooms joined
+    rooms: Vec<String>,
+    // peers of every room
+    peers: Vec<String>,
+    // where the ident came from, for analytics
+    country: Option<String>,
+    // user ID from the identity provider's token at ident
+    subject: Option<String>,
+    // service whose profile was picked at ident
+    profile: Option<String>,
+    // joins rooms as a spectator, see `Signal::Spectate`
+    spectator: bool,
+    // declared at ident
+    capabilities: Capabilities,
+    // written ahead for `/ident` and not handed out or polled yet, see `pool`
+    pooled: bool,
 }
 impl Default for AuthMetadata {
     fn default() -> Self {
+        let config = Config::default();
+
         AuthMetadata {
-            kill_at: SystemTime::now() + Duration::from_secs(MAX_CONNECTION),
-            next_poll: SystemTime::now() + Duration::from_secs(FIRST_POLL),
+            kill_at: SystemTime::now() + Duration::from_secs(config.max_connection),
+            started_at: SystemTime::now(),
+            next_poll: SystemTime::now() + Duration::from_secs(config.first_poll),
             service: None,
-            room: None,
-            peer: None,
+            rooms: vec![],
+            peers: vec![],
+            country: None,
+            subject: None,
+            profile: None,
+            spectator: false,
+            capabilities: Capabilities::default(),
+            pooled: false,
         }
     }
 }
 
-impl Metadata for AuthMetadata {}
-impl From<HashMap<String, String>> for AuthMetadata {
-    fn from(value: HashMap<String, String>) -> Self {
-        let kill_at = value
-            .get("kill_at")
-            .filter(|v| !v.is_empty())
-            .map(|v| v.parse().unwrap())
-            .map(|v| UNIX_EPOCH + Duration::from_secs(v))
-            .expect("missing kill_at");
-        let next_poll = value
-            .get("next_poll")
-            .filter(|v| !v.is_empty())
-            .map(|v| v.parse().unwrap())
-            .map(|v| UNIX_EPOCH + Duration::from_secs(v))
-            .expect("missing next_poll");
+impl Metadata for AuthMetadata {
+    /// A bucket past `kill_at`, so the hourly cleanup gets to archive what
+    /// it never read before backends that `expires` drop it.
+    fn expire_at(&self) -> Option<SystemTime> {
+        Some(self.kill_at + Duration::from_secs(EXPIRY_BUCKET))
+    }
+}
+impl TryFrom<HashMap<String, String>> for AuthMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let time = |name: &str| {
+            value
+                .get(name)
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    v.parse()
+                        .map(|v| UNIX_EPOCH + Duration::from_secs(v))
+                        .map_err(|_| Corrupted(format!("invalid {}", name)))
+                })
+                .transpose()
+        };
+        let kill_at = time("kill_at")?.ok_or_else(|| Corrupted("missing kill_at".to_owned()))?;
+        // Auths from before refreshes were never extended
+        let started_at = time("started_at")?
+            .unwrap_or_else(|| kill_at - Duration::from_secs(Config::default().max_connection));
+        let next_poll =
+            time("next_poll")?.ok_or_else(|| Corrupted("missing next_poll".to_owned()))?;
         let service = value.get("service").filter(|v| !v.is_empty()).cloned();
-        let room = value.get("room").filter(|v| !v.is_empty()).cloned();
-        let peer = value.get("peer").filter(|v| !v.is_empty()).cloned();
+        let country = value.get("country").filter(|v| !v.is_empty()).cloned();
+        let subject = value.get("subject").filter(|v| !v.is_empty()).cloned();
+        let profile = value.get("profile").filter(|v| !v.is_empty()).cloned();
+        let spectator = value.get("role").is_some_and(|v| v == "spectator");
+        // Auths from before capabilities handle everything
+        let capabilities = match value.get("caps") {
+            Some(caps) => {
+                let has = |name: &str| caps.split(',').any(|c| c == name);
+                let max_sdp_size = value
+                    .get("max_sdp")
+                    .filter(|v| !v.is_empty())
+                    .map(|v| {
+                        v.parse()
+                            .map_err(|_| Corrupted("invalid max_sdp".to_owned()))
+                    })
+                    .transpose()?;
+                Capabilities {
+                    websocket: has("websocket"),
+                    bare: has("bare"),
+                    relay: has("relay"),
+                    max_sdp_size,
+                }
+            }
+            None => Capabilities::default(),
+        };
+        let list = |name: &str| {
+            value
+                .get(name)
+                .filter(|v| !v.is_empty())
+                .map(|v| v.split(',').map(str::to_owned).collect())
+                .unwrap_or_default()
+        };
 
-        AuthMetadata {
+        Ok(AuthMetadata {
             kill_at,
+            started_at,
             next_poll,
             service,
-            room,
-            peer,
-        }
+            rooms: list("room"),
+            peers: list("peers"),
+            country,
+            subject,
+            profile,
+            spectator,
+            capabilities,
+            pooled: value.get("pooled").is_some_and(|v| v == "1"),
+        })
     }
 }
 impl From<AuthMetadata> for HashMap<String, String> {
@@ -91,6 +484,12 @@ impl From<AuthMetadata> for HashMap<String, String> {
             .expect("time travel on kill_at?")
             .as_secs()
             .to_string();
+        let started_at = value
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .expect("time travel on started_at?")
+            .as_secs()
+            .to_string();
         let next_poll = value
             .next_poll
             .duration_since(UNIX_EPOCH)
@@ -98,203 +497,1761 @@ impl From<AuthMetadata> for HashMap<String, String> {
             .as_secs()
             .to_string();
         let service = value.service.unwrap_or_default();
-        let room = value.room.unwrap_or_default();
-        let peer = value.peer.unwrap_or_default();
+        let rooms = value.rooms.join(",");
+        let peers = value.peers.join(",");
 
         map.insert("kill_at".to_owned(), kill_at);
+        map.insert("started_at".to_owned(), started_at);
         map.insert("next_poll".to_owned(), next_poll);
         map.insert("service".to_owned(), service);
-        map.insert("room".to_owned(), room);
-        map.insert("peer".to_owned(), peer);
+        map.insert("room".to_owned(), rooms);
+        map.insert("peers".to_owned(), peers);
+        map.insert("country".to_owned(), value.country.unwrap_or_default());
+        map.insert("subject".to_owned(), value.subject.unwrap_or_default());
+        map.insert("profile".to_owned(), value.profile.unwrap_or_default());
+        let role = if value.spectator { "spectator" } else { "" };
+        map.insert("role".to_owned(), role.to_owned());
+        let caps = value.capabilities;
+        let caps_list = [
+            ("websocket", caps.websocket),
+            ("bare", caps.bare),
+            ("relay", caps.relay),
+        ]
+        .into_iter()
+        .filter(|(_, has)| *has)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(",");
+        map.insert("caps".to_owned(), caps_list);
+        let max_sdp = caps.max_sdp_size.map(|v| v.to_string());
+        map.insert("max_sdp".to_owned(), max_sdp.unwrap_or_default());
+        let pooled = if value.pooled { "1" } else { "" };
+        map.insert("pooled".to_owned(), pooled.to_owned());
         map
     }
 }
 
 impl Auth {
+    /// Creates an auth keyed `{expiry bucket}:{key}`, so cleanup can list auths by expiry.
+    ///
+    /// Keys carry over 160 bits of randomness, so they're never checked for collisions.
+    pub fn create_expiring(clock: &dyn Clock, config: &Config) -> Result<Self> {
+        let kill_at = clock.now() + Duration::from_secs(config.max_connection);
+        let mut auth = Self::create_in(&expiry_bucket(kill_at))?;
+        auth.start(clock, config);
+        Ok(auth)
+    }
+
+    /// Bucket from the key, `None` for keys from before buckets.
+    pub fn expiry_bucket(&self) -> Option<&str> {
+        self.key.split_once(':').map(|(bucket, _)| bucket)
+    }
+
+    /// Rebuilds a started auth that was handed out without being stored from
+    /// what its token carries.
+    ///
+    /// Tokens without `started_at` are taken to be as old as their first lifetime.
+    pub fn resume(
+        &mut self,
+        kill_at: SystemTime,
+        started_at: Option<SystemTime>,
+        service: Option<String>,
+        country: Option<String>,
+        subject: Option<String>,
+        config: &Config,
+    ) {
+        self.meta.kill_at = kill_at;
+        self.meta.started_at =
+            started_at.unwrap_or_else(|| kill_at - Duration::from_secs(config.max_connection));
+        // Signed tokens don't carry it, their service's profile is the one picked at ident
+        self.meta.profile = service.clone();
+        self.meta.service = service;
+        self.meta.country = country;
+        self.meta.subject = subject;
+    }
+
+    /// Sets the token lifetime and first poll of a new auth.
+    pub fn start(&mut self, clock: &dyn Clock, config: &Config) {
+        let now = clock.now();
+        self.meta.kill_at = now + Duration::from_secs(config.max_connection);
+        self.meta.started_at = now;
+        self.meta.next_poll = now + Duration::from_secs(config.first_poll);
+        self.modified = true;
+    }
+
+    /// Keeps a new auth alive without polling until `kill_at`, for the pool
+    /// `/ident` hands keys out of.
+    pub fn pool(&mut self) {
+        self.meta.pooled = true;
+        self.meta.next_poll = self.meta.kill_at;
+        self.modified = true;
+    }
+
+    /// Takes what `/ident` set on `ident`, handed out with the key of this
+    /// pooled auth. What a poll that got here first did is kept.
+    pub fn adopt(&mut self, ident: Auth) {
+        let meta = ident.meta;
+        if self.meta.pooled {
+            self.meta.next_poll = meta.next_poll;
+        }
+        self.meta.kill_at = meta.kill_at;
+        self.meta.started_at = meta.started_at;
+        // Or the one a `Signal::SetService` of that poll set
+        self.meta.service = meta.service.or(self.meta.service.take());
+        self.meta.country = meta.country;
+        self.meta.subject = meta.subject;
+        self.meta.profile = meta.profile;
+        self.meta.capabilities = meta.capabilities;
+        self.meta.pooled = false;
+        self.modified = true;
+    }
+
+    /// Pushes `kill_at` a full token lifetime from now, as long as the session
+    /// stays within `max_session`. It never moves back.
+    pub fn extend(&mut self, clock: &dyn Clock, config: &Config) -> SystemTime {
+        let wanted = clock.now() + Duration::from_secs(config.max_connection);
+        let cap = self.meta.started_at + Duration::from_secs(config.max_session);
+        self.meta.kill_at = self.meta.kill_at.max(wanted.min(cap));
+        self.modified = true;
+        self.meta.kill_at
+    }
+
+    pub fn started_at(&self) -> SystemTime {
+        self.meta.started_at
+    }
+
     pub fn set_service(&mut self, service: String) {
         self.meta.service = Some(service);
         self.modified = true;
     }
 
-    pub fn set_room(&mut self, room: &Room) {
-        self.meta.room = Some(room.key.clone());
+    pub fn add_room(&mut self, room: &Room, slot: u8) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.rooms.insert(
+            room.key.clone(),
+            Membership {
+                slot,
+                notices: room
+                    .metadata()
+                    .map(Signal::RoomMeta)
+                    .into_iter()
+                    .chain(room.ice_servers().map(Signal::IceServers))
+                    .collect(),
+                ..Default::default()
+            },
+        );
+        if !self.meta.rooms.contains(&room.key) {
+            self.meta.rooms.push(room.key.clone());
+        }
         self.modified = true;
     }
 
-    pub fn set_peer(&mut self, peer: Option<String>) {
-        self.meta.peer = peer;
+    /// Whether an SDP sent now would be held for the first peer of `room`.
+    pub fn is_holding(&self, room: &str) -> bool {
+        let data = self.data.as_ref().expect("invalid state");
+        data.rooms
+            .get(room)
+            .is_some_and(|m| m.hold_code && !m.has_members() && !m.has_offer())
+    }
+
+    /// Holds back the `Signal::JoinRoom` of a room this auth just created.
+    pub fn hold_code(&mut self, room: &str) {
+        let data = self.data.as_mut().expect("invalid state");
+        if let Some(membership) = data.rooms.get_mut(room) {
+            membership.hold_code = true;
+            self.modified = true;
+        }
+    }
+
+    /// Forgets a room that's gone without telling anyone.
+    pub fn drop_room(&mut self, room: &str) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.rooms.remove(room);
+        self.meta.rooms.retain(|r| r != room);
+        self.update_peers();
+    }
+
+    /// Tracks the other members of `room` and whether this auth owns it.
+    pub fn set_peers(&mut self, clock: &dyn Clock, room: &Room) {
+        let peers = room.get_peers(self);
+        let is_owner = room.is_owner(self);
+        let data = self.data.as_mut().expect("invalid state");
+        let membership = match data.rooms.get_mut(&room.key) {
+            Some(membership) => membership,
+            None => return,
+        };
+
+        if is_owner && !membership.owner {
+            membership.notices.push(Signal::RoomOwner);
+        }
+        if is_owner != membership.owner {
+            membership.owner = is_owner;
+            self.modified = true;
+        }
+
+        let gone: Vec<String> = membership
+            .links
+            .keys()
+            .filter(|key| !peers.iter().any(|(_, k)| k == *key))
+            .cloned()
+            .collect();
+        for key in gone.iter() {
+            let link = membership.links.remove(key).expect("invalid state");
+            // Someone else on its slot took over from a peer that died
+            let replaced = peers.iter().any(|(slot, _)| *slot == link.slot);
+            let notice = if room.is_kicked(key) || replaced {
+                Signal::PeerGone(link.slot)
+            } else {
+                Signal::PeerLeft(link.slot)
+            };
+            membership.notices.push(notice);
+            self.modified = true;
+        }
+
+        for (slot, key) in peers.iter() {
+            if !membership.links.contains_key(key) {
+                membership.notices.push(Signal::PeerJoined(*slot));
+                // Spectators get what's relayed from now on and nothing else
+                let (mut offer, sent_sdp, ice_done) = if is_spectator_slot(*slot) {
+                    (vec![], false, false)
+                } else {
+                    membership.take_offer()
+                };
+                if room.handed_offer(&self.key, key).is_some() {
+                    // The peer got the SDP from the room already
+                    offer.retain(|s| !matches!(s, Signal::SetSDP(_)));
+                }
+                let queue: Vec<Signal> = membership
+                    .public_key
+                    .clone()
+                    .filter(|_| !is_spectator_slot(*slot))
+                    .map(Signal::PublicKey)
+                    .into_iter()
+                    .chain(offer)
+                    .collect();
+                membership.links.insert(
+                    key.clone(),
+                    Link {
+                        slot: *slot,
+                        sent_sdp,
+                        ice_done,
+                        queued_at: queue.iter().map(|_| clock.now()).collect(),
+                        queue,
+                        offer: room.handed_offer(key, &self.key).cloned(),
+                        ..Default::default()
+                    },
+                );
+                self.modified = true;
+            }
+        }
+
+        self.update_peers();
+    }
+
+    fn update_peers(&mut self) {
+        let data = self.data.as_ref().expect("invalid state");
+        let keys: BTreeSet<&String> = data.rooms.values().flat_map(|m| m.links.keys()).collect();
+        let keys: Vec<String> = keys.into_iter().cloned().collect();
+        if keys != self.meta.peers {
+            self.meta.peers = keys;
+            self.modified = true;
+        }
+    }
+
+    pub fn kill_at(&self) -> SystemTime {
+        self.meta.kill_at
+    }
+
+    pub fn next_poll(&self) -> SystemTime {
+        self.meta.next_poll
+    }
+
+    pub fn set_country(&mut self, country: String) {
+        self.meta.country = Some(country);
         self.modified = true;
     }
 
+    pub fn get_country(&self) -> Option<&String> {
+        self.meta.country.as_ref()
+    }
+
+    /// Ties the auth to a user of the identity provider.
+    pub fn set_subject(&mut self, subject: String) {
+        self.meta.subject = Some(subject);
+        self.modified = true;
+    }
+
+    pub fn get_subject(&self) -> Option<&String> {
+        self.meta.subject.as_ref()
+    }
+
+    /// Marks the start of negotiation, returning whether this is the first SDP.
+    pub fn start_negotiation(&mut self, clock: &dyn Clock) -> bool {
+        let data = self.data.as_mut().expect("invalid state");
+        if data.sdp_at.is_some() {
+            return false;
+        }
+        data.sdp_at = Some(clock.now());
+        self.modified = true;
+        true
+    }
+
+    /// Seconds from the first SDP until `at`.
+    pub fn negotiation_time(&self, at: SystemTime) -> Option<f64> {
+        let data = self.data.as_ref().expect("invalid state");
+        let since = data.sdp_at?;
+        Some(at.duration_since(since).unwrap_or_default().as_secs_f64())
+    }
+
     pub fn get_service(&self) -> Option<&String> {
         self.meta.service.as_ref()
     }
 
-    pub fn get_room(&self) -> Option<&String> {
-        self.meta.room.as_ref()
+    /// Sets the profile the session's tunables come from, see `Config::for_profile`.
+    pub fn set_profile(&mut self, profile: String) {
+        self.meta.profile = Some(profile);
+        self.modified = true;
+    }
+
+    pub fn get_profile(&self) -> Option<&str> {
+        self.meta.profile.as_deref()
+    }
+
+    /// What the client declared at ident, signed tokens don't carry it so
+    /// resumed sessions handle everything.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.meta.capabilities = capabilities;
+        self.modified = true;
+    }
+
+    pub fn get_capabilities(&self) -> &Capabilities {
+        &self.meta.capabilities
+    }
+
+    /// Largest SDP the client may send, with the one it declared.
+    fn max_sdp_size(&self, config: &Config) -> usize {
+        let declared = self.meta.capabilities.max_sdp_size;
+        declared.map_or(config.max_sdp_size, |max| max.min(config.max_sdp_size))
+    }
+
+    /// Joins rooms as a spectator from now on.
+    pub fn spectate(&mut self) {
+        self.meta.spectator = true;
+        self.modified = true;
+    }
+
+    pub fn is_spectator(&self) -> bool {
+        self.meta.spectator
+    }
+
+    pub fn get_rooms(&self) -> &[String] {
+        &self.meta.rooms
+    }
+
+    pub fn get_peers(&self) -> &[String] {
+        &self.meta.peers
+    }
+
+    pub fn summary(&self, clock: &dyn Clock, config: &Config) -> SessionSummary<'_> {
+        SessionSummary {
+            token: &self.key,
+            service: self.meta.service.as_ref(),
+            rooms: &self.meta.rooms,
+            peers: &self.meta.peers,
+            kill_at: self.meta.kill_at,
+            next_poll: self.meta.next_poll,
+            alive: self.is_alive(clock, config),
+        }
+    }
+
+    pub fn transcript(&self, clock: &dyn Clock, config: &Config) -> Transcript<'_> {
+        Transcript {
+            summary: self.summary(clock, config),
+            started_at: self.meta.started_at,
+            country: self.meta.country.as_ref(),
+            data: self.data.as_ref(),
+        }
+    }
+
+    /// Loads the peers that still exist in storage.
+    pub async fn load_peers(&self, storage: &dyn Storage) -> Result<Vec<Auth>> {
+        let mut peers = vec![];
+        for key in self.meta.peers.iter() {
+            if let Some(peer) = Auth::load(storage, key).await? {
+                peers.push(peer);
+            }
+        }
+        Ok(peers)
+    }
+
+    fn link(&self, room: &str, peer: &str) -> Option<&Link> {
+        let data = self.data.as_ref().expect("invalid state");
+        data.rooms.get(room)?.links.get(peer)
+    }
+
+    /// Leaves the session for good, the object is deleted on the next cleanup.
+    pub fn leave(&mut self, clock: &dyn Clock) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.rooms.clear();
+
+        // Nothing else is linked to this auth anymore
+        self.meta.rooms.clear();
+        self.meta.peers.clear();
+        self.meta.kill_at = clock.now();
+        self.modified = true;
+    }
+
+    /// Moves the session over to a new auth under `key`, for a transfer. This
+    /// one is left dead with nothing linked to it, like after `leave`.
+    pub fn hand_over(&mut self, clock: &dyn Clock, key: String) -> Auth {
+        let mut heir = Auth::unsaved(key);
+        heir.data = self.data.replace(AuthData::default());
+        heir.meta = std::mem::take(&mut self.meta);
+        self.leave(clock);
+        heir
+    }
+
+    /// Follows peer `old` to the auth it was handed over to, see `hand_over`.
+    pub fn rename_peer(&mut self, old: &str, new: &str) {
+        let data = self.data.as_mut().expect("invalid state");
+        for membership in data.rooms.values_mut() {
+            if let Some(link) = membership.links.remove(old) {
+                membership.links.insert(new.to_owned(), link);
+            }
+        }
+        for peer in self.meta.peers.iter_mut().filter(|peer| *peer == old) {
+            *peer = new.to_owned();
+        }
+        self.modified = true;
+    }
+
+    /// Response to an earlier poll sent with the same idempotency key.
+    pub fn replay(&self, key: &str) -> Option<Vec<Signal>> {
+        let data = self.data.as_ref().expect("invalid state");
+        data.replies
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, signals)| signals.clone())
+    }
+
+    /// Keeps the response to replay it if the poll is retried, over any earlier
+    /// one under the same key.
+    pub fn remember(&mut self, key: String, signals: &[Signal]) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.replies.retain(|(k, _)| *k != key);
+        if data.replies.len() >= MAX_REPLIES {
+            data.replies.remove(0);
+        }
+        data.replies.push((key, signals.to_vec()));
+        self.modified = true;
+    }
+
+    /// Replays every room and peer queue from the start on the next pull.
+    pub fn resync(&mut self) {
+        let data = self.data.as_mut().expect("invalid state");
+        for membership in data.rooms.values_mut() {
+            membership.sent_join = false;
+            if membership.owner {
+                membership.notices.push(Signal::RoomOwner);
+            }
+            for link in membership.links.values_mut() {
+                link.read = 0;
+                link.read_connect = false;
+                link.sent_role = false;
+                link.resync = true;
+            }
+        }
+        self.modified = true;
+    }
+
+    /// Ends the session right away, e.g. after a `Signal::Kick`.
+    pub fn expire(&mut self, clock: &dyn Clock) {
+        self.meta.kill_at = clock.now();
+        self.modified = true;
+    }
+
+    /// Slots the signals kick, along with the room selected by `Signal::Room` if any.
+    pub fn kicks(&self, signals: &[Signal]) -> Vec<(Option<String>, u8)> {
+        let service = self.meta.service.clone().unwrap_or_default();
+        let mut room = None;
+        let mut kicks = vec![];
+        for signal in signals.iter() {
+            match signal {
+                Signal::Room(code) => room = Some(room_key(&service, code)),
+                Signal::Kick(slot) => kicks.push((room.clone(), *slot)),
+                _ => {}
+            }
+        }
+        kicks
+    }
+
+    /// Hints the signals accept, along with the room selected by `Signal::Room` if any.
+    pub fn accepts(&self, signals: &[Signal]) -> Vec<(Option<String>, String)> {
+        let service = self.meta.service.clone().unwrap_or_default();
+        let mut room = None;
+        let mut accepts = vec![];
+        for signal in signals.iter() {
+            match signal {
+                Signal::Room(code) => room = Some(room_key(&service, code)),
+                Signal::Accept(hint) => accepts.push((room.clone(), hint.clone())),
+                _ => {}
+            }
+        }
+        accepts
+    }
+
+    /// Queues a notice from the server for the room, e.g. a `Signal::JoinRequest`.
+    pub fn notify(&mut self, room: &str, notice: Signal) {
+        let data = self.data.as_mut().expect("invalid state");
+        if let Some(membership) = data.rooms.get_mut(room) {
+            membership.notices.push(notice);
+            self.modified = true;
+        }
     }
 
-    pub fn get_peer(&self) -> Option<&String> {
-        self.meta.peer.as_ref()
+    /// Signals queued for the peers that they haven't read yet.
+    fn unread(&self, peers: &[Auth]) -> usize {
+        let data = self.data.as_ref().expect("invalid state");
+        let mut unread = 0;
+        for (room, membership) in data.rooms.iter() {
+            for (key, link) in membership.links.iter() {
+                let read = peers
+                    .iter()
+                    .find(|p| p.key == *key)
+                    .and_then(|p| p.link(room, &self.key))
+                    .map_or(0, |l| l.read);
+                unread += link.queue.len().saturating_sub(read);
+            }
+        }
+        unread
     }
 
-    pub fn poll(&mut self) {
-        let secs = if self.meta.peer.is_some() {
-            // Fast polling after both parties are connected
-            FAST_POLL
+    /// Schedules the next poll, later than usual when polling faster wouldn't help.
+    ///
+    /// `load` is the global error rate over its limit, see `load::load`. Peers
+    /// are only looked at when given.
+    pub fn poll(
+        &mut self,
+        clock: &dyn Clock,
+        config: &Config,
+        peers: &[Auth],
+        load: f64,
+    ) -> Option<Backoff> {
+        let mut secs = if !self.meta.peers.is_empty() {
+            // Fast polling once there are peers in the room
+            config.fast_poll
         } else {
-            POLL
+            config.poll
         };
-        self.meta.next_poll = SystemTime::now() + Duration::from_secs(secs);
+        let base = secs;
+
+        let mut backoff = None;
+        if !peers.is_empty() && !peers.iter().any(|p| p.is_alive(clock, config)) {
+            // Nobody to answer until someone joins again
+            secs = secs.max(config.poll);
+            backoff = Some(Backoff::Idle);
+        }
+        if !peers.is_empty() && self.unread(peers) >= config.max_queue / 2 {
+            secs = secs.max(config.poll);
+            backoff = Some(Backoff::Queue);
+        }
+        if load > 1.0 {
+            secs = (secs as f64 * load).ceil() as u64;
+            backoff = Some(Backoff::Load);
+        }
+        let secs = secs.min(config.max_backoff.max(base));
+
+        self.meta.next_poll = clock.now() + Duration::from_secs(secs);
+        self.meta.pooled = false;
+        self.data.as_mut().expect("invalid state").backoff = backoff;
         self.modified = true;
+        backoff
     }
 
-    pub fn send_signal<S>(&mut self, signals: S)
+    /// Queues the signals for the peers, returning the tokens of the ones that received any.
+    ///
+    /// Signals are addressed to every peer of every room until a `Signal::Room`
+    /// selects a room, and a `Signal::Peer` a single slot in it. Candidates are
+    /// deduplicated and batched into one `Signal::AddCandidates` per poll.
+    pub fn send_signal<S>(
+        &mut self,
+        clock: &dyn Clock,
+        signals: S,
+        config: &Config,
+    ) -> std::result::Result<Vec<String>, SendError>
     where
         S: IntoIterator<Item = Signal>,
     {
+        let service = self.meta.service.clone().unwrap_or_default();
+        let max_sdp_size = self.max_sdp_size(config);
+        let filters = filter::chain(config);
         let data = self.data.as_mut().expect("invalid state");
+        let mut room = None;
+        let mut target = None;
+        let mut queued = vec![];
+        let received_at = clock.now();
 
-        for signal in signals.into_iter() {
+        let signals = signals.into_iter().flat_map(|signal| match signal {
+            Signal::AddCandidates(batch) => batch.into_iter().map(Signal::AddCandidate).collect(),
+            signal => vec![signal],
+        });
+        for signal in signals {
             if !signal.can_send() {
                 continue;
             }
+            if let Signal::Room(ref code) = signal {
+                room = Some(room_key(&service, code));
+                target = None;
+                continue;
+            }
+            if let Signal::Peer(slot) = signal {
+                target = Some(slot);
+                continue;
+            }
+            if let Signal::Kick(_) | Signal::Accept(_) = signal {
+                // Handled on the rooms, see `kicks` and `accepts`
+                continue;
+            }
+            if let Signal::Ack(_) = signal {
+                // See `ack`
+                continue;
+            }
+            if self.meta.spectator {
+                return Err(SendError::Spectating);
+            }
+            if let Signal::SetSDP(ref sdp) = signal {
+                if sdp.len() > max_sdp_size {
+                    return Err(SendError::SdpTooLarge);
+                }
+            }
+            if let Signal::AddCandidate(ref ice) = signal {
+                check_candidate(ice).map_err(SendError::InvalidCandidate)?;
+            }
+            if let Signal::Sealed { ref ciphertext, .. } = signal {
+                if ciphertext.len() > max_sdp_size {
+                    return Err(SendError::SdpTooLarge);
+                }
+            }
+            let signal = match filter::send(&filters, signal) {
+                Verdict::Pass(signal) => signal,
+                Verdict::Drop => continue,
+                Verdict::Reject(name) => return Err(SendError::Filtered(name)),
+            };
+            if let Signal::PublicKey(ref public_key) = signal {
+                if public_key.len() > MAX_KEY_SIZE {
+                    return Err(SendError::KeyTooLarge);
+                }
+                let memberships = data
+                    .rooms
+                    .iter_mut()
+                    .filter(|(key, _)| room.as_ref().is_none_or(|r| r == *key));
+                for (_, membership) in memberships {
+                    membership.public_key = Some(public_key.clone());
+                    self.modified = true;
+                }
+            }
+            if let Signal::Relay(ref msg) = signal {
+                if msg.len() > config.max_relay_size {
+                    continue;
+                }
+                if data.relayed + msg.len() > config.relay_quota {
+                    // Out of relay quota for this session
+                    continue;
+                }
+                data.relayed += msg.len();
+            }
+            if let Signal::Stats(ref report) = signal {
+                if report.to_string().len() > config.max_stats_size {
+                    continue;
+                }
+                let now = received_at;
+                let interval = Duration::from_secs(config.stats_interval);
+                if data.stats_at.is_some_and(|at| now < at + interval) {
+                    // Reporting too often, the next one will do
+                    continue;
+                }
+                data.stats_at = Some(now);
+            }
 
-            match signal {
-                Signal::SetSDP(_) => {
-                    if data.sent_sdp {
-                        // Can't set SDP twice
-                        continue;
-                    }
+            let memberships = data
+                .rooms
+                .iter_mut()
+                .filter(|(key, _)| room.as_ref().is_none_or(|r| r == *key));
+            for (_, membership) in memberships {
+                self.modified |= membership.hold(&signal, config);
+            }
 
-                    data.sent_sdp = true;
-                    self.modified = true;
+            let links = data
+                .rooms
+                .iter_mut()
+                .filter(|(key, _)| room.as_ref().is_none_or(|r| r == *key))
+                .flat_map(|(_, membership)| membership.links.iter_mut());
+            for (key, link) in links {
+                if target.is_some_and(|slot| slot != link.slot) {
+                    continue;
                 }
-                Signal::AddCandidate(ref ice) => {
-                    if data.ice_done {
-                        // Already done with ICE candidates
-                        continue;
+                if is_spectator_slot(link.slot) && !matches!(signal, Signal::Relay(_)) {
+                    // Spectators take part in no negotiation
+                    continue;
+                }
+
+                let signal = match signal {
+                    Signal::SetSDP(_) => {
+                        if link.sent_sdp {
+                            // Can't set SDP twice
+                            continue;
+                        }
+
+                        link.sent_sdp = true;
+                        signal.clone()
+                    }
+                    Signal::Sealed { .. } => {
+                        // Most likely the SDP, whatever's inside
+                        link.sent_sdp = true;
+                        signal.clone()
                     }
+                    Signal::AddCandidate(ref ice) => {
+                        if link.ice_done {
+                            // Already done with ICE candidates
+                            continue;
+                        }
+                        let duplicate = link.queue.iter().any(|s| match s {
+                            Signal::AddCandidate(c) => same_candidate(c, ice),
+                            Signal::AddCandidates(batch) => {
+                                batch.iter().any(|c| same_candidate(c, ice))
+                            }
+                            _ => false,
+                        });
+                        if duplicate {
+                            // Retried poll
+                            continue;
+                        }
+                        if link.candidates() >= config.max_candidates {
+                            return Err(SendError::TooManyCandidates);
+                        }
+
+                        if ice.0.is_empty() {
+                            link.ice_done = true;
+                        }
 
-                    if ice.0.is_empty() {
-                        data.ice_done = true;
-                        self.modified = true;
+                        let start = *link.batch_start.get_or_insert(link.queue.len());
+                        let last = link.queue.len().checked_sub(1).filter(|i| *i >= start);
+                        if let Some(Signal::AddCandidates(batch)) = last.map(|i| &mut link.queue[i])
+                        {
+                            batch.push(ice.clone());
+                            self.modified = true;
+                            continue;
+                        }
+                        Signal::AddCandidates(vec![ice.clone()])
                     }
+                    Signal::Renegotiate(_) => {
+                        // New offer/answer round, the peer learns its number
+                        link.generation += 1;
+                        link.sent_sdp = false;
+                        link.ice_done = false;
+                        Signal::Renegotiate(link.generation)
+                    }
+                    Signal::Relay(_) => {
+                        link.relay = true;
+                        signal.clone()
+                    }
+                    Signal::Restart => {
+                        // Leaves just the restart in the queue, the peer resets when reading it
+                        link.restart(link.restarts + 1);
+                        signal.clone()
+                    }
+                    _ => signal.clone(),
+                };
+
+                if link.queue.len() >= config.max_queue {
+                    return Err(SendError::QueueFull);
                 }
-                _ => {}
-            };
+                self.modified = true;
+                link.queue.push(signal);
+                link.queued_at.push(received_at);
+                if !queued.contains(key) {
+                    queued.push(key.clone());
+                }
+            }
+        }
+
+        Ok(queued)
+    }
 
+    fn read_signals(
+        &mut self,
+        room: &str,
+        peer: &Auth,
+        config: &Config,
+        budget: &mut Budget,
+    ) -> Vec<Signal> {
+        let p_link = peer.link(room, &self.key);
+        let p_restarts = p_link.map_or(0, |p_link| p_link.restarts);
+        let relay = self.get_capabilities().relay;
+        let data = self.data.as_mut().expect("invalid state");
+        let membership = data.rooms.get_mut(room).expect("invalid state");
+        let link = membership.links.get_mut(&peer.key).expect("invalid state");
+        if p_restarts > link.restarts {
+            // Peer restarted, what we sent was for the connection that failed
+            link.restart(p_restarts);
             self.modified = true;
-            data.queue.push(signal);
         }
-    }
 
-    fn read_signals(&mut self, peer: &Auth) -> Vec<Signal> {
-        self.try_connect(peer);
+        self.try_connect(room, peer, config);
 
         let data = self.data.as_mut().expect("invalid state");
-        let queue = &peer.data.as_ref().expect("invalid state").queue;
+        let membership = data.rooms.get_mut(room).expect("invalid state");
+        let link = membership.links.get_mut(&peer.key).expect("invalid state");
+        let (queue, queued_at) = match p_link {
+            // Until it reads our restart, the peer's queue is from before it
+            Some(p_link) if p_link.restarts == link.restarts => {
+                (&p_link.queue[..], &p_link.queued_at[..])
+            }
+            _ => (&[][..], &[][..]),
+        };
 
-        let signals = queue.get(data.read..).unwrap_or_default();
-        data.read = queue.len();
+        let start = link.read;
+        let unread = queue.get(start..).unwrap_or_default();
+        // What's over the budget waits for the next pull
+        let signals = &unread[..unread.len().min(budget.left)];
+        budget.left -= signals.len();
+        let drained = signals.len() == unread.len();
+        budget.cut |= !drained;
+        let read = if drained {
+            queue.len()
+        } else {
+            start + signals.len()
+        };
+        if link.read != read {
+            link.read = read;
+            self.modified = true;
+        }
 
-        signals.to_vec()
-    }
+        for signal in signals.iter() {
+            if matches!(signal, Signal::Relay(_)) {
+                link.relay = true;
+            }
+            if let Signal::Renegotiate(generation) = signal {
+                // Peer started a new round, we need to answer it.
+                // Rounds we started ourselves at the same time are already reset.
+                if *generation > link.generation {
+                    link.generation = *generation;
+                    link.sent_sdp = false;
+                    link.ice_done = false;
+                }
+            }
+        }
 
-    pub fn pull_signals(&mut self, peer: Option<&Auth>) -> Vec<Signal> {
-        let mut signals = match peer {
-            Some(peer) => self.read_signals(peer),
-            None => vec![],
+        // Stamped once per poll of the peer, signals it sent together share the time
+        let mut stamped = vec![];
+        let mut last = None;
+        for (i, signal) in signals.iter().enumerate() {
+            if !relay && matches!(signal, Signal::Relay(_)) {
+                continue;
+            }
+            if let Some(at) = queued_at.get(start + i).copied() {
+                if last != Some(at) {
+                    stamped.push(Signal::ReceivedAt(at));
+                    last = Some(at);
+                }
+            }
+            stamped.push(signal.clone());
+        }
+        let mut signals = stamped;
+        if let Some(at) = link.connect_at.filter(|_| drained) {
+            if !link.read_connect {
+                link.read_connect = true;
+                signals.push(Signal::ConnectAt(at));
+            }
         };
+        signals
+    }
 
+    /// Drops what the client acknowledged with a `Signal::Ack`, turning acks on.
+    ///
+    /// A stale ack means the responses after it got lost, so those are kept for
+    /// redelivery.
+    pub fn ack(&mut self, signals: &[Signal]) {
+        let acked = signals
+            .iter()
+            .filter_map(|s| match s {
+                Signal::Ack(seq) => Some(*seq),
+                _ => None,
+            })
+            .max();
         let data = self.data.as_mut().expect("invalid state");
-        if let Some(ref room) = self.meta.room {
-            if !data.sent_join {
-                data.sent_join = true;
-                signals.push(Signal::JoinRoom(room.clone()));
-            }
+        let acked = match acked {
+            Some(acked) => acked,
+            None => return,
         };
-        if let Some(at) = data.connect_at {
-            if !data.read_connect {
-                data.read_connect = true;
-                signals.push(Signal::ConnectAt(at));
+        data.acks = true;
+        data.unacked.retain(|(seq, _)| *seq > acked);
+        self.modified = true;
+    }
+
+    /// Signals for every room, each group starting with its `Signal::Room`.
+    ///
+    /// Once the client acks, they follow a `Signal::Seq` and everything unacked
+    /// from earlier responses. What's redelivered counts against `MAX_PULL`
+    /// first, new signals wait until the older ones fit.
+    pub fn pull_signals(
+        &mut self,
+        clock: &dyn Clock,
+        peers: &[Auth],
+        config: &Config,
+    ) -> Vec<Signal> {
+        let mut signals = vec![];
+        let max = match config.max_pull {
+            0 => usize::MAX,
+            max => max,
+        };
+        let data = self.data.as_ref().expect("invalid state");
+        // Whole groups, oldest first, and at least one
+        let (mut replayed, mut groups) = (0, 0);
+        for (_, group) in data.unacked.iter().filter(|_| data.acks) {
+            if groups > 0 && replayed + group.len() > max {
+                break;
             }
+            replayed += group.len();
+            groups += 1;
+        }
+        let backlog = data.acks && groups < data.unacked.len();
+        let mut budget = Budget {
+            left: max.saturating_sub(replayed),
+            cut: backlog,
         };
+
+        for room in self.meta.rooms.clone().iter().filter(|_| !backlog) {
+            let pulled = self.pull_room(clock, room, peers, config, &mut budget);
+            if !pulled.is_empty() {
+                signals.push(Signal::Room(room_code(room).to_owned()));
+                signals.extend(pulled);
+            }
+        }
+        let filters = filter::chain(config);
+        if !filters.is_empty() {
+            signals = signals
+                .into_iter()
+                .filter_map(|signal| filter::pull(&filters, signal))
+                .collect();
+        }
+
+        // Once this poll's connection times were handed out
+        let state = self.session_state(clock, peers, config);
+        let data = self.data.as_mut().expect("invalid state");
+        if data.acks && backlog {
+            // Acking the last group handed out leaves the rest for the next pull
+            let seq = data.unacked[groups - 1].0;
+            let unacked = data.unacked[..groups]
+                .iter()
+                .flat_map(|(_, group)| group.iter().cloned());
+            signals = [Signal::Seq(seq)].into_iter().chain(unacked).collect();
+        } else if data.acks {
+            // Every group names its room, so they can be repeated as they are
+            data.seq += 1;
+            if !signals.is_empty() {
+                data.unacked.push((data.seq, signals));
+            }
+            let mut kept: usize = data.unacked.iter().map(|(_, group)| group.len()).sum();
+            while kept > MAX_UNACKED && data.unacked.len() > 1 {
+                // Never acked, the client most likely doesn't wait for them anymore
+                kept -= data.unacked.remove(0).1.len();
+            }
+            let unacked = data
+                .unacked
+                .iter()
+                .flat_map(|(_, group)| group.iter().cloned());
+            signals = [Signal::Seq(data.seq)].into_iter().chain(unacked).collect();
+            self.modified = true;
+        }
+        if let Some(backoff) = data.backoff {
+            signals.push(Signal::Backoff(backoff));
+        }
+        if budget.cut {
+            signals.push(Signal::HasMore);
+        }
+        let left = self
+            .meta
+            .kill_at
+            .duration_since(clock.now())
+            .unwrap_or_default();
+        if left.as_secs() < config.expiry_warning {
+            signals.push(Signal::ExpiresIn(left.as_secs()));
+        }
+        signals.push(Signal::State(state));
         signals.push(Signal::NextPoll(self.meta.next_poll));
         signals
     }
 
-    pub fn try_connect(&mut self, peer: &Auth) {
+    fn pull_room(
+        &mut self,
+        clock: &dyn Clock,
+        room: &str,
+        peers: &[Auth],
+        config: &Config,
+        budget: &mut Budget,
+    ) -> Vec<Signal> {
+        let mut signals = vec![];
+
+        let data = self.data.as_mut().expect("invalid state");
+        let membership = match data.rooms.get_mut(room) {
+            Some(membership) => membership,
+            None => return signals,
+        };
+        if membership.join_pending() {
+            membership.sent_join = true;
+            signals.push(Signal::JoinRoom(room_code(room).to_owned()));
+            self.modified = true;
+        }
+        if !membership.notices.is_empty() {
+            signals.append(&mut membership.notices);
+            self.modified = true;
+        }
+
+        let own_slot = membership.slot;
+        for peer in peers.iter() {
+            let data = self.data.as_mut().expect("invalid state");
+            let membership = data.rooms.get_mut(room).expect("invalid state");
+            let link = match membership.links.get_mut(&peer.key) {
+                Some(link) => link,
+                None => continue,
+            };
+            if !peer.is_alive(clock, config) {
+                // Stopped polling, nothing it sent will be answered anymore
+                let read_connect = peer
+                    .link(room, &self.key)
+                    .is_some_and(|p_link| p_link.read_connect);
+                let ahead = link.connect_at.is_some_and(|at| at > clock.now());
+                if link.connect_at.is_some() && (ahead || !read_connect) {
+                    // Gone before it learned when to connect or before the time
+                    // came, nobody to connect to
+                    link.connect_at = None;
+                    link.read_connect = false;
+                    signals.push(Signal::ConnectCancelled(link.slot));
+                    self.modified = true;
+                }
+                if !link.sent_gone {
+                    link.sent_gone = true;
+                    signals.push(Signal::PeerGone(link.slot));
+                    self.modified = true;
+                }
+                continue;
+            }
+
+            let slot = link.slot;
+            let role = if link.sent_role || is_spectator_slot(own_slot.max(link.slot)) {
+                None
+            } else if own_slot < link.slot {
+                Some(Role::Offerer)
+            } else {
+                Some(Role::Answerer)
+            };
+            if role.is_some() {
+                link.sent_role = true;
+                self.modified = true;
+            }
+            let resync = std::mem::take(&mut link.resync);
+            let offer = link.offer.take();
+            if offer.is_some() {
+                self.modified = true;
+            }
+
+            let read = self.read_signals(room, peer, config, budget);
+            let state = if resync {
+                self.link(room, &peer.key).map(Link::state)
+            } else {
+                None
+            };
+            if role.is_some() || offer.is_some() || !read.is_empty() || state.is_some() {
+                signals.push(Signal::Peer(slot));
+                signals.extend(role.map(Signal::Role));
+                signals.extend(offer);
+                signals.extend(read);
+                signals.extend(state.map(Signal::LinkState));
+            }
+        }
+
+        signals
+    }
+
+    pub fn try_connect(&mut self, room: &str, peer: &Auth, config: &Config) {
         let s_data = self.data.as_mut().expect("invalid state");
-        let p_data = peer.data.as_ref().expect("invalid state");
+        let s_membership = s_data.rooms.get_mut(room).expect("invalid state");
+        let s_link = s_membership
+            .links
+            .get_mut(&peer.key)
+            .expect("invalid state");
+        let p_link = match peer.link(room, &self.key) {
+            Some(link) => link,
+            None => return,
+        };
 
-        if s_data.connect_at.is_some() {
+        if s_link.restarts != p_link.restarts {
+            return;
+        }
+        if s_link.connect_at.is_some() {
             return;
         }
-        if p_data.connect_at.is_some() {
-            s_data.connect_at = p_data.connect_at;
-            s_data.read_connect = false;
+        if p_link.connect_at.is_some() {
+            s_link.connect_at = p_link.connect_at;
+            s_link.read_connect = false;
             self.modified = true;
             return;
         }
 
         // Need both SDPs
-        if !s_data.sent_sdp || !p_data.sent_sdp {
+        if !s_link.sent_sdp || !p_link.sent_sdp {
             return;
         }
         // Need at least one ICE list to be done
-        if !s_data.ice_done && !p_data.ice_done {
+        if !s_link.ice_done && !p_link.ice_done {
             return;
         }
 
-        let at = peer.meta.next_poll + Duration::from_secs(CONNECT);
-        s_data.connect_at = Some(at);
-        s_data.read_connect = false;
+        let at = peer.meta.next_poll + Duration::from_secs(config.connect);
+        s_link.connect_at = Some(at);
+        s_link.read_connect = false;
         self.modified = true;
     }
 
-    pub fn is_done(&self, peer: &Auth) -> bool {
-        let s_data = self.data.as_ref().expect("invalid state");
-        let p_data = peer.data.as_ref().expect("invalid state");
+    /// Whether `room`'s members are the peers this auth knows of in it.
+    pub fn knows_peers(&self, room: &Room) -> bool {
+        let peers = room.get_peers(self);
+        let data = self.data.as_ref().expect("invalid state");
+        data.rooms.get(&room.key).is_some_and(|membership| {
+            membership.links.len() == peers.len()
+                && peers
+                    .iter()
+                    .all(|(_, key)| membership.links.contains_key(key))
+        })
+    }
 
+    /// Whether a pull would hand out anything new, without changing a thing.
+    pub fn has_unread(&self, clock: &dyn Clock, peers: &[Auth], config: &Config) -> bool {
+        let data = self.data.as_ref().expect("invalid state");
+        for (room, membership) in data.rooms.iter() {
+            if membership.join_pending() || !membership.notices.is_empty() {
+                return true;
+            }
+            for (key, link) in membership.links.iter() {
+                let peer = match peers.iter().find(|p| p.key == *key) {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                if !peer.is_alive(clock, config) && !link.sent_gone {
+                    return true;
+                }
+                if link.connect_at.is_some() && !link.read_connect {
+                    return true;
+                }
+                let p_link = match peer.link(room, &self.key) {
+                    Some(p_link) => p_link,
+                    None => continue,
+                };
+                if p_link.restarts != link.restarts
+                    || p_link.queue.len() > link.read
+                    || p_link.connect_at.is_some() && link.connect_at.is_none()
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Where the session stands, as far as its furthest link got until every one is done.
+    pub fn session_state(
+        &self,
+        clock: &dyn Clock,
+        peers: &[Auth],
+        config: &Config,
+    ) -> SessionState {
+        if !self.is_alive(clock, config) {
+            return SessionState::Expired;
+        }
+        let data = self.data.as_ref().expect("invalid state");
+        if data.rooms.is_empty() {
+            return SessionState::Created;
+        }
+        if data.rooms.values().all(|m| m.links.is_empty()) {
+            return SessionState::Joined;
+        }
+        if self.is_all_done(peers) {
+            return SessionState::Done;
+        }
+
+        let mut state = SessionState::Joined;
+        for (room, membership) in data.rooms.iter() {
+            let links = membership
+                .links
+                .iter()
+                .filter(|(_, link)| !is_spectator_slot(link.slot));
+            for (key, link) in links {
+                let peer = peers
+                    .iter()
+                    .find(|p| p.key == *key)
+                    .and_then(|p| p.link(room, &self.key));
+                state = state.max(link.session_state(peer, clock.now()));
+            }
+        }
+        state
+    }
+
+    /// Whether every link in every room is done, see `is_done`. Links to
+    /// spectators don't count, they never connect.
+    pub fn is_all_done(&self, peers: &[Auth]) -> bool {
+        let data = self.data.as_ref().expect("invalid state");
+        data.rooms.iter().all(|(room, membership)| {
+            membership
+                .links
+                .iter()
+                .filter(|(_, link)| !is_spectator_slot(link.slot))
+                .all(|(key, _)| {
+                    peers
+                        .iter()
+                        .find(|peer| peer.key == *key)
+                        .is_some_and(|peer| self.is_done(room, peer))
+                })
+        })
+    }
+
+    pub fn is_done(&self, room: &str, peer: &Auth) -> bool {
+        let s_link = match self.link(room, &peer.key) {
+            Some(link) => link,
+            None => return false,
+        };
+        let p_link = match peer.link(room, &self.key) {
+            Some(link) => link,
+            None => return false,
+        };
+
+        // still relaying messages through us
+        if s_link.relay || p_link.relay {
+            return false;
+        }
+        // one side is starting over
+        if s_link.restarts != p_link.restarts {
+            return false;
+        }
         // didn't establish a p2p connection
-        if s_data.connect_at.is_none() {
+        if s_link.connect_at.is_none() {
             return false;
         }
         // not all ice candidates were sent
-        if !s_data.ice_done || !p_data.ice_done {
+        if !s_link.ice_done || !p_link.ice_done {
             return false;
         }
         // not all messages have been read
-        if p_data.queue.len() - s_data.read > 0 {
+        if p_link.queue.len() - s_link.read > 0 {
             return false;
         }
 
         true
     }
 
-    pub fn is_alive(&self) -> bool {
+    /// Whether the token is unkilled and polled within the grace period, by the time of `clock`.
+    pub fn is_alive(&self, clock: &dyn Clock, config: &Config) -> bool {
         let limit = self
             .meta
             .kill_at
-            .min(self.meta.next_poll + Duration::from_secs(GRACE_PERIOD));
-        SystemTime::now() < limit
+            .min(self.meta.next_poll + Duration::from_secs(config.grace_period));
+        clock.now() < limit
     }
 
-    pub fn get_keys_to_kill(&self) -> Vec<String> {
-        if self.is_alive() {
+    pub fn get_keys_to_kill(&self, clock: &dyn Clock, config: &Config) -> Vec<String> {
+        if self.is_alive(clock, config) {
             return vec![];
         }
 
         let mut keys = vec![Self::get_bucket_key(&self.key)];
-        match &self.meta.peer {
-            Some(k) => keys.push(Self::get_bucket_key(k)),
-            None => {}
+        if self.meta.spectator {
+            // Its peers and rooms carry on without it
+            return keys;
+        }
+        for k in self.meta.peers.iter() {
+            keys.push(Self::get_bucket_key(k));
+        }
+        for k in self.meta.rooms.iter() {
+            keys.push(Room::get_bucket_key(k));
+        }
+        keys
+    }
+
+    /// What `peers` queued for this auth that it never read, leaving out
+    /// queues from before a restart it didn't get to.
+    pub fn undelivered(&self, peers: &[Auth]) -> Vec<Undelivered> {
+        let data = self.data.as_ref().expect("invalid state");
+        let mut undelivered = vec![];
+        for (room, membership) in data.rooms.iter() {
+            for (key, link) in membership.links.iter() {
+                let p_link = peers
+                    .iter()
+                    .find(|p| p.key == *key)
+                    .and_then(|peer| peer.link(room, &self.key))
+                    .filter(|p_link| p_link.restarts == link.restarts);
+                let signals = match p_link {
+                    Some(p_link) => p_link.queue.get(link.read..).unwrap_or_default(),
+                    None => continue,
+                };
+                if !signals.is_empty() {
+                    undelivered.push(Undelivered {
+                        room: room.clone(),
+                        from: key.clone(),
+                        slot: link.slot,
+                        to: self.key.clone(),
+                        signals: signals.to_vec(),
+                    });
+                }
+            }
+        }
+        undelivered
+    }
+}
+
+/// Signals one peer queued that the other never read, see `Auth::undelivered`.
+pub struct Undelivered {
+    pub room: String,
+    pub from: String,
+    /// The sender's
+    pub slot: u8,
+    pub to: String,
+    pub signals: Vec<Signal>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use web_time::{Duration, SystemTime};
+
+    use super::{expiry_bucket, legacy_prefixes, Auth, MAX_UNACKED};
+    use crate::{
+        config::Config,
+        proto::{Backoff, Capabilities, SessionState, Signal},
+        testing::{auth, pair, FakeClock, SeededKeys},
+    };
+
+    fn done() -> Signal {
+        Signal::AddCandidate((String::new(), None, None))
+    }
+
+    fn count(signals: &[Signal], matching: impl Fn(&Signal) -> bool) -> usize {
+        signals.iter().filter(|s| matching(s)).count()
+    }
+
+    fn connect_at(signals: &[Signal]) -> Option<web_time::SystemTime> {
+        signals.iter().find_map(|s| match s {
+            Signal::ConnectAt(at) => Some(*at),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn alive_through_grace_period() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (a, _, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = a.next_poll();
+
+        clock.advance(config.grace_period - 1);
+        assert!(a.is_alive(&clock, &config));
+        clock.advance(1);
+        assert!(!a.is_alive(&clock, &config));
+    }
+
+    #[test]
+    fn dead_at_kill_within_grace_period() {
+        let config = Config::default();
+        let clock = FakeClock::default();
+        let (mut a, _, _) = pair(&mut SeededKeys(1), &clock, &config);
+        let clock = FakeClock(a.kill_at());
+        a.poll(
+            &FakeClock(a.kill_at() - Duration::from_secs(1)),
+            &config,
+            &[],
+            0.0,
+        );
+
+        assert!(a.next_poll() + Duration::from_secs(config.grace_period) > clock.0);
+        assert!(!a.is_alive(&clock, &config));
+    }
+
+    #[test]
+    fn idle_backoff_once_peers_are_gone() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (mut a, b, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = b.next_poll();
+        let peers = [b];
+
+        assert!(a.poll(&clock, &config, &peers, 0.0).is_none());
+        clock.advance(config.grace_period);
+        assert!(matches!(
+            a.poll(&clock, &config, &peers, 0.0),
+            Some(Backoff::Idle)
+        ));
+        assert_eq!(a.next_poll(), clock.0 + Duration::from_secs(config.poll));
+    }
+
+    #[test]
+    fn connect_after_next_poll_of_peer() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (mut a, mut b, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = a.next_poll();
+        a.send_signal(
+            &clock,
+            [Signal::SetSDP("offer".to_owned()), done()],
+            &config,
+        )
+        .unwrap();
+        a.poll(&clock, &config, &[], 0.0);
+        b.send_signal(&clock, [Signal::SetSDP("answer".to_owned())], &config)
+            .unwrap();
+
+        let expected = a.next_poll() + Duration::from_secs(config.connect);
+        let at = connect_at(&b.pull_signals(&clock, &[a], &config));
+        assert_eq!(at, Some(expected));
+    }
+
+    #[test]
+    fn no_connect_before_any_ice_done() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (mut a, mut b, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = a.next_poll();
+        a.send_signal(&clock, [Signal::SetSDP("offer".to_owned())], &config)
+            .unwrap();
+        b.send_signal(&clock, [Signal::SetSDP("answer".to_owned())], &config)
+            .unwrap();
+
+        assert!(connect_at(&b.pull_signals(&clock, &[a], &config)).is_none());
+    }
+
+    #[test]
+    fn connect_cancelled_when_peer_dies_first() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (mut a, mut b, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = a.next_poll();
+        a.send_signal(
+            &clock,
+            [Signal::SetSDP("offer".to_owned()), done()],
+            &config,
+        )
+        .unwrap();
+        b.send_signal(&clock, [Signal::SetSDP("answer".to_owned())], &config)
+            .unwrap();
+        let peers = [a];
+        assert!(connect_at(&b.pull_signals(&clock, &peers, &config)).is_some());
+
+        // `a` never polled again to read its own
+        clock.advance(config.grace_period);
+        let pulled = b.pull_signals(&clock, &peers, &config);
+        assert!(matches!(
+            pulled[..],
+            [
+                Signal::Room(_),
+                Signal::ConnectCancelled(0),
+                Signal::PeerGone(0),
+                ..
+            ]
+        ));
+        let pulled = b.pull_signals(&clock, &peers, &config);
+        assert!(!pulled
+            .iter()
+            .any(|s| matches!(s, Signal::ConnectCancelled(_) | Signal::PeerGone(_))));
+    }
+
+    #[test]
+    fn peer_joined_once() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (mut a, mut b, room) = pair(&mut SeededKeys(1), &clock, &config);
+        let joined = |slot: u8| move |s: &Signal| matches!(s, Signal::PeerJoined(n) if *n == slot);
+
+        let mut pulled = vec![];
+        for _ in 0..3 {
+            a.set_peers(&clock, &room);
+            b.set_peers(&clock, &room);
+            pulled.push((
+                a.pull_signals(&clock, slice::from_ref(&b), &config),
+                b.pull_signals(&clock, slice::from_ref(&a), &config),
+            ));
+            clock.advance(1);
+        }
+        let (to_a, to_b): (Vec<_>, Vec<_>) = pulled.into_iter().unzip();
+        assert_eq!(count(&to_a[0], joined(1)), 1);
+        assert_eq!(count(&to_b[0], joined(0)), 1);
+        assert_eq!(count(&to_a.concat(), joined(1)), 1);
+        assert_eq!(count(&to_b.concat(), joined(0)), 1);
+    }
+
+    #[test]
+    fn peer_gone_once() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (a, mut b, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = a.next_poll();
+        let peers = [a];
+        let gone = |s: &Signal| matches!(s, Signal::PeerGone(_));
+        assert_eq!(count(&b.pull_signals(&clock, &peers, &config), gone), 0);
+
+        // `a` stops polling
+        clock.advance(config.grace_period);
+        assert!(b.has_unread(&clock, &peers, &config));
+        let pulled = b.pull_signals(&clock, &peers, &config);
+        assert_eq!(count(&pulled, |s| matches!(s, Signal::PeerGone(0))), 1);
+        for _ in 0..3 {
+            clock.advance(config.poll);
+            assert!(!b.has_unread(&clock, &peers, &config));
+            assert_eq!(count(&b.pull_signals(&clock, &peers, &config), gone), 0);
+        }
+    }
+
+    #[test]
+    fn connect_cancelled_when_peer_dies_before_connecting() {
+        let config = Config {
+            connect: Config::default().grace_period * 2,
+            ..Default::default()
         };
-        match &self.meta.room {
-            Some(k) => keys.push(Room::get_bucket_key(k)),
-            None => {}
+        let mut clock = FakeClock::default();
+        let (mut a, mut b, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = a.next_poll();
+        a.send_signal(
+            &clock,
+            [Signal::SetSDP("offer".to_owned()), done()],
+            &config,
+        )
+        .unwrap();
+        b.send_signal(&clock, [Signal::SetSDP("answer".to_owned())], &config)
+            .unwrap();
+        assert!(connect_at(&b.pull_signals(&clock, slice::from_ref(&a), &config)).is_some());
+        assert!(connect_at(&a.pull_signals(&clock, slice::from_ref(&b), &config)).is_some());
+
+        // `a` read its own but stopped polling before the time came
+        clock.advance(config.grace_period);
+        let peers = [a];
+        let pulled = b.pull_signals(&clock, &peers, &config);
+        assert_eq!(
+            count(&pulled, |s| matches!(s, Signal::ConnectCancelled(0))),
+            1
+        );
+        let pulled = b.pull_signals(&clock, &peers, &config);
+        assert_eq!(
+            count(&pulled, |s| matches!(s, Signal::ConnectCancelled(_))),
+            0
+        );
+    }
+
+    #[test]
+    fn session_states() {
+        let config = Config::default();
+        let mut keys = SeededKeys(1);
+        let mut clock = FakeClock::default();
+        let alone = auth(&mut keys, &clock, &config);
+        let (mut a, mut b, _) = pair(&mut keys, &clock, &config);
+        clock.0 = a.next_poll();
+        assert_eq!(
+            alone.session_state(&clock, &[], &config),
+            SessionState::Created
+        );
+        assert_eq!(b.session_state(&clock, &[], &config), SessionState::Joined);
+
+        a.send_signal(
+            &clock,
+            [Signal::SetSDP("offer".to_owned()), done()],
+            &config,
+        )
+        .unwrap();
+        let peers = [a];
+        assert_eq!(
+            b.session_state(&clock, &peers, &config),
+            SessionState::Negotiating
+        );
+
+        b.send_signal(&clock, [Signal::SetSDP("answer".to_owned())], &config)
+            .unwrap();
+        let at = connect_at(&b.pull_signals(&clock, &peers, &config)).unwrap();
+        assert_eq!(
+            b.session_state(&clock, &peers, &config),
+            SessionState::Scheduled
+        );
+
+        clock.0 = at;
+        assert_eq!(
+            b.session_state(&clock, &peers, &config),
+            SessionState::Connected
+        );
+
+        b.send_signal(&clock, [done()], &config).unwrap();
+        assert_eq!(b.session_state(&clock, &peers, &config), SessionState::Done);
+
+        b.expire(&clock);
+        assert_eq!(
+            b.session_state(&clock, &peers, &config),
+            SessionState::Expired
+        );
+    }
+
+    #[test]
+    fn expired_once_polls_stop() {
+        let config = Config::default();
+        let mut clock = FakeClock::default();
+        let (a, b, _) = pair(&mut SeededKeys(1), &clock, &config);
+        clock.0 = a.next_poll();
+        let peers = [b];
+        assert_eq!(
+            a.session_state(&clock, &peers, &config),
+            SessionState::Joined
+        );
+
+        clock.advance(config.grace_period);
+        assert_eq!(
+            a.session_state(&clock, &peers, &config),
+            SessionState::Expired
+        );
+    }
+
+    #[test]
+    fn redelivered_until_acked() {
+        let config = Config::default();
+        let clock = FakeClock::default();
+        let (_, mut b, room) = pair(&mut SeededKeys(1), &clock, &config);
+        let joined = |s: &Signal| matches!(s, Signal::PeerJoined(_));
+        let owner = |s: &Signal| matches!(s, Signal::RoomOwner);
+        b.ack(&[Signal::Ack(0)]);
+        let first = b.pull_signals(&clock, &[], &config);
+        assert!(matches!(first[0], Signal::Seq(1)));
+        assert_eq!(count(&first, joined), 1);
+
+        b.notify(&room.key, Signal::RoomOwner);
+        let second = b.pull_signals(&clock, &[], &config);
+        assert!(matches!(second[0], Signal::Seq(2)));
+        assert_eq!((count(&second, joined), count(&second, owner)), (1, 1));
+
+        // The second response got lost
+        b.ack(&[Signal::Ack(1)]);
+        let third = b.pull_signals(&clock, &[], &config);
+        assert!(matches!(third[0], Signal::Seq(3)));
+        assert_eq!((count(&third, joined), count(&third, owner)), (0, 1));
+
+        b.ack(&[Signal::Ack(3)]);
+        let fourth = b.pull_signals(&clock, &[], &config);
+        assert_eq!((count(&fourth, joined), count(&fourth, owner)), (0, 0));
+    }
+
+    #[test]
+    fn unacked_capped() {
+        let config = Config::default();
+        let clock = FakeClock::default();
+        let (_, mut b, room) = pair(&mut SeededKeys(1), &clock, &config);
+        let notice = |s: &Signal| matches!(s, Signal::JoinRequest(_));
+        b.ack(&[Signal::Ack(0)]);
+        for _ in 0..3 {
+            for _ in 0..MAX_UNACKED / 2 {
+                b.notify(&room.key, Signal::JoinRequest(String::new()));
+            }
+            b.pull_signals(&clock, &[], &config);
+        }
+
+        // Just the last pull, the one before would go over
+        let pulled = b.pull_signals(&clock, &[], &config);
+        assert_eq!(count(&pulled, notice), MAX_UNACKED / 2);
+    }
+
+    #[test]
+    fn replay_counts_against_max_pull() {
+        let config = Config {
+            max_pull: 4,
+            ..Default::default()
         };
-        keys
+        let clock = FakeClock::default();
+        let (mut a, mut b, room) = pair(&mut SeededKeys(1), &clock, &config);
+        b.set_capabilities(Capabilities {
+            relay: true,
+            ..Default::default()
+        });
+        let relays = (0..8).map(|i| Signal::Relay(vec![i]));
+        a.send_signal(&clock, relays, &config).unwrap();
+        let peers = [a];
+        let relay = |s: &Signal| matches!(s, Signal::Relay(_));
+        let more = |s: &Signal| matches!(s, Signal::HasMore);
+        b.ack(&[Signal::Ack(0)]);
+        let first = b.pull_signals(&clock, &peers, &config);
+        assert_eq!((count(&first, relay), count(&first, more)), (4, 1));
+
+        // Nothing new while the first four are redelivered
+        let second = b.pull_signals(&clock, &peers, &config);
+        assert!(matches!(second[0], Signal::Seq(2)));
+        assert_eq!((count(&second, relay), count(&second, more)), (4, 1));
+
+        // Over the budget with another group, only the oldest is handed out
+        b.notify(&room.key, Signal::RoomOwner);
+        b.pull_signals(&clock, &peers, &config);
+        let backlog = b.pull_signals(&clock, &peers, &config);
+        assert!(matches!(backlog[0], Signal::Seq(1)));
+        assert_eq!(count(&backlog, |s| matches!(s, Signal::RoomOwner)), 0);
+        assert_eq!((count(&backlog, relay), count(&backlog, more)), (4, 1));
+
+        b.ack(&[Signal::Ack(3)]);
+        let rest = b.pull_signals(&clock, &peers, &config);
+        assert_eq!((count(&rest, relay), count(&rest, more)), (4, 0));
+    }
+
+    #[test]
+    fn remembered_over_earlier_reply() {
+        let config = Config::default();
+        let clock = FakeClock::default();
+        let mut a = auth(&mut SeededKeys(1), &clock, &config);
+        a.remember("key".to_owned(), &[Signal::NextPoll(clock.0)]);
+        a.remember("key".to_owned(), &[Signal::RoomOwner]);
+        let replay = a.replay("key").unwrap();
+        assert_eq!(replay.len(), 1);
+        assert!(matches!(replay[0], Signal::RoomOwner));
+    }
+
+    #[test]
+    fn legacy_prefixes_skip_live_buckets() {
+        let now = SystemTime::now();
+        let hours = |n: u64| Duration::from_secs(n * 3600);
+        let prefixes = legacy_prefixes(now, now + hours(24));
+        let listed = |key: &str| prefixes.iter().any(|p| key.starts_with(p.as_str()));
+
+        assert!(listed("ZZZZZZZZ"));
+        assert!(listed(&format!("{}Z", &expiry_bucket(now + hours(1))[..9])));
+        assert!(listed(&expiry_bucket(now + hours(24 * 365 * 100))));
+        for n in 0..=24 {
+            assert!(!listed(&expiry_bucket(now + hours(n))), "{}", n);
+        }
+    }
+
+    #[test]
+    fn handed_over_session() {
+        let config = Config::default();
+        let clock = FakeClock::default();
+        let (mut a, mut b, mut room) = pair(&mut SeededKeys(1), &clock, &config);
+        let old = b.key.clone();
+        let mut heir = b.hand_over(&clock, "HEIR".to_owned());
+        room.rekey(&old, &heir.key);
+        a.rename_peer(&old, &heir.key);
+        assert!(!b.is_alive(&clock, &config));
+        assert_eq!(
+            b.get_keys_to_kill(&clock, &config),
+            [Auth::get_bucket_key(&old)]
+        );
+        assert_eq!(room.get_members(), [a.key.as_str(), "HEIR"]);
+        assert_eq!(a.get_peers(), ["HEIR"]);
+
+        // Nobody left, the links carry on
+        a.set_peers(&clock, &room);
+        heir.set_peers(&clock, &room);
+        a.send_signal(&clock, vec![Signal::SetSDP("offer".to_owned())], &config)
+            .unwrap();
+        let pulled = heir.pull_signals(&clock, &[a], &config);
+        assert!(pulled
+            .iter()
+            .any(|s| matches!(s, Signal::SetSDP(sdp) if sdp == "offer")));
+        assert_eq!(count(&pulled, |s| matches!(s, Signal::PeerLeft(_))), 0);
     }
 }
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{Env, Request, Response, Result};
+
+use crate::{
+    audit::{audit, Action},
+    clock::SystemClock,
+    config::Config,
+    db::{
+        secs, storage, time, BucketInfo, Corrupted, Data, Metadata, Storage, DEFAULT_LIMIT,
+        MAX_LIMIT,
+    },
+    error::ApiError,
+    room::{room_key, Room},
+    token,
+};
+
+/// Abuse report a peer filed against another, keyed `{millis}:{random}` so
+/// listings come out in time order, everything is in the metadata.
+pub type Report = Data<(), ReportMetadata, ReportInfo>;
+
+pub struct ReportInfo {}
+impl BucketInfo for ReportInfo {
+    const PREFIX: &'static str = "report";
+    const KEY_LENGTH: u8 = 8;
+}
+
+/// Ban of a token or IP from a service, keyed `{service}:{kind}:{value}`.
+pub type Ban = Data<(), BanMetadata, BanInfo>;
+
+pub struct BanInfo {}
+impl BucketInfo for BanInfo {
+    const PREFIX: &'static str = "ban";
+}
+
+/// Bytes of a report's or ban's reason, they're kept in the metadata.
+const MAX_REASON: usize = 256;
+
+/// Hash reports and token bans name sessions by, so neither holds a token.
+pub fn token_hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// What a ban matches.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    /// `token_hash` of a session
+    Token,
+    Ip,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Token => "token",
+            Self::Ip => "ip",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Self::Token, Self::Ip]
+            .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+fn ban_key(service: &str, kind: Kind, value: &str) -> String {
+    format!("{}:{}:{}", service, kind.name(), value)
+}
+
+pub struct ReportMetadata {
+    service: String,
+    // code of the room both were in
+    room: String,
+    reporter: String,
+    reported: String,
+    reason: String,
+    at: SystemTime,
+    expire_at: SystemTime,
+}
+impl Default for ReportMetadata {
+    fn default() -> Self {
+        ReportMetadata {
+            service: String::new(),
+            room: String::new(),
+            reporter: String::new(),
+            reported: String::new(),
+            reason: String::new(),
+            at: SystemTime::now(),
+            expire_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Metadata for ReportMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        Some(self.expire_at)
+    }
+}
+impl TryFrom<HashMap<String, String>> for ReportMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let text = |name: &str| value.get(name).cloned().unwrap_or_default();
+        Ok(ReportMetadata {
+            service: text("service"),
+            room: text("room"),
+            reporter: text("reporter"),
+            reported: text("reported"),
+            reason: text("reason"),
+            at: time(&value, "at").unwrap_or(UNIX_EPOCH),
+            expire_at: time(&value, "expire_at").unwrap_or(UNIX_EPOCH),
+        })
+    }
+}
+impl From<ReportMetadata> for HashMap<String, String> {
+    fn from(value: ReportMetadata) -> Self {
+        HashMap::from([
+            ("service".to_owned(), value.service),
+            ("room".to_owned(), value.room),
+            ("reporter".to_owned(), value.reporter),
+            ("reported".to_owned(), value.reported),
+            ("reason".to_owned(), value.reason),
+            ("at".to_owned(), secs(value.at).to_string()),
+            ("expire_at".to_owned(), secs(value.expire_at).to_string()),
+        ])
+    }
+}
+
+impl Report {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.meta.expire_at
+    }
+}
+
+pub struct BanMetadata {
+    reason: String,
+    at: SystemTime,
+    // `None` for good
+    expire_at: Option<SystemTime>,
+}
+impl Default for BanMetadata {
+    fn default() -> Self {
+        BanMetadata {
+            reason: String::new(),
+            at: SystemTime::now(),
+            expire_at: None,
+        }
+    }
+}
+
+impl Metadata for BanMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        self.expire_at
+    }
+}
+impl TryFrom<HashMap<String, String>> for BanMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        Ok(BanMetadata {
+            reason: value.get("reason").cloned().unwrap_or_default(),
+            at: time(&value, "at").unwrap_or(UNIX_EPOCH),
+            expire_at: time(&value, "expire_at"),
+        })
+    }
+}
+impl From<BanMetadata> for HashMap<String, String> {
+    fn from(value: BanMetadata) -> Self {
+        let mut map = HashMap::new();
+        map.insert("reason".to_owned(), value.reason);
+        map.insert("at".to_owned(), secs(value.at).to_string());
+        if let Some(expire_at) = value.expire_at {
+            map.insert("expire_at".to_owned(), secs(expire_at).to_string());
+        }
+        map
+    }
+}
+
+impl Ban {
+    pub fn is_expired(&self) -> bool {
+        self.meta
+            .expire_at
+            .is_some_and(|at| SystemTime::now() >= at)
+    }
+}
+
+/// Whether the session `key` or `ip` is banned from `service`, checked by
+/// `/ident` and every join.
+pub async fn is_banned(
+    storage: &dyn Storage,
+    service: &str,
+    key: Option<&str>,
+    ip: Option<&str>,
+) -> Result<bool> {
+    let bans = [
+        key.map(|key| ban_key(service, Kind::Token, &token_hash(key))),
+        ip.map(|ip| ban_key(service, Kind::Ip, ip)),
+    ];
+    for key in bans.iter().flatten() {
+        if Ban::load(storage, key)
+            .await?
+            .is_some_and(|ban| !ban.is_expired())
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[derive(Deserialize)]
+struct ReportRequest {
+    /// Room of the peer, needed when in several
+    code: Option<String>,
+    /// Slot of the peer, needed when the room has several
+    slot: Option<u8>,
+    #[serde(default)]
+    reason: String,
+}
+
+/// Flags a peer of the caller for abuse, for admins to review at `/admin/reports`.
+pub async fn report_peer(mut req: Request, env: Env) -> Result<Response> {
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => token,
+        None => return ApiError::MissingToken.into_response(),
+    };
+    let body = match req.json::<ReportRequest>().await {
+        Ok(b) => b,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+    };
+    if body.reason.len() > MAX_REASON {
+        return ApiError::TooLarge("reason").into_response();
+    }
+
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&SystemClock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    let service = match user.get_service() {
+        Some(service) => service.clone(),
+        None => return ApiError::RoomExpired.into_response(),
+    };
+    let key = match (body.code, user.get_rooms()) {
+        (Some(code), rooms) if rooms.contains(&room_key(&service, &code)) => {
+            room_key(&service, &code)
+        }
+        (Some(_), _) => return ApiError::RoomUnknown.into_response(),
+        (None, [key]) => key.clone(),
+        (None, []) => return ApiError::RoomExpired.into_response(),
+        (None, _) => {
+            return ApiError::Malformed("code needed when in several rooms".to_owned())
+                .into_response()
+        }
+    };
+    let room = match Room::load(&*storage, &key).await? {
+        Some(room) if !room.is_expired(&SystemClock) => room,
+        _ => return ApiError::RoomExpired.into_response(),
+    };
+    let peers = room.get_peers(&user);
+    let reported = match (body.slot, peers.as_slice()) {
+        (Some(slot), peers) => peers.iter().find(|(s, _)| *s == slot).map(|(_, k)| k),
+        (None, [(_, key)]) => Some(key),
+        (None, []) => None,
+        (None, _) => {
+            return ApiError::Malformed("slot needed with several peers".to_owned()).into_response()
+        }
+    };
+    let reported = match reported {
+        Some(reported) => reported,
+        None => return ApiError::Malformed("no such peer".to_owned()).into_response(),
+    };
+
+    let now = SystemTime::now();
+    let mut report = Report::create_at(now)?;
+    report.meta = ReportMetadata {
+        room: room.code().to_owned(),
+        service,
+        reporter: token_hash(&user.key),
+        reported: token_hash(reported),
+        reason: body.reason,
+        at: now,
+        expire_at: now + Duration::from_secs(config.report_ttl),
+    };
+    if !report.write(&*storage).await? {
+        return ApiError::Conflict.into_response();
+    }
+    Ok(Response::empty()?.with_status(204))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    service: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Entry of `/admin/reports`.
+#[derive(Serialize)]
+struct ReportSummary<'a> {
+    service: &'a str,
+    room: &'a str,
+    reporter: &'a str,
+    /// What a token ban takes
+    reported: &'a str,
+    reason: &'a str,
+    at: SystemTime,
+}
+
+/// Recent reports, newest first, `?service=` picking one and `?limit=` how many.
+pub async fn reports(req: &Request, storage: &dyn Storage) -> Result<Response> {
+    let query = req.query::<ListQuery>().unwrap_or(ListQuery {
+        service: None,
+        limit: None,
+    });
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let reports: Vec<Report> = storage
+        .list(ReportInfo::PREFIX)
+        .await?
+        .into_iter()
+        .filter_map(|entry| Report::read(entry).ok())
+        .filter(|report| !report.is_expired())
+        .filter(|report| {
+            query
+                .service
+                .as_ref()
+                .is_none_or(|svc| report.meta.service == *svc)
+        })
+        .collect();
+    let summaries: Vec<_> = reports
+        .iter()
+        .rev()
+        .take(limit)
+        .map(|report| ReportSummary {
+            service: &report.meta.service,
+            room: &report.meta.room,
+            reporter: &report.meta.reporter,
+            reported: &report.meta.reported,
+            reason: &report.meta.reason,
+            at: report.meta.at,
+        })
+        .collect();
+    Response::from_json(&summaries)
+}
+
+/// Entry of `/admin/bans`, and the body `POST /admin/bans` answers with.
+#[derive(Serialize)]
+struct BanSummary<'a> {
+    service: &'a str,
+    kind: Kind,
+    value: &'a str,
+    reason: &'a str,
+    at: SystemTime,
+    /// Banned for good without one
+    expire_at: Option<SystemTime>,
+}
+
+impl Ban {
+    fn summary(&self) -> Option<BanSummary<'_>> {
+        let mut parts = self.key.splitn(3, ':');
+        let (service, kind, value) = (parts.next()?, parts.next()?, parts.next()?);
+        Some(BanSummary {
+            service,
+            kind: Kind::from_name(kind)?,
+            value,
+            reason: &self.meta.reason,
+            at: self.meta.at,
+            expire_at: self.meta.expire_at,
+        })
+    }
+}
+
+/// Bans in force, `?service=` picking one.
+pub async fn bans(req: &Request, storage: &dyn Storage) -> Result<Response> {
+    let service = req.query::<ListQuery>().ok().and_then(|q| q.service);
+    let bans: Vec<Ban> = storage
+        .list(BanInfo::PREFIX)
+        .await?
+        .into_iter()
+        .filter_map(|entry| Ban::read(entry).ok())
+        .filter(|ban| !ban.is_expired())
+        .collect();
+    let summaries: Vec<_> = bans
+        .iter()
+        .filter_map(|ban| ban.summary())
+        .filter(|ban| service.as_ref().is_none_or(|svc| ban.service == *svc))
+        .collect();
+    Response::from_json(&summaries)
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    service: String,
+    kind: Kind,
+    /// The reported hash of a report for token bans
+    value: String,
+    #[serde(default)]
+    reason: String,
+    /// Seconds, banned for good without it
+    ttl: Option<u64>,
+}
+
+/// Bans what the body says, a ban that's there already is replaced.
+pub async fn ban(mut req: Request, storage: &dyn Storage, config: &Config) -> Result<Response> {
+    let body = match req.json::<BanRequest>().await {
+        Ok(b) => b,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+    };
+    if body.reason.len() > MAX_REASON {
+        return ApiError::TooLarge("reason").into_response();
+    }
+    let key = ban_key(&body.service, body.kind, &body.value);
+    let mut ban = match Ban::load(storage, &key).await? {
+        Some(ban) => ban,
+        None => Ban::unsaved(key.clone()),
+    };
+    let now = SystemTime::now();
+    ban.meta = BanMetadata {
+        reason: body.reason,
+        at: now,
+        expire_at: body.ttl.map(|ttl| now + Duration::from_secs(ttl)),
+    };
+    ban.modified = true;
+    let res = Response::from_json(&ban.summary())?;
+    if !ban.write(storage).await? {
+        return ApiError::Conflict.into_response();
+    }
+    audit(storage, config, Action::Banned, "admin", &key).await;
+    Ok(res.with_status(201))
+}
+
+/// Lifts the ban of `value`.
+pub async fn lift(
+    storage: &dyn Storage,
+    config: &Config,
+    service: &str,
+    kind: &str,
+    value: &str,
+) -> Result<Response> {
+    let kind = match Kind::from_name(kind) {
+        Some(kind) => kind,
+        None => return ApiError::NotFound.into_response(),
+    };
+    let key = ban_key(service, kind, value);
+    match Ban::load(storage, &key).await? {
+        Some(ban) => ban.delete(storage).await?,
+        None => return ApiError::NotFound.into_response(),
+    }
+    audit(storage, config, Action::Unbanned, "admin", &key).await;
+    Ok(Response::empty()?.with_status(204))
+}
@@ -0,0 +1,15 @@
+use web_time::SystemTime;
+
+/// Source of the current time, so time-dependent logic can run on a simulated clock.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// `Date.now()`, what everything but simulations runs on.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
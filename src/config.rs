@@ -0,0 +1,272 @@
+use std::{collections::HashMap, str::FromStr};
+
+use serde::Deserialize;
+use worker::{console_log, Env, Result};
+
+use crate::{db::BucketInfo, room::RoomInfo};
+
+/// KV namespace of runtime flags, its `profiles` key holds the profiles unless the var does.
+const BINDING: &str = "FLAGS";
+const PROFILES_KEY: &str = "profiles";
+
+/// Tunables read from the environment, every duration is in seconds.
+#[derive(Clone)]
+pub struct Config {
+    /// Time a client may be late to its next poll before it's considered gone
+    pub grace_period: u64,
+    /// Lifetime of a token
+    pub max_connection: u64,
+    /// Time left to a token's kill from which polls get a `Signal::ExpiresIn`
+    pub expiry_warning: u64,
+    /// Longest a token can be refreshed to last, counted from its ident
+    pub max_session: u64,
+    pub first_poll: u64,
+    /// Poll interval while waiting for peers
+    pub poll: u64,
+    /// Poll interval once there are peers
+    pub fast_poll: u64,
+    /// Delay between a peer's next poll and the scheduled connection
+    pub connect: u64,
+    pub max_peers: u8,
+    /// Spectators a room takes on top of its members
+    pub max_spectators: u8,
+    /// Largest `Signal::Relay` message, in bytes
+    pub max_relay_size: usize,
+    /// Bytes a token may relay during its lifetime
+    pub relay_quota: usize,
+    /// Largest `Signal::Stats` report, in bytes of JSON
+    pub max_stats_size: usize,
+    /// Shortest time between two `Signal::Stats` of a token
+    pub stats_interval: u64,
+    /// Largest `Signal::SetRoomMeta`, in bytes of JSON
+    pub max_room_meta_size: usize,
+    /// Longest a room can be reserved for
+    pub max_room_ttl: u64,
+    /// Longest a room lasts, members or not
+    pub max_room_duration: u64,
+    /// How long joining a deleted room answers `ROOM_EXPIRED` rather than `ROOM_UNKNOWN`
+    pub tombstone_ttl: u64,
+    /// Largest `Signal::SetSDP`, in bytes
+    pub max_sdp_size: usize,
+    /// Candidates queued per peer
+    pub max_candidates: usize,
+    /// Signals queued per peer
+    pub max_queue: usize,
+    /// Tokens a single `/poll/batch` may poll for
+    pub max_batch: usize,
+    /// Queued signals one pull hands out, 0 for no cap. The rest follow a
+    /// `Signal::HasMore` on the next pulls
+    pub max_pull: usize,
+    /// Longest a `/poll?wait=` is held, below `grace_period` so peers don't take it for gone
+    pub max_wait: u64,
+    /// Recent storage errors before clients are told to back off
+    pub max_errors: u64,
+    /// Longest poll interval a backoff stretches to
+    pub max_backoff: u64,
+    /// Tokens an IP may get per `quota_window`, 0 for no cap
+    pub ident_quota: u32,
+    /// Rooms an IP may create per `quota_window`, 0 for no cap
+    pub room_quota: u32,
+    pub quota_window: u64,
+    /// How long a `/transfer/start` code can be claimed
+    pub transfer_ttl: u64,
+    /// Longest an `/invite` can be redeemed for
+    pub invite_ttl: u64,
+    /// Auths the cron keeps written ahead for `/ident`, none by default
+    pub key_pool: usize,
+    /// How long audit records are kept
+    pub audit_ttl: u64,
+    /// How long abuse reports are kept
+    pub report_ttl: u64,
+    /// How long signals peers expired without reading are kept, none by default
+    pub dead_letter_ttl: u64,
+    /// Copy SDPs held by `Signal::HoldCode` into the room, so answerers get
+    /// them in the poll that joins
+    pub cache_offers: bool,
+    /// `,` separated filters signals go through, see `filter::chain`
+    pub signal_filters: String,
+    /// `;` separated words the `profanity` filter drops relays for
+    pub blocked_words: String,
+    /// Applies the `relay_only` filter, so no address but a TURN relay's
+    /// reaches the peers
+    pub force_relay: bool,
+    /// Characters random room codes are made of
+    pub room_alphabet: String,
+    pub room_code_length: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            grace_period: 20,
+            max_connection: 3600,
+            expiry_warning: 300,
+            max_session: 24 * 3600,
+            first_poll: 1,
+            poll: 10,
+            fast_poll: 1,
+            connect: 5,
+            max_peers: 2,
+            max_spectators: 8,
+            max_relay_size: 1024,
+            relay_quota: 64 * 1024,
+            max_stats_size: 512,
+            stats_interval: 2,
+            max_room_meta_size: 1024,
+            max_room_ttl: 24 * 3600,
+            max_room_duration: 24 * 3600,
+            tombstone_ttl: 3600,
+            max_sdp_size: 16 * 1024,
+            max_candidates: 64,
+            max_queue: 256,
+            max_batch: 16,
+            max_pull: 0,
+            max_wait: 15,
+            max_errors: 30,
+            max_backoff: 30,
+            ident_quota: 100,
+            room_quota: 50,
+            quota_window: 3600,
+            transfer_ttl: 120,
+            invite_ttl: 24 * 3600,
+            key_pool: 0,
+            audit_ttl: 30 * 24 * 3600,
+            report_ttl: 30 * 24 * 3600,
+            dead_letter_ttl: 0,
+            cache_offers: false,
+            signal_filters: String::new(),
+            blocked_words: String::new(),
+            force_relay: false,
+            room_alphabet: RoomInfo::ALPHABET.to_owned(),
+            room_code_length: RoomInfo::KEY_LENGTH,
+        }
+    }
+}
+
+/// Tunables a service gets instead of the deployment's, left out ones stay as they are.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Profile {
+    pub max_connection: Option<u64>,
+    pub max_session: Option<u64>,
+    pub first_poll: Option<u64>,
+    pub poll: Option<u64>,
+    pub fast_poll: Option<u64>,
+    pub connect: Option<u64>,
+    pub max_peers: Option<u8>,
+    pub force_relay: Option<bool>,
+}
+
+/// Profiles keyed by service name, as JSON in the `PROFILES` var or the
+/// `profiles` key of the `FLAGS` namespace.
+///
+/// Profiles that don't parse are logged and ignored.
+pub async fn profiles(env: &Env) -> Result<HashMap<String, Profile>> {
+    let json = match env.var("PROFILES").map(|v| v.to_string()) {
+        Ok(json) if !json.is_empty() => Some(json),
+        _ => match env.kv(BINDING) {
+            Ok(store) => store.get(PROFILES_KEY).text().await?,
+            Err(_) => None,
+        },
+    };
+    let json = match json {
+        Some(json) => json,
+        None => return Ok(HashMap::new()),
+    };
+    match serde_json::from_str(&json) {
+        Ok(profiles) => Ok(profiles),
+        Err(e) => {
+            console_log!("couldn't parse the profiles: {}", e);
+            Ok(HashMap::new())
+        }
+    }
+}
+
+fn var<T: FromStr>(env: &Env, name: &str, default: T) -> T {
+    env.var(name)
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(default)
+}
+
+impl Config {
+    pub fn from_env(env: &Env) -> Self {
+        let default = Config::default();
+
+        Config {
+            grace_period: var(env, "GRACE_PERIOD", default.grace_period),
+            max_connection: var(env, "MAX_CONNECTION", default.max_connection),
+            expiry_warning: var(env, "EXPIRY_WARNING", default.expiry_warning),
+            max_session: var(env, "MAX_SESSION", default.max_session),
+            first_poll: var(env, "FIRST_POLL", default.first_poll),
+            poll: var(env, "POLL", default.poll),
+            fast_poll: var(env, "FAST_POLL", default.fast_poll),
+            connect: var(env, "CONNECT", default.connect),
+            max_peers: var(env, "MAX_PEERS", default.max_peers),
+            max_spectators: var(env, "MAX_SPECTATORS", default.max_spectators),
+            max_relay_size: var(env, "MAX_RELAY_SIZE", default.max_relay_size),
+            relay_quota: var(env, "RELAY_QUOTA", default.relay_quota),
+            max_stats_size: var(env, "MAX_STATS_SIZE", default.max_stats_size),
+            stats_interval: var(env, "STATS_INTERVAL", default.stats_interval),
+            max_room_meta_size: var(env, "MAX_ROOM_META_SIZE", default.max_room_meta_size),
+            max_room_ttl: var(env, "MAX_ROOM_TTL", default.max_room_ttl),
+            max_room_duration: var(env, "MAX_ROOM_DURATION", default.max_room_duration),
+            tombstone_ttl: var(env, "TOMBSTONE_TTL", default.tombstone_ttl),
+            max_sdp_size: var(env, "MAX_SDP_SIZE", default.max_sdp_size),
+            max_candidates: var(env, "MAX_CANDIDATES", default.max_candidates),
+            max_queue: var(env, "MAX_QUEUE", default.max_queue),
+            max_batch: var(env, "MAX_BATCH", default.max_batch),
+            max_pull: var(env, "MAX_PULL", default.max_pull),
+            max_wait: var(env, "MAX_WAIT", default.max_wait),
+            max_errors: var(env, "MAX_ERRORS", default.max_errors),
+            max_backoff: var(env, "MAX_BACKOFF", default.max_backoff),
+            ident_quota: var(env, "IDENT_QUOTA", default.ident_quota),
+            room_quota: var(env, "ROOM_QUOTA", default.room_quota),
+            quota_window: var(env, "QUOTA_WINDOW", default.quota_window),
+            transfer_ttl: var(env, "TRANSFER_TTL", default.transfer_ttl),
+            invite_ttl: var(env, "INVITE_TTL", default.invite_ttl),
+            key_pool: var(env, "KEY_POOL", default.key_pool),
+            audit_ttl: var(env, "AUDIT_TTL", default.audit_ttl),
+            report_ttl: var(env, "REPORT_TTL", default.report_ttl),
+            dead_letter_ttl: var(env, "DEAD_LETTER_TTL", default.dead_letter_ttl),
+            cache_offers: var(env, "CACHE_OFFERS", default.cache_offers),
+            signal_filters: var(env, "SIGNAL_FILTERS", default.signal_filters),
+            blocked_words: var(env, "BLOCKED_WORDS", default.blocked_words),
+            force_relay: var(env, "FORCE_RELAY", default.force_relay),
+            room_alphabet: Some(var(env, "ROOM_ALPHABET", default.room_alphabet.clone()))
+                .filter(|a| !a.is_empty() && a.chars().all(|c| c.is_ascii_alphanumeric()))
+                .unwrap_or(default.room_alphabet),
+            room_code_length: Some(var(env, "ROOM_CODE_LENGTH", default.room_code_length))
+                .filter(|l| *l > 0)
+                .unwrap_or(default.room_code_length),
+        }
+    }
+
+    /// `from_env` with the overrides of the session's `profile`, the
+    /// deployment's own tunables if there's none or it's gone since.
+    pub async fn for_profile(env: &Env, profile: Option<&str>) -> Result<Self> {
+        let config = Self::from_env(env);
+        let profile = match profile {
+            Some(profile) => profile,
+            None => return Ok(config),
+        };
+        Ok(match profiles(env).await?.get(profile) {
+            Some(profile) => config.with_profile(profile),
+            None => config,
+        })
+    }
+
+    pub fn with_profile(self, profile: &Profile) -> Self {
+        Config {
+            max_connection: profile.max_connection.unwrap_or(self.max_connection),
+            max_session: profile.max_session.unwrap_or(self.max_session),
+            first_poll: profile.first_poll.unwrap_or(self.first_poll),
+            poll: profile.poll.unwrap_or(self.poll),
+            fast_poll: profile.fast_poll.unwrap_or(self.fast_poll),
+            connect: profile.connect.unwrap_or(self.connect),
+            max_peers: profile.max_peers.unwrap_or(self.max_peers),
+            force_relay: profile.force_relay.unwrap_or(self.force_relay),
+            ..self
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use web_time::SystemTime;
+use worker::{
+    async_trait, js_sys, wasm_bindgen::JsValue, D1Database, D1PreparedStatement, Env, Error, Result,
+};
+
+use crate::db::{secs, Entry, Page, Put, Storage};
+
+const BINDING: &str = "DB";
+const PAGE_SIZE: usize = 1000;
+/// Error of a batch whose guarded write didn't go through
+const GUARD_FAILED: &str = "CHECK constraint failed";
+/// One per bucket, see `migrations/` for their columns.
+const TABLES: [&str; 11] = [
+    "auth", "room", "lobby", "tomb", "audit", "ban", "report", "xfer", "pool", "invite", "dead",
+];
+
+#[derive(Deserialize)]
+struct Row {
+    key: String,
+    // JSON object
+    meta: String,
+    #[serde(default)]
+    body: Option<Vec<u8>>,
+    version: u64,
+}
+
+impl From<Row> for Entry {
+    fn from(row: Row) -> Self {
+        Entry {
+            key: row.key,
+            meta: serde_json::from_str(&row.meta).unwrap_or_default(),
+            body: row.body,
+            version: row.version.to_string(),
+        }
+    }
+}
+
+/// Table of a key or prefix, named after the bucket it starts with.
+fn table(key: &str) -> Result<&'static str> {
+    let bucket = key.split(':').next().unwrap_or_default();
+    TABLES
+        .into_iter()
+        .find(|t| *t == bucket)
+        .ok_or_else(|| Error::RustError(format!("no table for {}", key)))
+}
+
+/// Stores every bucket in its own D1 table, with the expiry in an indexed column.
+///
+/// The tables hold objects like the other backends do, it's no relational
+/// model: rows keep the encoded object and its metadata, rooms and peers aren't
+/// columns. Writes are conditional on the row's version, and `put_all` runs as
+/// one transaction, so a join lands in the room and the auth or in neither.
+///
+/// `cleanup` still lists rooms and auths page by page to find the dead ones,
+/// only the other buckets are left to `purge_expired`, one statement per table.
+pub struct D1Storage {
+    db: D1Database,
+}
+
+impl D1Storage {
+    pub fn new(env: &Env) -> Result<Self> {
+        Ok(Self {
+            db: env.d1(BINDING)?,
+        })
+    }
+
+    fn query(&self, sql: String, values: &[JsValue]) -> Result<D1PreparedStatement> {
+        self.db.prepare(sql).bind(values)
+    }
+
+    /// Statement storing `put` that returns the new version, `None` if its
+    /// version can't be the row's.
+    fn write(&self, put: Put) -> Result<Option<D1PreparedStatement>> {
+        let table = table(&put.key)?;
+        let key: JsValue = put.key.into();
+        let meta: JsValue = serde_json::to_string(&put.meta).unwrap().into();
+        let body: JsValue = js_sys::Uint8Array::from(&put.body[..]).into();
+        let expire_at = put
+            .expire_at
+            .map_or(JsValue::NULL, |t| (secs(t) as f64).into());
+
+        let statement = match put.version {
+            Some(version) => {
+                let version = match version.parse::<u64>() {
+                    Ok(version) => version as f64,
+                    Err(_) => return Ok(None),
+                };
+                let sql = format!(
+                    "UPDATE {} SET meta = ?2, body = ?3, expire_at = ?4, version = version + 1 \
+                     WHERE key = ?1 AND version = ?5 RETURNING version",
+                    table
+                );
+                self.query(sql, &[key, meta, body, expire_at, version.into()])?
+            }
+            None => {
+                let sql = format!(
+                    "INSERT INTO {} (key, meta, body, expire_at, version) VALUES (?1, ?2, ?3, ?4, 0) \
+                     ON CONFLICT (key) DO NOTHING RETURNING version",
+                    table
+                );
+                self.query(sql, &[key, meta, body, expire_at])?
+            }
+        };
+        Ok(Some(statement))
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Storage for D1Storage {
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let sql = format!("SELECT 1 AS found FROM {} WHERE key = ?1", table(key)?);
+        let found: Option<u8> = self.query(sql, &[key.into()])?.first(Some("found")).await?;
+        Ok(found.is_some())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Entry>> {
+        let sql = format!(
+            "SELECT key, meta, body, version FROM {} WHERE key = ?1",
+            table(key)?
+        );
+        let row: Option<Row> = self.query(sql, &[key.into()])?.first(None).await?;
+        Ok(row.map(Entry::from))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        meta: HashMap<String, String>,
+        version: Option<&str>,
+        expire_at: Option<SystemTime>,
+    ) -> Result<bool> {
+        let put = Put {
+            key: key.to_owned(),
+            body,
+            meta,
+            version: version.map(str::to_owned),
+            expire_at,
+        };
+        let statement = match self.write(put)? {
+            Some(statement) => statement,
+            None => return Ok(false),
+        };
+        // Nothing returned when the precondition failed
+        let written: Option<u64> = statement.first(Some("version")).await?;
+        Ok(written.is_some())
+    }
+
+    async fn put_all(&self, puts: Vec<Put>) -> Result<bool> {
+        let mut statements = vec![];
+        for put in puts {
+            match self.write(put)? {
+                Some(statement) => statements.push(statement),
+                None => return Ok(false),
+            }
+            // Fails the batch when the write above changed no row, see `0009_guard.sql`
+            statements.push(self.db.prepare("UPDATE guard SET ok = changes()"));
+        }
+        match self.db.batch(statements).await {
+            Ok(_) => Ok(true),
+            // Rolled back as a whole
+            Err(e) if e.to_string().contains(GUARD_FAILED) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let sql = format!("DELETE FROM {} WHERE key = ?1", table(key)?);
+        self.query(sql, &[key.into()])?.run().await?;
+        Ok(())
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page> {
+        let sql = format!(
+            "SELECT key, meta, version FROM {} \
+             WHERE substr(key, 1, length(?1)) = ?1 AND key > ?2 ORDER BY key LIMIT {}",
+            table(prefix)?,
+            PAGE_SIZE
+        );
+        let after = cursor.unwrap_or_default();
+        let rows: Vec<Row> = self
+            .query(sql, &[prefix.into(), after.into()])?
+            .all()
+            .await?
+            .results()?;
+
+        let entries: Vec<Entry> = rows.into_iter().map(Entry::from).collect();
+        // Resumes after the last key
+        let cursor = match entries.last() {
+            Some(entry) if entries.len() == PAGE_SIZE => Some(entry.key.clone()),
+            _ => None,
+        };
+        Ok(Page { entries, cursor })
+    }
+
+    fn expires(&self) -> bool {
+        true
+    }
+
+    async fn purge_expired(&self) -> Result<()> {
+        let now = secs(SystemTime::now()) as f64;
+        let mut statements = vec![];
+        for table in TABLES.iter() {
+            let sql = format!("DELETE FROM {} WHERE expire_at <= ?1", table);
+            statements.push(self.query(sql, &[now.into()])?);
+        }
+        // One transaction for every table
+        self.db.batch(statements).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::table;
+    use crate::{
+        audit::RecordInfo,
+        auth::AuthInfo,
+        ban::{BanInfo, ReportInfo},
+        db::BucketInfo,
+        deadletter::DeadLetterInfo,
+        invite::InviteInfo,
+        lobby::ListingInfo,
+        pool::PoolInfo,
+        room::{RoomInfo, TombstoneInfo},
+        transfer::TransferInfo,
+    };
+
+    #[test]
+    fn every_bucket_has_a_table() {
+        let prefixes = [
+            AuthInfo::PREFIX,
+            RoomInfo::PREFIX,
+            ListingInfo::PREFIX,
+            TombstoneInfo::PREFIX,
+            RecordInfo::PREFIX,
+            BanInfo::PREFIX,
+            ReportInfo::PREFIX,
+            TransferInfo::PREFIX,
+            PoolInfo::PREFIX,
+            InviteInfo::PREFIX,
+            DeadLetterInfo::PREFIX,
+        ];
+        for prefix in prefixes {
+            assert_eq!(table(&format!("{}:key", prefix)).unwrap(), prefix);
+        }
+    }
+}
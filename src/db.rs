@@ -1,23 +1,340 @@
-use std::{collections::HashMap, marker::PhantomData};
+use std::{collections::HashMap, fmt, marker::PhantomData};
 
-use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
 use serde::{de::DeserializeOwned, Serialize};
-use web_time::{SystemTime, UNIX_EPOCH};
-use worker::{Bucket, Object, Result};
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{
+    async_trait, console_log, js_sys, wasm_bindgen::JsCast, wasm_bindgen_futures::JsFuture,
+    worker_sys, Bucket, Env, Include, Result,
+};
 
-fn random_string(rng: &mut impl Rng, len: u8) -> String {
-    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+#[cfg(any(test, feature = "memory"))]
+use crate::memory::MemoryStorage;
+use crate::{
+    d1::D1Storage,
+    durable::DurableStorage,
+    keys::{CryptoKeys, KeyGenerator, ALPHANUMERIC},
+    kv::KvStorage,
+    replica::{ReplicatedStorage, REPLICA_BINDING},
+};
 
-    (0..len)
-        .map(|_| *ALPHABET.choose(rng).unwrap() as char)
-        .collect()
+/// Rows admin listings return unless `?limit=` asks otherwise, and at most.
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+/// Seconds since the epoch, how metadata and rows keep times.
+pub fn secs(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_secs()
+}
+
+/// Time kept in the metadata under `name` by `secs`.
+pub fn time(meta: &HashMap<String, String>, name: &str) -> Option<SystemTime> {
+    meta.get(name)
+        .and_then(|v| v.parse().ok())
+        .map(|v| UNIX_EPOCH + Duration::from_secs(v))
+}
+
+/// A stored object, listings leave `body` empty.
+pub struct Entry {
+    pub key: String,
+    pub meta: HashMap<String, String>,
+    pub body: Option<Vec<u8>>,
+    /// Changes on every write
+    pub version: String,
+}
+
+/// One write of `Storage::put_all`, on the same precondition as `put`.
+pub struct Put {
+    pub key: String,
+    pub body: Vec<u8>,
+    pub meta: HashMap<String, String>,
+    pub version: Option<String>,
+    pub expire_at: Option<SystemTime>,
+}
+
+/// One page of a listing, `cursor` is set when there's more to fetch.
+pub struct Page {
+    pub entries: Vec<Entry>,
+    pub cursor: Option<String>,
+}
+
+#[async_trait::async_trait(?Send)]
+pub trait Storage {
+    async fn exists(&self, key: &str) -> Result<bool>;
+    async fn get(&self, key: &str) -> Result<Option<Entry>>;
+    /// Writes only if the stored version is still `version`, or if there's no object when `None`.
+    ///
+    /// Returns `false` when the precondition failed. Backends that `expires` drop
+    /// the object by themselves after `expire_at`.
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        meta: HashMap<String, String>,
+        version: Option<&str>,
+        expire_at: Option<SystemTime>,
+    ) -> Result<bool>;
+    /// Writes all of `puts` or none of them, returning `false` if any precondition failed.
+    ///
+    /// Backends without transactions write them one after another, so the
+    /// earlier ones stay written when a later one fails.
+    async fn put_all(&self, puts: Vec<Put>) -> Result<bool> {
+        for put in puts {
+            let version = put.version.as_deref();
+            if !self
+                .put(&put.key, put.body, put.meta, version, put.expire_at)
+                .await?
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    async fn delete(&self, key: &str) -> Result<()>;
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page>;
+
+    /// Whether objects are deleted after their expiry without `cleanup`.
+    fn expires(&self) -> bool {
+        false
+    }
+
+    /// Deletes what's past its expiry, run by the cron after `cleanup` for
+    /// backends that `expires` but don't do it by themselves. Rooms and auths
+    /// are cleaned up by listing them either way.
+    async fn purge_expired(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Lists everything under `prefix`, following the cursors.
+    async fn list(&self, prefix: &str) -> Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut cursor = None;
+        loop {
+            let page = self.list_page(prefix, cursor).await?;
+            entries.extend(page.entries);
+            cursor = page.cursor;
+            if cursor.is_none() {
+                return Ok(entries);
+            }
+        }
+    }
+}
+
+/// Precondition of an R2 write.
+pub enum Condition<'a> {
+    /// The stored etag
+    Version(&'a str),
+    /// Only if there's no object yet
+    Absent,
+    /// Overwrites whatever is there
+    Any,
+}
+
+/// Writes to R2, returning `false` when `condition` failed.
+pub async fn put_object(
+    bucket: &Bucket,
+    key: &str,
+    body: Vec<u8>,
+    meta: HashMap<String, String>,
+    condition: Condition<'_>,
+    expire_at: Option<SystemTime>,
+) -> Result<bool> {
+    // The bindings' put builder can't set `onlyIf`, so build the options by hand
+    let only_if = js_sys::Object::new();
+    match condition {
+        Condition::Version(etag) => {
+            js_sys::Reflect::set(&only_if, &"etagMatches".into(), &etag.into())?;
+        }
+        Condition::Absent => {
+            js_sys::Reflect::set(&only_if, &"etagDoesNotMatch".into(), &"*".into())?;
+        }
+        Condition::Any => {}
+    };
+    let custom_metadata = js_sys::Object::new();
+    for (k, v) in meta.iter() {
+        js_sys::Reflect::set(&custom_metadata, &k.into(), &v.into())?;
+    }
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(&options, &"onlyIf".into(), &only_if)?;
+    js_sys::Reflect::set(&options, &"customMetadata".into(), &custom_metadata)?;
+    if let Some(expire_at) = expire_at {
+        // For lifecycle rules and anything reading the object over HTTP
+        let millis = expire_at
+            .duration_since(UNIX_EPOCH)
+            .expect("time travel on expire_at?")
+            .as_millis() as f64;
+        let http_metadata = js_sys::Object::new();
+        let cache_expiry = js_sys::Date::new(&millis.into());
+        js_sys::Reflect::set(&http_metadata, &"cacheExpiry".into(), &cache_expiry)?;
+        js_sys::Reflect::set(&options, &"httpMetadata".into(), &http_metadata)?;
+    }
+
+    let inner: &worker_sys::R2Bucket = bucket.as_ref().unchecked_ref();
+    let value = js_sys::Uint8Array::from(&body[..]);
+    let promise = inner.put(key.to_owned(), value.into(), options.into())?;
+    // R2 resolves to null when the precondition fails
+    Ok(!JsFuture::from(promise).await?.is_null())
+}
+
+#[async_trait::async_trait(?Send)]
+impl Storage for Bucket {
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.head(key).await?.is_some())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Entry>> {
+        let obj = match Bucket::get(self, key).execute().await? {
+            Some(obj) => obj,
+            None => return Ok(None),
+        };
+        let body = match obj.body() {
+            Some(b) => Some(b.bytes().await?),
+            None => None,
+        };
+
+        Ok(Some(Entry {
+            key: obj.key(),
+            meta: obj.custom_metadata()?,
+            body,
+            version: obj.etag(),
+        }))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        meta: HashMap<String, String>,
+        version: Option<&str>,
+        expire_at: Option<SystemTime>,
+    ) -> Result<bool> {
+        let condition = match version {
+            Some(etag) => Condition::Version(etag),
+            None => Condition::Absent,
+        };
+        put_object(self, key, body, meta, condition, expire_at).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        Bucket::delete(self, key).await
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page> {
+        let mut builder = Bucket::list(self)
+            .prefix(prefix)
+            .include(vec![Include::CustomMetadata]);
+        if let Some(cursor) = cursor {
+            builder = builder.cursor(cursor);
+        }
+        let objects = builder.execute().await?;
+
+        let entries = objects
+            .objects()
+            .iter()
+            .map(|obj| {
+                Ok(Entry {
+                    key: obj.key(),
+                    meta: obj.custom_metadata()?,
+                    body: None,
+                    version: obj.etag(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Page {
+            entries,
+            cursor: objects.cursor().filter(|_| objects.truncated()),
+        })
+    }
+}
+
+/// Picks the storage backend configured by the `STORAGE` var, defaulting to R2.
+///
+/// R2 is replicated to the replica bucket when it's bound.
+pub fn storage(env: &Env) -> Result<Box<dyn Storage>> {
+    let backend = env.var("STORAGE").map(|v| v.to_string()).ok();
+    match backend.as_deref() {
+        Some("durable") => Ok(Box::new(DurableStorage::new(env)?)),
+        Some("kv") => Ok(Box::new(KvStorage::new(env)?)),
+        Some("d1") => Ok(Box::new(D1Storage::new(env)?)),
+        #[cfg(any(test, feature = "memory"))]
+        Some("memory") => Ok(Box::new(MemoryStorage::shared())),
+        #[cfg(not(any(test, feature = "memory")))]
+        Some("memory") => Err(worker::Error::RustError(
+            "memory storage needs the memory feature".to_owned(),
+        )),
+        _ => match env.bucket(REPLICA_BINDING) {
+            Ok(replica) => Ok(Box::new(ReplicatedStorage::new(
+                env.bucket("rtc")?,
+                replica,
+            ))),
+            Err(_) => Ok(Box::new(env.bucket("rtc")?)),
+        },
+    }
+}
+
+/// Why a stored object can't be read back, it's treated as expired.
+#[derive(Debug)]
+pub struct Corrupted(pub String);
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupted object: {}", self.0)
+    }
+}
+
+pub trait Metadata:
+    TryFrom<HashMap<String, String>, Error = Corrupted> + Into<HashMap<String, String>>
+{
+    /// When the object can be dropped, for backends that expire objects.
+    fn expire_at(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+/// Metadata of objects that only keep when they expire.
+pub struct ExpiringMetadata {
+    pub expire_at: SystemTime,
+}
+impl Default for ExpiringMetadata {
+    fn default() -> Self {
+        ExpiringMetadata {
+            expire_at: SystemTime::now(),
+        }
+    }
 }
 
-pub trait Metadata: From<HashMap<String, String>> + Into<HashMap<String, String>> {}
+impl Metadata for ExpiringMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        Some(self.expire_at)
+    }
+}
+impl TryFrom<HashMap<String, String>> for ExpiringMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let expire_at =
+            time(&value, "expire_at").ok_or_else(|| Corrupted("missing expire_at".to_owned()))?;
+        Ok(ExpiringMetadata { expire_at })
+    }
+}
+impl From<ExpiringMetadata> for HashMap<String, String> {
+    fn from(value: ExpiringMetadata) -> Self {
+        HashMap::from([("expire_at".to_owned(), secs(value.expire_at).to_string())])
+    }
+}
 
 pub trait BucketInfo {
     const PREFIX: &'static str = "";
     const KEY_LENGTH: u8 = 0;
+    /// Characters random keys are drawn from
+    const ALPHABET: &'static str = ALPHANUMERIC;
+}
+
+/// Alphabet and length of new random keys.
+pub struct KeySpec<'a> {
+    pub alphabet: &'a str,
+    pub length: u8,
 }
 
 pub struct Data<O, M, B> {
@@ -25,6 +342,8 @@ pub struct Data<O, M, B> {
     pub key: String,
     pub data: Option<O>,
     pub meta: M,
+    // version it was read at, `None` if never stored
+    version: Option<String>,
     info: PhantomData<B>,
 }
 
@@ -38,81 +357,132 @@ where
         format!("{}:{}", B::PREFIX, key)
     }
 
-    fn remove_prefix(key: String) -> String {
+    fn remove_prefix(key: String) -> std::result::Result<String, Corrupted> {
         key.get((B::PREFIX.len() + 1)..)
-            .expect("invalid key")
-            .to_owned()
+            .map(str::to_owned)
+            .ok_or_else(|| Corrupted(format!("invalid key {}", key)))
     }
 
-    async fn new_key(bucket: &Bucket) -> Result<String> {
-        let now = SystemTime::now()
+    /// The bucket's own alphabet and length.
+    pub fn key_spec() -> KeySpec<'static> {
+        KeySpec {
+            alphabet: B::ALPHABET,
+            length: B::KEY_LENGTH,
+        }
+    }
+
+    /// New object stored as `namespace:key` under a random key, so keys only need
+    /// to be unique per namespace.
+    ///
+    /// Nothing is read to check the key is free. Storing a new object only works
+    /// while there's none under its key, so callers retry short keys with another
+    /// one on a lost write, and long ones don't collide in practice.
+    pub fn create_in(namespace: &str) -> Result<Self> {
+        Self::create_with(&mut CryptoKeys, &Self::key_spec(), Some(namespace))
+    }
+
+    /// New object keyed `{millis}:{random}` by `at`, so listings come out in time order.
+    pub fn create_at(at: SystemTime) -> Result<Self> {
+        let millis = at
             .duration_since(UNIX_EPOCH)
             .expect("time travel?")
-            .as_secs();
-        let mut rng = SmallRng::seed_from_u64(now);
-
-        loop {
-            let key = random_string(&mut rng, B::KEY_LENGTH);
+            .as_millis();
+        Self::create_in(&format!("{:013}", millis))
+    }
 
-            // Retry if object already exists
-            let obj = bucket.head(Self::get_bucket_key(&key)).await?;
-            if obj.is_none() {
-                return Ok(key);
-            }
-        }
+    /// `create_in` drawing the key from `keys`, as `spec` says.
+    pub fn create_with(
+        keys: &mut impl KeyGenerator,
+        spec: &KeySpec<'_>,
+        namespace: Option<&str>,
+    ) -> Result<Self> {
+        let random = keys.key_in(spec.alphabet, spec.length)?;
+        let key = match namespace {
+            Some(namespace) => format!("{}:{}", namespace, random),
+            None => random,
+        };
+        Ok(Self::unsaved(key))
     }
 
-    pub async fn create(bucket: &Bucket) -> Result<Self> {
-        let key = Self::new_key(bucket).await?;
-        Ok(Self {
+    /// New object under a key picked by the caller.
+    pub fn unsaved(key: String) -> Self {
+        Self {
             modified: true,
             key,
             data: Some(Default::default()),
             meta: Default::default(),
+            version: None,
             info: PhantomData,
-        })
+        }
     }
 
-    pub async fn load(bucket: &Bucket, key: &str) -> Result<Option<Self>> {
-        match bucket.get(Self::get_bucket_key(key)).execute().await? {
-            Some(obj) => Ok(Some(Self::_read(key.to_owned(), &obj).await?)),
+    /// `None` for corrupted objects too, cleanup deletes them like expired ones.
+    pub async fn load(storage: &dyn Storage, key: &str) -> Result<Option<Self>> {
+        match storage.get(&Self::get_bucket_key(key)).await? {
+            Some(entry) => match Self::_read(key.to_owned(), entry) {
+                Ok(object) => Ok(Some(object)),
+                Err(e) => {
+                    console_log!("couldn't read {}: {}", Self::get_bucket_key(key), e);
+                    Ok(None)
+                }
+            },
             None => Ok(None),
         }
     }
 
-    async fn _read(key: String, obj: &Object) -> Result<Self> {
-        let meta: M = obj.custom_metadata()?.into();
-        let data = match obj.body() {
-            Some(b) => Some(b.bytes().await?),
+    fn _read(key: String, entry: Entry) -> std::result::Result<Self, Corrupted> {
+        let meta = M::try_from(entry.meta)?;
+        let data = match entry.body {
+            Some(d) => Some(serde_bare::de::from_slice(&d).map_err(|e| Corrupted(e.to_string()))?),
             None => None,
         };
-        let data = data.map(|d| serde_bare::de::from_slice(&d).unwrap());
 
         Ok(Self {
             modified: false,
             key,
             data,
             meta,
+            version: Some(entry.version),
             info: PhantomData,
         })
     }
 
-    pub async fn read(obj: &Object) -> Result<Self> {
-        Self::_read(Self::remove_prefix(obj.key()), obj).await
+    pub fn read(entry: Entry) -> std::result::Result<Self, Corrupted> {
+        Self::_read(Self::remove_prefix(entry.key.clone())?, entry)
+    }
+
+    pub async fn delete(self, storage: &dyn Storage) -> Result<()> {
+        storage.delete(&Self::get_bucket_key(&self.key)).await
     }
 
-    pub async fn write(self, bucket: &Bucket) -> Result<()> {
+    /// Stores the object unless someone else wrote it since it was read.
+    ///
+    /// Returns `false` on such a conflict, the caller should retry from a fresh read.
+    pub async fn write(self, storage: &dyn Storage) -> Result<bool> {
+        match self.into_put() {
+            Some(put) => {
+                let version = put.version.as_deref();
+                storage
+                    .put(&put.key, put.body, put.meta, version, put.expire_at)
+                    .await
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// What `write` would store, for `Storage::put_all`. `None` when unmodified.
+    pub fn into_put(self) -> Option<Put> {
         if !self.modified {
-            return Ok(());
+            return None;
         }
 
-        let key = Self::get_bucket_key(&self.key);
         let data = self.data.as_ref().unwrap();
-        bucket
-            .put(key, serde_bare::ser::to_vec(data).unwrap())
-            .custom_metadata(self.meta)
-            .execute()
-            .await?;
-        Ok(())
+        Some(Put {
+            key: Self::get_bucket_key(&self.key),
+            body: serde_bare::ser::to_vec(data).unwrap(),
+            expire_at: self.meta.expire_at(),
+            meta: self.meta.into(),
+            version: self.version,
+        })
     }
 }
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{console_log, Request, Response, Result};
+
+use crate::{
+    auth::Auth,
+    ban::token_hash,
+    config::Config,
+    db::{secs, time, BucketInfo, Corrupted, Data, Metadata, Storage, DEFAULT_LIMIT, MAX_LIMIT},
+    proto::Signal,
+    room::room_code,
+};
+
+/// Signals queued between peers that expired before reading them, kept for
+/// `DEAD_LETTER_TTL`. Keyed `{millis}:{random}` like reports.
+pub type DeadLetter = Data<DeadLetterData, DeadLetterMetadata, DeadLetterInfo>;
+
+pub struct DeadLetterInfo {}
+impl BucketInfo for DeadLetterInfo {
+    const PREFIX: &'static str = "dead";
+    const KEY_LENGTH: u8 = 8;
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeadLetterData {
+    signals: Vec<Signal>,
+}
+
+pub struct DeadLetterMetadata {
+    service: String,
+    // code of the room they were queued in
+    room: String,
+    // `token_hash` of the sender and the peer it was for
+    from: String,
+    to: String,
+    // the sender's
+    slot: u8,
+    at: SystemTime,
+    expire_at: SystemTime,
+}
+impl Default for DeadLetterMetadata {
+    fn default() -> Self {
+        DeadLetterMetadata {
+            service: String::new(),
+            room: String::new(),
+            from: String::new(),
+            to: String::new(),
+            slot: 0,
+            at: SystemTime::now(),
+            expire_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Metadata for DeadLetterMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        Some(self.expire_at)
+    }
+}
+impl TryFrom<HashMap<String, String>> for DeadLetterMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let text = |name: &str| value.get(name).cloned().unwrap_or_default();
+        Ok(DeadLetterMetadata {
+            service: text("service"),
+            room: text("room"),
+            from: text("from"),
+            to: text("to"),
+            slot: text("slot").parse().unwrap_or_default(),
+            at: time(&value, "at").unwrap_or(UNIX_EPOCH),
+            expire_at: time(&value, "expire_at").unwrap_or(UNIX_EPOCH),
+        })
+    }
+}
+impl From<DeadLetterMetadata> for HashMap<String, String> {
+    fn from(value: DeadLetterMetadata) -> Self {
+        HashMap::from([
+            ("service".to_owned(), value.service),
+            ("room".to_owned(), value.room),
+            ("from".to_owned(), value.from),
+            ("to".to_owned(), value.to),
+            ("slot".to_owned(), value.slot.to_string()),
+            ("at".to_owned(), secs(value.at).to_string()),
+            ("expire_at".to_owned(), secs(value.expire_at).to_string()),
+        ])
+    }
+}
+
+impl DeadLetter {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.meta.expire_at
+    }
+}
+
+/// Keeps what `auth` and its peers never read of each other's queues, before
+/// cleanup deletes them all. Nothing is kept while `DEAD_LETTER_TTL` is 0.
+///
+/// Returns the auths covered, whose own expiry needn't archive them again.
+pub async fn archive(storage: &dyn Storage, config: &Config, auth: Auth) -> Vec<String> {
+    if config.dead_letter_ttl == 0 {
+        return vec![];
+    }
+    let mut group = match auth.load_peers(storage).await {
+        Ok(peers) => peers,
+        Err(e) => {
+            console_log!("couldn't archive undelivered signals: {}", e);
+            return vec![];
+        }
+    };
+    let service = auth.get_service().cloned().unwrap_or_default();
+    // Spectators go alone, their peers carry on
+    let spectating = auth.is_spectator();
+    group.push(auth);
+    let covered = if spectating {
+        &group[group.len() - 1..]
+    } else {
+        &group[..]
+    };
+    let now = SystemTime::now();
+    let undelivered = covered.iter().flat_map(|auth| auth.undelivered(&group));
+    for undelivered in undelivered {
+        let written = async {
+            let mut letter = DeadLetter::create_at(now)?;
+            letter.meta = DeadLetterMetadata {
+                service: service.clone(),
+                room: room_code(&undelivered.room).to_owned(),
+                from: token_hash(&undelivered.from),
+                to: token_hash(&undelivered.to),
+                slot: undelivered.slot,
+                at: now,
+                expire_at: now + Duration::from_secs(config.dead_letter_ttl),
+            };
+            letter.data = Some(DeadLetterData {
+                signals: undelivered.signals,
+            });
+            letter.write(storage).await
+        };
+        if let Err(e) = written.await {
+            console_log!("couldn't archive undelivered signals: {}", e);
+        }
+    }
+    covered.iter().map(|auth| auth.key.clone()).collect()
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    service: Option<String>,
+    /// Room code
+    room: Option<String>,
+    /// `token_hash` of either side
+    token: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Entry of `/admin/dead-letters`.
+#[derive(Serialize)]
+struct DeadLetterSummary<'a> {
+    service: &'a str,
+    room: &'a str,
+    from: &'a str,
+    to: &'a str,
+    slot: u8,
+    at: SystemTime,
+    signals: &'a [Signal],
+}
+
+/// Recent dead letters, newest first, picked by `?service=`, `?room=` and
+/// `?token=` for when an offer never arrived.
+pub async fn dead_letters(req: &Request, storage: &dyn Storage) -> Result<Response> {
+    let query = req.query::<ListQuery>().unwrap_or(ListQuery {
+        service: None,
+        room: None,
+        token: None,
+        limit: None,
+    });
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let matches = |value: &str, wanted: &Option<String>| wanted.as_ref().is_none_or(|w| value == w);
+
+    let keys: Vec<String> = storage
+        .list(DeadLetterInfo::PREFIX)
+        .await?
+        .into_iter()
+        .filter_map(|entry| DeadLetter::read(entry).ok())
+        .filter(|letter| !letter.is_expired())
+        .filter(|letter| {
+            matches(&letter.meta.service, &query.service)
+                && matches(&letter.meta.room, &query.room)
+                && (matches(&letter.meta.from, &query.token)
+                    || matches(&letter.meta.to, &query.token))
+        })
+        .map(|letter| letter.key)
+        .collect();
+    // Listings leave the signals out
+    let mut letters = vec![];
+    for key in keys.iter().rev().take(limit) {
+        if let Some(letter) = DeadLetter::load(storage, key).await? {
+            letters.push(letter);
+        }
+    }
+    let summaries: Vec<_> = letters
+        .iter()
+        .map(|letter| DeadLetterSummary {
+            service: &letter.meta.service,
+            room: &letter.meta.room,
+            from: &letter.meta.from,
+            to: &letter.meta.to,
+            slot: letter.meta.slot,
+            at: letter.meta.at,
+            signals: letter
+                .data
+                .as_ref()
+                .map_or(&[][..], |data| &data.signals[..]),
+        })
+        .collect();
+    Response::from_json(&summaries)
+}
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+
+use futures_util::future::join_all;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use web_time::SystemTime;
+use worker::{
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, Env, Error,
+    ListOptions, Method, ObjectNamespace, Request, RequestInit, Response, Result, State,
+};
+
+use crate::db::{Entry, Page, Put, Storage};
+
+const BINDING: &str = "STORE";
+const PAGE_SIZE: usize = 1000;
+/// Objects the keys are spread over, changing it loses track of what's stored.
+const SHARDS: usize = 16;
+
+/// Keys and metadata of a listed page, along with whether there's more.
+type Listed = (Vec<(String, HashMap<String, String>, u64)>, Option<String>);
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    meta: HashMap<String, String>,
+    body: Vec<u8>,
+    version: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Command {
+    Exists(String),
+    Get(String),
+    Put(String, Record, Option<String>),
+    PutAll(Vec<(String, Record, Option<String>)>),
+    Delete(String),
+    List(String, Option<String>),
+}
+
+/// Spreads the objects over `SHARDS` Durable Objects by their key, each of
+/// which sees its latest write.
+///
+/// `put_all` is a transaction when its writes all land in one shard, across
+/// shards they're written one after another. Pages are listed from every
+/// shard at once and merged, so keys still come in order.
+pub struct DurableStorage {
+    namespace: ObjectNamespace,
+}
+
+/// Shard `key` is stored in.
+fn shard(key: &str) -> usize {
+    Sha256::digest(key.as_bytes())[0] as usize % SHARDS
+}
+
+/// First `PAGE_SIZE` keys of the pages of every shard, and the cursor if any
+/// shard has more. Keys past the last one of a full page may be missing, so
+/// nothing after the first of those is taken.
+fn merge(pages: Vec<Listed>) -> Listed {
+    let more = pages.iter().any(|(_, cursor)| cursor.is_some());
+    let mut records: Vec<_> = pages.into_iter().flat_map(|(records, _)| records).collect();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    records.truncate(PAGE_SIZE);
+    let cursor = match records.last() {
+        Some((key, _, _)) if more => Some(format!("{}\0", key)),
+        _ => None,
+    };
+    (records, cursor)
+}
+
+impl DurableStorage {
+    pub fn new(env: &Env) -> Result<Self> {
+        Ok(DurableStorage {
+            namespace: env.durable_object(BINDING)?,
+        })
+    }
+
+    /// Runs `command` in the object of `shard`, its errors come back as `Error::RustError`.
+    async fn send<T: DeserializeOwned>(&self, shard: usize, command: &Command) -> Result<T> {
+        let body = serde_bare::ser::to_vec(command).map_err(bare_error)?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(js_sys::Uint8Array::from(&body[..]).into()));
+
+        let req = Request::new_with_init("https://storage/", &init)?;
+        let stub = self
+            .namespace
+            .id_from_name(&format!("storage:{}", shard))?
+            .get_stub()?;
+        let mut res = stub.fetch_with_request(req).await?;
+        if res.status_code() != 200 {
+            let message = res.text().await.unwrap_or_default();
+            return Err(Error::RustError(format!(
+                "storage object failed with {}: {}",
+                res.status_code(),
+                message
+            )));
+        }
+        serde_bare::de::from_slice(&res.bytes().await?).map_err(bare_error)
+    }
+}
+
+fn bare_error(e: serde_bare::error::Error) -> Error {
+    Error::RustError(format!("malformed storage command or response: {}", e))
+}
+
+#[async_trait::async_trait(?Send)]
+impl Storage for DurableStorage {
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.send(shard(key), &Command::Exists(key.to_owned()))
+            .await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Entry>> {
+        let record: Option<Record> = self.send(shard(key), &Command::Get(key.to_owned())).await?;
+        Ok(record.map(|r| Entry {
+            key: key.to_owned(),
+            meta: r.meta,
+            body: Some(r.body),
+            version: r.version.to_string(),
+        }))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        meta: HashMap<String, String>,
+        version: Option<&str>,
+        _expire_at: Option<SystemTime>,
+    ) -> Result<bool> {
+        let record = Record {
+            meta,
+            body,
+            version: 0,
+        };
+        let command = Command::Put(key.to_owned(), record, version.map(str::to_owned));
+        self.send(shard(key), &command).await
+    }
+
+    async fn put_all(&self, puts: Vec<Put>) -> Result<bool> {
+        let shards: Vec<usize> = puts.iter().map(|put| shard(&put.key)).collect();
+        if !shards.windows(2).all(|w| w[0] == w[1]) {
+            for put in puts {
+                let version = put.version.as_deref();
+                if !self
+                    .put(&put.key, put.body, put.meta, version, put.expire_at)
+                    .await?
+                {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
+        let shard = match shards.first() {
+            Some(shard) => *shard,
+            None => return Ok(true),
+        };
+        let writes = puts
+            .into_iter()
+            .map(|put| {
+                let record = Record {
+                    meta: put.meta,
+                    body: put.body,
+                    version: 0,
+                };
+                (put.key, record, put.version)
+            })
+            .collect();
+        self.send(shard, &Command::PutAll(writes)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.send(shard(key), &Command::Delete(key.to_owned()))
+            .await
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page> {
+        let command = Command::List(prefix.to_owned(), cursor);
+        let pages = join_all((0..SHARDS).map(|shard| self.send(shard, &command))).await;
+        let (records, cursor) = merge(pages.into_iter().collect::<Result<_>>()?);
+        Ok(Page {
+            entries: records
+                .into_iter()
+                .map(|(key, meta, version)| Entry {
+                    key,
+                    meta,
+                    body: None,
+                    version: version.to_string(),
+                })
+                .collect(),
+            cursor,
+        })
+    }
+}
+
+#[durable_object]
+pub struct StorageObject {
+    state: State,
+}
+
+impl StorageObject {
+    async fn get(&self, key: &str) -> Result<Option<Record>> {
+        self.state.storage().get::<Option<Record>>(key).await
+    }
+
+    /// Writes `record` if the stored version still matches, bumping it.
+    async fn put(&self, key: &str, mut record: Record, version: Option<String>) -> Result<bool> {
+        let current = self.get(key).await?.map(|r| r.version.to_string());
+        if current != version {
+            return Ok(false);
+        }
+
+        record.version = current.map_or(0, |v| v.parse::<u64>().unwrap_or(0) + 1);
+        self.state.storage().put(key, record).await?;
+        Ok(true)
+    }
+
+    /// Writes all of `writes` if every stored version still matches, or none.
+    ///
+    /// Nothing else runs in the object while it waits on its own storage.
+    async fn put_all(&self, writes: Vec<(String, Record, Option<String>)>) -> Result<bool> {
+        let mut records = vec![];
+        for (key, mut record, version) in writes {
+            let current = self.get(&key).await?.map(|r| r.version.to_string());
+            if current != version {
+                return Ok(false);
+            }
+            record.version = current.map_or(0, |v| v.parse::<u64>().unwrap_or(0) + 1);
+            records.push((key, record));
+        }
+
+        let entries = js_sys::Object::new();
+        for (key, record) in records.iter() {
+            let value = serde_wasm_bindgen::to_value(record)?;
+            js_sys::Reflect::set(&entries, &key.into(), &value)?;
+        }
+        self.state.storage().put_multiple_raw(entries).await?;
+        Ok(true)
+    }
+
+    /// Lists up to `PAGE_SIZE` records, starting at the `cursor` key.
+    async fn list(&self, prefix: &str, cursor: Option<String>) -> Result<Listed> {
+        let mut options = ListOptions::new().prefix(prefix).limit(PAGE_SIZE);
+        if let Some(start) = cursor.as_deref() {
+            options = options.start(start);
+        }
+        let map = self.state.storage().list_with_options(options).await?;
+
+        let mut records = vec![];
+        for entry in map.entries() {
+            let entry: js_sys::Array = entry?.into();
+            let key = entry
+                .get(0)
+                .as_string()
+                .ok_or_else(|| Error::RustError("non-string key in storage".to_owned()))?;
+            let record: Record = serde_wasm_bindgen::from_value(entry.get(1))?;
+            records.push((key, record.meta, record.version));
+        }
+
+        // Smallest key after the last one, so the next page doesn't repeat it
+        let cursor = match records.last() {
+            Some((key, _, _)) if records.len() == PAGE_SIZE => Some(format!("{}\0", key)),
+            _ => None,
+        };
+        Ok((records, cursor))
+    }
+}
+
+#[durable_object]
+impl DurableObject for StorageObject {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let command: Command = match serde_bare::de::from_slice(&req.bytes().await?) {
+            Ok(command) => command,
+            Err(e) => return Response::error(format!("Malformed command: {}", e), 400),
+        };
+
+        let mut storage = self.state.storage();
+        let body = match command {
+            Command::Exists(key) => serde_bare::ser::to_vec(&self.get(&key).await?.is_some()),
+            Command::Get(key) => serde_bare::ser::to_vec(&self.get(&key).await?),
+            Command::Put(key, record, version) => {
+                serde_bare::ser::to_vec(&self.put(&key, record, version).await?)
+            }
+            Command::PutAll(writes) => serde_bare::ser::to_vec(&self.put_all(writes).await?),
+            Command::Delete(key) => {
+                storage.delete(&key).await?;
+                serde_bare::ser::to_vec(&())
+            }
+            Command::List(prefix, cursor) => {
+                serde_bare::ser::to_vec(&self.list(&prefix, cursor).await?)
+            }
+        };
+        Response::from_bytes(body.map_err(bare_error)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{merge, Listed, PAGE_SIZE};
+
+    fn page(keys: impl Iterator<Item = String>, more: bool) -> Listed {
+        let records = keys.map(|key| (key, HashMap::new(), 0)).collect();
+        (records, more.then(String::new))
+    }
+
+    #[test]
+    fn merged_up_to_the_first_full_page() {
+        let full = page((0..PAGE_SIZE).map(|i| format!("b:{:04}", i * 2)), true);
+        let short = page((0..10).map(|i| format!("b:{:04}", i * 2 + 1)), false);
+        let (records, cursor) = merge(vec![full, short]);
+
+        assert_eq!(records.len(), PAGE_SIZE);
+        assert!(records.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(records[19].0, "b:0019");
+        assert_eq!(cursor, Some(format!("{}\0", records[PAGE_SIZE - 1].0)));
+
+        let (records, cursor) = merge(vec![page(["b:1".to_owned()].into_iter(), false)]);
+        assert_eq!((records.len(), cursor), (1, None));
+    }
+}
@@ -0,0 +1,225 @@
+use serde::{Serialize, Serializer};
+use worker::{Response, Result};
+
+use crate::proto::{ErrorResponse, PROTOCOL_VERSIONS};
+
+/// Errors returned to clients as `{ "code", "message", "retry_after" }`.
+pub enum ApiError {
+    MethodNotAllowed,
+    NotFound,
+    MissingToken,
+    InvalidToken,
+    Malformed(String),
+    CantSend,
+    NeedService,
+    ServiceNotAllowed,
+    RoomExpired,
+    RoomUnknown,
+    RoomFull,
+    WrongPassword,
+    /// The room only lets in some users, and not this one
+    NotAllowed,
+    ConnectionDone,
+    Conflict,
+    JoinConflict,
+    /// Room signals of one poll contradicting each other
+    ConflictingJoin,
+    InvalidCode,
+    CodeTaken,
+    /// Transfer code that's unknown, expired or claimed already
+    InvalidTransfer,
+    InvalidInvite,
+    /// Names the limit that was hit
+    TooLarge(&'static str),
+    /// Names the part of the candidate that's malformed
+    InvalidCandidate(&'static str),
+    /// Room ICE servers outside of `ICE_ALLOWED`
+    IceServersNotAllowed,
+    ExpectedUpgrade,
+    UnsupportedVersion,
+    RateLimited(u64),
+    /// Too many tokens or rooms from one IP, seconds until there's room again
+    QuotaExceeded(u64),
+    Unavailable(u64),
+    /// Draining for maintenance, no new tokens or rooms
+    Maintenance(u64),
+    Unauthorized,
+    /// `/ident` without a known `X-Api-Key`, once keys are configured
+    InvalidApiKey,
+    /// `/ident` without a solved Turnstile challenge, once it's required
+    ChallengeFailed,
+    /// `/ident` without a valid ID token, once the identity provider is configured
+    InvalidIdToken,
+    /// The token or IP is on the service's ban list
+    Banned,
+    /// Names the signal filter that rejected the poll
+    Filtered(&'static str),
+    ServerError,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MethodNotAllowed => "METHOD_NOT_ALLOWED",
+            Self::NotFound => "NOT_FOUND",
+            Self::MissingToken => "MISSING_TOKEN",
+            Self::InvalidToken => "INVALID_TOKEN",
+            Self::Malformed(_) => "MALFORMED_REQUEST",
+            Self::CantSend => "CANT_SEND",
+            Self::NeedService => "NEED_SERVICE",
+            Self::ServiceNotAllowed => "SERVICE_NOT_ALLOWED",
+            Self::RoomExpired => "ROOM_EXPIRED",
+            Self::RoomUnknown => "ROOM_UNKNOWN",
+            Self::RoomFull => "ROOM_FULL",
+            Self::WrongPassword => "WRONG_PASSWORD",
+            Self::NotAllowed => "NOT_ALLOWED",
+            Self::ConnectionDone => "CONNECTION_DONE",
+            Self::Conflict => "CONFLICT",
+            Self::JoinConflict => "JOIN_CONFLICT",
+            Self::ConflictingJoin => "CONFLICTING_JOIN",
+            Self::InvalidCode => "INVALID_CODE",
+            Self::CodeTaken => "CODE_TAKEN",
+            Self::InvalidTransfer => "INVALID_TRANSFER",
+            Self::InvalidInvite => "INVALID_INVITE",
+            Self::TooLarge(_) => "PAYLOAD_TOO_LARGE",
+            Self::InvalidCandidate(_) => "INVALID_CANDIDATE",
+            Self::IceServersNotAllowed => "ICE_SERVERS_NOT_ALLOWED",
+            Self::ExpectedUpgrade => "EXPECTED_UPGRADE",
+            Self::UnsupportedVersion => "UNSUPPORTED_VERSION",
+            Self::RateLimited(_) => "RATE_LIMITED",
+            Self::QuotaExceeded(_) => "QUOTA_EXCEEDED",
+            Self::Unavailable(_) => "UNAVAILABLE",
+            Self::Maintenance(_) => "MAINTENANCE",
+            Self::Unauthorized => "UNAUTHORIZED",
+            Self::InvalidApiKey => "INVALID_API_KEY",
+            Self::ChallengeFailed => "CHALLENGE_FAILED",
+            Self::InvalidIdToken => "INVALID_ID_TOKEN",
+            Self::Banned => "BANNED",
+            Self::Filtered(_) => "FILTERED",
+            Self::ServerError => "SERVER_ERROR",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::MethodNotAllowed => "Method Not Allowed".to_owned(),
+            Self::NotFound => "Page Not Found".to_owned(),
+            Self::MissingToken => "Missing token.".to_owned(),
+            Self::InvalidToken => "Invalid token.".to_owned(),
+            Self::Malformed(e) => format!("Malformed request: {}", e),
+            Self::CantSend => "Invalid signals: can't send.".to_owned(),
+            Self::NeedService => "Need to set service.".to_owned(),
+            Self::ServiceNotAllowed => "Service not allowed.".to_owned(),
+            Self::RoomExpired => "Room expired.".to_owned(),
+            Self::RoomUnknown => "Unknown room code.".to_owned(),
+            Self::RoomFull => "Room is full.".to_owned(),
+            Self::WrongPassword => "Wrong password.".to_owned(),
+            Self::NotAllowed => "Not allowed in this room.".to_owned(),
+            Self::ConnectionDone => "Connection done.".to_owned(),
+            Self::Conflict => "Concurrent update, retry.".to_owned(),
+            Self::JoinConflict => "Room changed while joining, retry.".to_owned(),
+            Self::ConflictingJoin => "Conflicting room signals in one poll.".to_owned(),
+            Self::InvalidCode => "Invalid room code.".to_owned(),
+            Self::CodeTaken => "Room code is taken.".to_owned(),
+            Self::InvalidTransfer => "Unknown, expired or used transfer code.".to_owned(),
+            Self::InvalidInvite => "Unknown, expired or used invite.".to_owned(),
+            Self::TooLarge(limit) => format!("Payload too large: {} limit exceeded.", limit),
+            Self::InvalidCandidate(part) => format!("Invalid ICE candidate: bad {}.", part),
+            Self::IceServersNotAllowed => "ICE servers not allowed.".to_owned(),
+            Self::ExpectedUpgrade => "Expected websocket upgrade.".to_owned(),
+            Self::UnsupportedVersion => format!(
+                "Unsupported signal version, expected {} to {}.",
+                PROTOCOL_VERSIONS.start(),
+                PROTOCOL_VERSIONS.end()
+            ),
+            Self::RateLimited(_) => "Too Many Requests".to_owned(),
+            Self::QuotaExceeded(_) => "Too many tokens or rooms created, retry later.".to_owned(),
+            Self::Unavailable(_) => "Storage unavailable, retry later.".to_owned(),
+            Self::Maintenance(_) => "Down for maintenance, retry later.".to_owned(),
+            Self::Unauthorized => "Unauthorized".to_owned(),
+            Self::InvalidApiKey => "Missing or unknown API key.".to_owned(),
+            Self::ChallengeFailed => "Missing or failed Turnstile challenge.".to_owned(),
+            Self::InvalidIdToken => "Missing or invalid ID token.".to_owned(),
+            Self::Banned => "Banned from this service.".to_owned(),
+            Self::Filtered(name) => format!("Signal rejected by the {} filter.", name),
+            Self::ServerError => "server logic error.".to_owned(),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::MethodNotAllowed => 405,
+            Self::NotFound => 404,
+            Self::MissingToken => 403,
+            Self::InvalidToken => 403,
+            Self::Malformed(_) => 400,
+            Self::CantSend => 400,
+            Self::NeedService => 400,
+            Self::ServiceNotAllowed => 403,
+            Self::RoomExpired => 400,
+            Self::RoomUnknown => 404,
+            Self::RoomFull => 400,
+            Self::WrongPassword => 403,
+            Self::NotAllowed => 403,
+            Self::ConnectionDone => 400,
+            Self::Conflict => 409,
+            Self::JoinConflict => 409,
+            Self::ConflictingJoin => 400,
+            Self::InvalidCode => 400,
+            Self::CodeTaken => 409,
+            Self::InvalidTransfer => 404,
+            Self::InvalidInvite => 404,
+            Self::TooLarge(_) => 413,
+            Self::InvalidCandidate(_) => 400,
+            Self::IceServersNotAllowed => 403,
+            Self::ExpectedUpgrade => 426,
+            Self::UnsupportedVersion => 400,
+            Self::RateLimited(_) => 429,
+            Self::QuotaExceeded(_) => 429,
+            Self::Unavailable(_) => 503,
+            Self::Maintenance(_) => 503,
+            Self::Unauthorized => 401,
+            Self::InvalidApiKey => 401,
+            Self::ChallengeFailed => 403,
+            Self::InvalidIdToken => 401,
+            Self::Banned => 403,
+            Self::Filtered(_) => 400,
+            Self::ServerError => 500,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::RateLimited(secs) => Some(*secs),
+            Self::QuotaExceeded(secs) => Some(*secs),
+            Self::Unavailable(secs) => Some(*secs),
+            Self::Maintenance(secs) => Some(*secs),
+            Self::JoinConflict => Some(1),
+            _ => None,
+        }
+    }
+
+    pub fn into_response(self) -> Result<Response> {
+        let mut res = Response::from_json(&self)?.with_status(self.status());
+        if let Some(secs) = self.retry_after() {
+            res.headers_mut().set("Retry-After", &secs.to_string())?;
+        }
+        Ok(res)
+    }
+}
+
+impl From<&ApiError> for ErrorResponse {
+    fn from(value: &ApiError) -> Self {
+        ErrorResponse {
+            code: value.code().to_owned(),
+            message: value.message(),
+            retry_after: value.retry_after(),
+        }
+    }
+}
+
+impl Serialize for ApiError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        ErrorResponse::from(self).serialize(serializer)
+    }
+}
@@ -0,0 +1,237 @@
+use worker::console_log;
+
+use crate::{config::Config, proto::Signal};
+
+/// What a filter makes of a signal.
+pub enum Verdict {
+    /// Goes on, maybe rewritten
+    Pass(Signal),
+    /// Left out without telling the sender, like relays over quota
+    Drop,
+    /// Fails the whole poll, naming the filter
+    Reject(&'static str),
+}
+
+/// Check on the signals clients send and pull, `SIGNAL_FILTERS` picks the
+/// ones that apply, in order.
+pub trait Filter {
+    /// On a signal sent, once it's validated and before it's queued.
+    fn send(&self, signal: Signal) -> Verdict {
+        Verdict::Pass(signal)
+    }
+
+    /// On a signal pulled, `None` leaves it out of the response.
+    fn pull(&self, signal: Signal) -> Option<Signal> {
+        Some(signal)
+    }
+}
+
+/// Drops relayed messages with any of `BLOCKED_WORDS` in them, ignoring case.
+struct Profanity {
+    words: Vec<String>,
+}
+
+impl Filter for Profanity {
+    fn send(&self, signal: Signal) -> Verdict {
+        if let Signal::Relay(ref msg) = signal {
+            let text = String::from_utf8_lossy(msg).to_lowercase();
+            if self.words.iter().any(|word| text.contains(word.as_str())) {
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Pass(signal)
+    }
+}
+
+/// Whether the line of a candidate is of type `relay`, the empty end of
+/// candidates counting as one.
+fn is_relay_candidate(line: &str) -> bool {
+    let line = line.strip_prefix("a=").unwrap_or(line);
+    let line = line.strip_prefix("candidate:").unwrap_or(line);
+    line.is_empty() || line.split(' ').nth(7) == Some("relay")
+}
+
+/// Line of an SDP without the address it names, if it has one. Connection
+/// lines get the `0.0.0.0` of trickle ICE, the relay candidates say where to go.
+fn without_address(line: &str) -> Option<String> {
+    let body = line.trim_end();
+    let ending = &line[body.len()..];
+    let (head, family) = if let Some(rest) = body.strip_prefix("a=rtcp:") {
+        let (port, family) = rest.split_once(' ')?;
+        (format!("a=rtcp:{} ", port), family)
+    } else {
+        ("c=".to_owned(), body.strip_prefix("c=")?)
+    };
+    let unspecified = if family.starts_with("IN IP6 ") {
+        "IN IP6 ::"
+    } else if family.starts_with("IN IP4 ") {
+        "IN IP4 0.0.0.0"
+    } else {
+        return None;
+    };
+    Some(format!("{}{}{}", head, unspecified, ending))
+}
+
+/// Keeps candidates to TURN relays, in SDPs too, so peers only ever connect
+/// through them and see no address but the relays'.
+struct RelayOnly;
+
+impl Filter for RelayOnly {
+    fn send(&self, signal: Signal) -> Verdict {
+        match signal {
+            Signal::AddCandidate(ref ice) if !is_relay_candidate(&ice.0) => Verdict::Drop,
+            // Can't be checked
+            Signal::Sealed { .. } => Verdict::Reject("relay_only"),
+            Signal::SetSDP(sdp) => {
+                let lines: Vec<String> = sdp
+                    .split_inclusive('\n')
+                    .filter(|line| {
+                        !line.starts_with("a=candidate:") || is_relay_candidate(line.trim_end())
+                    })
+                    .map(|line| without_address(line).unwrap_or_else(|| line.to_owned()))
+                    .collect();
+                Verdict::Pass(Signal::SetSDP(lines.concat()))
+            }
+            signal => Verdict::Pass(signal),
+        }
+    }
+}
+
+/// Filters named in `SIGNAL_FILTERS`, unknown names are logged and skipped.
+///
+/// `relay_only` comes last for services that force relays without it.
+pub fn chain(config: &Config) -> Vec<Box<dyn Filter>> {
+    let mut filters: Vec<Box<dyn Filter>> = vec![];
+    let names: Vec<&str> = config.signal_filters.split(',').map(str::trim).collect();
+    for name in names.iter().copied() {
+        match name {
+            "" => {}
+            "profanity" => filters.push(Box::new(Profanity {
+                words: config
+                    .blocked_words
+                    .split(';')
+                    .map(|word| word.trim().to_lowercase())
+                    .filter(|word| !word.is_empty())
+                    .collect(),
+            })),
+            "relay_only" => filters.push(Box::new(RelayOnly)),
+            name => console_log!("unknown signal filter {}", name),
+        }
+    }
+    if config.force_relay && !names.contains(&"relay_only") {
+        filters.push(Box::new(RelayOnly));
+    }
+    filters
+}
+
+/// Runs `signal` through the `send` of every filter, the first that doesn't
+/// pass it decides.
+pub fn send(filters: &[Box<dyn Filter>], mut signal: Signal) -> Verdict {
+    for filter in filters.iter() {
+        signal = match filter.send(signal) {
+            Verdict::Pass(signal) => signal,
+            verdict => return verdict,
+        };
+    }
+    Verdict::Pass(signal)
+}
+
+/// Runs `signal` through the `pull` of every filter.
+pub fn pull(filters: &[Box<dyn Filter>], signal: Signal) -> Option<Signal> {
+    filters
+        .iter()
+        .try_fold(signal, |signal, filter| filter.pull(signal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_relay_candidate, without_address, Filter, Profanity, RelayOnly, Verdict};
+    use crate::proto::Signal;
+
+    const HOST: &str = "candidate:1 1 udp 2122260223 192.168.1.2 54321 typ host";
+    const SRFLX: &str =
+        "candidate:2 1 udp 1686052607 203.0.113.7 54321 typ srflx raddr 192.168.1.2 rport 54321";
+    const RELAY: &str =
+        "candidate:3 1 udp 41885439 198.51.100.9 3478 typ relay raddr 203.0.113.7 rport 54321";
+
+    #[test]
+    fn relay_candidates() {
+        assert!(!is_relay_candidate(HOST));
+        assert!(!is_relay_candidate(SRFLX));
+        assert!(is_relay_candidate(RELAY));
+        assert!(is_relay_candidate(&format!("a={}", RELAY)));
+        assert!(!is_relay_candidate(&format!("a={}", HOST)));
+        // End of candidates
+        assert!(is_relay_candidate(""));
+        assert!(is_relay_candidate("a=candidate:"));
+    }
+
+    #[test]
+    fn addresses_left_out() {
+        let cases = [
+            ("c=IN IP4 203.0.113.7\r\n", "c=IN IP4 0.0.0.0\r\n"),
+            ("c=IN IP6 2001:db8::7\r\n", "c=IN IP6 ::\r\n"),
+            (
+                "a=rtcp:9 IN IP4 203.0.113.7\r\n",
+                "a=rtcp:9 IN IP4 0.0.0.0\r\n",
+            ),
+            ("a=rtcp:9 IN IP6 2001:db8::7\n", "a=rtcp:9 IN IP6 ::\n"),
+            ("c=IN IP4 203.0.113.7", "c=IN IP4 0.0.0.0"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(without_address(line).as_deref(), Some(expected));
+        }
+        assert!(without_address("a=rtcp:9\r\n").is_none());
+        assert!(without_address("m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n").is_none());
+        assert!(without_address(&format!("a={}\r\n", RELAY)).is_none());
+    }
+
+    #[test]
+    fn sdp_relays_only() {
+        let sdp = format!(
+            "v=0\r\nc=IN IP4 203.0.113.7\r\na=rtcp:9 IN IP6 2001:db8::7\r\na={}\r\na={}\r\na={}\r\na=end-of-candidates\r\n",
+            HOST, SRFLX, RELAY
+        );
+        let expected = format!(
+            "v=0\r\nc=IN IP4 0.0.0.0\r\na=rtcp:9 IN IP6 ::\r\na={}\r\na=end-of-candidates\r\n",
+            RELAY
+        );
+        assert!(matches!(
+            RelayOnly.send(Signal::SetSDP(sdp)),
+            Verdict::Pass(Signal::SetSDP(sdp)) if sdp == expected
+        ));
+    }
+
+    #[test]
+    fn trickled_relays_only() {
+        let candidate = |line: &str| Signal::AddCandidate((line.to_owned(), None, None));
+        assert!(matches!(RelayOnly.send(candidate(HOST)), Verdict::Drop));
+        assert!(matches!(RelayOnly.send(candidate(SRFLX)), Verdict::Drop));
+        assert!(matches!(
+            RelayOnly.send(candidate(RELAY)),
+            Verdict::Pass(Signal::AddCandidate(_))
+        ));
+        // End of candidates
+        assert!(matches!(
+            RelayOnly.send(candidate("")),
+            Verdict::Pass(Signal::AddCandidate(_))
+        ));
+    }
+
+    #[test]
+    fn profanity_dropped_ignoring_case() {
+        let filter = Profanity {
+            words: vec!["darn".to_owned()],
+        };
+        let relay = |text: &str| Signal::Relay(text.as_bytes().to_vec());
+        assert!(matches!(filter.send(relay("oh DaRn it")), Verdict::Drop));
+        assert!(matches!(
+            filter.send(relay("all good")),
+            Verdict::Pass(Signal::Relay(_))
+        ));
+        assert!(matches!(
+            filter.send(Signal::SetSDP("darn".to_owned())),
+            Verdict::Pass(Signal::SetSDP(_))
+        ));
+    }
+}
@@ -0,0 +1,809 @@
+use web_time::{SystemTime, UNIX_EPOCH};
+use worker::{Context, Env, Headers, Request, Response, Result};
+
+use crate::{
+    error::ApiError,
+    limit::limit,
+    maintenance,
+    poll::{exchange, issue, Caller},
+    proto::{Capabilities, IceCandidate, IceServer, IdentResponse, Signal},
+};
+
+/// Path prefix of the methods, followed by the method name.
+const SERVICE: &str = "/grpc/signalling.v1.Signalling/";
+/// Binary gRPC-Web, with or without `+proto`. The base64 `-text` variant isn't spoken.
+const GRPC_MIME: &str = "application/grpc-web";
+const GRPC_TEXT_MIME: &str = "application/grpc-web-text";
+// frame flags
+const DATA: u8 = 0;
+const TRAILERS: u8 = 0x80;
+// wire types
+const VARINT: u64 = 0;
+const I64: u64 = 1;
+const LEN: u64 = 2;
+const I32: u64 = 5;
+
+type Parsed<T> = std::result::Result<T, ApiError>;
+
+fn malformed() -> ApiError {
+    ApiError::Malformed("invalid protobuf message".to_owned())
+}
+
+fn millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_millis() as u64
+}
+
+/// Protobuf encoder, fields are written even when they hold their default.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.varint((field as u64) << 3 | VARINT);
+        self.varint(value);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.varint((field as u64) << 3 | LEN);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut Writer)) {
+        let mut inner = Writer::default();
+        build(&mut inner);
+        self.bytes(field, &inner.0);
+    }
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn int<T: TryFrom<u64>>(&self) -> Parsed<T> {
+        match self {
+            Self::Varint(value) => T::try_from(*value).map_err(|_| malformed()),
+            Self::Bytes(_) => Err(malformed()),
+        }
+    }
+
+    fn bytes(&self) -> Parsed<&'a [u8]> {
+        match self {
+            Self::Bytes(bytes) => Ok(bytes),
+            Self::Varint(_) => Err(malformed()),
+        }
+    }
+
+    fn string(&self) -> Parsed<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| malformed())
+    }
+}
+
+/// Protobuf decoder, fixed width fields are skipped as no message has any.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.buf.split_first()?;
+            self.buf = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, len: u64) -> Option<&'a [u8]> {
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= self.buf.len())?;
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(taken)
+    }
+
+    /// Next field number and value, `None` at the end of the message.
+    fn field(&mut self) -> Parsed<Option<(u32, Value<'a>)>> {
+        loop {
+            if self.buf.is_empty() {
+                return Ok(None);
+            }
+            let key = self.varint().ok_or_else(malformed)?;
+            let field = (key >> 3) as u32;
+            let value = match key & 7 {
+                VARINT => Value::Varint(self.varint().ok_or_else(malformed)?),
+                LEN => {
+                    let len = self.varint().ok_or_else(malformed)?;
+                    Value::Bytes(self.take(len).ok_or_else(malformed)?)
+                }
+                I64 => {
+                    self.take(8).ok_or_else(malformed)?;
+                    continue;
+                }
+                I32 => {
+                    self.take(4).ok_or_else(malformed)?;
+                    continue;
+                }
+                _ => return Err(malformed()),
+            };
+            return Ok(Some((field, value)));
+        }
+    }
+}
+
+fn write_candidate(w: &mut Writer, (candidate, mid, mline): &IceCandidate) {
+    w.bytes(1, candidate.as_bytes());
+    if let Some(mid) = mid {
+        w.bytes(2, mid.as_bytes());
+    }
+    if let Some(index) = mline {
+        w.uint(3, *index as u64);
+    }
+}
+
+fn write_ice_server(w: &mut Writer, server: &IceServer) {
+    for url in server.urls.iter() {
+        w.bytes(1, url.as_bytes());
+    }
+    if let Some(ref username) = server.username {
+        w.bytes(2, username.as_bytes());
+    }
+    if let Some(ref credential) = server.credential {
+        w.bytes(3, credential.as_bytes());
+    }
+}
+
+/// `IceServers`, with its `IceServer`s on field 1.
+fn read_ice_servers(buf: &[u8]) -> Parsed<Vec<IceServer>> {
+    let mut servers = vec![];
+    let mut reader = Reader { buf };
+    while let Some((field, value)) = reader.field()? {
+        if field != 1 {
+            continue;
+        }
+        let mut server = IceServer {
+            urls: vec![],
+            username: None,
+            credential: None,
+        };
+        let mut fields = Reader {
+            buf: value.bytes()?,
+        };
+        while let Some((field, value)) = fields.field()? {
+            match field {
+                1 => server.urls.push(value.string()?),
+                2 => server.username = Some(value.string()?),
+                3 => server.credential = Some(value.string()?),
+                _ => {}
+            }
+        }
+        servers.push(server);
+    }
+    Ok(servers)
+}
+
+fn read_candidate(buf: &[u8]) -> Parsed<IceCandidate> {
+    let mut ice = (String::new(), None, None);
+    let mut reader = Reader { buf };
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => ice.0 = value.string()?,
+            2 => ice.1 = Some(value.string()?),
+            3 => ice.2 = Some(value.int()?),
+            _ => {}
+        }
+    }
+    Ok(ice)
+}
+
+/// Field numbers are those of `Signal` in `proto/signalling.proto`.
+fn write_signal(w: &mut Writer, signal: &Signal) {
+    match signal {
+        Signal::SetSDP(sdp) => w.bytes(1, sdp.as_bytes()),
+        Signal::AddCandidate(ice) => w.message(2, |w| write_candidate(w, ice)),
+        Signal::JoinRoom(code) => w.bytes(3, code.as_bytes()),
+        Signal::ConnectAt(at) => w.uint(4, millis(*at)),
+        Signal::NextPoll(at) => w.uint(5, millis(*at)),
+        Signal::SetService(service) => w.bytes(6, service.as_bytes()),
+        Signal::Peer(slot) => w.uint(7, *slot as u64),
+        Signal::Renegotiate(generation) => w.uint(8, *generation as u64),
+        Signal::Leave => w.message(9, |_| {}),
+        Signal::PeerLeft(slot) => w.uint(10, *slot as u64),
+        Signal::Password(password) => w.bytes(11, password.as_bytes()),
+        Signal::Relay(message) => w.bytes(12, message),
+        Signal::Room(code) => w.bytes(13, code.as_bytes()),
+        Signal::Role(role) => w.uint(14, *role as u64),
+        Signal::PeerJoined(slot) => w.uint(15, *slot as u64),
+        Signal::PeerGone(slot) => w.uint(16, *slot as u64),
+        Signal::AddCandidates(batch) => w.message(17, |w| {
+            for ice in batch.iter() {
+                w.message(1, |w| write_candidate(w, ice));
+            }
+        }),
+        Signal::Resync => w.message(18, |_| {}),
+        Signal::LinkState(state) => w.message(19, |w| {
+            w.uint(1, state.generation as u64);
+            w.uint(2, state.sent_sdp as u64);
+            w.uint(3, state.ice_done as u64);
+            if let Some(at) = state.connect_at {
+                w.uint(4, millis(at));
+            }
+        }),
+        Signal::RoomOwner => w.message(20, |_| {}),
+        Signal::Kick(slot) => w.uint(21, *slot as u64),
+        Signal::Backoff(backoff) => w.uint(22, *backoff as u64),
+        Signal::PublicKey(key) => w.bytes(23, key),
+        Signal::Sealed { nonce, ciphertext } => w.message(24, |w| {
+            w.bytes(1, nonce);
+            w.bytes(2, ciphertext);
+        }),
+        Signal::Seq(seq) => w.uint(25, *seq),
+        Signal::Ack(seq) => w.uint(26, *seq),
+        Signal::Restart => w.message(27, |_| {}),
+        Signal::Stats(report) => w.bytes(28, report.to_string().as_bytes()),
+        Signal::ReceivedAt(at) => w.uint(29, millis(*at)),
+        Signal::State(state) => w.uint(30, *state as u64),
+        Signal::HoldCode => w.message(31, |_| {}),
+        Signal::SetRoomMeta(metadata) => w.bytes(32, metadata.to_string().as_bytes()),
+        Signal::RoomMeta(metadata) => w.bytes(33, metadata.to_string().as_bytes()),
+        Signal::ExpiresIn(secs) => w.uint(34, *secs),
+        Signal::Spectate => w.message(35, |_| {}),
+        Signal::ConnectCancelled(slot) => w.uint(36, *slot as u64),
+        Signal::SetIceServers(servers) => w.message(37, |w| {
+            for server in servers.iter() {
+                w.message(1, |w| write_ice_server(w, server));
+            }
+        }),
+        Signal::IceServers(servers) => w.message(38, |w| {
+            for server in servers.iter() {
+                w.message(1, |w| write_ice_server(w, server));
+            }
+        }),
+        Signal::ScreenJoins => w.message(39, |_| {}),
+        Signal::JoinRequest(hint) => w.bytes(40, hint.as_bytes()),
+        Signal::Accept(hint) => w.bytes(41, hint.as_bytes()),
+        Signal::HasMore => w.message(42, |_| {}),
+    }
+}
+
+/// Only the signals clients send, the ones only servers send are `CantSend`.
+fn read_signal(buf: &[u8]) -> Parsed<Signal> {
+    let mut signal = None;
+    let mut reader = Reader { buf };
+    while let Some((field, value)) = reader.field()? {
+        // The last member of a oneof wins
+        signal = Some(match field {
+            1 => Signal::SetSDP(value.string()?),
+            2 => Signal::AddCandidate(read_candidate(value.bytes()?)?),
+            3 => Signal::JoinRoom(value.string()?),
+            6 => Signal::SetService(value.string()?),
+            7 => Signal::Peer(value.int()?),
+            8 => Signal::Renegotiate(value.int()?),
+            9 => Signal::Leave,
+            11 => Signal::Password(value.string()?),
+            12 => Signal::Relay(value.bytes()?.to_vec()),
+            13 => Signal::Room(value.string()?),
+            17 => {
+                let mut batch = vec![];
+                let mut candidates = Reader {
+                    buf: value.bytes()?,
+                };
+                while let Some((field, value)) = candidates.field()? {
+                    if field == 1 {
+                        batch.push(read_candidate(value.bytes()?)?);
+                    }
+                }
+                Signal::AddCandidates(batch)
+            }
+            18 => Signal::Resync,
+            21 => Signal::Kick(value.int()?),
+            23 => Signal::PublicKey(value.bytes()?.to_vec()),
+            24 => {
+                let (mut nonce, mut ciphertext) = (vec![], vec![]);
+                let mut sealed = Reader {
+                    buf: value.bytes()?,
+                };
+                while let Some((field, value)) = sealed.field()? {
+                    match field {
+                        1 => nonce = value.bytes()?.to_vec(),
+                        2 => ciphertext = value.bytes()?.to_vec(),
+                        _ => {}
+                    }
+                }
+                Signal::Sealed { nonce, ciphertext }
+            }
+            26 => Signal::Ack(value.int()?),
+            27 => Signal::Restart,
+            28 => {
+                let report = serde_json::from_slice(value.bytes()?).map_err(|_| malformed())?;
+                Signal::Stats(report)
+            }
+            31 => Signal::HoldCode,
+            32 => {
+                let metadata = serde_json::from_slice(value.bytes()?).map_err(|_| malformed())?;
+                Signal::SetRoomMeta(metadata)
+            }
+            35 => Signal::Spectate,
+            37 => Signal::SetIceServers(read_ice_servers(value.bytes()?)?),
+            39 => Signal::ScreenJoins,
+            41 => Signal::Accept(value.string()?),
+            4 | 5 | 10 | 14 | 15 | 16 | 19 | 20 | 22 | 25 | 29 | 30 | 33 | 34 | 36 | 38 | 40
+            | 42 => return Err(ApiError::CantSend),
+            _ => continue,
+        });
+    }
+    signal.ok_or_else(malformed)
+}
+
+/// `IdentRequest`, the service asked for and the client's capabilities.
+fn read_ident(buf: &[u8]) -> Parsed<(Option<String>, Capabilities)> {
+    let mut service = None;
+    let mut capabilities = Capabilities::default();
+    let mut reader = Reader { buf };
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => service = Some(value.string()?),
+            2 => capabilities = read_capabilities(value.bytes()?)?,
+            _ => {}
+        }
+    }
+    Ok((service, capabilities))
+}
+
+/// `Capabilities`, unlike a missing message, unset fields mean not supported.
+fn read_capabilities(buf: &[u8]) -> Parsed<Capabilities> {
+    let mut capabilities = Capabilities {
+        websocket: false,
+        bare: false,
+        relay: false,
+        max_sdp_size: None,
+    };
+    let mut reader = Reader { buf };
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => capabilities.websocket = value.int::<u64>()? != 0,
+            2 => capabilities.bare = value.int::<u64>()? != 0,
+            3 => capabilities.relay = value.int::<u64>()? != 0,
+            4 => capabilities.max_sdp_size = Some(value.int()?),
+            _ => {}
+        }
+    }
+    Ok(capabilities)
+}
+
+fn write_ident(res: &IdentResponse) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes(1, res.token.as_bytes());
+    for server in res.ice_servers.iter() {
+        w.message(2, |w| write_ice_server(w, server));
+    }
+    w.uint(3, res.signal_versions.0 as u64);
+    w.uint(4, res.signal_versions.1 as u64);
+    for transport in res.transports.iter() {
+        w.uint(5, *transport as u64);
+    }
+    let features = &res.features;
+    w.message(6, |w| {
+        w.uint(1, features.websocket as u64);
+        w.uint(2, features.bare as u64);
+        w.uint(3, features.relay as u64);
+        if let Some(max_sdp_size) = features.max_sdp_size {
+            w.uint(4, max_sdp_size as u64);
+        }
+    });
+    w.0
+}
+
+struct PollRequest {
+    token: String,
+    signals: Vec<Signal>,
+    idempotency_key: Option<String>,
+}
+
+fn read_poll(buf: &[u8]) -> Parsed<PollRequest> {
+    let mut poll = PollRequest {
+        token: String::new(),
+        signals: vec![],
+        idempotency_key: None,
+    };
+    let mut reader = Reader { buf };
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => poll.token = value.string()?,
+            2 => poll.signals.push(read_signal(value.bytes()?)?),
+            3 => poll.idempotency_key = Some(value.string()?),
+            _ => {}
+        }
+    }
+    Ok(poll)
+}
+
+fn write_poll(signals: &[Signal]) -> Vec<u8> {
+    let mut w = Writer::default();
+    for signal in signals.iter() {
+        w.message(1, |w| write_signal(w, signal));
+    }
+    w.uint(2, millis(SystemTime::now()));
+    w.0
+}
+
+fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![flag];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Message of the first frame, uncompressed as no compression is offered.
+fn unframe(body: &[u8]) -> Option<&[u8]> {
+    let (&flag, rest) = body.split_first()?;
+    if flag != DATA || rest.len() < 4 {
+        return None;
+    }
+    let (len, rest) = rest.split_at(4);
+    let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+    rest.get(..len)
+}
+
+/// `grpc-message` is percent-encoded.
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// gRPC status code of an error, from its HTTP status.
+fn status(e: &ApiError) -> u16 {
+    match e.status() {
+        400 | 426 => 3,
+        401 => 16,
+        403 => 7,
+        404 => 5,
+        405 => 12,
+        409 => 10,
+        413 | 429 => 8,
+        503 => 14,
+        _ => 13,
+    }
+}
+
+fn reply(message: &[u8]) -> Result<Response> {
+    let mut body = frame(DATA, message);
+    body.extend(frame(TRAILERS, b"grpc-status:0\r\n"));
+    let mut res = Response::from_bytes(body)?;
+    res.headers_mut()
+        .set("Content-Type", "application/grpc-web+proto")?;
+    Ok(res)
+}
+
+/// Trailers-only response, the error's code is in `x-error-code`.
+fn failed(e: ApiError) -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/grpc-web+proto")?;
+    headers.set("grpc-status", &status(&e).to_string())?;
+    headers.set("grpc-message", &percent_encode(&e.message()))?;
+    headers.set("x-error-code", e.code())?;
+    if let Some(secs) = e.retry_after() {
+        headers.set("Retry-After", &secs.to_string())?;
+    }
+    Ok(Response::empty()?.with_headers(headers))
+}
+
+/// Answers gRPC-Web calls of `signalling.v1.Signalling`, see `proto/signalling.proto`.
+///
+/// The methods go through the same checks as `/ident` and `/poll`, with the
+/// headers they look at sent as metadata.
+pub async fn grpc(mut req: Request, env: Env, ctx: &Context) -> Result<Response> {
+    let is_binary = req
+        .headers()
+        .get("Content-Type")?
+        .is_some_and(|v| v.starts_with(GRPC_MIME) && !v.starts_with(GRPC_TEXT_MIME));
+    if !is_binary {
+        return failed(ApiError::Malformed(format!("expected {}", GRPC_MIME)));
+    }
+    let body = req.bytes().await?;
+    let message = match unframe(&body) {
+        Some(message) => message,
+        None => return failed(malformed()),
+    };
+
+    let method = req.path().strip_prefix(SERVICE).map(str::to_owned);
+    let res = match method.as_deref() {
+        Some("Ident") => {
+            let (service, capabilities) = match read_ident(message) {
+                Ok(ident) => ident,
+                Err(e) => return failed(e),
+            };
+            if maintenance::is_on(&env).await? {
+                return failed(ApiError::Maintenance(maintenance::RETRY_AFTER));
+            }
+            issue(&req, &env, ctx, service, capabilities)
+                .await?
+                .map(|res| write_ident(&res))
+        }
+        Some("Poll") => {
+            let poll = match read_poll(message) {
+                Ok(poll) => poll,
+                Err(e) => return failed(e),
+            };
+            // The token isn't in a header for the router to limit
+            if let Some(limited) = limit(&env, &format!("token:{}", poll.token)).await? {
+                return failed(ApiError::RateLimited(limited.retry_after));
+            }
+            let caller = Caller::of(&req);
+            exchange(
+                &env,
+                &caller,
+                &poll.token,
+                poll.signals,
+                poll.idempotency_key,
+            )
+            .await?
+            .map(|signals| write_poll(&signals))
+        }
+        _ => Err(ApiError::NotFound),
+    };
+    match res {
+        Ok(message) => reply(&message),
+        Err(e) => failed(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use serde_json::json;
+    use web_time::{Duration, UNIX_EPOCH};
+
+    use super::{frame, read_signal, unframe, write_signal, Reader, Writer, DATA, TRAILERS};
+    use crate::{
+        error::ApiError,
+        proto::{Backoff, IceServer, LinkState, Role, SessionState, Signal},
+    };
+
+    /// Name of the signal in the `Signal` oneof of `proto/signalling.proto`.
+    fn name(signal: &Signal) -> &'static str {
+        match signal {
+            Signal::SetSDP(_) => "set_sdp",
+            Signal::AddCandidate(_) => "add_candidate",
+            Signal::JoinRoom(_) => "join_room",
+            Signal::ConnectAt(_) => "connect_at",
+            Signal::NextPoll(_) => "next_poll",
+            Signal::SetService(_) => "set_service",
+            Signal::Peer(_) => "peer",
+            Signal::Renegotiate(_) => "renegotiate",
+            Signal::Leave => "leave",
+            Signal::PeerLeft(_) => "peer_left",
+            Signal::Password(_) => "password",
+            Signal::Relay(_) => "relay",
+            Signal::Room(_) => "room",
+            Signal::Role(_) => "role",
+            Signal::PeerJoined(_) => "peer_joined",
+            Signal::PeerGone(_) => "peer_gone",
+            Signal::AddCandidates(_) => "add_candidates",
+            Signal::Resync => "resync",
+            Signal::LinkState(_) => "link_state",
+            Signal::RoomOwner => "room_owner",
+            Signal::Kick(_) => "kick",
+            Signal::Backoff(_) => "backoff",
+            Signal::PublicKey(_) => "public_key",
+            Signal::Sealed { .. } => "sealed",
+            Signal::Seq(_) => "seq",
+            Signal::Ack(_) => "ack",
+            Signal::Restart => "restart",
+            Signal::Stats(_) => "stats",
+            Signal::ReceivedAt(_) => "received_at",
+            Signal::State(_) => "state",
+            Signal::HoldCode => "hold_code",
+            Signal::SetRoomMeta(_) => "set_room_meta",
+            Signal::RoomMeta(_) => "room_meta",
+            Signal::ExpiresIn(_) => "expires_in",
+            Signal::Spectate => "spectate",
+            Signal::ConnectCancelled(_) => "connect_cancelled",
+            Signal::SetIceServers(_) => "set_ice_servers",
+            Signal::IceServers(_) => "ice_servers",
+            Signal::ScreenJoins => "screen_joins",
+            Signal::JoinRequest(_) => "join_request",
+            Signal::Accept(_) => "accept",
+            Signal::HasMore => "has_more",
+        }
+    }
+
+    /// One of every signal.
+    fn samples() -> Vec<Signal> {
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let ice = (
+            "candidate:1 1 udp 1 192.0.2.1 9 typ host".to_owned(),
+            Some("0".to_owned()),
+            Some(0),
+        );
+        let servers = vec![IceServer {
+            urls: vec!["turn:turn.example.com".to_owned()],
+            username: Some("user".to_owned()),
+            credential: Some("secret".to_owned()),
+        }];
+        vec![
+            Signal::SetSDP("v=0".to_owned()),
+            Signal::AddCandidate(ice.clone()),
+            Signal::JoinRoom("ABCD".to_owned()),
+            Signal::ConnectAt(at),
+            Signal::NextPoll(at),
+            Signal::SetService("test".to_owned()),
+            Signal::Peer(1),
+            Signal::Renegotiate(2),
+            Signal::Leave,
+            Signal::PeerLeft(1),
+            Signal::Password("hunter2".to_owned()),
+            Signal::Relay(vec![1, 2, 3]),
+            Signal::Room("ABCD".to_owned()),
+            Signal::Role(Role::Answerer),
+            Signal::PeerJoined(1),
+            Signal::PeerGone(1),
+            Signal::AddCandidates(vec![ice.clone(), (String::new(), None, None)]),
+            Signal::Resync,
+            Signal::LinkState(LinkState {
+                generation: 1,
+                sent_sdp: true,
+                ice_done: false,
+                connect_at: Some(at),
+            }),
+            Signal::RoomOwner,
+            Signal::Kick(1),
+            Signal::Backoff(Backoff::Idle),
+            Signal::PublicKey(vec![4; 32]),
+            Signal::Sealed {
+                nonce: vec![5; 12],
+                ciphertext: vec![6; 40],
+            },
+            Signal::Seq(300),
+            Signal::Ack(300),
+            Signal::Restart,
+            Signal::Stats(json!({"rtt": 0.05})),
+            Signal::ReceivedAt(at),
+            Signal::State(SessionState::Connected),
+            Signal::HoldCode,
+            Signal::SetRoomMeta(json!({"topic": "chess"})),
+            Signal::RoomMeta(json!({"topic": "chess"})),
+            Signal::ExpiresIn(3600),
+            Signal::Spectate,
+            Signal::ConnectCancelled(1),
+            Signal::SetIceServers(servers.clone()),
+            Signal::IceServers(servers),
+            Signal::ScreenJoins,
+            Signal::JoinRequest("hint".to_owned()),
+            Signal::Accept("hint".to_owned()),
+            Signal::HasMore,
+        ]
+    }
+
+    /// Field numbers of the `Signal` oneof.
+    fn proto_fields() -> HashMap<String, u32> {
+        let proto = include_str!("../proto/signalling.proto");
+        let (_, oneof) = proto.split_once("oneof signal {").unwrap();
+        let (oneof, _) = oneof.split_once('}').unwrap();
+        oneof
+            .lines()
+            .filter_map(|line| {
+                let (field, number) = line.trim().strip_suffix(';')?.split_once(" = ")?;
+                let (_, name) = field.split_once(' ')?;
+                Some((name.to_owned(), number.parse().unwrap()))
+            })
+            .collect()
+    }
+
+    fn encode(signal: &Signal) -> Vec<u8> {
+        let mut w = Writer::default();
+        write_signal(&mut w, signal);
+        w.0
+    }
+
+    #[test]
+    fn signal_fields_match_proto() {
+        let fields = proto_fields();
+        let samples = samples();
+        let names: BTreeSet<&str> = samples.iter().map(name).collect();
+        assert_eq!(names, fields.keys().map(String::as_str).collect());
+
+        for signal in samples.iter() {
+            let buf = encode(signal);
+            let mut reader = Reader { buf: &buf };
+            let (field, _) = reader.field().ok().flatten().unwrap();
+            assert_eq!(field, fields[name(signal)], "{}", name(signal));
+            assert!(matches!(reader.field(), Ok(None)), "{}", name(signal));
+        }
+    }
+
+    #[test]
+    fn client_signals_round_trip() {
+        let server_only = [
+            "connect_at",
+            "next_poll",
+            "peer_left",
+            "role",
+            "peer_joined",
+            "peer_gone",
+            "link_state",
+            "room_owner",
+            "backoff",
+            "seq",
+            "received_at",
+            "state",
+            "room_meta",
+            "expires_in",
+            "connect_cancelled",
+            "ice_servers",
+            "join_request",
+            "has_more",
+        ];
+        for signal in samples().iter() {
+            let buf = encode(signal);
+            match read_signal(&buf) {
+                Ok(read) => {
+                    assert!(!server_only.contains(&name(signal)), "{}", name(signal));
+                    assert_eq!(encode(&read), buf, "{}", name(signal));
+                }
+                Err(ApiError::CantSend) => {
+                    assert!(server_only.contains(&name(signal)), "{}", name(signal))
+                }
+                Err(_) => panic!("{} unreadable", name(signal)),
+            }
+        }
+    }
+
+    #[test]
+    fn varints() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut w = Writer::default();
+            w.varint(value);
+            let mut reader = Reader { buf: &w.0 };
+            assert_eq!(reader.varint(), Some(value));
+            assert!(reader.buf.is_empty());
+        }
+        assert_eq!((Reader { buf: &[0x80] }).varint(), None);
+        assert_eq!((Reader { buf: &[0xff; 11] }).varint(), None);
+
+        // A length past the end of the message
+        let mut w = Writer::default();
+        w.bytes(1, b"v=0");
+        let truncated = &w.0[..w.0.len() - 1];
+        assert!(read_signal(truncated).is_err());
+        assert!(read_signal(&[0x0a, 0x80]).is_err());
+    }
+
+    #[test]
+    fn frames() {
+        let framed = frame(DATA, b"message");
+        assert_eq!(unframe(&framed), Some(&b"message"[..]));
+        let mut trailing = framed.clone();
+        trailing.extend(frame(TRAILERS, b"grpc-status:0"));
+        assert_eq!(unframe(&trailing), Some(&b"message"[..]));
+
+        assert_eq!(unframe(&framed[..framed.len() - 1]), None);
+        assert_eq!(unframe(&framed[..3]), None);
+        assert_eq!(unframe(&[]), None);
+        assert_eq!(unframe(&frame(TRAILERS, b"message")), None);
+        assert_eq!(unframe(&frame(DATA, b"")), Some(&b""[..]));
+    }
+}
@@ -0,0 +1,51 @@
+use serde::Serialize;
+use web_time::Instant;
+use worker::{Env, Response, Result};
+
+use crate::db::storage;
+
+#[derive(Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    version: &'static str,
+    ok: bool,
+    checks: Vec<Check>,
+}
+
+async fn check_storage(env: &Env) -> Check {
+    let start = Instant::now();
+    // Probing a missing key is enough to reach the backend
+    let res = match storage(env) {
+        Ok(storage) => storage.exists("health").await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    Check {
+        name: "storage",
+        ok: res.is_ok(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        error: res.err().map(|e| e.to_string()),
+    }
+}
+
+/// Reports whether the worker can reach its storage, without touching sessions.
+pub async fn health(env: Env) -> Result<Response> {
+    let checks = vec![check_storage(&env).await];
+    let ok = checks.iter().all(|c| c.ok);
+
+    let status = if ok { 200 } else { 503 };
+    Ok(Response::from_json(&HealthResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        ok,
+        checks,
+    })?
+    .with_status(status))
+}
@@ -0,0 +1,177 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use web_time::{Duration, SystemTime};
+use worker::{Env, Request, Response, Result};
+
+use crate::{
+    clock::SystemClock,
+    config::Config,
+    db::{storage, BucketInfo, Data, ExpiringMetadata, Storage},
+    error::ApiError,
+    keys::CryptoKeys,
+    proto::InviteResponse,
+    room::{room_code, room_key, Room},
+    signing::{keys, signing_key, Purpose},
+    token::{self, mac},
+};
+
+/// Single-use invite into a room, redeemed by `/join`, keyed by its id.
+pub type Invite = Data<InviteData, ExpiringMetadata, InviteInfo>;
+
+pub struct InviteInfo {}
+impl BucketInfo for InviteInfo {
+    const PREFIX: &'static str = "invite";
+    // shared as links, long enough not to be guessed
+    const KEY_LENGTH: u8 = 16;
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct InviteData {
+    // key of the room it lets in
+    room: String,
+    claimed: bool,
+}
+
+impl Invite {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.meta.expire_at
+    }
+
+    /// Service and code of the room it lets in.
+    pub fn room(&self) -> (&str, &str) {
+        let data = self.data.as_ref().expect("invalid state");
+        (
+            data.room.split_once(':').map_or("", |(service, _)| service),
+            room_code(&data.room),
+        )
+    }
+
+    /// Uses the invite up, `false` if someone else did in the meantime.
+    pub async fn claim(self, storage: &dyn Storage) -> Result<bool> {
+        self.set_claimed(storage, true).await
+    }
+
+    /// Hands invite `id` back after the join it was claimed for failed.
+    pub async fn release(storage: &dyn Storage, id: &str) -> Result<()> {
+        if let Some(invite) = Invite::load(storage, id).await? {
+            // A lost write leaves it used up, rather than letting two peers in
+            invite.set_claimed(storage, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_claimed(mut self, storage: &dyn Storage, claimed: bool) -> Result<bool> {
+        let data = self.data.as_mut().expect("invalid state");
+        data.claimed = claimed;
+        self.modified = true;
+        self.write(storage).await
+    }
+}
+
+fn message(id: &str) -> String {
+    format!("invite.{}", id)
+}
+
+/// Invite that `invite` names, as long as it's neither expired nor used.
+///
+/// It must carry the signature of a `Purpose::Jwt` key once there's any.
+pub async fn open(env: &Env, storage: &dyn Storage, invite: &str) -> Result<Option<Invite>> {
+    let keys = keys(env, Purpose::Jwt).await?;
+    let id = if keys.is_empty() {
+        invite
+    } else {
+        let signed = invite
+            .split_once('.')
+            .and_then(|(id, signature)| Some((id, URL_SAFE_NO_PAD.decode(signature).ok()?)));
+        let (id, signature) = match signed {
+            Some(signed) => signed,
+            None => return Ok(None),
+        };
+        let message = message(id);
+        if !keys
+            .iter()
+            .any(|key| mac(&key.secret, &message).verify_slice(&signature).is_ok())
+        {
+            return Ok(None);
+        }
+        id
+    };
+    Ok(Invite::load(storage, id).await?.filter(|invite| {
+        !invite.is_expired() && !invite.data.as_ref().expect("invalid state").claimed
+    }))
+}
+
+#[derive(Deserialize)]
+struct InviteRequest {
+    /// Room to invite into, needed when in several
+    code: Option<String>,
+    /// Seconds, `INVITE_TTL` at most
+    ttl: Option<u64>,
+}
+
+/// Hands a member an invite into its room, which lets one peer `/join` until
+/// it expires.
+pub async fn invite(mut req: Request, env: Env) -> Result<Response> {
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => token,
+        None => return ApiError::MissingToken.into_response(),
+    };
+    let body = match req.json::<InviteRequest>().await {
+        Ok(b) => b,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+    };
+
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&SystemClock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    if user.is_spectator() {
+        return ApiError::CantSend.into_response();
+    }
+    let service = match user.get_service() {
+        Some(service) => service.clone(),
+        None => return ApiError::RoomExpired.into_response(),
+    };
+    let key = match (body.code, user.get_rooms()) {
+        (Some(code), rooms) if rooms.contains(&room_key(&service, &code)) => {
+            room_key(&service, &code)
+        }
+        (Some(_), _) => return ApiError::RoomUnknown.into_response(),
+        (None, [key]) => key.clone(),
+        (None, []) => return ApiError::RoomExpired.into_response(),
+        (None, _) => {
+            return ApiError::Malformed("code needed when in several rooms".to_owned())
+                .into_response()
+        }
+    };
+    match Room::load(&*storage, &key).await? {
+        Some(room) if !room.is_expired(&SystemClock) => {}
+        _ => return ApiError::RoomExpired.into_response(),
+    }
+
+    let ttl = body.ttl.unwrap_or(config.invite_ttl).min(config.invite_ttl);
+    let expire_at = SystemTime::now() + Duration::from_secs(ttl);
+    let mut invite = Invite::create_with(&mut CryptoKeys, &Invite::key_spec(), None)?;
+    invite.meta.expire_at = expire_at;
+    invite.data.as_mut().expect("invalid state").room = key.clone();
+    let id = invite.key.clone();
+    if !invite.write(&*storage).await? {
+        return ApiError::Conflict.into_response();
+    }
+
+    let invite = match signing_key(&env, Purpose::Jwt).await? {
+        Some(key) => {
+            let signature = mac(&key.secret, &message(&id)).finalize().into_bytes();
+            format!("{}.{}", id, URL_SAFE_NO_PAD.encode(signature))
+        }
+        None => id,
+    };
+    Response::from_json(&InviteResponse {
+        invite,
+        code: room_code(&key).to_owned(),
+        expire_at,
+    })
+}
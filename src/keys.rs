@@ -0,0 +1,101 @@
+use worker::{js_sys, wasm_bindgen::JsCast, Error, Result};
+
+pub const ALPHANUMERIC: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+/// Crockford's base 32, without `I`, `L`, `O` and `U` that are easily misread.
+pub const CROCKFORD: &str = "0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Source of the random bytes new keys are made of.
+pub trait KeyGenerator {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<()>;
+
+    /// Random key of `len` alphanumeric characters.
+    fn key(&mut self, len: u8) -> Result<String> {
+        self.key_in(ALPHANUMERIC, len)
+    }
+
+    /// Random key of `len` characters from `alphabet`, which must be ASCII.
+    fn key_in(&mut self, alphabet: &str, len: u8) -> Result<String> {
+        let alphabet = alphabet.as_bytes();
+        // Largest multiple of the alphabet size, so every character is as likely
+        let limit = (u8::MAX as usize + 1) / alphabet.len() * alphabet.len();
+
+        let mut key = String::with_capacity(len as usize);
+        let mut buf = [0u8; 64];
+        while key.len() < len as usize {
+            self.fill(&mut buf)?;
+            for b in buf.iter().filter(|b| (**b as usize) < limit) {
+                if key.len() == len as usize {
+                    break;
+                }
+                key.push(alphabet[*b as usize % alphabet.len()] as char);
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// `crypto.getRandomValues`, a CSPRNG.
+pub struct CryptoKeys;
+
+impl KeyGenerator for CryptoKeys {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<()> {
+        let scope: web_sys::WorkerGlobalScope = js_sys::global().unchecked_into();
+        let crypto = scope.crypto().map_err(Error::from)?;
+        crypto
+            .get_random_values_with_u8_array(buf)
+            .map_err(Error::from)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use worker::Result;
+
+    use super::{KeyGenerator, ALPHANUMERIC, CROCKFORD};
+    use crate::testing::SeededKeys;
+
+    /// Every byte in turn, from `.0` on.
+    struct Counter(u8);
+
+    impl KeyGenerator for Counter {
+        fn fill(&mut self, buf: &mut [u8]) -> Result<()> {
+            for b in buf.iter_mut() {
+                *b = self.0;
+                self.0 = self.0.wrapping_add(1);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn length_and_alphabet() {
+        let mut keys = SeededKeys(1);
+        for alphabet in [ALPHANUMERIC, CROCKFORD, "AB", "0123456789"] {
+            for len in [0, 1, 6, 32, 200] {
+                let key = keys.key_in(alphabet, len).unwrap();
+                assert_eq!(key.len(), len as usize);
+                assert!(key.chars().all(|c| alphabet.contains(c)), "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn uniform() {
+        // One of each byte the alphabet size divides, the rest is rejected
+        let limit = 256 / ALPHANUMERIC.len() * ALPHANUMERIC.len();
+        let key = Counter(0).key(limit as u8).unwrap();
+        for c in ALPHANUMERIC.chars() {
+            let count = key.chars().filter(|k| *k == c).count();
+            assert_eq!(count, limit / ALPHANUMERIC.len(), "{}", c);
+        }
+    }
+
+    #[test]
+    fn rejects_bytes_over_limit() {
+        // 252 to 255 would make the first few characters more likely
+        assert_eq!(Counter(252).key(3).unwrap(), "ABC");
+        // Alphabets dividing 256 reject nothing
+        assert_eq!(Counter(255).key_in(CROCKFORD, 2).unwrap(), "Z0");
+    }
+}
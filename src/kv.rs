@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use web_time::{SystemTime, UNIX_EPOCH};
+use worker::{async_trait, kv::KvStore, Env, Result};
+
+use crate::db::{Entry, Page, Storage};
+
+const BINDING: &str = "KV";
+// KV refuses expirations closer than a minute away
+const MIN_TTL: u64 = 60;
+
+#[derive(Serialize, Deserialize, Default)]
+struct KvMetadata {
+    meta: HashMap<String, String>,
+    version: u64,
+}
+
+/// Stores objects in Workers KV, which deletes them by itself once they expire.
+///
+/// KV has no conditional writes, versions are only compared before writing so
+/// concurrent polls can still overwrite each other.
+pub struct KvStorage {
+    store: KvStore,
+}
+
+impl KvStorage {
+    pub fn new(env: &Env) -> Result<Self> {
+        Ok(Self {
+            store: env.kv(BINDING)?,
+        })
+    }
+
+    async fn version(&self, key: &str) -> Result<Option<u64>> {
+        let (_, meta) = self
+            .store
+            .get(key)
+            .bytes_with_metadata::<KvMetadata>()
+            .await?;
+        Ok(meta.map(|m| m.version))
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Storage for KvStorage {
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.version(key).await?.is_some())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Entry>> {
+        let (body, meta) = self
+            .store
+            .get(key)
+            .bytes_with_metadata::<KvMetadata>()
+            .await?;
+        Ok(body.map(|body| {
+            let meta = meta.unwrap_or_default();
+            Entry {
+                key: key.to_owned(),
+                meta: meta.meta,
+                body: Some(body),
+                version: meta.version.to_string(),
+            }
+        }))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        meta: HashMap<String, String>,
+        version: Option<&str>,
+        expire_at: Option<SystemTime>,
+    ) -> Result<bool> {
+        let current = self.version(key).await?.map(|v| v.to_string());
+        if current.as_deref() != version {
+            return Ok(false);
+        }
+
+        let version = current.map_or(0, |v| v.parse::<u64>().unwrap_or(0) + 1);
+        let mut put = self
+            .store
+            .put_bytes(key, &body)?
+            .metadata(KvMetadata { meta, version })?;
+        if let Some(expire_at) = expire_at {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time travel?")
+                .as_secs();
+            let expire_at = expire_at
+                .duration_since(UNIX_EPOCH)
+                .expect("time travel on expire_at?")
+                .as_secs();
+            put = put.expiration(expire_at.max(now + MIN_TTL));
+        }
+        put.execute().await?;
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        Ok(self.store.delete(key).await?)
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page> {
+        let mut builder = self.store.list().prefix(prefix.to_owned());
+        if let Some(cursor) = cursor {
+            builder = builder.cursor(cursor);
+        }
+        let res = builder.execute().await?;
+
+        let mut entries = vec![];
+        for key in res.keys.into_iter() {
+            let meta: KvMetadata = key
+                .metadata
+                .and_then(|m| serde_json::from_value(m).ok())
+                .unwrap_or_default();
+            entries.push(Entry {
+                key: key.name,
+                meta: meta.meta,
+                body: None,
+                version: meta.version.to_string(),
+            });
+        }
+        Ok(Page {
+            entries,
+            cursor: res.cursor.filter(|_| !res.list_complete),
+        })
+    }
+
+    fn expires(&self) -> bool {
+        true
+    }
+}
@@ -1,48 +1,86 @@
+pub mod proto;
+
+#[cfg(feature = "server")]
+mod admin;
+#[cfg(feature = "server")]
+mod analytics;
+#[cfg(feature = "server")]
+mod apikey;
+#[cfg(feature = "server")]
+mod audit;
+#[cfg(feature = "server")]
 mod auth;
+#[cfg(feature = "server")]
+mod ban;
+#[cfg(feature = "server")]
+mod clock;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "server")]
+mod d1;
+#[cfg(feature = "server")]
 mod db;
+#[cfg(feature = "server")]
+mod deadletter;
+#[cfg(feature = "server")]
+mod durable;
+#[cfg(feature = "server")]
+mod error;
+#[cfg(feature = "server")]
+mod filter;
+#[cfg(feature = "server")]
+mod grpc;
+#[cfg(feature = "server")]
+mod health;
+#[cfg(feature = "server")]
+mod invite;
+#[cfg(feature = "server")]
+mod keys;
+#[cfg(feature = "server")]
+mod kv;
+#[cfg(feature = "server")]
+mod limit;
+#[cfg(feature = "server")]
+mod load;
+#[cfg(feature = "server")]
+mod lobby;
+#[cfg(feature = "server")]
+mod log;
+#[cfg(feature = "server")]
+mod maintenance;
+#[cfg(feature = "server")]
+mod matcher;
+#[cfg(all(feature = "server", any(test, feature = "memory")))]
+mod memory;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod oidc;
+#[cfg(feature = "server")]
+mod openapi;
+#[cfg(feature = "server")]
+mod otlp;
+#[cfg(feature = "server")]
 mod poll;
+#[cfg(feature = "server")]
+mod pool;
+#[cfg(feature = "server")]
+mod replica;
+#[cfg(feature = "server")]
 mod room;
-
-use poll::{cleanup, ident, poll};
-use worker::{
-    event, Context, Cors, Env, Headers, Method, Request, Response, Result, ScheduleContext,
-    ScheduledEvent,
-};
-
-async fn handle(req: Request, env: Env) -> Result<Response> {
-    if !matches!(req.method(), Method::Post) {
-        return Response::error("Method Not Allowed", 405);
-    }
-
-    let path = req.path();
-    if path == "/ident" {
-        return ident(env).await;
-    } else if path == "/poll" {
-        return poll(req, env).await;
-    }
-
-    Response::error("Page Not Found", 404)
-}
-
-#[event(fetch)]
-async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    let cors = Cors::new()
-        .with_max_age(86400)
-        .with_credentials(true)
-        .with_methods([Method::Options, Method::Post])
-        .with_origins(["*"])
-        .with_allowed_headers(["Authorization", "*"]);
-
-    if matches!(req.method(), Method::Options) {
-        let mut headers = Headers::new();
-        headers.set("Allow", "OPTIONS, POST")?;
-        return Response::empty()?.with_headers(headers).with_cors(&cors);
-    }
-    handle(req, env).await?.with_cors(&cors)
-}
-
-#[event(scheduled)]
-async fn do_cleanup(_evt: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
-    let bucket = env.bucket("rtc").expect("missing R2 bucket");
-    cleanup(bucket).await;
-}
+#[cfg(feature = "server")]
+mod router;
+#[cfg(feature = "server")]
+mod signing;
+#[cfg(all(feature = "server", test))]
+mod testing;
+#[cfg(feature = "server")]
+mod token;
+#[cfg(feature = "server")]
+mod transfer;
+#[cfg(feature = "server")]
+mod turn;
+#[cfg(feature = "server")]
+mod turnstile;
+#[cfg(feature = "server")]
+mod ws;
@@ -0,0 +1,124 @@
+use web_time::{Duration, SystemTime};
+use worker::{
+    async_trait, durable_object, wasm_bindgen, wasm_bindgen_futures, Env, Headers, Request,
+    Response, Result,
+};
+
+const BINDING: &str = "LIMITER";
+const DEFAULT_RATE: u32 = 60;
+/// Set on the first rejection since the key's last allowed request.
+const FIRST_HEADER: &str = "X-Limit-First";
+
+/// A request turned away by `limit`.
+pub struct Limited {
+    pub retry_after: u64,
+    /// Whether the key just went over, the rejections after it are the same story
+    pub first: bool,
+}
+
+fn rate(env: &Env) -> u32 {
+    env.var("RATE_LIMIT")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(DEFAULT_RATE)
+}
+
+/// Takes a request slot for `key`, returning how many seconds to wait if there's none left.
+///
+/// Rate limiting is skipped when the limiter binding isn't configured.
+pub async fn limit(env: &Env, key: &str) -> Result<Option<Limited>> {
+    take(env, key, rate(env), 60).await
+}
+
+/// Counts one creation against the `cap` of `key` per `window` seconds,
+/// returning how many seconds to wait if it's used up.
+///
+/// Like rate limits, skipped without the limiter binding or with a `cap` of 0.
+pub async fn quota(env: &Env, key: &str, cap: u32, window: u64) -> Result<Option<u64>> {
+    if cap == 0 {
+        return Ok(None);
+    }
+    let limited = take(env, &format!("quota:{}", key), cap, window).await?;
+    Ok(limited.map(|limited| limited.retry_after))
+}
+
+async fn take(env: &Env, key: &str, rate: u32, window: u64) -> Result<Option<Limited>> {
+    let namespace = match env.durable_object(BINDING) {
+        Ok(namespace) => namespace,
+        Err(_) => return Ok(None),
+    };
+
+    let stub = namespace.id_from_name(key)?.get_stub()?;
+    let url = format!("https://limiter/?rate={}&window={}", rate, window);
+    let res = stub.fetch_with_str(&url).await?;
+    if res.status_code() != 429 {
+        return Ok(None);
+    }
+
+    let retry_after = res
+        .headers()
+        .get("Retry-After")?
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+    let first = res.headers().get(FIRST_HEADER)?.is_some();
+    Ok(Some(Limited { retry_after, first }))
+}
+
+pub fn too_many_requests(retry_after: u64) -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Retry-After", &retry_after.to_string())?;
+    Ok(Response::error("Too Many Requests", 429)?.with_headers(headers))
+}
+
+/// Token bucket refilling `rate` requests per `window` seconds, a minute by default,
+/// kept in memory.
+#[durable_object]
+pub struct RateLimiter {
+    tokens: f64,
+    updated: SystemTime,
+    // turned the last request away
+    limited: bool,
+}
+
+#[durable_object]
+impl DurableObject for RateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            tokens: f64::MAX,
+            updated: SystemTime::now(),
+            limited: false,
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .and_then(|(_, v)| v.parse::<u64>().ok())
+        };
+        let rate = param("rate").unwrap_or(DEFAULT_RATE as u64) as f64;
+        let window = param("window").unwrap_or(60).max(1) as f64;
+
+        let now = SystemTime::now();
+        let elapsed = now
+            .duration_since(self.updated)
+            .unwrap_or(Duration::ZERO)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate / window).min(rate);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            let wait = ((1.0 - self.tokens) * window / rate).ceil() as u64;
+            let mut res = too_many_requests(wait.max(1))?;
+            if !std::mem::replace(&mut self.limited, true) {
+                res.headers_mut().set(FIRST_HEADER, "1")?;
+            }
+            return Ok(res);
+        }
+
+        self.limited = false;
+        self.tokens -= 1.0;
+        Response::empty()
+    }
+}
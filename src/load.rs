@@ -0,0 +1,103 @@
+use std::cell::Cell;
+
+use web_time::{Duration, SystemTime};
+use worker::{
+    async_trait, console_log, durable_object, wasm_bindgen, wasm_bindgen_futures, Env, Request,
+    Response, Result,
+};
+
+use crate::config::Config;
+
+const BINDING: &str = "LOAD";
+// errors count half as much after this many seconds
+const HALF_LIFE: f64 = 60.0;
+/// How long an isolate goes by the errors it read last.
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+thread_local! {
+    /// Errors read last, and when.
+    static CACHED: Cell<Option<(SystemTime, f64)>> = const { Cell::new(None) };
+}
+
+fn stub(env: &Env) -> Result<worker::Stub> {
+    env.durable_object(BINDING)?
+        .id_from_name("load")?
+        .get_stub()
+}
+
+/// Notes a failed request, skipped when the load binding isn't configured.
+pub async fn report(env: &Env) {
+    let stub = match stub(env) {
+        Ok(stub) => stub,
+        Err(_) => return,
+    };
+
+    if let Err(e) = stub.fetch_with_str("https://load/report").await {
+        console_log!("couldn't report load: {}", e);
+    }
+}
+
+/// Recent errors over `MAX_ERRORS`, above 1 clients should poll slower.
+///
+/// Read from the object once every `CACHE_TTL` per isolate. Always 0 when the
+/// load binding isn't configured.
+pub async fn load(env: &Env, config: &Config) -> f64 {
+    let now = SystemTime::now();
+    let errors = match CACHED.get() {
+        Some((at, errors)) if now < at + CACHE_TTL => errors,
+        _ => {
+            let errors = errors(env).await;
+            CACHED.set(Some((now, errors)));
+            errors
+        }
+    };
+    errors / config.max_errors.max(1) as f64
+}
+
+async fn errors(env: &Env) -> f64 {
+    let stub = match stub(env) {
+        Ok(stub) => stub,
+        Err(_) => return 0.0,
+    };
+
+    match stub.fetch_with_str("https://load/").await {
+        Ok(mut res) => res.json::<f64>().await.unwrap_or_default(),
+        Err(e) => {
+            console_log!("couldn't read load: {}", e);
+            0.0
+        }
+    }
+}
+
+/// Decaying count of recent errors, kept in memory.
+#[durable_object]
+pub struct LoadMonitor {
+    errors: f64,
+    updated: SystemTime,
+}
+
+#[durable_object]
+impl DurableObject for LoadMonitor {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            errors: 0.0,
+            updated: SystemTime::now(),
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let now = SystemTime::now();
+        let elapsed = now
+            .duration_since(self.updated)
+            .unwrap_or(Duration::ZERO)
+            .as_secs_f64();
+        self.errors *= 0.5f64.powf(elapsed / HALF_LIFE);
+        self.updated = now;
+
+        if req.path() == "/report" {
+            self.errors += 1.0;
+            return Response::empty();
+        }
+        Response::from_json(&self.errors)
+    }
+}
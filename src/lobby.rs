@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{Env, Request, Response, Result};
+
+use crate::{
+    clock::SystemClock,
+    db::{storage, BucketInfo, Corrupted, Data, Metadata, Storage},
+    error::ApiError,
+    poll::is_service_allowed,
+    proto::{PublicRoom, RoomStatus},
+    room::{room_code, room_key, Room},
+};
+
+/// Index entry of a public room, keyed by room key, everything is in the metadata.
+pub type Listing = Data<(), ListingMetadata, ListingInfo>;
+
+pub struct ListingInfo {}
+impl BucketInfo for ListingInfo {
+    const PREFIX: &'static str = "lobby";
+}
+
+pub struct ListingMetadata {
+    name: String,
+    created_at: SystemTime,
+    occupancy: usize,
+    max_members: u8,
+    // the room's own expiry
+    expire_at: Option<SystemTime>,
+}
+impl Default for ListingMetadata {
+    fn default() -> Self {
+        ListingMetadata {
+            name: String::new(),
+            created_at: SystemTime::now(),
+            occupancy: 0,
+            max_members: 0,
+            expire_at: None,
+        }
+    }
+}
+
+impl Metadata for ListingMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        self.expire_at
+    }
+}
+impl TryFrom<HashMap<String, String>> for ListingMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let number = |name: &str| value.get(name).and_then(|v| v.parse::<u64>().ok());
+        let time = |name: &str| number(name).map(|v| UNIX_EPOCH + Duration::from_secs(v));
+
+        Ok(ListingMetadata {
+            name: value.get("name").cloned().unwrap_or_default(),
+            created_at: time("created_at").unwrap_or(UNIX_EPOCH),
+            occupancy: number("occupancy").unwrap_or_default() as usize,
+            max_members: number("max_members").unwrap_or_default() as u8,
+            expire_at: time("expire_at"),
+        })
+    }
+}
+impl From<ListingMetadata> for HashMap<String, String> {
+    fn from(value: ListingMetadata) -> Self {
+        let mut map = HashMap::new();
+        let time = |v: SystemTime| {
+            v.duration_since(UNIX_EPOCH)
+                .expect("time travel?")
+                .as_secs()
+                .to_string()
+        };
+
+        map.insert("name".to_owned(), value.name);
+        map.insert("created_at".to_owned(), time(value.created_at));
+        map.insert("occupancy".to_owned(), value.occupancy.to_string());
+        map.insert("max_members".to_owned(), value.max_members.to_string());
+        map.insert(
+            "expire_at".to_owned(),
+            value.expire_at.map(time).unwrap_or_default(),
+        );
+        map
+    }
+}
+
+impl Listing {
+    pub fn is_expired(&self) -> bool {
+        self.meta.expire_at.is_some_and(|t| SystemTime::now() >= t)
+    }
+}
+
+/// How a public room should show up in the lobby, taken before the room is written.
+pub struct ListingUpdate {
+    key: String,
+    // `None` once the room can't be joined anymore
+    open: Option<ListingMetadata>,
+}
+
+impl ListingUpdate {
+    /// `None` for rooms that aren't public.
+    pub fn of(room: &Room) -> Option<Self> {
+        let name = room.public_name()?;
+        let open = !room.is_full()
+            && !room.is_expired(&SystemClock)
+            && (room.occupancy() > 0 || room.is_reserved(&SystemClock));
+
+        Some(ListingUpdate {
+            key: room.key.clone(),
+            open: open.then(|| ListingMetadata {
+                name: name.to_owned(),
+                created_at: SystemTime::now(),
+                occupancy: room.occupancy(),
+                max_members: room.max_members(),
+                expire_at: room.meta.expire_at(),
+            }),
+        })
+    }
+
+    /// Lists or unlists the room, lost races are fine as the next update catches up.
+    pub async fn apply(self, storage: &dyn Storage) -> Result<()> {
+        let mut meta = match self.open {
+            Some(meta) => meta,
+            None => return unlist(storage, &self.key).await,
+        };
+
+        let mut listing = match Listing::load(storage, &self.key).await? {
+            Some(listing) => {
+                meta.created_at = listing.meta.created_at;
+                listing
+            }
+            None => Listing::unsaved(self.key),
+        };
+        listing.meta = meta;
+        listing.modified = true;
+        listing.write(storage).await?;
+        Ok(())
+    }
+}
+
+pub async fn unlist(storage: &dyn Storage, key: &str) -> Result<()> {
+    storage.delete(&Listing::get_bucket_key(key)).await
+}
+
+#[derive(Deserialize)]
+struct LobbyQuery {
+    service: String,
+}
+
+/// Open public rooms of `?service=`, oldest first.
+pub async fn lobby(req: Request, env: Env) -> Result<Response> {
+    let query = match req.query::<LobbyQuery>() {
+        Ok(q) => q,
+        Err(_) => return ApiError::NeedService.into_response(),
+    };
+    if !is_service_allowed(&env, &query.service)? {
+        return ApiError::ServiceNotAllowed.into_response();
+    }
+
+    let storage = storage(&env)?;
+    let prefix = Listing::get_bucket_key(&format!("{}:", query.service));
+    let mut rooms: Vec<PublicRoom> = storage
+        .list(&prefix)
+        .await?
+        .into_iter()
+        .filter_map(|entry| Listing::read(entry).ok())
+        .filter(|listing| !listing.is_expired())
+        .map(|listing| PublicRoom {
+            code: room_code(&listing.key).to_owned(),
+            name: listing.meta.name,
+            created_at: listing.meta.created_at,
+            occupancy: listing.meta.occupancy,
+            max_members: listing.meta.max_members,
+        })
+        .collect();
+    rooms.sort_by_key(|room| room.created_at);
+    Response::from_json(&rooms)
+}
+
+/// Whether room `code` of `?service=` can be joined, so clients can check a
+/// code before getting a token. Expired rooms don't exist anymore.
+pub async fn room_status(req: Request, env: Env, code: &str) -> Result<Response> {
+    let query = match req.query::<LobbyQuery>() {
+        Ok(q) => q,
+        Err(_) => return ApiError::NeedService.into_response(),
+    };
+    if !is_service_allowed(&env, &query.service)? {
+        return ApiError::ServiceNotAllowed.into_response();
+    }
+
+    let storage = storage(&env)?;
+    let room = Room::load(&*storage, &room_key(&query.service, code)).await?;
+    let status = match room {
+        Some(room) if !room.is_expired(&SystemClock) => room.status(),
+        _ => RoomStatus {
+            exists: false,
+            full: false,
+            service: query.service,
+            created_at: None,
+            protected: false,
+        },
+    };
+    Response::from_json(&status)
+}
@@ -0,0 +1,100 @@
+use std::fmt::Display;
+
+use worker::{console_log, Env, Request};
+
+use crate::{
+    keys::{CryptoKeys, KeyGenerator},
+    room::room_code,
+};
+
+const ID_LENGTH: u8 = 12;
+// characters of the token shown, enough to tell sessions apart
+const TOKEN_PREFIX: usize = 8;
+
+/// How much gets logged, from the `LOG_LEVEL` var.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn from_env(env: &Env) -> Self {
+        match env.var("LOG_LEVEL").map(|v| v.to_string()).as_deref() {
+            Ok("error") => Self::Error,
+            Ok("debug") => Self::Debug,
+            _ => Self::Info,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+/// Enough of a token to tell sessions apart, without the expiry bucket and never all of it.
+pub fn token_prefix(key: &str) -> String {
+    let key = key.rsplit(':').next().unwrap_or(key);
+    key.chars().take(TOKEN_PREFIX).collect()
+}
+
+/// ID tying together the log lines of a request, its `CF-Ray` when there's one.
+pub fn request_id(req: Option<&Request>) -> String {
+    let ray = req.and_then(|req| req.headers().get("CF-Ray").ok().flatten());
+    ray.or_else(|| CryptoKeys.key(ID_LENGTH).ok())
+        .unwrap_or_default()
+}
+
+/// Tags log lines with the request, and the session once it's known, as `key=value` pairs.
+pub struct Trace {
+    id: String,
+    token: String,
+    level: Level,
+}
+
+impl Trace {
+    pub fn new(env: &Env, id: String) -> Self {
+        Trace {
+            id,
+            token: "-".to_owned(),
+            level: Level::from_env(env),
+        }
+    }
+
+    /// Only a prefix of the key past its expiry bucket is logged, never the whole token.
+    pub fn set_token(&mut self, key: &str) {
+        self.token = token_prefix(key);
+    }
+
+    /// Logs `message`, `room` being a room key, if `level` is verbose enough.
+    pub fn log(&self, level: Level, room: Option<&str>, message: impl Display) {
+        if level > self.level {
+            return;
+        }
+        console_log!(
+            "level={} req={} token={} room={} {}",
+            level.name(),
+            self.id,
+            self.token,
+            room.map(room_code).unwrap_or("-"),
+            message
+        );
+    }
+
+    pub fn error(&self, room: Option<&str>, message: impl Display) {
+        self.log(Level::Error, room, message);
+    }
+
+    pub fn info(&self, room: Option<&str>, message: impl Display) {
+        self.log(Level::Info, room, message);
+    }
+
+    pub fn debug(&self, room: Option<&str>, message: impl Display) {
+        self.log(Level::Debug, room, message);
+    }
+}
@@ -0,0 +1,25 @@
+use worker::{Env, Result};
+
+/// KV namespace of runtime flags, read on every token or room creation.
+const BINDING: &str = "FLAGS";
+const KEY: &str = "maintenance";
+/// Seconds clients wait before trying again.
+pub const RETRY_AFTER: u64 = 60;
+
+/// Whether new tokens and rooms are refused so the deployment can drain, set
+/// with the `MAINTENANCE` var or the `maintenance` key of the `FLAGS` namespace.
+///
+/// Existing sessions keep polling in the rooms they're in.
+pub async fn is_on(env: &Env) -> Result<bool> {
+    let enabled = |v: &str| v == "true";
+    if env
+        .var("MAINTENANCE")
+        .is_ok_and(|v| enabled(&v.to_string()))
+    {
+        return Ok(true);
+    }
+    match env.kv(BINDING) {
+        Ok(store) => Ok(store.get(KEY).text().await?.is_some_and(|v| enabled(&v))),
+        Err(_) => Ok(false),
+    }
+}
@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use web_time::{Duration, SystemTime};
+use worker::{
+    async_trait, durable_object, wasm_bindgen, wasm_bindgen_futures, Env, Request, Response,
+    Result, State, Stub,
+};
+
+use crate::{
+    clock::SystemClock,
+    config::Config,
+    db::{secs, storage},
+    error::ApiError,
+    poll::{exchange, has_bare, is_service_allowed, protocol_version, respond, room_quota, Caller},
+    proto::Signal,
+    room::{room_key, Room, CREATE_ATTEMPTS},
+    token,
+    ws::notify,
+};
+
+const BINDING: &str = "MATCHER";
+// waiting rooms that went away are skipped, up to this many times
+const MAX_ATTEMPTS: usize = 3;
+
+/// A token waiting in its own room for someone to be paired with.
+#[derive(Clone, Serialize, Deserialize)]
+struct Waiting {
+    key: String,
+    code: String,
+    // seconds since the epoch, when the room's reservation runs out
+    expire_at: u64,
+}
+
+/// Takes the oldest waiting token off the queue, or the caller's own entry if it's waiting already.
+async fn pair(stub: &Stub, key: &str) -> Result<Option<Waiting>> {
+    let url = format!("https://matcher/pair?key={}", key);
+    stub.fetch_with_str(&url).await?.json().await
+}
+
+async fn wait(stub: &Stub, waiting: &Waiting) -> Result<()> {
+    let url = format!(
+        "https://matcher/wait?key={}&code={}&expire_at={}",
+        waiting.key, waiting.code, waiting.expire_at
+    );
+    stub.fetch_with_str(&url).await?;
+    Ok(())
+}
+
+/// Pairs the caller with the oldest token waiting on the same service, joining its room.
+///
+/// With nobody waiting, the caller gets a room of its own to wait in instead.
+/// Either way the response is that of a poll joining the room.
+pub async fn quick_match(req: Request, env: Env) -> Result<Response> {
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => token,
+        None => return ApiError::MissingToken.into_response(),
+    };
+    let version = match protocol_version(&req)? {
+        Ok(version) => version,
+        Err(e) => return e.into_response(),
+    };
+
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&SystemClock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    let config = Config::for_profile(&env, user.get_profile()).await?;
+    let service = match user.get_service() {
+        Some(service) => service.clone(),
+        None => return ApiError::NeedService.into_response(),
+    };
+    if !is_service_allowed(&env, &service)? {
+        return ApiError::ServiceNotAllowed.into_response();
+    }
+
+    let stub = env
+        .durable_object(BINDING)?
+        .id_from_name(&service)?
+        .get_stub()?;
+    for _ in 0..MAX_ATTEMPTS {
+        let partner = match pair(&stub, &user.key).await? {
+            Some(waiting) => waiting,
+            None => break,
+        };
+        if partner.key != user.key {
+            // Its peer may have given up since, leaving the room empty
+            match Room::load(&*storage, &room_key(&service, &partner.code)).await? {
+                Some(room)
+                    if !room.is_expired(&SystemClock)
+                        && !room.is_full()
+                        && room.occupancy() > 0 => {}
+                _ => continue,
+            }
+        }
+
+        let signals = vec![Signal::JoinRoom(partner.code)];
+        match exchange(&env, &Caller::of(&req), &token, signals, None).await? {
+            Ok(signals) => {
+                if partner.key != user.key {
+                    notify(&env, &partner.key).await;
+                }
+                return respond(&signals, has_bare(&req, "Accept")?, version);
+            }
+            Err(
+                ApiError::RoomUnknown
+                | ApiError::RoomExpired
+                | ApiError::RoomFull
+                | ApiError::JoinConflict,
+            ) => continue,
+            Err(e) => return e.into_response(),
+        }
+    }
+
+    // Nobody to pair with, wait in a room of our own
+    let caller = Caller::of(&req);
+    if let Some(retry_after) = room_quota(&env, &config, caller.ip.as_deref()).await? {
+        return ApiError::QuotaExceeded(retry_after).into_response();
+    }
+    let now = SystemTime::now();
+    let expire_at = now + Duration::from_secs(config.max_room_ttl);
+    let close_at = now + Duration::from_secs(config.max_room_duration);
+    let mut code = None;
+    for _ in 0..CREATE_ATTEMPTS {
+        let mut room = Room::create(&config, &service)?;
+        room.reserve(&SystemClock, service.clone(), 2, None, expire_at, close_at);
+        let created = room.code().to_owned();
+        // Lost only when the random code is taken
+        if room.write(&*storage).await? {
+            code = Some(created);
+            break;
+        }
+    }
+    let code = match code {
+        Some(code) => code,
+        None => return ApiError::Conflict.into_response(),
+    };
+
+    let signals = vec![Signal::JoinRoom(code.clone())];
+    let signals = match exchange(&env, &caller, &token, signals, None).await? {
+        Ok(signals) => signals,
+        Err(e) => return e.into_response(),
+    };
+    // Only once it's in the room, so whoever pairs with it finds it there
+    let waiting = Waiting {
+        key: user.key,
+        code,
+        expire_at: secs(expire_at),
+    };
+    wait(&stub, &waiting).await?;
+    respond(&signals, has_bare(&req, "Accept")?, version)
+}
+
+/// Queue of tokens waiting for a match, one object per service.
+#[durable_object]
+pub struct Matchmaker {
+    state: State,
+}
+
+impl Matchmaker {
+    /// Waiting tokens in line, oldest first, leaving out expired rooms.
+    async fn queue(&self) -> Vec<Waiting> {
+        let queue: Option<Vec<Waiting>> = self.state.storage().get("queue").await.ok();
+        let now = secs(SystemTime::now());
+        queue
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|w| w.expire_at > now)
+            .collect()
+    }
+}
+
+#[durable_object]
+impl DurableObject for Matchmaker {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+                .unwrap_or_default()
+        };
+
+        let mut queue = self.queue().await;
+        let found = if req.path() == "/wait" {
+            if !queue.iter().any(|w| w.key == param("key")) {
+                queue.push(Waiting {
+                    key: param("key"),
+                    code: param("code"),
+                    expire_at: param("expire_at").parse().unwrap_or_default(),
+                });
+            }
+            None
+        } else {
+            match queue.iter().position(|w| w.key == param("key")) {
+                // Still waiting, kept in line
+                Some(own) => Some(queue[own].clone()),
+                None if !queue.is_empty() => Some(queue.remove(0)),
+                None => None,
+            }
+        };
+        self.state.storage().put("queue", &queue).await?;
+        Response::from_json(&found)
+    }
+}
@@ -0,0 +1,340 @@
+use std::{cell::RefCell, collections::BTreeMap, collections::HashMap, rc::Rc};
+
+use web_time::SystemTime;
+use worker::{async_trait, Result};
+
+use crate::db::{Entry, Page, Put, Storage};
+
+const PAGE_SIZE: usize = 1000;
+
+struct Record {
+    meta: HashMap<String, String>,
+    body: Vec<u8>,
+    version: u64,
+}
+
+type Records = Rc<RefCell<BTreeMap<String, Record>>>;
+
+thread_local! {
+    static SHARED: Records = Rc::default();
+}
+
+/// Keeps objects in memory, for local development and for driving the logic
+/// without a worker.
+///
+/// Clones share their objects. `shared` is kept for the lifetime of the isolate,
+/// so it's lost whenever the worker is evicted.
+#[derive(Default, Clone)]
+pub struct MemoryStorage {
+    records: Records,
+}
+
+impl MemoryStorage {
+    pub fn shared() -> Self {
+        Self {
+            records: SHARED.with(Rc::clone),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Storage for MemoryStorage {
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.records.borrow().contains_key(key))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Entry>> {
+        Ok(self.records.borrow().get(key).map(|r| Entry {
+            key: key.to_owned(),
+            meta: r.meta.clone(),
+            body: Some(r.body.clone()),
+            version: r.version.to_string(),
+        }))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        meta: HashMap<String, String>,
+        version: Option<&str>,
+        _expire_at: Option<SystemTime>,
+    ) -> Result<bool> {
+        let mut records = self.records.borrow_mut();
+        let current = records.get(key).map(|r| r.version);
+        if current.map(|v| v.to_string()).as_deref() != version {
+            return Ok(false);
+        }
+
+        let version = current.map_or(0, |v| v + 1);
+        records.insert(
+            key.to_owned(),
+            Record {
+                meta,
+                body,
+                version,
+            },
+        );
+        Ok(true)
+    }
+
+    async fn put_all(&self, puts: Vec<Put>) -> Result<bool> {
+        let mut records = self.records.borrow_mut();
+        let current =
+            |records: &BTreeMap<String, Record>, key: &str| records.get(key).map(|r| r.version);
+        let matching = puts
+            .iter()
+            .all(|put| current(&records, &put.key).map(|v| v.to_string()) == put.version);
+        if !matching {
+            return Ok(false);
+        }
+
+        for put in puts {
+            let version = current(&records, &put.key).map_or(0, |v| v + 1);
+            records.insert(
+                put.key,
+                Record {
+                    meta: put.meta,
+                    body: put.body,
+                    version,
+                },
+            );
+        }
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.records.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page> {
+        let records = self.records.borrow();
+        let start = cursor.unwrap_or_else(|| prefix.to_owned());
+        let entries: Vec<Entry> = records
+            .range(start..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .take(PAGE_SIZE)
+            .map(|(key, r)| Entry {
+                key: key.clone(),
+                meta: r.meta.clone(),
+                body: None,
+                version: r.version.to_string(),
+            })
+            .collect();
+
+        // Smallest key after the last one, like the Durable Object backend
+        let cursor = match entries.last() {
+            Some(entry) if entries.len() == PAGE_SIZE => Some(format!("{}\0", entry.key)),
+            _ => None,
+        };
+        Ok(Page { entries, cursor })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use web_time::{Duration, SystemTime};
+
+    use super::MemoryStorage;
+    use crate::{
+        auth::Auth,
+        clock::SystemClock,
+        config::Config,
+        db::Storage,
+        proto::Signal,
+        room::Room,
+        testing::{auth, block_on, pair, SeededKeys},
+    };
+
+    /// Writes the auth and reads it back, like the next poll would.
+    fn save(storage: &MemoryStorage, auth: Auth) -> Auth {
+        let key = auth.key.clone();
+        block_on(async {
+            assert!(auth.write(storage).await.unwrap());
+            Auth::load(storage, &key).await.unwrap().unwrap()
+        })
+    }
+
+    /// Two auths in a room they both joined, `a` on the first slot.
+    fn joined(storage: &MemoryStorage, config: &Config) -> (Auth, Auth, String) {
+        let (a, b, room) = pair(&mut SeededKeys(1), &SystemClock, config);
+        let key = room.key.clone();
+        block_on(async { assert!(room.write(storage).await.unwrap()) });
+        (save(storage, a), save(storage, b), key)
+    }
+
+    fn candidate(line: &str) -> Signal {
+        Signal::AddCandidate((line.to_owned(), Some("0".to_owned()), Some(0)))
+    }
+
+    #[test]
+    fn join() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let (a, b, key) = joined(&storage, &config);
+
+        let room = block_on(Room::load(&storage, &key)).unwrap().unwrap();
+        assert_eq!(room.get_members(), vec![a.key.clone(), b.key.clone()]);
+        assert!(room.is_full());
+        assert!(room.is_owner(&a));
+        assert_eq!(a.get_peers(), [b.key.as_str()]);
+        assert_eq!(b.get_peers(), [a.key.as_str()]);
+        assert_eq!(a.get_rooms(), [key]);
+
+        let peers = block_on(a.load_peers(&storage)).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].key, b.key);
+    }
+
+    #[test]
+    fn join_full_room() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let (_, _, key) = joined(&storage, &config);
+
+        let mut room = block_on(Room::load(&storage, &key)).unwrap().unwrap();
+        let mut c = auth(&mut SeededKeys(2), &SystemClock, &config);
+        let close_at = SystemTime::now() + Duration::from_secs(3600);
+        assert!(room
+            .join_room(&SystemClock, &mut c, 2, None, close_at)
+            .is_err());
+        assert!(c.get_rooms().is_empty());
+    }
+
+    #[test]
+    fn exchange() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let (mut a, b, _) = joined(&storage, &config);
+
+        let signals = vec![
+            Signal::SetSDP("offer".to_owned()),
+            candidate("candidate:1 1 udp 2122260223 192.0.2.1 54400 typ host"),
+        ];
+        assert_eq!(
+            a.send_signal(&SystemClock, signals, &config).unwrap(),
+            [b.key.as_str()]
+        );
+        let a = save(&storage, a);
+
+        let mut b = block_on(Auth::load(&storage, &b.key)).unwrap().unwrap();
+        let peers = block_on(b.load_peers(&storage)).unwrap();
+        let pulled = b.pull_signals(&SystemClock, &peers, &config);
+        assert!(pulled.iter().any(|s| matches!(s, Signal::Role(_))));
+        assert!(pulled
+            .iter()
+            .any(|s| matches!(s, Signal::SetSDP(sdp) if sdp == "offer")));
+        assert!(pulled
+            .iter()
+            .any(|s| matches!(s, Signal::AddCandidates(batch) if batch.len() == 1)));
+
+        // Read once
+        let b = save(&storage, b);
+        let mut b = block_on(Auth::load(&storage, &b.key)).unwrap().unwrap();
+        let pulled = b.pull_signals(&SystemClock, &[a], &config);
+        assert!(!pulled.iter().any(|s| matches!(s, Signal::SetSDP(_))));
+    }
+
+    #[test]
+    fn connect_at() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let (mut a, mut b, _) = joined(&storage, &config);
+
+        let offer = vec![Signal::SetSDP("offer".to_owned()), candidate("")];
+        a.send_signal(&SystemClock, offer, &config).unwrap();
+        let a = save(&storage, a);
+        b.send_signal(
+            &SystemClock,
+            vec![Signal::SetSDP("answer".to_owned())],
+            &config,
+        )
+        .unwrap();
+        let mut b = save(&storage, b);
+
+        // Taken from when the peer polls next, so both hear of it in time
+        let pulled = b.pull_signals(&SystemClock, &[a], &config);
+        let at = pulled.iter().find_map(|s| match s {
+            Signal::ConnectAt(at) => Some(*at),
+            _ => None,
+        });
+        let a = block_on(Auth::load(&storage, &b.get_peers()[0]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            at,
+            Some(a.next_poll() + Duration::from_secs(config.connect))
+        );
+        let b = save(&storage, b);
+
+        let mut a = a;
+        let pulled = a.pull_signals(&SystemClock, &[b], &config);
+        let own = pulled.iter().find_map(|s| match s {
+            Signal::ConnectAt(at) => Some(*at),
+            _ => None,
+        });
+        assert_eq!(own, at);
+    }
+
+    #[test]
+    fn cleanup() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let (mut a, b, key) = joined(&storage, &config);
+        assert!(a.get_keys_to_kill(&SystemClock, &config).is_empty());
+
+        a.expire(&SystemClock);
+        let keys = a.get_keys_to_kill(&SystemClock, &config);
+        assert_eq!(
+            keys,
+            [
+                Auth::get_bucket_key(&a.key),
+                Auth::get_bucket_key(&b.key),
+                Room::get_bucket_key(&key),
+            ]
+        );
+        block_on(async {
+            for key in keys.iter() {
+                storage.delete(key).await.unwrap();
+            }
+            assert!(Auth::load(&storage, &b.key).await.unwrap().is_none());
+            assert!(Room::load(&storage, &key).await.unwrap().is_none());
+            assert!(storage.list("").await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn put_all() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let (a, mut b, key) = joined(&storage, &config);
+
+        let mut room = block_on(Room::load(&storage, &key)).unwrap().unwrap();
+        room.free_slot(&b.key);
+        let mut stale = block_on(Auth::load(&storage, &b.key)).unwrap().unwrap();
+        b.modified = true;
+        let b = save(&storage, b);
+        stale.expire(&SystemClock);
+        block_on(async {
+            // Written by someone else since, so the room isn't either
+            let writes = [room.into_put(), stale.into_put()];
+            assert!(!storage
+                .put_all(writes.into_iter().flatten().collect())
+                .await
+                .unwrap());
+            let room = Room::load(&storage, &key).await.unwrap().unwrap();
+            assert_eq!(room.get_members(), [a.key.as_str(), b.key.as_str()]);
+
+            let mut room = room;
+            room.free_slot(&b.key);
+            let writes = [room.into_put(), b.into_put()];
+            assert!(storage
+                .put_all(writes.into_iter().flatten().collect())
+                .await
+                .unwrap());
+            let room = Room::load(&storage, &key).await.unwrap().unwrap();
+            assert_eq!(room.get_members(), [a.key.as_str()]);
+        });
+    }
+}
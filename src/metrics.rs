@@ -0,0 +1,150 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use worker::{
+    async_trait, console_log, durable_object, wasm_bindgen, wasm_bindgen_futures, Env, Method,
+    Request, RequestInit, Response, Result, State,
+};
+
+use crate::{admin::is_admin, error::ApiError};
+
+const BINDING: &str = "METRICS";
+
+#[derive(Clone, Copy)]
+pub enum Counter {
+    Idents,
+    Polls,
+    Heartbeats,
+    RoomsCreated,
+    RoomsJoined,
+    FailedJoins,
+    Cleaned,
+    InvalidCandidates,
+}
+
+impl Counter {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Idents => "signalling_idents_total",
+            Self::Polls => "signalling_polls_total",
+            Self::Heartbeats => "signalling_heartbeats_total",
+            Self::RoomsCreated => "signalling_rooms_created_total",
+            Self::RoomsJoined => "signalling_rooms_joined_total",
+            Self::FailedJoins => "signalling_failed_joins_total",
+            Self::Cleaned => "signalling_cleaned_objects_total",
+            Self::InvalidCandidates => "signalling_invalid_candidates_total",
+        }
+    }
+}
+
+fn stub(env: &Env) -> Result<worker::Stub> {
+    env.durable_object(BINDING)?
+        .id_from_name("metrics")?
+        .get_stub()
+}
+
+thread_local! {
+    /// Counted in this isolate since the last `flush`.
+    static PENDING: RefCell<BTreeMap<&'static str, u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Adds `by` to the counter, without waiting on the object until `flush`.
+pub fn count(counter: Counter, by: u64) {
+    PENDING.with(|pending| *pending.borrow_mut().entry(counter.name()).or_default() += by);
+}
+
+/// Adds what was counted since the last time in one request, run through
+/// `wait_until` once the response is out. Metrics are dropped when the
+/// binding isn't configured.
+pub async fn flush(env: Env) {
+    let pending = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    if pending.is_empty() {
+        return;
+    }
+    let stub = match stub(&env) {
+        Ok(stub) => stub,
+        Err(_) => return,
+    };
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(serde_json::to_string(&pending).unwrap().into()));
+    let sent = match Request::new_with_init("https://metrics/inc", &init) {
+        Ok(req) => stub.fetch_with_request(req).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = sent {
+        console_log!("couldn't count {} counters: {}", pending.len(), e);
+    }
+}
+
+/// Exposes the counters in Prometheus' text format.
+pub async fn metrics(req: Request, env: Env) -> Result<Response> {
+    if !is_admin(&req, &env)? {
+        return ApiError::Unauthorized.into_response();
+    }
+
+    let mut res = stub(&env)?.fetch_with_str("https://metrics/").await?;
+    let counters: BTreeMap<String, u64> = res.json().await?;
+
+    let mut text = String::new();
+    for (name, value) in counters.iter() {
+        text.push_str(&format!("# TYPE {} counter\n{} {}\n", name, name, value));
+    }
+
+    let mut res = Response::ok(text)?;
+    res.headers_mut()
+        .set("Content-Type", "text/plain; version=0.0.4")?;
+    Ok(res)
+}
+
+#[durable_object]
+pub struct Metrics {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for Metrics {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let mut storage = self.state.storage();
+
+        if req.path() == "/inc" {
+            let counts: BTreeMap<String, u64> = match req.json().await {
+                Ok(counts) => counts,
+                Err(e) => return Response::error(format!("Malformed counts: {}", e), 400),
+            };
+            for (name, by) in counts.iter() {
+                let value = storage.get::<Option<u64>>(name).await?.unwrap_or(0);
+                storage.put(name, value + by).await?;
+            }
+            return Response::empty();
+        }
+
+        let mut counters = BTreeMap::new();
+        for entry in storage.list().await?.entries() {
+            let entry: worker::js_sys::Array = entry?.into();
+            let name = entry.get(0).as_string().expect("invalid counter");
+            let value = entry.get(1).as_f64().unwrap_or_default() as u64;
+            counters.insert(name, value);
+        }
+        Response::from_json(&counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count, Counter, PENDING};
+
+    #[test]
+    fn counted_until_flushed() {
+        count(Counter::Polls, 1);
+        count(Counter::Polls, 2);
+        count(Counter::Cleaned, 5);
+        let pending = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+        assert_eq!(pending["signalling_polls_total"], 3);
+        assert_eq!(pending["signalling_cleaned_objects_total"], 5);
+    }
+}
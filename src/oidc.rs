@@ -0,0 +1,201 @@
+use std::cell::RefCell;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{
+    js_sys, wasm_bindgen::JsCast, wasm_bindgen_futures::JsFuture, Env, Error, Fetch, Result, Url,
+};
+
+/// Turns the requirement on, keys are fetched from there.
+const JWKS_URL: &str = "OIDC_JWKS_URL";
+/// Expected `iss` and `aud`, both needed along with `OIDC_JWKS_URL`.
+const ISSUER: &str = "OIDC_ISSUER";
+const AUDIENCE: &str = "OIDC_AUDIENCE";
+/// How long fetched keys are trusted before fetching them again.
+const JWKS_TTL: Duration = Duration::from_secs(3600);
+/// Unknown `kid`s only refetch keys this long after the last fetch, so bogus
+/// tokens can't have every request fetch them.
+const MIN_REFETCH: Duration = Duration::from_secs(60);
+const RS256: &str = r#"{"name":"RSASSA-PKCS1-v1_5","hash":"SHA-256"}"#;
+
+/// Keys fetched from `url` at `fetched_at`, kept for the lifetime of the isolate.
+struct Jwks {
+    url: String,
+    fetched_at: SystemTime,
+    keys: Vec<serde_json::Value>,
+}
+
+thread_local! {
+    static CACHE: RefCell<Option<Jwks>> = const { RefCell::new(None) };
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+    iss: Option<String>,
+    // a string or a list of them
+    #[serde(default)]
+    aud: serde_json::Value,
+}
+
+fn var(env: &Env, name: &str) -> Option<String> {
+    env.var(name)
+        .ok()
+        .map(|v| v.to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Whether `/ident` needs an ID token from the identity provider, on once `OIDC_JWKS_URL` is set.
+///
+/// Idents fail rather than take any token of the provider while
+/// `OIDC_ISSUER` or `OIDC_AUDIENCE` is missing.
+pub fn is_required(env: &Env) -> bool {
+    var(env, JWKS_URL).is_some()
+}
+
+/// The cached key `kid` names, fetching the keys again when they're stale or
+/// don't have it. Keys without a `kid` match any.
+async fn key(url: &str, kid: Option<&str>) -> Result<Option<serde_json::Value>> {
+    let matches = |key: &serde_json::Value| {
+        kid.is_none_or(|kid| {
+            key.get("kid")
+                .and_then(|k| k.as_str())
+                .is_none_or(|k| k == kid)
+        })
+    };
+    let now = SystemTime::now();
+    let (found, stale) = CACHE.with(|cache| match cache.borrow().as_ref() {
+        Some(jwks) if jwks.url == url => {
+            let age = now.duration_since(jwks.fetched_at).unwrap_or_default();
+            let found = jwks.keys.iter().find(|key| matches(key)).cloned();
+            let stale = age >= JWKS_TTL || (found.is_none() && age >= MIN_REFETCH);
+            (found, stale)
+        }
+        _ => (None, true),
+    });
+    if !stale {
+        return Ok(found);
+    }
+
+    #[derive(Deserialize)]
+    struct Set {
+        keys: Vec<serde_json::Value>,
+    }
+    let parsed = Url::parse(url).map_err(|e| Error::RustError(e.to_string()))?;
+    let set: Set = Fetch::Url(parsed).send().await?.json().await?;
+    let found = set.keys.iter().find(|key| matches(key)).cloned();
+    CACHE.with(|cache| {
+        *cache.borrow_mut() = Some(Jwks {
+            url: url.to_owned(),
+            fetched_at: now,
+            keys: set.keys,
+        })
+    });
+    Ok(found)
+}
+
+/// Checks the RS256 signature of `message` with `jwk` through WebCrypto.
+async fn verify_rs256(jwk: &serde_json::Value, message: &str, signature: &[u8]) -> Result<bool> {
+    let scope: web_sys::WorkerGlobalScope = js_sys::global().unchecked_into();
+    let subtle = scope.crypto().map_err(Error::from)?.subtle();
+    let algorithm: js_sys::Object = js_sys::JSON::parse(RS256)
+        .map_err(Error::from)?
+        .unchecked_into();
+    let key_data: js_sys::Object = js_sys::JSON::parse(&jwk.to_string())
+        .map_err(Error::from)?
+        .unchecked_into();
+    let usages = js_sys::Array::of1(&"verify".into());
+
+    let key = subtle
+        .import_key_with_object("jwk", &key_data, &algorithm, false, &usages)
+        .map_err(Error::from)?;
+    let key: web_sys::CryptoKey = JsFuture::from(key).await?.unchecked_into();
+    let verified = subtle
+        .verify_with_object_and_u8_array_and_u8_array(
+            &algorithm,
+            &key,
+            signature,
+            message.as_bytes(),
+        )
+        .map_err(Error::from)?;
+    Ok(JsFuture::from(verified).await?.as_bool().unwrap_or(false))
+}
+
+/// Subject of a valid ID token: signed by a key of the JWKS, not expired, and
+/// from the configured issuer for the configured audience.
+pub async fn subject(env: &Env, token: &str) -> Result<Option<String>> {
+    let url = match var(env, JWKS_URL) {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let (issuer, audience) = match (var(env, ISSUER), var(env, AUDIENCE)) {
+        (Some(issuer), Some(audience)) => (issuer, audience),
+        // Tokens the provider issued to anyone for any client would pass
+        _ => {
+            return Err(Error::RustError(format!(
+                "{} needs {} and {}",
+                JWKS_URL, ISSUER, AUDIENCE
+            )))
+        }
+    };
+    let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).ok();
+    let (message, signature) = match token.rsplit_once('.') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let (header, payload) = match message.split_once('.') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let header: Header = match decode(header).and_then(|h| serde_json::from_slice(&h).ok()) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let claims: Claims = match decode(payload).and_then(|p| serde_json::from_slice(&p).ok()) {
+        Some(claims) => claims,
+        None => return Ok(None),
+    };
+    let signature = match decode(signature) {
+        Some(signature) => signature,
+        None => return Ok(None),
+    };
+    if header.alg != "RS256" {
+        return Ok(None);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_secs();
+    if claims.exp <= now {
+        return Ok(None);
+    }
+    if claims.iss.as_ref() != Some(&issuer) {
+        return Ok(None);
+    }
+    let listed = match &claims.aud {
+        serde_json::Value::String(aud) => *aud == audience,
+        serde_json::Value::Array(auds) => auds.iter().any(|aud| *aud == *audience),
+        _ => false,
+    };
+    if !listed {
+        return Ok(None);
+    }
+
+    let jwk = match key(&url, header.kid.as_deref()).await? {
+        Some(jwk) => jwk,
+        None => return Ok(None),
+    };
+    if !verify_rs256(&jwk, message, &signature).await? {
+        return Ok(None);
+    }
+    Ok(Some(claims.sub))
+}
@@ -0,0 +1,748 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use web_time::UNIX_EPOCH;
+use worker::{Response, Result};
+
+use crate::{
+    error::ApiError,
+    proto::{
+        Backoff, LinkState, Role, SessionState, Signal, Transport, PROTOCOL_VERSIONS,
+        VERSION_HEADER,
+    },
+};
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Names the values take in JSON, so renames show up as they're sent.
+fn names<T: Serialize>(values: &[T]) -> Vec<Value> {
+    values
+        .iter()
+        .map(|value| serde_json::to_value(value).expect("serializable"))
+        .collect()
+}
+
+fn bytes() -> Value {
+    json!({ "type": "array", "items": { "type": "integer", "minimum": 0, "maximum": 255 } })
+}
+
+fn slot() -> Value {
+    json!({ "type": "integer", "minimum": 0, "maximum": 255 })
+}
+
+/// One of every signal, the payloads don't matter.
+fn signals() -> Vec<Signal> {
+    let time = UNIX_EPOCH;
+    let candidate = (String::new(), None, None);
+    vec![
+        Signal::SetSDP(String::new()),
+        Signal::AddCandidate(candidate.clone()),
+        Signal::JoinRoom(String::new()),
+        Signal::ConnectAt(time),
+        Signal::NextPoll(time),
+        Signal::SetService(String::new()),
+        Signal::Peer(0),
+        Signal::Renegotiate(0),
+        Signal::Leave,
+        Signal::PeerLeft(0),
+        Signal::Password(String::new()),
+        Signal::Relay(vec![]),
+        Signal::Role(Role::Offerer),
+        Signal::PeerJoined(0),
+        Signal::PeerGone(0),
+        Signal::AddCandidates(vec![candidate]),
+        Signal::Resync,
+        Signal::LinkState(LinkState {
+            generation: 0,
+            sent_sdp: false,
+            ice_done: false,
+            connect_at: None,
+        }),
+        Signal::RoomOwner,
+        Signal::Kick(0),
+        Signal::Backoff(Backoff::Load),
+        Signal::PublicKey(vec![]),
+        Signal::Sealed {
+            nonce: vec![],
+            ciphertext: vec![],
+        },
+        Signal::Seq(0),
+        Signal::Ack(0),
+        Signal::Restart,
+        Signal::Stats(Value::Null),
+        Signal::ReceivedAt(time),
+        Signal::State(SessionState::Created),
+        Signal::HoldCode,
+        Signal::SetRoomMeta(Value::Null),
+        Signal::RoomMeta(Value::Null),
+        Signal::ExpiresIn(0),
+        Signal::Spectate,
+        Signal::ConnectCancelled(0),
+        Signal::SetIceServers(vec![]),
+        Signal::IceServers(vec![]),
+        Signal::ScreenJoins,
+        Signal::JoinRequest(String::new()),
+        Signal::Accept(String::new()),
+        Signal::HasMore,
+        Signal::Room(String::new()),
+    ]
+}
+
+/// Schema of what the signal carries, `None` for the ones sent as their name.
+///
+/// The match has to cover every signal, new ones don't build until they're
+/// described here and added to `signals`.
+fn payload(signal: &Signal) -> Option<Value> {
+    let string = json!({ "type": "string" });
+    let uint = json!({ "type": "integer", "minimum": 0 });
+    let any = json!({});
+    let servers = json!({ "type": "array", "items": schema("IceServer") });
+    Some(match signal {
+        Signal::SetSDP(_)
+        | Signal::JoinRoom(_)
+        | Signal::SetService(_)
+        | Signal::Password(_)
+        | Signal::Room(_)
+        | Signal::JoinRequest(_)
+        | Signal::Accept(_) => string,
+        Signal::AddCandidate(_) => schema("IceCandidate"),
+        Signal::AddCandidates(_) => json!({ "type": "array", "items": schema("IceCandidate") }),
+        Signal::ConnectAt(_) | Signal::NextPoll(_) | Signal::ReceivedAt(_) => schema("Time"),
+        Signal::Peer(_)
+        | Signal::PeerLeft(_)
+        | Signal::PeerJoined(_)
+        | Signal::PeerGone(_)
+        | Signal::Kick(_)
+        | Signal::ConnectCancelled(_) => slot(),
+        Signal::Renegotiate(_) | Signal::Seq(_) | Signal::Ack(_) | Signal::ExpiresIn(_) => uint,
+        Signal::Relay(_) | Signal::PublicKey(_) => bytes(),
+        Signal::Role(_) => schema("Role"),
+        Signal::LinkState(_) => schema("LinkState"),
+        Signal::Backoff(_) => schema("Backoff"),
+        Signal::State(_) => schema("SessionState"),
+        Signal::Sealed { .. } => json!({
+            "type": "object",
+            "required": ["nonce", "ciphertext"],
+            "properties": { "nonce": bytes(), "ciphertext": bytes() },
+        }),
+        Signal::Stats(_) | Signal::SetRoomMeta(_) | Signal::RoomMeta(_) => any,
+        Signal::SetIceServers(_) | Signal::IceServers(_) => servers,
+        Signal::Leave
+        | Signal::Resync
+        | Signal::RoomOwner
+        | Signal::Restart
+        | Signal::HoldCode
+        | Signal::Spectate
+        | Signal::ScreenJoins
+        | Signal::HasMore => return None,
+    })
+}
+
+/// `Signal` as serde writes it to JSON, externally tagged.
+fn signal_schema() -> Value {
+    let mut variants = vec![];
+    for signal in signals().iter() {
+        let sent_by = if signal.can_send() {
+            "clients and the server"
+        } else {
+            "the server, or only along with joining"
+        };
+        let value = serde_json::to_value(signal).expect("serializable");
+        let variant = match (payload(signal), value) {
+            (None, name) => json!({ "type": "string", "enum": [name], "description": sent_by }),
+            (Some(payload), Value::Object(tagged)) => {
+                let name = tagged.keys().next().expect("tagged").clone();
+                json!({
+                    "type": "object",
+                    "required": [name],
+                    "properties": { name: payload },
+                    "additionalProperties": false,
+                    "description": sent_by,
+                })
+            }
+            (Some(_), _) => unreachable!("signals with a payload are tagged"),
+        };
+        variants.push(variant);
+    }
+    json!({ "oneOf": variants })
+}
+
+/// One of every error, for the codes they answer with.
+fn errors() -> Vec<ApiError> {
+    vec![
+        ApiError::MethodNotAllowed,
+        ApiError::NotFound,
+        ApiError::MissingToken,
+        ApiError::InvalidToken,
+        ApiError::Malformed(String::new()),
+        ApiError::CantSend,
+        ApiError::NeedService,
+        ApiError::ServiceNotAllowed,
+        ApiError::RoomExpired,
+        ApiError::RoomUnknown,
+        ApiError::RoomFull,
+        ApiError::WrongPassword,
+        ApiError::NotAllowed,
+        ApiError::ConnectionDone,
+        ApiError::Conflict,
+        ApiError::JoinConflict,
+        ApiError::ConflictingJoin,
+        ApiError::InvalidCode,
+        ApiError::CodeTaken,
+        ApiError::InvalidTransfer,
+        ApiError::InvalidInvite,
+        ApiError::TooLarge(""),
+        ApiError::InvalidCandidate(""),
+        ApiError::IceServersNotAllowed,
+        ApiError::ExpectedUpgrade,
+        ApiError::UnsupportedVersion,
+        ApiError::RateLimited(0),
+        ApiError::QuotaExceeded(0),
+        ApiError::Unavailable(0),
+        ApiError::Maintenance(0),
+        ApiError::Unauthorized,
+        ApiError::InvalidApiKey,
+        ApiError::ChallengeFailed,
+        ApiError::InvalidIdToken,
+        ApiError::Banned,
+        ApiError::Filtered(""),
+        ApiError::ServerError,
+    ]
+}
+
+fn components() -> Value {
+    let mut codes: Vec<&str> = errors().iter().map(ApiError::code).collect();
+    codes.sort();
+    codes.dedup();
+    let time = json!({
+        "type": "object",
+        "required": ["secs_since_epoch", "nanos_since_epoch"],
+        "properties": {
+            "secs_since_epoch": { "type": "integer", "minimum": 0 },
+            "nanos_since_epoch": { "type": "integer", "minimum": 0 },
+        },
+    });
+    let capabilities = json!({
+        "type": "object",
+        "properties": {
+            "websocket": { "type": "boolean", "default": true },
+            "bare": { "type": "boolean", "default": true },
+            "relay": { "type": "boolean", "default": true },
+            "maxSdpSize": { "type": ["integer", "null"], "minimum": 0 },
+        },
+    });
+    json!({
+        "schemas": {
+            "Time": time,
+            "Signal": signal_schema(),
+            "IceCandidate": {
+                "description": "Candidate string, `sdpMid` and `sdpMLineIndex`",
+                "type": "array",
+                "prefixItems": [
+                    { "type": "string" },
+                    { "type": ["string", "null"] },
+                    { "type": ["integer", "null"], "minimum": 0 },
+                ],
+                "minItems": 3,
+                "maxItems": 3,
+            },
+            "IceServer": {
+                "type": "object",
+                "required": ["urls"],
+                "properties": {
+                    "urls": { "type": "array", "items": { "type": "string" } },
+                    "username": { "type": "string" },
+                    "credential": { "type": "string" },
+                },
+            },
+            "LinkState": {
+                "type": "object",
+                "required": ["generation", "sent_sdp", "ice_done", "connect_at"],
+                "properties": {
+                    "generation": { "type": "integer", "minimum": 0 },
+                    "sent_sdp": { "type": "boolean" },
+                    "ice_done": { "type": "boolean" },
+                    "connect_at": { "oneOf": [schema("Time"), { "type": "null" }] },
+                },
+            },
+            "Role": { "enum": names(&[Role::Offerer, Role::Answerer]) },
+            "Backoff": { "enum": names(&[Backoff::Load, Backoff::Queue, Backoff::Idle]) },
+            "SessionState": {
+                "enum": names(&[
+                    SessionState::Created,
+                    SessionState::Joined,
+                    SessionState::Negotiating,
+                    SessionState::Scheduled,
+                    SessionState::Connected,
+                    SessionState::Done,
+                    SessionState::Expired,
+                ]),
+            },
+            "Transport": {
+                "enum": names(&[Transport::WebTransport, Transport::WebSocket, Transport::Poll]),
+            },
+            "Capabilities": capabilities,
+            "IdentRequest": {
+                "type": "object",
+                "properties": { "capabilities": schema("Capabilities") },
+            },
+            "IdentResponse": {
+                "type": "object",
+                "required": ["token", "iceServers", "signalVersions"],
+                "properties": {
+                    "token": { "type": "string" },
+                    "iceServers": { "type": "array", "items": schema("IceServer") },
+                    "signalVersions": {
+                        "description": "Lowest and highest `X-Signal-Version`",
+                        "type": "array",
+                        "items": { "type": "integer" },
+                        "minItems": 2,
+                        "maxItems": 2,
+                    },
+                    "transports": { "type": "array", "items": schema("Transport") },
+                    "features": schema("Capabilities"),
+                },
+            },
+            "PollResponse": {
+                "type": "object",
+                "required": ["signals", "server_time"],
+                "properties": {
+                    "signals": { "type": "array", "items": schema("Signal") },
+                    "server_time": schema("Time"),
+                    "has_more": { "type": "boolean", "default": false },
+                },
+            },
+            "HostRequest": {
+                "type": "object",
+                "properties": {
+                    "service": { "type": "string" },
+                    "password": { "type": "string" },
+                    "capabilities": schema("Capabilities"),
+                },
+            },
+            "HostResponse": {
+                "type": "object",
+                "required": ["token", "iceServers", "roomCode", "nextPoll"],
+                "properties": {
+                    "token": { "type": "string" },
+                    "iceServers": { "type": "array", "items": schema("IceServer") },
+                    "roomCode": { "type": "string" },
+                    "nextPoll": schema("Time"),
+                },
+            },
+            "JoinRequest": {
+                "type": "object",
+                "description": "Either `code` or `invite` is needed",
+                "properties": {
+                    "code": { "type": "string" },
+                    "invite": { "type": "string" },
+                    "service": { "type": "string" },
+                    "password": { "type": "string" },
+                    "signals": { "type": "array", "items": schema("Signal") },
+                    "capabilities": schema("Capabilities"),
+                },
+            },
+            "JoinResponse": {
+                "type": "object",
+                "required": ["token", "iceServers", "signals", "serverTime"],
+                "properties": {
+                    "token": { "type": "string" },
+                    "iceServers": { "type": "array", "items": schema("IceServer") },
+                    "signals": { "type": "array", "items": schema("Signal") },
+                    "serverTime": schema("Time"),
+                },
+            },
+            "ErrorResponse": {
+                "type": "object",
+                "required": ["code", "message"],
+                "properties": {
+                    "code": { "type": "string", "enum": codes },
+                    "message": { "type": "string" },
+                    "retry_after": { "type": "integer", "minimum": 0 },
+                },
+            },
+        },
+        "responses": {
+            "Error": {
+                "description": "Whatever went wrong, `code` tells which",
+                "content": { "application/json": { "schema": schema("ErrorResponse") } },
+            },
+        },
+        "parameters": {
+            "Version": {
+                "name": VERSION_HEADER,
+                "in": "header",
+                "schema": {
+                    "type": "integer",
+                    "minimum": PROTOCOL_VERSIONS.start(),
+                    "maximum": PROTOCOL_VERSIONS.end(),
+                    "default": PROTOCOL_VERSIONS.start(),
+                },
+            },
+            "Token": {
+                "name": "Authorization",
+                "in": "header",
+                "required": true,
+                "description": "Token from `/ident`",
+                "schema": { "type": "string" },
+            },
+        },
+    })
+}
+
+/// OpenAPI description of `/ident`, `/poll`, `/host` and `/join`, built from
+/// the wire types.
+///
+/// Bodies added along with `/host` are camelCase throughout, older ones keep
+/// their snake_case fields.
+fn document() -> Value {
+    let signals = json!({ "type": "array", "items": schema("Signal") });
+    let polled = json!({
+        "description": "Bare signals in version 1, a `PollResponse` from version 2",
+        "content": {
+            "application/json": {
+                "schema": { "oneOf": [signals.clone(), schema("PollResponse")] },
+            },
+        },
+    });
+    json!({
+        "openapi": "3.1.0",
+        "info": { "title": "signalling", "version": env!("CARGO_PKG_VERSION") },
+        "paths": {
+            "/ident": {
+                "post": {
+                    "summary": "Hands out a token, the body can be left empty",
+                    "requestBody": {
+                        "content": { "application/json": { "schema": schema("IdentRequest") } },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The new token",
+                            "content": {
+                                "application/json": { "schema": schema("IdentResponse") },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/poll": {
+                "parameters": [
+                    { "$ref": "#/components/parameters/Token" },
+                    { "$ref": "#/components/parameters/Version" },
+                ],
+                "post": {
+                    "summary": "Sends signals and pulls the ones queued",
+                    "requestBody": {
+                        "content": { "application/json": { "schema": signals } },
+                    },
+                    "responses": {
+                        "200": polled.clone(),
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+                "get": {
+                    "summary": "Pulls the signals queued, sending none",
+                    "responses": {
+                        "200": polled,
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/host": {
+                "post": {
+                    "summary": "Hands out a token in a new room",
+                    "requestBody": {
+                        "content": { "application/json": { "schema": schema("HostRequest") } },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The new token and the room's code",
+                            "content": {
+                                "application/json": { "schema": schema("HostResponse") },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/join": {
+                "post": {
+                    "summary": "Hands out a token in the room by code or invite",
+                    "requestBody": {
+                        "content": { "application/json": { "schema": schema("JoinRequest") } },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The new token and what the peers queued",
+                            "content": {
+                                "application/json": { "schema": schema("JoinResponse") },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+        },
+        "components": components(),
+    })
+}
+
+/// Serves the description for client generators, it changes along with `Signal`.
+pub fn openapi() -> Result<Response> {
+    Response::from_json(&document())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use web_time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{document, errors, signals};
+    use crate::{
+        error::ApiError,
+        proto::{
+            Capabilities, ErrorResponse, HostResponse, IceServer, IdentRequest, IdentResponse,
+            JoinResponse, LinkState, PollResponse, Signal, Transport,
+        },
+    };
+
+    /// Checks `value` against `schema`, for the keywords the document uses.
+    fn check(doc: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+        if let Some(Value::String(path)) = schema.get("$ref") {
+            let target = doc.pointer(&path[1..]).ok_or(format!("no {}", path))?;
+            return check(doc, target, value);
+        }
+        if let Some(Value::Array(options)) = schema.get("oneOf") {
+            let passed = options
+                .iter()
+                .filter(|option| check(doc, option, value).is_ok())
+                .count();
+            if passed != 1 {
+                return Err(format!("{} matches {} of oneOf", value, passed));
+            }
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => types.as_str().into_iter().collect(),
+            };
+            let matches = |t: &&str| match *t {
+                "string" => value.is_string(),
+                "integer" => value.is_u64() || value.is_i64(),
+                "number" => value.is_number(),
+                "boolean" => value.is_boolean(),
+                "array" => value.is_array(),
+                "object" => value.is_object(),
+                "null" => value.is_null(),
+                _ => false,
+            };
+            if !types.iter().any(matches) {
+                return Err(format!("{} isn't {:?}", value, types));
+            }
+        }
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.contains(value) {
+                return Err(format!("{} isn't one of {:?}", value, options));
+            }
+        }
+        if let Some(n) = value.as_f64() {
+            let below = schema
+                .get("minimum")
+                .and_then(Value::as_f64)
+                .is_some_and(|m| n < m);
+            let above = schema
+                .get("maximum")
+                .and_then(Value::as_f64)
+                .is_some_and(|m| n > m);
+            if below || above {
+                return Err(format!("{} out of range", n));
+            }
+        }
+        if let Value::Object(fields) = value {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for required in schema
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+            {
+                let required = required.as_str().unwrap();
+                if !fields.contains_key(required) {
+                    return Err(format!("{} lacks {}", value, required));
+                }
+            }
+            for (name, field) in fields.iter() {
+                match properties.and_then(|p| p.get(name)) {
+                    Some(property) => check(doc, property, field)?,
+                    None if schema.get("additionalProperties") == Some(&json!(false)) => {
+                        return Err(format!("{} isn't expected", name))
+                    }
+                    None => {}
+                }
+            }
+        }
+        if let Value::Array(items) = value {
+            let len = items.len() as u64;
+            let short = schema
+                .get("minItems")
+                .and_then(Value::as_u64)
+                .is_some_and(|m| len < m);
+            let long = schema
+                .get("maxItems")
+                .and_then(Value::as_u64)
+                .is_some_and(|m| len > m);
+            if short || long {
+                return Err(format!("{} has {} items", value, len));
+            }
+            let prefix = schema.get("prefixItems").and_then(Value::as_array);
+            for (i, item) in items.iter().enumerate() {
+                if let Some(item_schema) = prefix.and_then(|p| p.get(i)).or(schema.get("items")) {
+                    check(doc, item_schema, item)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn valid<T: serde::Serialize>(doc: &Value, name: &str, value: &T) {
+        let schema = json!({ "$ref": format!("#/components/schemas/{}", name) });
+        let value = serde_json::to_value(value).unwrap();
+        if let Err(e) = check(doc, &schema, &value) {
+            panic!("{} {}: {}", name, value, e);
+        }
+    }
+
+    /// Position of the error, new ones don't build until they're numbered here.
+    fn index(e: &ApiError) -> usize {
+        match e {
+            ApiError::MethodNotAllowed => 0,
+            ApiError::NotFound => 1,
+            ApiError::MissingToken => 2,
+            ApiError::InvalidToken => 3,
+            ApiError::Malformed(_) => 4,
+            ApiError::CantSend => 5,
+            ApiError::NeedService => 6,
+            ApiError::ServiceNotAllowed => 7,
+            ApiError::RoomExpired => 8,
+            ApiError::RoomUnknown => 9,
+            ApiError::RoomFull => 10,
+            ApiError::WrongPassword => 11,
+            ApiError::NotAllowed => 12,
+            ApiError::ConnectionDone => 13,
+            ApiError::Conflict => 14,
+            ApiError::JoinConflict => 15,
+            ApiError::ConflictingJoin => 16,
+            ApiError::InvalidCode => 17,
+            ApiError::CodeTaken => 18,
+            ApiError::InvalidTransfer => 19,
+            ApiError::InvalidInvite => 20,
+            ApiError::TooLarge(_) => 21,
+            ApiError::InvalidCandidate(_) => 22,
+            ApiError::IceServersNotAllowed => 23,
+            ApiError::ExpectedUpgrade => 24,
+            ApiError::UnsupportedVersion => 25,
+            ApiError::RateLimited(_) => 26,
+            ApiError::QuotaExceeded(_) => 27,
+            ApiError::Unavailable(_) => 28,
+            ApiError::Maintenance(_) => 29,
+            ApiError::Unauthorized => 30,
+            ApiError::InvalidApiKey => 31,
+            ApiError::ChallengeFailed => 32,
+            ApiError::InvalidIdToken => 33,
+            ApiError::Banned => 34,
+            ApiError::Filtered(_) => 35,
+            ApiError::ServerError => 36,
+        }
+    }
+
+    #[test]
+    fn every_signal_and_error_listed() {
+        // BARE numbers the variants in order, one past the last isn't a signal
+        let variants: Vec<u8> = signals()
+            .iter()
+            .map(|signal| serde_bare::ser::to_vec(signal).unwrap()[0])
+            .collect();
+        assert_eq!(variants, (0..variants.len() as u8).collect::<Vec<_>>());
+        assert!(serde_bare::de::from_slice::<Signal>(&[variants.len() as u8]).is_err());
+
+        let listed: Vec<usize> = errors().iter().map(index).collect();
+        assert_eq!(listed, (0..listed.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn values_match_document() {
+        let doc = document();
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let server = IceServer {
+            urls: vec!["stun:stun.example.com".to_owned()],
+            username: None,
+            credential: None,
+        };
+
+        let mut sent = signals();
+        sent.extend([
+            Signal::AddCandidate(("candidate:1".to_owned(), Some("0".to_owned()), Some(1))),
+            Signal::LinkState(LinkState {
+                generation: 3,
+                sent_sdp: true,
+                ice_done: true,
+                connect_at: Some(at),
+            }),
+            Signal::Stats(json!({ "rtt": 0.1 })),
+            Signal::IceServers(vec![server.clone()]),
+        ]);
+        for signal in sent.iter() {
+            valid(&doc, "Signal", signal);
+        }
+        for e in errors().iter() {
+            valid(&doc, "ErrorResponse", &ErrorResponse::from(e));
+        }
+
+        valid(&doc, "IdentRequest", &IdentRequest::default());
+        valid(
+            &doc,
+            "IdentResponse",
+            &IdentResponse {
+                token: "token".to_owned(),
+                ice_servers: vec![server.clone()],
+                signal_versions: (1, 2),
+                transports: vec![Transport::WebSocket, Transport::Poll],
+                features: Capabilities {
+                    max_sdp_size: Some(65536),
+                    ..Default::default()
+                },
+            },
+        );
+        valid(
+            &doc,
+            "HostResponse",
+            &HostResponse {
+                token: "token".to_owned(),
+                ice_servers: vec![server.clone()],
+                room_code: "ABCD".to_owned(),
+                next_poll: SystemTime::now(),
+            },
+        );
+        valid(
+            &doc,
+            "JoinResponse",
+            &JoinResponse {
+                token: "token".to_owned(),
+                ice_servers: vec![server],
+                signals: sent.clone(),
+                server_time: SystemTime::now(),
+            },
+        );
+        valid(
+            &doc,
+            "PollResponse",
+            &PollResponse {
+                signals: sent,
+                server_time: SystemTime::now(),
+                has_more: true,
+            },
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use serde_json::json;
+use web_time::{SystemTime, UNIX_EPOCH};
+use worker::{
+    async_trait, console_log, wasm_bindgen::JsValue, Env, Fetch, Headers, Method, Request,
+    RequestInit, Result,
+};
+
+use crate::{
+    db::{Entry, Page, Storage},
+    keys::{CryptoKeys, KeyGenerator},
+};
+
+const HEX: &str = "0123456789abcdef";
+/// `SpanKind` of OTLP, polls are `SERVER` spans and storage operations `CLIENT` ones.
+const INTERNAL: u8 = 1;
+const SERVER: u8 = 2;
+const CLIENT: u8 = 3;
+
+/// Whether spans are exported, once `OTLP_ENDPOINT` is set.
+pub fn is_enabled(env: &Env) -> bool {
+    env.var("OTLP_ENDPOINT")
+        .is_ok_and(|v| !v.to_string().is_empty())
+}
+
+struct Span {
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+/// A span that's running, `Spans::end` records it.
+pub struct Started {
+    name: &'static str,
+    start: SystemTime,
+}
+
+/// Spans of one request, under a root span `Spans::finish` closes.
+///
+/// The default records nothing, clones add to the same spans.
+#[derive(Clone, Default)]
+pub struct Spans(Option<Rc<RefCell<Vec<Span>>>>);
+
+impl Spans {
+    pub fn recording() -> Self {
+        Spans(Some(Rc::default()))
+    }
+
+    pub fn start(&self, name: &'static str) -> Started {
+        Started {
+            name,
+            start: SystemTime::now(),
+        }
+    }
+
+    fn record(&self, span: Span) {
+        if let Some(ref spans) = self.0 {
+            spans.borrow_mut().push(span);
+        }
+    }
+
+    /// Records a phase of the request.
+    pub fn end(&self, started: Started) {
+        self.record(Span {
+            name: started.name.to_owned(),
+            kind: INTERNAL,
+            start: started.start,
+            end: SystemTime::now(),
+            attributes: vec![],
+        });
+    }
+
+    /// Records the root span, the request as a whole.
+    pub fn finish(&self, started: Started, attributes: Vec<(&'static str, String)>) {
+        self.record(Span {
+            name: started.name.to_owned(),
+            kind: SERVER,
+            start: started.start,
+            end: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    /// Wraps `storage` so every operation gets a span, unless nothing's recorded.
+    pub fn storage(&self, storage: Box<dyn Storage>) -> Box<dyn Storage> {
+        match self.0 {
+            Some(_) => Box::new(TracedStorage {
+                inner: storage,
+                spans: self.clone(),
+            }),
+            None => storage,
+        }
+    }
+
+    /// Only the kind of object is recorded, auth keys are tokens.
+    fn operation(&self, name: &str, key: &str, start: SystemTime) {
+        let prefix = key.split(':').next().unwrap_or_default();
+        self.record(Span {
+            name: format!("storage.{}", name),
+            kind: CLIENT,
+            start,
+            end: SystemTime::now(),
+            attributes: vec![("db.prefix", prefix.to_owned())],
+        });
+    }
+}
+
+/// Storage recording a span per operation.
+struct TracedStorage {
+    inner: Box<dyn Storage>,
+    spans: Spans,
+}
+
+#[async_trait::async_trait(?Send)]
+impl Storage for TracedStorage {
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let start = SystemTime::now();
+        let res = self.inner.exists(key).await;
+        self.spans.operation("exists", key, start);
+        res
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Entry>> {
+        let start = SystemTime::now();
+        let res = self.inner.get(key).await;
+        self.spans.operation("get", key, start);
+        res
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        meta: HashMap<String, String>,
+        version: Option<&str>,
+        expire_at: Option<SystemTime>,
+    ) -> Result<bool> {
+        let start = SystemTime::now();
+        let res = self.inner.put(key, body, meta, version, expire_at).await;
+        self.spans.operation("put", key, start);
+        res
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let start = SystemTime::now();
+        let res = self.inner.delete(key).await;
+        self.spans.operation("delete", key, start);
+        res
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page> {
+        let start = SystemTime::now();
+        let res = self.inner.list_page(prefix, cursor).await;
+        self.spans.operation("list", prefix, start);
+        res
+    }
+
+    fn expires(&self) -> bool {
+        self.inner.expires()
+    }
+
+    async fn purge_expired(&self) -> Result<()> {
+        self.inner.purge_expired().await
+    }
+}
+
+fn nanos(at: SystemTime) -> String {
+    at.duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_nanos()
+        .to_string()
+}
+
+/// OTLP/HTTP JSON of `spans`, the root span parenting every other one.
+fn body(spans: Vec<Span>) -> Result<serde_json::Value> {
+    let trace_id = CryptoKeys.key_in(HEX, 32)?;
+    let root_id = CryptoKeys.key_in(HEX, 16)?;
+    let mut out = vec![];
+    for span in spans.into_iter() {
+        let is_root = span.kind == SERVER;
+        let span_id = if is_root {
+            root_id.clone()
+        } else {
+            CryptoKeys.key_in(HEX, 16)?
+        };
+        let attributes: Vec<_> = span
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+            .collect();
+        out.push(json!({
+            "traceId": trace_id,
+            "spanId": span_id,
+            "parentSpanId": if is_root { "" } else { root_id.as_str() },
+            "name": span.name,
+            "kind": span.kind,
+            "startTimeUnixNano": nanos(span.start),
+            "endTimeUnixNano": nanos(span.end),
+            "attributes": attributes,
+        }));
+    }
+    Ok(json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": "signalling"}}],
+            },
+            "scopeSpans": [{"scope": {"name": "signalling"}, "spans": out}],
+        }],
+    }))
+}
+
+/// Sends the spans to `OTLP_ENDPOINT`, as `wait_until` work once the response
+/// is out. `OTLP_HEADERS` is a secret of `;` separated `name=value` pairs, for
+/// collectors that want a key.
+pub async fn export(env: Env, spans: Spans) {
+    let spans = match spans.0 {
+        Some(spans) => spans.take(),
+        None => return,
+    };
+    let exported = async {
+        let endpoint = env.var("OTLP_ENDPOINT")?.to_string();
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        if let Ok(pairs) = env.secret("OTLP_HEADERS") {
+            for (name, value) in pairs
+                .to_string()
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+            {
+                headers.set(name.trim(), value.trim())?;
+            }
+        }
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(JsValue::from_str(&body(spans)?.to_string())));
+        let req = Request::new_with_init(&endpoint, &init)?;
+        let res = Fetch::Request(req).send().await?;
+        Ok::<u16, worker::Error>(res.status_code())
+    };
+    match exported.await {
+        Ok(status) if status < 300 => {}
+        Ok(status) => console_log!("OTLP collector answered {}", status),
+        Err(e) => console_log!("couldn't export spans: {}", e),
+    }
+}
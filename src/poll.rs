@@ -1,46 +1,69 @@
 use std::collections::HashSet;
 
-use serde::{Deserialize, Serialize};
-use web_time::SystemTime;
-use worker::{console_log, Bucket, Env, Include, Request, Response, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::{future::join_all, stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use web_time::{Duration, SystemTime};
+use worker::{console_log, js_sys, Context, Delay, Env, Error, Request, Response, Result};
 
 use crate::{
-    auth::{Auth, AuthInfo},
-    db::BucketInfo,
-    room::Room,
+    analytics::{record, Dimensions, Event},
+    apikey,
+    audit::{audit, Action, Record},
+    auth::{expiry_bucket, legacy_prefixes, Auth, AuthInfo, SendError},
+    ban::{self, Ban, Report},
+    clock::{Clock, SystemClock},
+    config::{profiles, Config},
+    db::{storage, BucketInfo, Corrupted, Data, Entry, Metadata, Put, Storage},
+    deadletter::{self, DeadLetter},
+    error::ApiError,
+    filter::{self, Verdict},
+    invite::{self, Invite},
+    limit::{limit, quota},
+    load::load,
+    lobby::{Listing, ListingUpdate},
+    log::{request_id, token_prefix, Trace},
+    maintenance,
+    metrics::{count, Counter},
+    oidc,
+    otlp::{self, Spans},
+    pool,
+    proto::{
+        BatchPoll, BatchResponse, BatchResult, Capabilities, ErrorResponse, HostResponse,
+        IceServer, IdentRequest, IdentResponse, JoinResponse, PollResponse, RefreshResponse,
+        Signal, Transport, BARE_MIME, PROTOCOL_VERSIONS, VERSION_HEADER,
+    },
+    room::{
+        bury, create_unique, is_buried, room_key, vanity_code, JoinError, Room, RoomInfo, Screened,
+        Tombstone, CREATE_ATTEMPTS,
+    },
+    token,
+    transfer::Transfer,
+    turn::{ice_servers, is_ice_allowed},
+    turnstile,
+    ws::{self, notify},
 };
 
-pub type IceCandidate = (String, Option<String>, Option<u16>);
+/// Deletes in flight at once during cleanup.
+const DELETE_CONCURRENCY: usize = 16;
+/// Age before cleanup checks a room for members that are gone, the creator's
+/// auth is written after the room.
+const ORPHAN_GRACE: Duration = Duration::from_secs(600);
 
-#[derive(Serialize, Deserialize, Clone)]
-pub enum Signal {
-    SetSDP(String),
-    AddCandidate(IceCandidate),
-    JoinRoom(String),
-    ConnectAt(SystemTime),
-    NextPoll(SystemTime),
-    SetService(String),
-}
+/// Queue cleanup defers its deletions to, when bound.
+pub const DELETION_QUEUE: &str = "DELETIONS";
+// queue messages carry up to this many keys
+const KEYS_PER_MESSAGE: usize = 100;
 
-impl Signal {
-    pub fn can_send(&self) -> bool {
-        match self {
-            Self::SetSDP(_) => true,
-            Self::AddCandidate(_) => true,
-            Self::JoinRoom(_) => false,
-            Self::ConnectAt(_) => false,
-            Self::NextPoll(_) => false,
-            Self::SetService(_) => false,
-        }
-    }
-}
+/// Longest display name of a public room, in characters.
+const MAX_NAME_LENGTH: usize = 64;
 
-#[derive(Serialize, Deserialize)]
-struct IdentResponse {
-    token: String,
-}
+/// Time between storage checks of a held poll, in milliseconds, give or take `WAIT_JITTER`.
+const WAIT_INTERVAL: f64 = 1000.0;
+const WAIT_JITTER: f64 = 250.0;
 
-fn is_service_allowed(env: &Env, svc: &str) -> Result<bool> {
+/// Checks `svc` against the `;` separated `SERVICES` allow-list.
+pub fn is_service_allowed(env: &Env, svc: &str) -> Result<bool> {
     Ok(env
         .var("SERVICES")?
         .to_string()
@@ -48,126 +71,1836 @@ fn is_service_allowed(env: &Env, svc: &str) -> Result<bool> {
         .any(|v| v == svc))
 }
 
-pub async fn ident(env: Env) -> Result<Response> {
-    let bucket = env.bucket("rtc")?;
-    let auth = Auth::create(&bucket).await?;
-    let token = auth.key.clone();
-    auth.write(&bucket).await?;
-    Response::from_json(&IdentResponse { token })
+#[derive(Deserialize)]
+struct IdentQuery {
+    service: Option<String>,
+}
+
+/// Hands out a new token, `?service=` sets the service upfront.
+///
+/// With API keys configured the service is the one of the `X-Api-Key` instead.
+pub async fn ident(mut req: Request, env: Env, ctx: &Context) -> Result<Response> {
+    let service = req.query::<IdentQuery>().ok().and_then(|q| q.service);
+    let body = req.text().await?;
+    let body = if body.trim().is_empty() {
+        IdentRequest::default()
+    } else {
+        match serde_json::from_str::<IdentRequest>(&body) {
+            Ok(body) => body,
+            Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+        }
+    };
+    match issue(&req, &env, ctx, service, body.capabilities).await? {
+        Ok(body) => Response::from_json(&body),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// A new session that passed the checks of `/ident`, not stored yet.
+struct Minted {
+    auth: Auth,
+    config: Config,
+    // drawn from the pool, so it's stored already
+    pooled: bool,
 }
 
-pub async fn poll(mut req: Request, env: Env) -> Result<Response> {
+/// Checks the request may get a token and creates its auth, for `service`
+/// when it asks for one. `pooled` takes a key from the pool if there's any.
+async fn mint(
+    req: &Request,
+    env: &Env,
+    clock: &dyn Clock,
+    mut service: Option<String>,
+    pooled: bool,
+) -> Result<std::result::Result<Minted, ApiError>> {
+    if apikey::is_required(env) {
+        let keyed = match req.headers().get(apikey::HEADER)? {
+            Some(key) => apikey::service(env, &key).await?,
+            None => None,
+        };
+        let keyed = match keyed {
+            Some(keyed) => keyed,
+            None => return Ok(Err(ApiError::InvalidApiKey)),
+        };
+        if service.as_ref().is_some_and(|svc| *svc != keyed) {
+            return Ok(Err(ApiError::ServiceNotAllowed));
+        }
+        service = Some(keyed);
+    }
+    if let Some(ref svc) = service {
+        if !is_service_allowed(env, svc)? {
+            return Ok(Err(ApiError::ServiceNotAllowed));
+        }
+    }
+
+    // Resolved once, the session keeps it even if the service's profile changes
+    let profile = match service {
+        Some(ref svc) => profiles(env).await?.remove(svc),
+        None => None,
+    };
+    let config = match profile {
+        Some(ref profile) => Config::from_env(env).with_profile(profile),
+        None => Config::from_env(env),
+    };
+    let ip = req.headers().get("CF-Connecting-IP")?;
+    if let (Some(svc), Some(ip)) = (&service, &ip) {
+        if ban::is_banned(&*storage(env)?, svc, None, Some(ip)).await? {
+            return Ok(Err(ApiError::Banned));
+        }
+    }
+    if let Some(ref ip) = ip {
+        let key = format!("idents:{}", ip);
+        if let Some(retry_after) = quota(env, &key, config.ident_quota, config.quota_window).await?
+        {
+            audit(
+                &*storage(env)?,
+                &config,
+                Action::QuotaExceeded,
+                ip,
+                "idents",
+            )
+            .await;
+            return Ok(Err(ApiError::QuotaExceeded(retry_after)));
+        }
+    }
+    if turnstile::is_required(env) {
+        let solved = match req.headers().get(turnstile::HEADER)? {
+            Some(token) => turnstile::verify(env, &token, ip.as_deref()).await?,
+            None => false,
+        };
+        if !solved {
+            return Ok(Err(ApiError::ChallengeFailed));
+        }
+    }
+    let subject = if oidc::is_required(env) {
+        let bearer = req.headers().get("Authorization")?;
+        let subject = match bearer.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+            Some(id_token) => oidc::subject(env, id_token).await?,
+            None => None,
+        };
+        match subject {
+            Some(subject) => Some(subject),
+            None => return Ok(Err(ApiError::InvalidIdToken)),
+        }
+    } else {
+        None
+    };
+    // Signed tokens aren't stored at ident anyway
+    let pooled = if pooled && !token::is_signed(env) {
+        pool::take(&*storage(env)?, &config).await?
+    } else {
+        None
+    };
+    let mut auth = match pooled {
+        Some(ref key) => {
+            let mut auth = Auth::unsaved(key.clone());
+            auth.start(clock, &config);
+            auth
+        }
+        None => Auth::create_expiring(clock, &config)?,
+    };
+    if let Some(svc) = service {
+        if profile.is_some() {
+            auth.set_profile(svc.clone());
+        }
+        auth.set_service(svc);
+    }
+    if let Some(country) = req.cf().and_then(|cf| cf.country()) {
+        auth.set_country(country);
+    }
+    if let Some(subject) = subject {
+        auth.set_subject(subject);
+    }
+    Ok(Ok(Minted {
+        auth,
+        config,
+        pooled: pooled.is_some(),
+    }))
+}
+
+/// Token for `ident` and its gRPC counterpart, `service` being the one asked
+/// for and `capabilities` what the client declared.
+///
+/// Sessions with a pooled key are stored through `ctx` after responding.
+pub async fn issue(
+    req: &Request,
+    env: &Env,
+    ctx: &Context,
+    service: Option<String>,
+    capabilities: Capabilities,
+) -> Result<std::result::Result<IdentResponse, ApiError>> {
+    let Minted {
+        mut auth,
+        config,
+        pooled,
+    } = match mint(req, env, &SystemClock, service, true).await? {
+        Ok(minted) => minted,
+        Err(e) => return Ok(Err(e)),
+    };
+    let transports = transports(env)
+        .into_iter()
+        .filter(|t| *t != Transport::WebSocket || capabilities.websocket)
+        .collect();
+    if capabilities != Capabilities::default() {
+        auth.set_capabilities(capabilities);
+    }
+    let key = auth.key.clone();
+    let dimensions = Dimensions::of(&auth);
+    let token = match token::sign(env, &auth).await? {
+        // Stored on its first poll instead
+        Some(token) => token,
+        // The pooled auth is there already, the session is stored once the response is out
+        None if pooled => {
+            ctx.wait_until(pool::settle(env.clone(), auth));
+            key.clone()
+        }
+        None => {
+            if !auth.write(&*storage(env)?).await? {
+                return Ok(Err(ApiError::Conflict));
+            }
+            key.clone()
+        }
+    };
+    count(Counter::Idents, 1);
+    record(env, Event::Ident, &dimensions, 0.0);
+    Ok(Ok(IdentResponse {
+        ice_servers: ice_servers(env, &config, &key).await?,
+        token,
+        signal_versions: (*PROTOCOL_VERSIONS.start(), *PROTOCOL_VERSIONS.end()),
+        transports,
+        features: Capabilities {
+            websocket: ws::is_enabled(env),
+            bare: true,
+            relay: true,
+            max_sdp_size: Some(config.max_sdp_size),
+        },
+    }))
+}
+
+/// Transports this deployment offers, WebTransport isn't among them as Workers
+/// can't take its sessions, so clients fall back to websockets or `/poll`.
+fn transports(env: &Env) -> Vec<Transport> {
+    let mut transports = vec![];
+    if ws::is_enabled(env) {
+        transports.push(Transport::WebSocket);
+    }
+    transports.push(Transport::Poll);
+    transports
+}
+
+#[derive(Deserialize)]
+struct CreateRoomRequest {
+    service: String,
+    /// Seconds until the reservation expires, defaults to the longest allowed
+    ttl: Option<u64>,
+    password: Option<String>,
+    /// Human friendly code to use instead of a random one
+    code: Option<String>,
+    /// Seconds the room lasts once created, members or not, defaults to the longest allowed
+    max_duration: Option<u64>,
+    /// Lists the room in `/rooms`
+    #[serde(default)]
+    public: bool,
+    /// Shown in `/rooms`, defaults to the code
+    name: Option<String>,
+    /// Identity provider subjects that may join, anyone when left out
+    #[serde(default)]
+    allowed: Vec<String>,
+    /// Handed to everyone joining instead of the ones from `/ident`, see `Signal::SetIceServers`
+    #[serde(default)]
+    ice_servers: Vec<IceServer>,
+}
+
+#[derive(Serialize)]
+struct CreateRoomResponse {
+    code: String,
+    expire_at: SystemTime,
+}
+
+/// Reserves a room code to be joined later, it's kept until its TTL runs out.
+///
+/// The code is random unless the request picks one.
+pub async fn create_room(mut req: Request, env: Env) -> Result<Response> {
     let token = match req.headers().get("Authorization")? {
         Some(token) => token,
-        None => return Response::error("Missing token.", 403),
+        None => return ApiError::MissingToken.into_response(),
     };
-    let signals = match req.json::<Vec<Signal>>().await {
-        Ok(s) => s,
-        Err(e) => return Response::error(format!("Malformed request: {}", e), 400),
+    let body = match req.json::<CreateRoomRequest>().await {
+        Ok(b) => b,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
     };
-    if signals
-        .iter()
-        .filter(|s| !matches!(s, Signal::JoinRoom(_) | Signal::SetService(_)))
-        .any(|s| !s.can_send())
+
+    let clock = SystemClock;
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&clock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    let config = Config::for_profile(&env, user.get_profile()).await?;
+    if apikey::is_required(&env) && user.get_service() != Some(&body.service) {
+        // Only for the service of the key the token was minted with
+        return ApiError::ServiceNotAllowed.into_response();
+    }
+    if !is_service_allowed(&env, &body.service)? {
+        return ApiError::ServiceNotAllowed.into_response();
+    }
+
+    let ttl = body
+        .ttl
+        .unwrap_or(config.max_room_ttl)
+        .min(config.max_room_ttl);
+    let expire_at = clock.now() + Duration::from_secs(ttl);
+    let duration = body
+        .max_duration
+        .unwrap_or(config.max_room_duration)
+        .min(config.max_room_duration);
+    let close_at = clock.now() + Duration::from_secs(duration);
+    let vanity = match body.code.as_deref().map(vanity_code) {
+        Some(Some(code)) => Some(code),
+        Some(None) => return ApiError::InvalidCode.into_response(),
+        None => None,
+    };
+    let ip = req.headers().get("CF-Connecting-IP")?;
+    if let Some(retry_after) = room_quota(&env, &config, ip.as_deref()).await? {
+        return ApiError::QuotaExceeded(retry_after).into_response();
+    }
+    if body
+        .name
+        .as_ref()
+        .is_some_and(|name| name.chars().count() > MAX_NAME_LENGTH)
     {
-        return Response::error("Invalid signals: can't send.", 400);
+        return ApiError::Malformed("name too long".to_owned()).into_response();
+    }
+    if !body.ice_servers.is_empty() && !is_ice_allowed(&env, &body.ice_servers) {
+        return ApiError::IceServersNotAllowed.into_response();
+    }
+
+    // A chosen code that's taken stays taken, random ones are drawn again
+    let attempts = if vanity.is_some() { 1 } else { CREATE_ATTEMPTS };
+    let created = create_unique(&*storage, attempts, || {
+        let mut room = match vanity {
+            // Storing it only works if nobody has the code yet
+            Some(ref code) => Room::unsaved(room_key(&body.service, code)),
+            None => Room::create(&config, &body.service)?,
+        };
+        room.reserve(
+            &clock,
+            body.service.clone(),
+            config.max_peers,
+            body.password.as_deref(),
+            expire_at,
+            close_at,
+        );
+        let code = room.code().to_owned();
+        if body.public {
+            room.publish(body.name.clone().unwrap_or_else(|| code.clone()));
+        }
+        if !body.allowed.is_empty() {
+            room.allow(body.allowed.clone());
+        }
+        if !body.ice_servers.is_empty() {
+            room.set_ice_servers(&body.ice_servers);
+        }
+        let listing = ListingUpdate::of(&room);
+        Ok((room, (code, listing)))
+    })
+    .await?;
+    let (code, listing) = match created {
+        Some(created) => created,
+        None if vanity.is_some() => return ApiError::CodeTaken.into_response(),
+        None => return ApiError::Conflict.into_response(),
+    };
+
+    if let Some(listing) = listing {
+        listing.apply(&*storage).await?;
+    }
+
+    count(Counter::RoomsCreated, 1);
+    Response::from_json(&CreateRoomResponse { code, expire_at })
+}
+
+#[derive(Deserialize)]
+struct HostRequest {
+    /// Needed unless the API key picks it
+    service: Option<String>,
+    password: Option<String>,
+    /// As in `/ident`
+    #[serde(default)]
+    capabilities: Capabilities,
+}
+
+/// `/ident` and the first `/poll` creating a room in one request, the token
+/// comes back already in the room with its code.
+///
+/// Only the room and the auth are written.
+pub async fn host(mut req: Request, env: Env) -> Result<Response> {
+    let body = match req.json::<HostRequest>().await {
+        Ok(b) => b,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+    };
+    let clock = SystemClock;
+    let Minted {
+        mut auth, config, ..
+    } = match mint(&req, &env, &clock, body.service, false).await? {
+        Ok(minted) => minted,
+        Err(e) => return e.into_response(),
+    };
+    let service = match auth.get_service() {
+        Some(service) => service.clone(),
+        None => return ApiError::NeedService.into_response(),
+    };
+    let ip = req.headers().get("CF-Connecting-IP")?;
+    if let Some(retry_after) = room_quota(&env, &config, ip.as_deref()).await? {
+        return ApiError::QuotaExceeded(retry_after).into_response();
+    }
+    if body.capabilities != Capabilities::default() {
+        auth.set_capabilities(body.capabilities);
+    }
+
+    let storage = storage(&env)?;
+    let close_at = clock.now() + Duration::from_secs(config.max_room_duration);
+    let mut lost: Option<String> = None;
+    let created = create_unique(&*storage, CREATE_ATTEMPTS, || {
+        if let Some(room_key) = lost.take() {
+            // Someone has the random code already, draw another
+            auth.drop_room(&room_key);
+        }
+        let mut room = Room::create(&config, &service)?;
+        let joined = room.join_room(
+            &clock,
+            &mut auth,
+            config.max_peers,
+            body.password.as_deref(),
+            close_at,
+        );
+        if joined.is_err() {
+            // Nobody is in a new room
+            return Err(Error::RustError("couldn't join a new room".to_owned()));
+        }
+        auth.set_peers(&clock, &room);
+        lost = Some(room.key.clone());
+        let code = room.code().to_owned();
+        Ok((room, code))
+    })
+    .await?;
+    let code = match created {
+        Some(code) => code,
+        None => return ApiError::Conflict.into_response(),
+    };
+
+    let key = auth.key.clone();
+    let next_poll = auth.next_poll();
+    let dimensions = Dimensions::of(&auth);
+    // Stored even when signed, it's in a room already
+    let token = token::sign(&env, &auth)
+        .await?
+        .unwrap_or_else(|| key.clone());
+    if !auth.write(&*storage).await? {
+        return ApiError::Conflict.into_response();
+    }
+    count(Counter::Idents, 1);
+    count(Counter::RoomsCreated, 1);
+    record(&env, Event::Ident, &dimensions, 0.0);
+    record(&env, Event::Join, &dimensions, 0.0);
+    Response::from_json(&HostResponse {
+        ice_servers: ice_servers(&env, &config, &key).await?,
+        token,
+        room_code: code,
+        next_poll,
+    })
+}
+
+#[derive(Deserialize)]
+struct JoinRequest {
+    /// Needed without an invite
+    code: Option<String>,
+    /// From `/invite`, it names the room and service
+    invite: Option<String>,
+    /// Needed unless the API key or the invite picks it
+    service: Option<String>,
+    password: Option<String>,
+    /// Sent along with the join, such as the answer's SDP
+    #[serde(default)]
+    signals: Vec<Signal>,
+    /// As in `/ident`
+    #[serde(default)]
+    capabilities: Capabilities,
+}
+
+/// `/ident` and a first `/poll` joining the room by `code` in one request,
+/// the response has what the peers queued already.
+///
+/// An invite takes the place of the code, it's claimed ahead of joining so
+/// nobody else gets in with it, and handed back if joining fails.
+pub async fn join(mut req: Request, env: Env) -> Result<Response> {
+    let body = match req.json::<JoinRequest>().await {
+        Ok(b) => b,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+    };
+    if !is_sendable(&body.signals) {
+        return ApiError::CantSend.into_response();
+    }
+    let storage = storage(&env)?;
+    let invite = match body.invite {
+        Some(ref invite) => match invite::open(&env, &*storage, invite).await? {
+            Some(invite) => Some(invite),
+            None => return ApiError::InvalidInvite.into_response(),
+        },
+        None => None,
+    };
+    let (service, code) = match (invite.as_ref().map(Invite::room), body.code) {
+        (Some((service, _)), _) if body.service.as_ref().is_some_and(|svc| svc != service) => {
+            return ApiError::ServiceNotAllowed.into_response()
+        }
+        (Some((service, code)), _) => (Some(service.to_owned()), code.to_owned()),
+        (None, Some(code)) => (body.service, code),
+        (None, None) => {
+            return ApiError::Malformed("code or invite needed".to_owned()).into_response()
+        }
+    };
+    let caller = Caller::of(&req);
+    let Minted {
+        mut auth, config, ..
+    } = match mint(&req, &env, &*caller.clock, service, false).await? {
+        Ok(minted) => minted,
+        Err(e) => return e.into_response(),
+    };
+    let claimed = invite.as_ref().map(|invite| invite.key.clone());
+    if let Some(invite) = invite {
+        if !invite.claim(&*storage).await? {
+            // Redeemed by someone else in the meantime
+            return ApiError::InvalidInvite.into_response();
+        }
+    }
+    if body.capabilities != Capabilities::default() {
+        auth.set_capabilities(body.capabilities);
     }
 
-    let bucket = env.bucket("rtc")?;
-    let mut user = match Auth::load(&bucket, &token).await? {
+    let mut signals = vec![Signal::JoinRoom(code)];
+    if let Some(password) = body.password {
+        signals.push(Signal::Password(password));
+    }
+    signals.extend(body.signals);
+    let key = auth.key.clone();
+    let dimensions = Dimensions::of(&auth);
+    let mut trace = Trace::new(&env, caller.request_id.clone());
+    trace.set_token(&key);
+    let joined = async {
+        // Stored by the round even when signed, it's in a room then
+        let token = token::sign(&env, &auth)
+            .await?
+            .unwrap_or_else(|| key.clone());
+        let ice = ice_servers(&env, &config, &key).await?;
+        let played = play(&env, &caller, &mut trace, auth, config, signals, None).await?;
+        Ok::<_, worker::Error>(played.map(|signals| (token, ice, signals)))
+    }
+    .await;
+    if let (Some(id), false) = (claimed, matches!(joined, Ok(Ok(_)))) {
+        if let Err(e) = Invite::release(&*storage, &id).await {
+            console_log!("couldn't release invite: {}", e);
+        }
+    }
+    let (token, ice, signals) = match joined? {
+        Ok(joined) => joined,
+        Err(e) => {
+            trace.info(None, format_args!("failed code={}", e.code()));
+            return e.into_response();
+        }
+    };
+    count(Counter::Idents, 1);
+    record(&env, Event::Ident, &dimensions, 0.0);
+    Response::from_json(&JoinResponse {
+        token,
+        ice_servers: ice,
+        signals,
+        server_time: caller.clock.now(),
+    })
+}
+
+/// Whether `header` asks for BARE instead of JSON.
+pub fn has_bare(req: &Request, header: &str) -> Result<bool> {
+    Ok(req
+        .headers()
+        .get(header)?
+        .is_some_and(|v| v.contains(BARE_MIME)))
+}
+
+/// Protocol version the request asks for.
+pub fn protocol_version(req: &Request) -> Result<std::result::Result<u32, ApiError>> {
+    let version = match req.headers().get(VERSION_HEADER)? {
+        Some(v) => v.trim().parse().ok(),
+        None => Some(*PROTOCOL_VERSIONS.start()),
+    };
+    Ok(version
+        .filter(|v| PROTOCOL_VERSIONS.contains(v))
+        .ok_or(ApiError::UnsupportedVersion))
+}
+
+/// Encodes the polled signals in `version`, errors stay JSON either way.
+pub fn respond(signals: &[Signal], bare: bool, version: u32) -> Result<Response> {
+    let body = PollResponse {
+        signals: signals.to_vec(),
+        server_time: SystemTime::now(),
+        has_more: signals.iter().any(|s| matches!(s, Signal::HasMore)),
+    };
+    let mut res = match (bare, version) {
+        (true, 1) => Response::from_bytes(serde_bare::ser::to_vec(&signals).unwrap())?,
+        (true, _) => Response::from_bytes(serde_bare::ser::to_vec(&body).unwrap())?,
+        (false, 1) => Response::from_json(&signals)?,
+        (false, _) => Response::from_json(&body)?,
+    };
+    if bare {
+        res.headers_mut().set("Content-Type", BARE_MIME)?;
+    }
+    res.headers_mut()
+        .set(VERSION_HEADER, &version.to_string())?;
+    Ok(res)
+}
+
+/// Polls with the signals in the body, `?wait=` holds the response until there's news.
+///
+/// With `OTLP_ENDPOINT` set, its phases and storage operations are exported as spans.
+pub async fn poll(mut req: Request, env: Env, ctx: &Context) -> Result<Response> {
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => token,
+        None => return ApiError::MissingToken.into_response(),
+    };
+    let version = match protocol_version(&req)? {
+        Ok(version) => version,
+        Err(e) => return e.into_response(),
+    };
+    let signals = if has_bare(&req, "Content-Type")? {
+        serde_bare::de::from_slice::<Vec<Signal>>(&req.bytes().await?).map_err(|e| e.to_string())
+    } else {
+        req.json::<Vec<Signal>>().await.map_err(|e| e.to_string())
+    };
+    let signals = match signals {
+        Ok(s) => s,
+        Err(e) => return ApiError::Malformed(e).into_response(),
+    };
+
+    let idempotency_key = req.headers().get("Idempotency-Key")?;
+    let wait = req.query::<WaitQuery>().ok().and_then(|q| q.wait);
+    let mut caller = Caller::of(&req);
+    if otlp::is_enabled(&env) {
+        caller.spans = Spans::recording();
+    }
+    let started = caller.spans.start("poll");
+    let res = hold(&env, &caller, &token, signals, idempotency_key, wait).await?;
+    let outcome = match res {
+        Ok(_) => "ok".to_owned(),
+        Err(ref e) => e.code().to_owned(),
+    };
+    let attributes = vec![
+        ("request.id", caller.request_id.clone()),
+        ("outcome", outcome),
+    ];
+    caller.spans.finish(started, attributes);
+    if otlp::is_enabled(&env) {
+        ctx.wait_until(otlp::export(env.clone(), caller.spans.clone()));
+    }
+    match res {
+        Ok(signals) => respond(&signals, has_bare(&req, "Accept")?, version),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct WaitQuery {
+    wait: Option<u64>,
+}
+
+/// Whether the response has anything besides the bookkeeping every poll gets.
+fn has_news(signals: &[Signal]) -> bool {
+    signals.iter().any(|s| {
+        !matches!(
+            s,
+            Signal::Seq(_)
+                | Signal::Backoff(_)
+                | Signal::ExpiresIn(_)
+                | Signal::State(_)
+                | Signal::NextPoll(_)
+        )
+    })
+}
+
+/// Whether polling now would get anything, only reading storage.
+async fn has_unread(
+    env: &Env,
+    clock: &dyn Clock,
+    storage: &dyn Storage,
+    config: &Config,
+    token: &str,
+) -> Result<bool> {
+    let user = match token::session(env, storage, config, token).await? {
+        Some(user) if user.is_alive(clock, config) => user,
+        // Let the poll tell what's wrong
+        _ => return Ok(true),
+    };
+    for key in user.get_rooms().iter() {
+        match Room::load(storage, key).await? {
+            Some(room) if user.knows_peers(&room) => {}
+            _ => return Ok(true),
+        }
+    }
+    let peers = user.load_peers(storage).await?;
+    Ok(user.has_unread(clock, &peers, config))
+}
+
+/// `exchange`, holding an empty response for up to `wait` seconds until the
+/// peers send something.
+///
+/// Storage is checked every second or so meanwhile, and polled again once
+/// there's something to pull. That response replaces the empty one under
+/// `idempotency_key`.
+async fn hold(
+    env: &Env,
+    caller: &Caller,
+    token: &str,
+    signals: Vec<Signal>,
+    idempotency_key: Option<String>,
+    wait: Option<u64>,
+) -> Result<std::result::Result<Vec<Signal>, ApiError>> {
+    let res = exchange(env, caller, token, signals, idempotency_key.clone()).await?;
+    match res {
+        Ok(ref signals) if wait.unwrap_or_default() > 0 && !has_news(signals) => {}
+        res => return Ok(res),
+    }
+
+    let storage = caller.spans.storage(storage(env)?);
+    let config = Config::from_env(env);
+    let config = match token::session(env, &*storage, &config, token).await? {
+        Some(user) => Config::for_profile(env, user.get_profile()).await?,
+        None => return Ok(res),
+    };
+    let wait = wait.unwrap_or_default().min(config.max_wait);
+    let clock = &*caller.clock;
+    let deadline = clock.now() + Duration::from_secs(wait);
+    loop {
+        let jitter = (js_sys::Math::random() * 2.0 - 1.0) * WAIT_JITTER;
+        Delay::from(Duration::from_millis((WAIT_INTERVAL + jitter) as u64)).await;
+        if clock.now() >= deadline || has_unread(env, clock, &*storage, &config, token).await? {
+            break;
+        }
+    }
+    traced(env, caller, token, vec![], idempotency_key, false).await
+}
+
+/// `poll` for several tokens at once, the polls run concurrently.
+///
+/// Always JSON, a failed poll only fails its own entry and every token counts
+/// against its rate limit as if it had polled alone.
+pub async fn poll_batch(mut req: Request, env: Env) -> Result<Response> {
+    let polls = match req.json::<Vec<BatchPoll>>().await {
+        Ok(polls) => polls,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+    };
+    let config = Config::from_env(&env);
+    if polls.len() > config.max_batch {
+        return ApiError::TooLarge("batch").into_response();
+    }
+    let tokens: HashSet<&str> = polls.iter().map(|p| p.token.as_str()).collect();
+    if tokens.len() != polls.len() {
+        // Its polls would race each other
+        return ApiError::Malformed("token polled twice".to_owned()).into_response();
+    }
+
+    let (env, caller) = (&env, &Caller::of(&req));
+    let results = join_all(polls.into_iter().map(|p| async move {
+        if let Some(limited) = limit(env, &format!("token:{}", p.token)).await? {
+            return Ok(Err(ApiError::RateLimited(limited.retry_after)));
+        }
+        exchange(env, caller, &p.token, p.signals, None).await
+    }))
+    .await;
+
+    let mut body = BatchResponse {
+        results: vec![],
+        server_time: caller.clock.now(),
+    };
+    for res in results {
+        body.results.push(match res? {
+            Ok(signals) => BatchResult {
+                signals: Some(signals),
+                error: None,
+            },
+            Err(e) => BatchResult {
+                signals: None,
+                error: Some(ErrorResponse::from(&e)),
+            },
+        });
+    }
+    Response::from_json(&body)
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    token: String,
+    // base64url encoded JSON array
+    signals: Option<String>,
+    wait: Option<u64>,
+}
+
+/// `poll` for clients that can't send a body, everything goes in the query string.
+pub async fn poll_query(req: Request, env: Env) -> Result<Response> {
+    let query = match req.query::<PollQuery>() {
+        Ok(q) => q,
+        Err(_) => return ApiError::MissingToken.into_response(),
+    };
+    let version = match protocol_version(&req)? {
+        Ok(version) => version,
+        Err(e) => return e.into_response(),
+    };
+    let signals = match query.signals.as_deref() {
+        Some(encoded) => {
+            let json = match URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('=')) {
+                Ok(json) => json,
+                Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+            };
+            match serde_json::from_slice::<Vec<Signal>>(&json) {
+                Ok(s) => s,
+                Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+            }
+        }
+        None => vec![],
+    };
+
+    let idempotency_key = req.headers().get("Idempotency-Key")?;
+    let caller = Caller::of(&req);
+    let (token, wait) = (&query.token, query.wait);
+    match hold(&env, &caller, token, signals, idempotency_key, wait).await? {
+        Ok(signals) => respond(&signals, has_bare(&req, "Accept")?, version),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Keeps a session alive without exchanging signals, answering with the next `Signal::NextPoll`.
+///
+/// The peers aren't loaded, so new signals wait for the next full poll.
+pub async fn heartbeat(req: Request, env: Env) -> Result<Response> {
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => token,
+        None => return ApiError::MissingToken.into_response(),
+    };
+    let version = match protocol_version(&req)? {
+        Ok(version) => version,
+        Err(e) => return e.into_response(),
+    };
+
+    let clock = SystemClock;
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let mut user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&clock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    let config = Config::for_profile(&env, user.get_profile()).await?;
+    count(Counter::Heartbeats, 1);
+
+    let backoff = user.poll(&clock, &config, &[], load(&env, &config).await);
+    let signals: Vec<Signal> = backoff
+        .map(Signal::Backoff)
+        .into_iter()
+        .chain([Signal::NextPoll(user.next_poll())])
+        .collect();
+    if !user.write(&*storage).await? {
+        return ApiError::Conflict.into_response();
+    }
+    respond(&signals, has_bare(&req, "Accept")?, version)
+}
+
+/// Extends the session by up to `MAX_CONNECTION`, as long as it stays within `MAX_SESSION`.
+///
+/// Its rooms are kept around for as long, signed tokens are handed out again
+/// with the new expiry.
+pub async fn refresh(req: Request, env: Env) -> Result<Response> {
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => token,
+        None => return ApiError::MissingToken.into_response(),
+    };
+
+    let clock = SystemClock;
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let mut user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&clock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    let config = Config::for_profile(&env, user.get_profile()).await?;
+
+    let kill_at = user.extend(&clock, &config);
+    for key in user.get_rooms().to_vec().iter() {
+        let mut room = match Room::load(&*storage, key).await? {
+            Some(room) => room,
+            None => continue,
+        };
+        room.extend(&user);
+        if !room.write(&*storage).await? {
+            return ApiError::Conflict.into_response();
+        }
+    }
+    let token = token::sign(&env, &user)
+        .await?
+        .unwrap_or_else(|| user.key.clone());
+    if !user.write(&*storage).await? {
+        return ApiError::Conflict.into_response();
+    }
+    Response::from_json(&RefreshResponse { token, kill_at })
+}
+
+/// Where a poll comes from, for logs and quotas.
+pub struct Caller {
+    pub request_id: String,
+    /// The upgrade's for websockets, polls without one skip the room quota
+    pub ip: Option<String>,
+    /// Recorded for `/poll` when exporting to OTLP
+    pub spans: Spans,
+    pub clock: Box<dyn Clock>,
+}
+
+impl Caller {
+    pub fn of(req: &Request) -> Self {
+        Caller {
+            request_id: request_id(Some(req)),
+            ip: req.headers().get("CF-Connecting-IP").ok().flatten(),
+            spans: Spans::default(),
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Messages of a socket opened from `ip`.
+    pub fn socket(ip: Option<String>) -> Self {
+        Caller {
+            request_id: request_id(None),
+            ip,
+            spans: Spans::default(),
+            clock: Box::new(SystemClock),
+        }
+    }
+}
+
+/// Counts a new room against the `ROOM_QUOTA` of `ip`, how long to wait if it's used up.
+pub async fn room_quota(env: &Env, config: &Config, ip: Option<&str>) -> Result<Option<u64>> {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+    let retry_after = quota(
+        env,
+        &format!("rooms:{}", ip),
+        config.room_quota,
+        config.quota_window,
+    )
+    .await?;
+    if retry_after.is_some() {
+        audit(&*storage(env)?, config, Action::QuotaExceeded, ip, "rooms").await;
+    }
+    Ok(retry_after)
+}
+
+/// Runs a single poll round for `token`, shared by the HTTP and websocket transports.
+///
+/// A retried poll with the same `idempotency_key` gets the earlier response back
+/// without its signals being queued twice. Log lines are tagged with the caller's request.
+pub async fn exchange(
+    env: &Env,
+    caller: &Caller,
+    token: &str,
+    signals: Vec<Signal>,
+    idempotency_key: Option<String>,
+) -> Result<std::result::Result<Vec<Signal>, ApiError>> {
+    traced(env, caller, token, signals, idempotency_key, true).await
+}
+
+/// `exchange`, only replaying the response under `idempotency_key` if `replay`
+/// and keeping the new one over it otherwise.
+async fn traced(
+    env: &Env,
+    caller: &Caller,
+    token: &str,
+    signals: Vec<Signal>,
+    idempotency_key: Option<String>,
+    replay: bool,
+) -> Result<std::result::Result<Vec<Signal>, ApiError>> {
+    let mut trace = Trace::new(env, caller.request_id.clone());
+    let res = round(
+        env,
+        caller,
+        &mut trace,
+        token,
+        signals,
+        idempotency_key,
+        replay,
+    )
+    .await;
+    match res {
+        Ok(Err(ref e)) => trace.info(None, format_args!("failed code={}", e.code())),
+        Err(ref e) => trace.error(None, format_args!("failed error={}", e)),
+        Ok(Ok(ref signals)) => trace.debug(None, format_args!("polled out={}", signals.len())),
+    }
+    res
+}
+
+async fn round(
+    env: &Env,
+    caller: &Caller,
+    trace: &mut Trace,
+    token: &str,
+    signals: Vec<Signal>,
+    idempotency_key: Option<String>,
+    replay: bool,
+) -> Result<std::result::Result<Vec<Signal>, ApiError>> {
+    if !is_sendable(&signals) {
+        return Ok(Err(ApiError::CantSend));
+    }
+
+    let config = Config::from_env(env);
+    let spans = &caller.spans;
+    let storage = spans.storage(storage(env)?);
+    let started = spans.start("session");
+    let user = match token::session(env, &*storage, &config, token).await? {
         Some(user) => user,
-        None => return Response::error("Invalid token.", 403),
+        None => return Ok(Err(ApiError::InvalidToken)),
     };
+    if !user.is_alive(&*caller.clock, &config) {
+        // Waiting for cleanup
+        return Ok(Err(ApiError::InvalidToken));
+    }
+    let config = Config::for_profile(env, user.get_profile()).await?;
+    spans.end(started);
+    trace.set_token(&user.key);
+    trace.debug(None, format_args!("polling in={}", signals.len()));
+    let key = idempotency_key.as_deref().filter(|_| replay);
+    if let Some(replay) = key.and_then(|k| user.replay(k)) {
+        return Ok(Ok(replay));
+    }
+
+    play(env, caller, trace, user, config, signals, idempotency_key).await
+}
+
+/// Whether a client may send all of `signals`, counting the ones a round
+/// takes instead of forwarding.
+fn is_sendable(signals: &[Signal]) -> bool {
+    signals
+        .iter()
+        .filter(|s| {
+            !matches!(
+                s,
+                Signal::JoinRoom(_)
+                    | Signal::SetService(_)
+                    | Signal::Password(_)
+                    | Signal::Resync
+                    | Signal::HoldCode
+                    | Signal::SetRoomMeta(_)
+                    | Signal::SetIceServers(_)
+                    | Signal::Spectate
+                    | Signal::ScreenJoins
+            )
+        })
+        .all(|s| s.can_send())
+}
+
+/// The round itself, once `user` is loaded with the `config` of its profile.
+async fn play(
+    env: &Env,
+    caller: &Caller,
+    trace: &mut Trace,
+    mut user: Auth,
+    config: Config,
+    signals: Vec<Signal>,
+    idempotency_key: Option<String>,
+) -> Result<std::result::Result<Vec<Signal>, ApiError>> {
+    let (spans, clock) = (&caller.spans, &*caller.clock);
+    let storage = spans.storage(storage(env)?);
+    count(Counter::Polls, 1);
+
+    if is_conflicting_join(&signals) {
+        return Ok(Err(ApiError::ConflictingJoin));
+    }
+    if signals.iter().any(|s| matches!(s, Signal::Leave)) {
+        return leave(env, clock, &*storage, user).await;
+    }
 
     if user.get_service().is_none() {
         let svc = match signals.iter().find(|s| matches!(s, Signal::SetService(_))) {
             Some(Signal::SetService(svc)) => svc,
-            None => return Response::error("Need to set service.", 400),
-            Some(_) => return Response::error("server logic error.", 500),
+            None => return Ok(Err(ApiError::NeedService)),
+            Some(_) => return Ok(Err(ApiError::ServerError)),
         };
 
-        if !is_service_allowed(&env, svc)? {
-            return Response::error("Invalid service.", 400);
+        // Keyed tokens get theirs at ident, anything else predates the keys
+        if apikey::is_required(env) || !is_service_allowed(env, svc)? {
+            return Ok(Err(ApiError::ServiceNotAllowed));
         }
 
         user.set_service(svc.clone());
     }
 
-    let peer = match user.get_peer() {
-        Some(peer) => Some(peer.clone()),
-        None => {
-            let room = match user.get_room() {
-                Some(code) => {
-                    // User is in room
-                    match Room::load(&bucket, code).await? {
-                        Some(room) => room,
-                        None => return Response::error("Room expired.", 400),
+    // Joining more rooms, or creating one when in none
+    let service = user.get_service().expect("invalid state").clone();
+    let codes = codes_to_join(&signals, &service, user.get_rooms());
+    if signals.iter().any(|s| matches!(s, Signal::Spectate)) && !user.is_spectator() {
+        if !user.get_rooms().is_empty() {
+            // Members can't turn into spectators
+            return Ok(Err(ApiError::CantSend));
+        }
+        if codes.is_empty() {
+            return Ok(Err(ApiError::Malformed(
+                "spectators join rooms by code".to_owned(),
+            )));
+        }
+        user.spectate();
+    }
+    let mut rooms = vec![];
+    let mut joined = vec![];
+    let started = spans.start("join");
+    if !codes.is_empty() || user.get_rooms().is_empty() {
+        // The allow-list may have changed since the service was set
+        if !is_service_allowed(env, &service)? {
+            return Ok(Err(ApiError::ServiceNotAllowed));
+        }
+        let ip = caller.ip.as_deref();
+        if ban::is_banned(&*storage, &service, Some(&user.key), ip).await? {
+            return Ok(Err(ApiError::Banned));
+        }
+        let password = signals.iter().find_map(|s| match s {
+            Signal::Password(password) => Some(password.as_str()),
+            _ => None,
+        });
+
+        let codes = if codes.is_empty() {
+            vec![None]
+        } else {
+            codes.into_iter().map(Some).collect()
+        };
+        for code in codes.into_iter() {
+            let room = match code {
+                Some(code) => Room::load(&*storage, &room_key(&service, code)).await?,
+                // Whatever it watched is gone
+                None if user.is_spectator() => return Ok(Err(ApiError::RoomExpired)),
+                None => {
+                    if maintenance::is_on(env).await? {
+                        return Ok(Err(ApiError::Maintenance(maintenance::RETRY_AFTER)));
+                    }
+                    let ip = caller.ip.as_deref();
+                    if let Some(retry_after) = room_quota(env, &config, ip).await? {
+                        return Ok(Err(ApiError::QuotaExceeded(retry_after)));
                     }
+                    // A code that's taken loses the write below like a join race would
+                    Some(Room::create(&config, &service)?)
                 }
-                None => {
-                    // Joining or creating
-                    let room = match signals.iter().find(|s| matches!(s, Signal::JoinRoom(_))) {
-                        Some(Signal::JoinRoom(code)) => Room::load(&bucket, code).await?,
-                        None => Some(Room::create(&bucket).await?),
-                        Some(_) => return Response::error("server logic error.", 500),
-                    };
-                    let mut room = match room {
-                        Some(room) => room,
-                        None => return Response::error("Room not found.", 404),
-                    };
-                    if !room.join_room(&mut user) {
-                        return Response::error("Room is full.", 400);
+            };
+            let mut room = match room {
+                Some(room) if !room.is_expired(clock) => room,
+                room => {
+                    count(Counter::FailedJoins, 1);
+                    let buried = match code {
+                        Some(code) => is_buried(&*storage, &room_key(&service, code)).await?,
+                        None => false,
                     };
-                    room
+                    if room.is_some() || buried {
+                        return Ok(Err(ApiError::RoomExpired));
+                    }
+                    return Ok(Err(ApiError::RoomUnknown));
+                }
+            };
+            if room.is_full() && !user.is_spectator() && !room.get_members().contains(&user.key) {
+                // An answerer that crashed keeps its slot until cleanup, take it over
+                if let Some(dead) = dead_answerer(clock, &*storage, &config, &room).await? {
+                    room.free_slot(&dead);
+                    if let Some(mut peer) = Auth::load(&*storage, &dead).await? {
+                        // So cleanup doesn't take the room down along with it
+                        peer.drop_room(&room.key);
+                        if !peer.write(&*storage).await? {
+                            return Ok(Err(ApiError::Conflict));
+                        }
+                    }
+                    trace.info(Some(&room.key), "answer slot taken over");
+                }
+            }
+            // Anyone else going in waits on the owner, see `Signal::ScreenJoins`.
+            // Those that wouldn't get in anyway fail joining below
+            let screened = (room.is_screened()
+                && !user.is_spectator()
+                && !room.get_members().contains(&user.key)
+                && room.may_join(&user, password).is_ok())
+            .then(|| room.screen_join(&user));
+            match screened {
+                Some(Screened::TurnedAway) => {
+                    count(Counter::FailedJoins, 1);
+                    return Ok(Err(ApiError::RoomFull));
                 }
+                Some(Screened::Pending(hint)) => {
+                    let key = room.key.clone();
+                    let backoff = user.poll(clock, &config, &[], load(env, &config).await);
+                    let signals: Vec<Signal> = [Signal::JoinRequest(hint)]
+                        .into_iter()
+                        .chain(backoff.map(Signal::Backoff))
+                        .chain([Signal::NextPoll(user.next_poll())])
+                        .collect();
+                    let writes = room.into_put().into_iter().chain(user.into_put());
+                    if !storage.put_all(writes.collect()).await? {
+                        return Ok(Err(ApiError::Conflict));
+                    }
+                    trace.info(Some(&key), "join pending");
+                    return Ok(Ok(signals));
+                }
+                Some(Screened::Accepted) | None => {}
+            }
+            let is_new = !user.is_spectator() && room.get_peers(&user).is_empty();
+            let close_at = clock.now() + Duration::from_secs(config.max_room_duration);
+            let joined_room = if user.is_spectator() {
+                room.spectate(&mut user, config.max_spectators, password)
+            } else {
+                room.join_room(clock, &mut user, config.max_peers, password, close_at)
+            };
+            let error = match joined_room {
+                Ok(()) => None,
+                Err(JoinError::Full) => Some(ApiError::RoomFull),
+                Err(JoinError::WrongPassword) => Some(ApiError::WrongPassword),
+                Err(JoinError::NotAllowed) => Some(ApiError::NotAllowed),
             };
+            if let Some(e) = error {
+                count(Counter::FailedJoins, 1);
+                return Ok(Err(e));
+            }
 
-            let peer = room.get_peer(&user).clone();
-            room.write(&bucket).await?;
-            user.set_peer(peer.clone());
-            peer
+            let counter = if is_new {
+                Counter::RoomsCreated
+            } else {
+                Counter::RoomsJoined
+            };
+            count(counter, 1);
+            trace.info(Some(&room.key), format_args!("joined new={}", is_new));
+            if code.is_none() && signals.iter().any(|s| matches!(s, Signal::HoldCode)) {
+                user.hold_code(&room.key);
+            }
+            if config.cache_offers && !is_new && !user.is_spectator() {
+                room.hand_offer(&user);
+            }
+            if is_new && signals.iter().any(|s| matches!(s, Signal::ScreenJoins)) {
+                room.screen();
+            }
+            if is_new {
+                let metadata = signals.iter().find_map(|s| match s {
+                    Signal::SetRoomMeta(metadata) => Some(metadata),
+                    _ => None,
+                });
+                if let Some(metadata) = metadata {
+                    if metadata.to_string().len() > config.max_room_meta_size {
+                        return Ok(Err(ApiError::TooLarge("room metadata")));
+                    }
+                    room.set_metadata(metadata);
+                }
+                let servers = signals.iter().find_map(|s| match s {
+                    Signal::SetIceServers(servers) => Some(servers),
+                    _ => None,
+                });
+                if let Some(servers) = servers {
+                    if !is_ice_allowed(env, servers) {
+                        return Ok(Err(ApiError::IceServersNotAllowed));
+                    }
+                    room.set_ice_servers(servers);
+                }
+            }
+            joined.push(room.key.clone());
+            rooms.push(room);
+        }
+    }
+
+    spans.end(started);
+
+    // Along with the rooms the user was in already
+    let started = spans.start("rooms");
+    for key in user.get_rooms().to_vec().iter() {
+        if rooms.iter().any(|room| room.key == *key) {
+            continue;
+        }
+        match Room::load(&*storage, key).await? {
+            Some(room) if !room.is_expired(clock) => rooms.push(room),
+            _ => user.drop_room(key),
+        }
+    }
+    if rooms.is_empty() {
+        return Ok(Err(ApiError::RoomExpired));
+    }
+
+    let kicks = user.kicks(&signals);
+    for room in rooms.iter_mut().filter(|room| room.is_owner(&user)) {
+        let slots = kicks
+            .iter()
+            .filter(|(key, _)| key.as_ref().is_none_or(|k| *k == room.key))
+            .map(|(_, slot)| *slot)
+            .collect::<Vec<u8>>();
+        for slot in slots {
+            let key = match room.kick(slot) {
+                Some(key) => key,
+                None => continue,
+            };
+            let (owner, kicked) = (token_prefix(&user.key), token_prefix(&key));
+            audit(&*storage, &config, Action::Kicked, &owner, &kicked).await;
+            if let Some(mut peer) = Auth::load(&*storage, &key).await? {
+                // So cleanup doesn't take the room down along with it
+                peer.drop_room(&room.key);
+                peer.expire(clock);
+                if !peer.write(&*storage).await? {
+                    return Ok(Err(ApiError::Conflict));
+                }
+            }
+        }
+    }
+
+    let accepts = user.accepts(&signals);
+    for room in rooms.iter_mut() {
+        if !room.is_owner(&user) {
+            continue;
+        }
+        let hints = accepts
+            .iter()
+            .filter(|(key, _)| key.as_ref().is_none_or(|k| *k == room.key))
+            .map(|(_, hint)| hint.clone())
+            .collect::<Vec<String>>();
+        for hint in hints {
+            if room.accept(&hint) {
+                trace.info(Some(&room.key), "join accepted");
+            }
+        }
+        for hint in room.untold_requests() {
+            user.notify(&room.key, Signal::JoinRequest(hint));
+        }
+    }
+
+    // Sealed ones need the public key first, which comes with the offerer's queue
+    let offer = signals
+        .iter()
+        .find(|s| matches!(s, Signal::SetSDP(sdp) if sdp.len() <= config.max_sdp_size))
+        .and_then(
+            |offer| match filter::send(&filter::chain(&config), offer.clone()) {
+                // As it would be queued
+                Verdict::Pass(offer) => Some(offer),
+                _ => None,
+            },
+        );
+    let mut all_full = true;
+    // Stored along with the user, see `commit`
+    let (mut writes, mut listings) = (vec![], vec![]);
+    for mut room in rooms.into_iter() {
+        // Nobody else is in the room yet to take it otherwise
+        let alone = room.get_members().len() == 1;
+        if let Some(offer) = offer.as_ref().filter(|_| config.cache_offers && alone) {
+            if user.is_holding(&room.key) {
+                room.cache_offer(&user, offer);
+            }
+        }
+        user.set_peers(clock, &room);
+        all_full &= room.is_full();
+        listings.extend(ListingUpdate::of(&room));
+        writes.extend(room.into_put());
+    }
+    let is_join = !joined.is_empty();
+
+    spans.end(started);
+
+    let started = spans.start("peers");
+    let peers = user.load_peers(&*storage).await?;
+    spans.end(started);
+
+    let done = all_full && !peers.is_empty() && user.is_all_done(&peers);
+    let reopens = signals.iter().any(|s| {
+        matches!(
+            s,
+            Signal::Renegotiate(_) | Signal::Relay(_) | Signal::Resync
+        )
+    });
+    if done && !reopens {
+        // The rooms are kept as they are, the user goes unwritten
+        if let Some(e) = commit(&*storage, writes, listings, is_join).await? {
+            return Ok(Err(e));
+        }
+        record(env, Event::Done, &Dimensions::of(&user), 0.0);
+        return Ok(Err(ApiError::ConnectionDone));
+    }
+
+    user.poll(clock, &config, &peers, load(env, &config).await);
+    user.ack(&signals);
+    if signals.iter().any(|s| matches!(s, Signal::Resync)) {
+        user.resync();
+    }
+    let has_sdp = signals
+        .iter()
+        .any(|s| matches!(s, Signal::SetSDP(_) | Signal::Sealed { .. }));
+    let first_sdp = has_sdp && user.start_negotiation(clock);
+    let queued = match user.send_signal(clock, signals, &config) {
+        Ok(queued) => queued,
+        // Nothing is written, the slots it took included
+        Err(e) => {
+            return Ok(Err(match e {
+                SendError::SdpTooLarge => ApiError::TooLarge("SDP"),
+                SendError::KeyTooLarge => ApiError::TooLarge("public key"),
+                SendError::TooManyCandidates => ApiError::TooLarge("candidates"),
+                SendError::QueueFull => ApiError::TooLarge("queue"),
+                SendError::InvalidCandidate(part) => {
+                    count(Counter::InvalidCandidates, 1);
+                    ApiError::InvalidCandidate(part)
+                }
+                SendError::Spectating => ApiError::CantSend,
+                SendError::Filtered(name) => ApiError::Filtered(name),
+            }));
         }
     };
-    let peer = match peer {
-        Some(peer) => Auth::load(&bucket, &peer).await?,
-        None => None,
-    };
+    if has_sdp {
+        trace.info(None, format_args!("sdp stored first={}", first_sdp));
+    }
+    let signals = user.pull_signals(clock, &peers, &config);
+    if let Some(key) = idempotency_key {
+        user.remember(key, &signals);
+    }
 
-    if let Some(ref peer) = peer {
-        if user.is_done(peer) {
-            return Response::error("Connection done.", 400);
+    let mut events: Vec<(Event, f64)> = joined.iter().map(|_| (Event::Join, 0.0)).collect();
+    if first_sdp {
+        events.push((Event::FirstSdp, 0.0));
+    }
+    for signal in signals.iter() {
+        if let Signal::ConnectAt(at) = signal {
+            let secs = user.negotiation_time(*at).unwrap_or_default();
+            trace.info(
+                None,
+                format_args!("connect_at computed negotiation={:.1}s", secs),
+            );
+            events.push((Event::Connect, secs));
         }
     }
+    let dimensions = Dimensions::of(&user);
+    let started = spans.start("write");
+    writes.extend(user.into_put());
+    if let Some(e) = commit(&*storage, writes, listings, is_join).await? {
+        return Ok(Err(e));
+    }
+    spans.end(started);
+
+    for (event, value) in events.into_iter() {
+        record(env, event, &dimensions, value);
+    }
+    for key in queued.iter() {
+        // Push the new signals right away if the peer holds a socket
+        notify(env, key).await;
+    }
 
-    user.poll();
-    user.send_signal(signals);
-    let signals = user.pull_signals(peer.as_ref());
-    user.write(&bucket).await?;
+    Ok(Ok(signals))
+}
 
-    Response::from_json(&signals)
+/// Stores the rooms and the user a poll changed in one go, so a join lands
+/// in both or in neither, then updates the rooms' listings.
+async fn commit(
+    storage: &dyn Storage,
+    writes: Vec<Put>,
+    listings: Vec<ListingUpdate>,
+    is_join: bool,
+) -> Result<Option<ApiError>> {
+    if !storage.put_all(writes).await? {
+        // Someone else joined or left in the meantime, losing a join race
+        // must not leave two peers on the same slot
+        if is_join {
+            return Ok(Some(ApiError::JoinConflict));
+        }
+        return Ok(Some(ApiError::Conflict));
+    }
+    for listing in listings {
+        listing.apply(storage).await?;
+    }
+    Ok(None)
 }
 
-pub async fn cleanup(bucket: Bucket) {
-    let objects = bucket
-        .list()
-        .prefix(AuthInfo::PREFIX)
-        .include(vec![Include::CustomMetadata])
-        .execute()
-        .await
-        .expect("couldn't list objects");
+/// Takes the user out of its rooms for good, telling the peers about it.
+pub async fn leave(
+    env: &Env,
+    clock: &dyn Clock,
+    storage: &dyn Storage,
+    mut user: Auth,
+) -> Result<std::result::Result<Vec<Signal>, ApiError>> {
+    for key in user.get_rooms().iter() {
+        if let Some(mut room) = Room::load(storage, key).await? {
+            let is_empty = room.leave_room(&user);
+            let listing = ListingUpdate::of(&room);
+            if is_empty && !room.is_reserved(clock) {
+                // The tombstone only tells joins it expired, the code can
+                // still be handed out again
+                room.delete(storage).await?;
+                bury(storage, &Config::from_env(env), key).await?;
+            } else if !room.write(storage).await? {
+                return Ok(Err(ApiError::Conflict));
+            }
+            if let Some(listing) = listing {
+                listing.apply(storage).await?;
+            }
+        }
+    }
 
-    let mut to_delete = HashSet::new();
-    for obj in objects.objects().iter() {
-        to_delete.extend(
-            Auth::read(obj)
-                .await
-                .unwrap_or_else(|_| panic!("couldn't read object {}", obj.key()))
-                .get_keys_to_kill(),
-        );
+    let peers = user.get_peers().to_vec();
+    user.leave(clock);
+    if !user.write(storage).await? {
+        return Ok(Err(ApiError::Conflict));
+    }
+
+    for key in peers.iter() {
+        notify(env, key).await;
+    }
+    Ok(Ok(vec![]))
+}
+
+/// First answerer of `room` that stopped polling, or whose token is gone entirely.
+async fn dead_answerer(
+    clock: &dyn Clock,
+    storage: &dyn Storage,
+    config: &Config,
+    room: &Room,
+) -> Result<Option<String>> {
+    for key in room.get_answerers() {
+        match Auth::load(storage, &key).await? {
+            Some(peer) if peer.is_alive(clock, config) => {}
+            _ => return Ok(Some(key)),
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the room signals of a poll can't all be followed: the same code
+/// joined twice, joining while leaving, or more than one password.
+fn is_conflicting_join(signals: &[Signal]) -> bool {
+    let mut codes = HashSet::new();
+    let mut passwords = 0;
+    let mut leaves = false;
+    for signal in signals.iter() {
+        match signal {
+            Signal::JoinRoom(code) if !codes.insert(code) => return true,
+            Signal::Password(_) => passwords += 1,
+            Signal::Leave => leaves = true,
+            _ => {}
+        }
+    }
+    passwords > 1 || (leaves && !codes.is_empty())
+}
+
+/// Codes of the `Signal::JoinRoom`s to follow, leaving out rooms of `rooms`,
+/// which a retried poll joined already.
+fn codes_to_join<'a>(signals: &'a [Signal], service: &str, rooms: &[String]) -> Vec<&'a String> {
+    signals
+        .iter()
+        .filter_map(|s| match s {
+            Signal::JoinRoom(code) => Some(code),
+            _ => None,
+        })
+        .filter(|code| !rooms.contains(&room_key(service, code)))
+        .collect()
+}
+
+/// Hands `keys` to the deletion queue when it's bound, deleting them right away otherwise.
+///
+/// Queued keys count as deleted, the consumer retries them until they are.
+async fn delete_all(env: &Env, storage: &dyn Storage, keys: &HashSet<String>) -> Vec<String> {
+    if keys.is_empty() {
+        return vec![];
+    }
+    let keys: Vec<String> = keys.iter().cloned().collect();
+    if let Ok(queue) = env.queue(DELETION_QUEUE) {
+        let batch: Vec<Vec<String>> = keys.chunks(KEYS_PER_MESSAGE).map(<[_]>::to_vec).collect();
+        match queue.send_batch(batch).await {
+            Ok(()) => return keys,
+            Err(e) => console_log!("couldn't queue deletions, deleting them now: {}", e),
+        }
+    }
+    delete_now(storage, &keys).await
+}
+
+/// Deletes `keys` a few at a time, returning the ones that are gone.
+pub async fn delete_now(storage: &dyn Storage, keys: &[String]) -> Vec<String> {
+    console_log!("deleting {:?}", keys);
+    let results: Vec<_> = stream::iter(keys.iter())
+        .map(|key| async move { (key, storage.delete(key).await) })
+        .buffer_unordered(DELETE_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut deleted = vec![];
+    for (key, result) in results.into_iter() {
+        match result {
+            Ok(()) => deleted.push(key.clone()),
+            // Picked up again by the next run
+            Err(e) => console_log!("couldn't delete {}: {}", key, e),
+        }
+    }
+    deleted
+}
+
+/// Bucket key of the listed object if it can go, corrupted ones being treated as expired.
+fn dead_key<T>(
+    entry: Entry,
+    read: impl FnOnce(Entry) -> std::result::Result<T, Corrupted>,
+    is_expired: impl FnOnce(&T) -> bool,
+) -> Option<String> {
+    let key = entry.key.clone();
+    match read(entry) {
+        Ok(object) => is_expired(&object).then_some(key),
+        Err(e) => {
+            console_log!("deleting {}: {}", key, e);
+            Some(key)
+        }
+    }
+}
+
+/// Deletes every expired object of a bucket, `what` naming it in the logs.
+async fn sweep<O, M, B>(
+    env: &Env,
+    storage: &dyn Storage,
+    what: &str,
+    is_expired: fn(&Data<O, M, B>) -> bool,
+) -> Vec<String>
+where
+    O: Serialize + DeserializeOwned + Default,
+    M: Metadata + Default,
+    B: BucketInfo,
+{
+    match storage.list(B::PREFIX).await {
+        Ok(entries) => {
+            let to_delete = entries
+                .into_iter()
+                .filter_map(|entry| dead_key(entry, Data::read, is_expired))
+                .collect();
+            delete_all(env, storage, &to_delete).await
+        }
+        Err(e) => {
+            console_log!("couldn't list {}: {}", what, e);
+            vec![]
+        }
+    }
+}
+
+/// Leaves tombstones for the rooms among `deleted` bucket keys.
+async fn bury_rooms(storage: &dyn Storage, config: &Config, deleted: &[String]) {
+    let prefix = format!("{}:", RoomInfo::PREFIX);
+    for key in deleted.iter().filter_map(|key| key.strip_prefix(&prefix)) {
+        if let Err(e) = bury(storage, config, key).await {
+            console_log!("couldn't bury {}: {}", key, e);
+        }
+    }
+}
+
+/// Bucket keys of the rooms in `rooms` none of whose members has an auth
+/// anymore, such as auths deleted by hand. Their expiry would never come.
+///
+/// Rooms that can't be checked are left to the next run.
+async fn orphans(storage: &dyn Storage, rooms: Vec<(String, String)>) -> Vec<String> {
+    let checked: Vec<_> = stream::iter(rooms)
+        .map(|(bucket_key, key)| async move {
+            let orphaned = async {
+                // Listings don't carry the members
+                let room = match Room::load(storage, &key).await? {
+                    Some(room) => room,
+                    None => return Ok(false),
+                };
+                for member in room.get_members().iter() {
+                    if storage.exists(&Auth::get_bucket_key(member)).await? {
+                        return Ok(false);
+                    }
+                }
+                Ok::<bool, worker::Error>(true)
+            };
+            match orphaned.await {
+                Ok(orphaned) => orphaned.then_some(bucket_key),
+                Err(e) => {
+                    console_log!("couldn't check {}: {}", key, e);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(DELETE_CONCURRENCY)
+        .collect()
+        .await;
+    checked.into_iter().flatten().collect()
+}
+
+/// Deletes expired rooms and sessions, page by page.
+///
+/// Reserved rooms stay until their own expiry, even if their members are gone.
+/// Other rooms go once none of their members' auths exist. Backends that
+/// `expires` get just that and the auths, whose undelivered signals would be
+/// lost otherwise, anything else goes by its expiry.
+/// Only auths in expiry buckets up to now are listed, those that stopped
+/// polling before are picked up once their bucket comes, along with the auths
+/// from before buckets. Storage failures are logged and left to the next run.
+pub async fn cleanup(env: &Env, clock: &dyn Clock, storage: &dyn Storage, config: &Config) {
+    let mut deleted = HashSet::new();
+    let mut reserved = HashSet::new();
+
+    let mut cursor = None;
+    loop {
+        let page = match storage.list_page(RoomInfo::PREFIX, cursor).await {
+            Ok(page) => page,
+            Err(e) => {
+                // Auths can't be cleaned up without knowing every reserved room
+                console_log!("couldn't list rooms: {}", e);
+                count(Counter::Cleaned, deleted.len() as u64);
+                return;
+            }
+        };
+
+        let mut to_delete = HashSet::new();
+        let mut settled = vec![];
+        for entry in page.entries.into_iter() {
+            let key = entry.key.clone();
+            let room = match Room::read(entry) {
+                Ok(room) => room,
+                Err(e) => {
+                    console_log!("deleting {}: {}", key, e);
+                    to_delete.insert(key);
+                    continue;
+                }
+            };
+            if room.is_expired(clock) {
+                to_delete.insert(key);
+            } else if room.is_reserved(clock) {
+                reserved.insert(key);
+            } else if room.is_created_before(clock.now() - ORPHAN_GRACE) {
+                settled.push((key, room.key));
+            }
+        }
+        to_delete.extend(orphans(storage, settled).await);
+        let rooms = delete_all(env, storage, &to_delete).await;
+        bury_rooms(storage, config, &rooms).await;
+        deleted.extend(rooms);
+
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    // Auths are listed by expiry, the ones past this bucket are still alive.
+    // Those from before buckets that sort after it are listed on their own
+    let now = clock.now();
+    let now_bucket = expiry_bucket(now);
+    let horizon = now + Duration::from_secs(config.max_session);
+    let legacy = legacy_prefixes(now, horizon)
+        .into_iter()
+        .map(|prefix| format!("{}:{}", AuthInfo::PREFIX, prefix));
+    // Auths whose undelivered signals are kept already, along with their peers'
+    let mut archived = HashSet::new();
+    for prefix in [AuthInfo::PREFIX.to_owned()].into_iter().chain(legacy) {
+        let mut past_expiry = true;
+        let mut cursor = None;
+        while past_expiry {
+            let page = match storage.list_page(&prefix, cursor).await {
+                Ok(page) => page,
+                Err(e) => {
+                    // The rest waits for the next run
+                    console_log!("couldn't list auths: {}", e);
+                    break;
+                }
+            };
+
+            let mut to_delete = HashSet::new();
+            for entry in page.entries.into_iter() {
+                let key = entry.key.clone();
+                let auth = match Auth::read(entry) {
+                    Ok(auth) => auth,
+                    Err(e) => {
+                        console_log!("deleting {}: {}", key, e);
+                        to_delete.insert(key);
+                        continue;
+                    }
+                };
+                if auth
+                    .expiry_bucket()
+                    .is_some_and(|b| b > now_bucket.as_str())
+                {
+                    past_expiry = false;
+                    break;
+                }
+                let keys = auth.get_keys_to_kill(clock, config);
+                if !keys.is_empty() {
+                    record(env, Event::Expired, &Dimensions::of(&auth), 0.0);
+                    if !archived.contains(&auth.key) && !deleted.contains(&key) {
+                        archived.extend(deadletter::archive(storage, config, auth).await);
+                    }
+                }
+                to_delete.extend(keys);
+            }
+            // Peers of an earlier page may show up again
+            to_delete.retain(|key| !deleted.contains(key) && !reserved.contains(key));
+            let rooms = delete_all(env, storage, &to_delete).await;
+            bury_rooms(storage, config, &rooms).await;
+            deleted.extend(rooms);
+
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+    }
+    if storage.expires() {
+        // Everything else goes by its expiry
+        count(Counter::Cleaned, deleted.len() as u64);
+        return;
+    }
+
+    // Listings outlive rooms deleted along with their auths until they expire too
+    deleted.extend(sweep(env, storage, "the lobby", Listing::is_expired).await);
+    deleted.extend(sweep(env, storage, "tombstones", Tombstone::is_expired).await);
+    deleted.extend(sweep(env, storage, "transfers", Transfer::is_expired).await);
+    deleted.extend(sweep(env, storage, "dead letters", DeadLetter::is_expired).await);
+    deleted.extend(sweep(env, storage, "invites", Invite::is_expired).await);
+    deleted.extend(sweep(env, storage, "the audit log", Record::is_expired).await);
+    deleted.extend(sweep(env, storage, "reports", Report::is_expired).await);
+    deleted.extend(sweep(env, storage, "bans", Ban::is_expired).await);
+
+    count(Counter::Cleaned, deleted.len() as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{codes_to_join, is_conflicting_join};
+    use crate::{proto::Signal, room::room_key};
+
+    fn join(code: &str) -> Signal {
+        Signal::JoinRoom(code.to_owned())
+    }
+
+    #[test]
+    fn same_room_joined_twice() {
+        assert!(is_conflicting_join(&[join("ABCDEF"), join("ABCDEF")]));
+    }
+
+    #[test]
+    fn several_rooms_joined_at_once() {
+        assert!(!is_conflicting_join(&[join("ABCDEF"), join("GHJKMN")]));
+    }
+
+    #[test]
+    fn join_while_leaving() {
+        assert!(is_conflicting_join(&[join("ABCDEF"), Signal::Leave]));
+        assert!(!is_conflicting_join(&[Signal::Leave]));
+    }
+
+    #[test]
+    fn two_passwords() {
+        let password = |p: &str| Signal::Password(p.to_owned());
+        assert!(is_conflicting_join(&[
+            join("ABCDEF"),
+            password("a"),
+            password("b")
+        ]));
+        assert!(!is_conflicting_join(&[join("ABCDEF"), password("a")]));
     }
 
-    console_log!("deleting {:?}", to_delete);
-    for key in to_delete.iter() {
-        bucket.delete(key).await.unwrap();
+    #[test]
+    fn join_room_already_in() {
+        let signals = [join("ABCDEF"), join("GHJKMN")];
+        let rooms = [room_key("test", "ABCDEF")];
+        assert!(!is_conflicting_join(&signals));
+        // Retried, only the other room is joined
+        assert_eq!(codes_to_join(&signals, "test", &rooms), ["GHJKMN"]);
+        // The code is another room in another service
+        assert_eq!(codes_to_join(&signals, "other", &rooms).len(), 2);
     }
 }
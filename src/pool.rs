@@ -0,0 +1,157 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+use web_time::{Duration, SystemTime};
+use worker::{console_log, Env, Result};
+
+use crate::{
+    auth::Auth,
+    clock::SystemClock,
+    config::Config,
+    db::{storage, BucketInfo, Corrupted, Data, Metadata, Storage},
+};
+
+/// Auths the cron writes ahead, so `/ident` can hand one out and store the
+/// session after responding.
+pub type Pool = Data<PoolData, PoolMetadata, PoolInfo>;
+
+pub struct PoolInfo {}
+impl BucketInfo for PoolInfo {
+    const PREFIX: &'static str = "pool";
+}
+
+/// The only pool object.
+const KEY: &str = "auths";
+/// Pooled auths an isolate claims at once, it hands them out without touching the pool.
+const BATCH: usize = 8;
+
+thread_local! {
+    // claimed by this isolate, not handed out yet
+    static CLAIMED: RefCell<Vec<Pooled>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Pooled {
+    key: String,
+    kill_at: SystemTime,
+}
+
+impl Pooled {
+    /// Only auths with over half their lifetime left are handed out, the rest expire unused.
+    fn is_fresh(&self, config: &Config) -> bool {
+        let left = Duration::from_secs(config.max_connection / 2);
+        self.kill_at > SystemTime::now() + left
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PoolData {
+    auths: Vec<Pooled>,
+}
+
+#[derive(Default)]
+pub struct PoolMetadata {}
+
+impl Metadata for PoolMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        None
+    }
+}
+impl TryFrom<HashMap<String, String>> for PoolMetadata {
+    type Error = Corrupted;
+
+    fn try_from(_: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        Ok(PoolMetadata {})
+    }
+}
+impl From<PoolMetadata> for HashMap<String, String> {
+    fn from(_: PoolMetadata) -> Self {
+        HashMap::new()
+    }
+}
+
+/// Key of a pooled auth for `/ident`, `None` once the pool ran dry.
+///
+/// Isolates claim `BATCH` auths with one conditional write, losing that race
+/// also gives `None`.
+pub async fn take(storage: &dyn Storage, config: &Config) -> Result<Option<String>> {
+    let claimed = CLAIMED.with(|claimed| {
+        let mut claimed = claimed.borrow_mut();
+        claimed.retain(|pooled| pooled.is_fresh(config));
+        claimed.pop()
+    });
+    if let Some(pooled) = claimed {
+        return Ok(Some(pooled.key));
+    }
+
+    let mut pool = match Pool::load(storage, KEY).await? {
+        Some(pool) => pool,
+        None => return Ok(None),
+    };
+    let data = pool.data.as_mut().expect("invalid state");
+    data.auths.retain(|pooled| pooled.is_fresh(config));
+    let mut batch = data.auths.split_off(data.auths.len().saturating_sub(BATCH));
+    if batch.is_empty() {
+        return Ok(None);
+    }
+    pool.modified = true;
+    if !pool.write(storage).await? {
+        return Ok(None);
+    }
+    let pooled = batch.pop().map(|pooled| pooled.key);
+    CLAIMED.with(|claimed| claimed.borrow_mut().extend(batch));
+    Ok(pooled)
+}
+
+/// Stores the session `/ident` handed out with a pooled key, run once the
+/// response is out.
+pub async fn settle(env: Env, ident: Auth) {
+    let settled = async {
+        let storage = storage(&env)?;
+        match Auth::load(&*storage, &ident.key).await? {
+            Some(mut user) => {
+                user.adopt(ident);
+                user.write(&*storage).await
+            }
+            // Corrupted, or gone before it was handed out
+            None => ident.write(&*storage).await,
+        }
+    };
+    match settled.await {
+        Ok(true) => {}
+        Ok(false) => console_log!("pooled auth changed while settling it"),
+        Err(e) => console_log!("couldn't settle a pooled auth: {}", e),
+    }
+}
+
+/// Tops the pool up to `KEY_POOL` auths, run by the cron.
+///
+/// There's no pool without it, and auths written for a pool that changed in
+/// the meantime expire unused.
+pub async fn refill(storage: &dyn Storage, config: &Config) -> Result<()> {
+    if config.key_pool == 0 {
+        return Ok(());
+    }
+    let mut pool = match Pool::load(storage, KEY).await? {
+        Some(pool) => pool,
+        None => Pool::unsaved(KEY.to_owned()),
+    };
+    let data = pool.data.as_mut().expect("invalid state");
+    data.auths.retain(|pooled| pooled.is_fresh(config));
+    for _ in data.auths.len()..config.key_pool {
+        let mut auth = Auth::create_expiring(&SystemClock, config)?;
+        auth.pool();
+        let pooled = Pooled {
+            key: auth.key.clone(),
+            kill_at: auth.kill_at(),
+        };
+        if auth.write(storage).await? {
+            data.auths.push(pooled);
+        }
+    }
+    pool.modified = true;
+    if !pool.write(storage).await? {
+        console_log!("pool changed while refilling it");
+    }
+    Ok(())
+}
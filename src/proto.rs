@@ -0,0 +1,454 @@
+//! Wire types shared with clients, built without the worker runtime when the
+//! `server` feature is off.
+
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+use web_time::SystemTime;
+
+/// Signal protocol versions this server speaks, clients pick one with `X-Signal-Version`.
+///
+/// Clients from before versioning send nothing and get the first one. Polls
+/// answer with bare signals in version 1, and a `PollResponse` from version 2.
+pub const PROTOCOL_VERSIONS: RangeInclusive<u32> = 1..=2;
+pub const VERSION_HEADER: &str = "X-Signal-Version";
+/// Content type of BARE encoded signals, JSON otherwise.
+pub const BARE_MIME: &str = "application/bare";
+
+pub type IceCandidate = (String, Option<String>, Option<u16>);
+
+/// Where negotiation with a peer stands, from this side.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LinkState {
+    pub generation: u32,
+    pub sent_sdp: bool,
+    pub ice_done: bool,
+    pub connect_at: Option<SystemTime>,
+}
+
+/// Side a peer takes in perfect negotiation, the offerer is the impolite one.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum Role {
+    Offerer,
+    Answerer,
+}
+
+/// Where a session stands, as far as its furthest peer got.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SessionState {
+    /// Not in a room yet
+    Created,
+    /// In a room, nobody sent an SDP yet
+    Joined,
+    /// SDPs and candidates are being exchanged
+    Negotiating,
+    /// Told when to connect, see `Signal::ConnectAt`
+    Scheduled,
+    /// Past the connection time
+    Connected,
+    /// Every peer connected and read everything, the next poll ends the session
+    Done,
+    /// Killed, kicked or no longer polling
+    Expired,
+}
+
+/// Why the server stretched the poll interval.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum Backoff {
+    /// Storage is failing more than usual
+    Load,
+    /// Peers haven't read what was sent yet
+    Queue,
+    /// No peer is polling anymore
+    Idle,
+}
+
+/// New signals go at the end, BARE knows them by their position.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Signal {
+    SetSDP(String),
+    AddCandidate(IceCandidate),
+    JoinRoom(String),
+    ConnectAt(SystemTime),
+    NextPoll(SystemTime),
+    SetService(String),
+    Peer(u8),
+    /// Starts a new negotiation round, the number is filled in by the server.
+    Renegotiate(u32),
+    Leave,
+    PeerLeft(u8),
+    /// Sets the room password when creating it, or proves it when joining.
+    Password(String),
+    /// Small application message for when the p2p connection can't be used.
+    Relay(Vec<u8>),
+    /// Role towards the peer of the preceding `Signal::Peer`, sent once per peer.
+    Role(Role),
+    /// A peer showed up in the room, sent once per peer.
+    PeerJoined(u8),
+    /// A peer stopped polling without leaving, it's cleaned up soon.
+    PeerGone(u8),
+    /// Candidates from a single poll, in order.
+    AddCandidates(Vec<IceCandidate>),
+    /// Asks for every signal again, e.g. after a page reload.
+    Resync,
+    /// Sent per peer after a `Signal::Resync`, following the replayed signals.
+    LinkState(LinkState),
+    /// This peer owns the room and may kick others.
+    RoomOwner,
+    /// Removes the peer on the slot from the room and ends its session, owners only.
+    /// Its peers get a `Signal::PeerGone`.
+    Kick(u8),
+    /// Sent before `Signal::NextPoll` when it's later than usual.
+    Backoff(Backoff),
+    /// Key for end-to-end encryption, published once per room and handed to
+    /// peers joining later too.
+    PublicKey(Vec<u8>),
+    /// Encrypted payload the server passes on without looking into it.
+    ///
+    /// The first one of a negotiation round stands in for the SDP when deriving
+    /// `connect_at`, the end of candidates still needs a plain empty `AddCandidate`.
+    Sealed {
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+    /// Numbers a response once the client acks, sent first.
+    Seq(u64),
+    /// Last `Signal::Seq` the client processed. Sending one turns acks on, after
+    /// which every response repeats what's unacked so lost responses are redelivered.
+    Ack(u64),
+    /// Drops the negotiation with the peer, queues included, so the same tokens
+    /// can negotiate again from scratch after the connection failed. The peer
+    /// gets it first thing of the new round, both stay in the room.
+    Restart,
+    /// Connection quality report, e.g. RTT and bitrate, passed on as it is.
+    /// Reports over `MAX_STATS_SIZE` or sent more often than every
+    /// `STATS_INTERVAL` seconds are dropped.
+    Stats(#[serde(with = "json_text")] serde_json::Value),
+    /// When the server got the signals that follow from the peer, sent again
+    /// whenever it changes.
+    ReceivedAt(SystemTime),
+    /// Sent with every poll, before `Signal::NextPoll`.
+    State(SessionState),
+    /// Along with the poll creating a room, holds back its `Signal::JoinRoom`
+    /// until an SDP was sent so whoever joins finds the offer waiting. What's
+    /// sent until then goes to the first peer to join.
+    HoldCode,
+    /// Application info about the room, e.g. the map, set along with the poll
+    /// that creates it or first joins it. Over `MAX_ROOM_META_SIZE` it is refused.
+    SetRoomMeta(#[serde(with = "json_text")] serde_json::Value),
+    /// The room's `Signal::SetRoomMeta`, for everyone joining after.
+    RoomMeta(#[serde(with = "json_text")] serde_json::Value),
+    /// Seconds until the token is killed, sent with every poll once it's
+    /// within `EXPIRY_WARNING`. `/refresh` extends it.
+    ExpiresIn(u64),
+    /// Along with `Signal::JoinRoom`, joins as a spectator that gets what the
+    /// members relay but can't send anything itself. Spectators are on slots
+    /// from `FIRST_SPECTATOR_SLOT` and take part in no negotiation, members
+    /// can relay to them with `Signal::Peer`.
+    Spectate,
+    /// The `Signal::ConnectAt` for the peer on the slot is off, it stopped
+    /// polling before it was told when to connect or before the time came.
+    ConnectCancelled(u8),
+    /// ICE servers for the room, set along with the poll that creates it.
+    /// Every URL's host must be in `ICE_ALLOWED`.
+    SetIceServers(#[serde(with = "json_text")] Vec<IceServer>),
+    /// The room's `Signal::SetIceServers`, for everyone joining after. Rooms
+    /// without send none, the ones from `/ident` apply.
+    IceServers(#[serde(with = "json_text")] Vec<IceServer>),
+    /// Along with the poll that creates the room, has whoever joins wait for
+    /// the owner's `Signal::Accept`. Up to `MAX_PENDING` wait at once.
+    ScreenJoins,
+    /// Someone asks into the owner's `Signal::ScreenJoins` room, under a hint
+    /// that's no token. The joiner gets the same one and keeps polling with
+    /// its `Signal::JoinRoom` until it's let in, or turned away with `ROOM_FULL`.
+    JoinRequest(String),
+    /// The owner lets the joiner with the hint in, the others waiting get
+    /// `ROOM_FULL`.
+    Accept(String),
+    /// The pull stopped at `MAX_PULL` signals, the client polls again right
+    /// away for the rest instead of waiting for its `Signal::NextPoll`.
+    HasMore,
+    /// Selects the room the next signals are about, both ways.
+    Room(String),
+}
+
+/// Keeps JSON as is in JSON, and as its text in formats that can't describe
+/// themselves like BARE.
+mod json_text {
+    use serde::{
+        de::{DeserializeOwned, Error as _},
+        ser::Error as _,
+        Deserialize, Deserializer, Serialize, Serializer,
+    };
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            value.serialize(serializer)
+        } else {
+            let text = serde_json::to_string(value).map_err(S::Error::custom)?;
+            text.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            T::deserialize(deserializer)
+        } else {
+            let text = String::deserialize(deserializer)?;
+            serde_json::from_str(&text).map_err(D::Error::custom)
+        }
+    }
+}
+
+impl Signal {
+    pub fn can_send(&self) -> bool {
+        match self {
+            Self::SetSDP(_) => true,
+            Self::AddCandidate(_) => true,
+            Self::JoinRoom(_) => false,
+            Self::ConnectAt(_) => false,
+            Self::NextPoll(_) => false,
+            Self::SetService(_) => false,
+            Self::Peer(_) => true,
+            Self::Renegotiate(_) => true,
+            Self::Leave => true,
+            Self::PeerLeft(_) => false,
+            Self::Password(_) => false,
+            Self::Relay(_) => true,
+            Self::Room(_) => true,
+            Self::Role(_) => false,
+            Self::PeerJoined(_) => false,
+            Self::PeerGone(_) => false,
+            Self::AddCandidates(_) => true,
+            Self::Resync => false,
+            Self::LinkState(_) => false,
+            Self::RoomOwner => false,
+            Self::Kick(_) => true,
+            Self::Backoff(_) => false,
+            Self::PublicKey(_) => true,
+            Self::Sealed { .. } => true,
+            Self::Seq(_) => false,
+            Self::Ack(_) => true,
+            Self::Restart => true,
+            Self::Stats(_) => true,
+            Self::ReceivedAt(_) => false,
+            Self::State(_) => false,
+            Self::HoldCode => false,
+            Self::SetRoomMeta(_) => false,
+            Self::RoomMeta(_) => false,
+            Self::ExpiresIn(_) => false,
+            Self::Spectate => false,
+            Self::ConnectCancelled(_) => false,
+            Self::SetIceServers(_) => false,
+            Self::IceServers(_) => false,
+            Self::ScreenJoins => false,
+            Self::JoinRequest(_) => false,
+            Self::Accept(_) => true,
+            Self::HasMore => false,
+        }
+    }
+}
+
+/// Same shape as the browser's `RTCIceServer`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Ways of exchanging signals, clients use the first one they support.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Bidirectional streams, not offered until Workers can accept WebTransport sessions
+    WebTransport,
+    /// `/ws`, when the socket binding is configured
+    WebSocket,
+    /// `/poll`, always offered
+    Poll,
+}
+
+/// What a client can take, in the body of `/ident`, or what the server
+/// offers, in its response. Clients sending none are taken to handle everything.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct Capabilities {
+    /// `/ws`, left out of the transports otherwise
+    pub websocket: bool,
+    /// BARE bodies, the client still asks for them per request with `Accept`
+    pub bare: bool,
+    /// `Signal::Relay`, dropped before reaching the client otherwise
+    pub relay: bool,
+    /// Largest SDP exchanged, in bytes, the smaller of the client's and the server's applies
+    #[serde(rename = "maxSdpSize")]
+    pub max_sdp_size: Option<usize>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            websocket: true,
+            bare: true,
+            relay: true,
+            max_sdp_size: None,
+        }
+    }
+}
+
+/// Body of `/ident`, it can be left empty.
+#[derive(Serialize, Deserialize, Default)]
+pub struct IdentRequest {
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+/// Body of `/ident`.
+#[derive(Serialize, Deserialize)]
+pub struct IdentResponse {
+    pub token: String,
+    #[serde(rename = "iceServers")]
+    pub ice_servers: Vec<IceServer>,
+    /// Lowest and highest `X-Signal-Version`
+    #[serde(rename = "signalVersions")]
+    pub signal_versions: (u32, u32),
+    /// Preferred first, older servers only had polling and websockets
+    #[serde(default)]
+    pub transports: Vec<Transport>,
+    /// What the server offers, of the client's `Capabilities`
+    #[serde(default)]
+    pub features: Capabilities,
+}
+
+/// Body of `/poll` from signal version 2.
+#[derive(Serialize, Deserialize)]
+pub struct PollResponse {
+    pub signals: Vec<Signal>,
+    /// When the response was made, to tell the client's clock offset
+    pub server_time: SystemTime,
+    /// There's a `Signal::HasMore` among the signals
+    #[serde(default)]
+    pub has_more: bool,
+}
+
+/// Entry of the `/poll/batch` body, one per token.
+#[derive(Serialize, Deserialize)]
+pub struct BatchPoll {
+    pub token: String,
+    #[serde(default)]
+    pub signals: Vec<Signal>,
+}
+
+/// Outcome of one `BatchPoll`, either the polled signals or why it failed.
+#[derive(Serialize, Deserialize)]
+pub struct BatchResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signals: Option<Vec<Signal>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+/// Body of `/poll/batch`, results are in the order of the request.
+#[derive(Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub results: Vec<BatchResult>,
+    pub server_time: SystemTime,
+}
+
+/// Body of `/refresh`.
+#[derive(Serialize, Deserialize)]
+pub struct RefreshResponse {
+    /// Replaces the old token, which stays valid until it would have expired
+    pub token: String,
+    pub kill_at: SystemTime,
+}
+
+/// Body of `/host`, a token that's in a new room already.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostResponse {
+    pub token: String,
+    pub ice_servers: Vec<IceServer>,
+    /// For the peers to join with
+    pub room_code: String,
+    /// When to poll first
+    pub next_poll: SystemTime,
+}
+
+/// Body of `/join`, a token that's in the room already.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinResponse {
+    pub token: String,
+    pub ice_servers: Vec<IceServer>,
+    /// What the peers queued, as the first poll would get it
+    pub signals: Vec<Signal>,
+    pub server_time: SystemTime,
+}
+
+/// Body of `/invite`.
+#[derive(Serialize, Deserialize)]
+pub struct InviteResponse {
+    /// For `/join`, once
+    pub invite: String,
+    /// Of the room it lets in
+    pub code: String,
+    pub expire_at: SystemTime,
+}
+
+/// Body of `/transfer/start`.
+#[derive(Serialize, Deserialize)]
+pub struct TransferStartResponse {
+    /// For `/transfer/claim`, once
+    pub code: String,
+    pub expire_at: SystemTime,
+}
+
+/// Body of `/transfer/claim`.
+#[derive(Serialize, Deserialize)]
+pub struct TransferClaimResponse {
+    /// New one for the session, the old one is refused from now on
+    pub token: String,
+    #[serde(rename = "iceServers")]
+    pub ice_servers: Vec<IceServer>,
+    pub kill_at: SystemTime,
+}
+
+/// Entry of `/rooms`, an open public room.
+#[derive(Serialize, Deserialize)]
+pub struct PublicRoom {
+    pub code: String,
+    pub name: String,
+    pub created_at: SystemTime,
+    pub occupancy: usize,
+    pub max_members: u8,
+}
+
+/// Body of `/room/{code}/status`, to check a code before joining it.
+#[derive(Serialize, Deserialize)]
+pub struct RoomStatus {
+    pub exists: bool,
+    /// No member slot left, spectators may still get in
+    pub full: bool,
+    pub service: String,
+    /// Unknown for rooms from before it was kept
+    pub created_at: Option<SystemTime>,
+    /// Joining needs a `Signal::Password`
+    pub protected: bool,
+}
+
+/// Body of every error response.
+#[derive(Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
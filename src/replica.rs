@@ -0,0 +1,351 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{async_trait, console_log, Bucket, Env, Error, Include, Result};
+
+use crate::db::{put_object, Condition, Entry, Page, Storage};
+
+/// R2 bucket every write is copied to, and read from while the primary fails.
+pub const REPLICA_BINDING: &str = "rtc_replica";
+/// Marks objects written to the replica alone, reconciliation copies them back.
+const FAILOVER: &str = "failover";
+/// Objects listed from each bucket per reconciliation, the next cron run goes on.
+const RECONCILE_PAGE: u32 = 250;
+/// Object of the primary that keeps track of reconciliation.
+const PROGRESS_KEY: &str = "replica:progress";
+
+/// R2 with write-through replication to a second bucket.
+///
+/// Writes go to the primary first and then to the replica without a
+/// precondition. When the primary fails, reads and writes fall back to the
+/// replica, its etags being the versions while it stands in.
+pub struct ReplicatedStorage {
+    primary: Bucket,
+    replica: Bucket,
+}
+
+impl ReplicatedStorage {
+    pub fn new(primary: Bucket, replica: Bucket) -> Self {
+        Self { primary, replica }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Storage for ReplicatedStorage {
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match Storage::exists(&self.primary, key).await {
+            Ok(found) => Ok(found),
+            Err(_) => Storage::exists(&self.replica, key).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Entry>> {
+        match Storage::get(&self.primary, key).await {
+            Ok(entry) => Ok(entry),
+            Err(e) => {
+                console_log!(
+                    "primary bucket failed, reading {} from the replica: {}",
+                    key,
+                    e
+                );
+                Storage::get(&self.replica, key).await
+            }
+        }
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        mut meta: HashMap<String, String>,
+        version: Option<&str>,
+        expire_at: Option<SystemTime>,
+    ) -> Result<bool> {
+        let written = Storage::put(
+            &self.primary,
+            key,
+            body.clone(),
+            meta.clone(),
+            version,
+            expire_at,
+        )
+        .await;
+        match written {
+            Ok(true) => {
+                let copied =
+                    put_object(&self.replica, key, body, meta, Condition::Any, expire_at).await;
+                if let Err(e) = copied {
+                    // Reconciliation catches up
+                    console_log!("couldn't replicate {}: {}", key, e);
+                }
+                Ok(true)
+            }
+            Ok(false) => Ok(false),
+            Err(e) => {
+                console_log!(
+                    "primary bucket failed, writing {} to the replica: {}",
+                    key,
+                    e
+                );
+                meta.insert(FAILOVER.to_owned(), "1".to_owned());
+                Storage::put(&self.replica, key, body, meta, version, expire_at).await
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let replica = Storage::delete(&self.replica, key).await;
+        match Storage::delete(&self.primary, key).await {
+            Ok(()) => Ok(()),
+            Err(_) => replica,
+        }
+    }
+
+    async fn list_page(&self, prefix: &str, cursor: Option<String>) -> Result<Page> {
+        match self.primary.list_page(prefix, cursor.clone()).await {
+            Ok(page) => Ok(page),
+            Err(_) => self.replica.list_page(prefix, cursor).await,
+        }
+    }
+}
+
+/// Objects of one page of a bucket, sorted by key with their etag and whether
+/// they were written during a failover.
+struct Listed {
+    objects: BTreeMap<String, (String, bool)>,
+    cursor: Option<String>,
+}
+
+impl Listed {
+    fn last(&self) -> Option<&String> {
+        self.objects.keys().next_back()
+    }
+}
+
+/// How far a bucket was listed.
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct Position {
+    cursor: Option<String>,
+    // listed to its end
+    done: bool,
+}
+
+/// Where reconciliation stopped, kept in the primary under `PROGRESS_KEY`.
+#[derive(Serialize, Deserialize, Default)]
+struct Progress {
+    primary: Position,
+    replica: Position,
+    // keys up to this one are reconciled
+    after: Option<String>,
+}
+
+impl Progress {
+    async fn load(bucket: &Bucket) -> Result<Self> {
+        let entry = match Storage::get(bucket, PROGRESS_KEY).await? {
+            Some(entry) => entry,
+            None => return Ok(Self::default()),
+        };
+        let body = entry.body.unwrap_or_default();
+        // Starts over from a progress it can't read
+        Ok(serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    async fn save(&self, bucket: &Bucket) -> Result<()> {
+        let body = serde_json::to_vec(self).unwrap();
+        let meta = HashMap::new();
+        put_object(bucket, PROGRESS_KEY, body, meta, Condition::Any, None).await?;
+        Ok(())
+    }
+}
+
+/// Next page of the bucket from `position`, empty once it's done.
+async fn objects(bucket: &Bucket, position: &Position) -> Result<Listed> {
+    if position.done {
+        return Ok(Listed {
+            objects: BTreeMap::new(),
+            cursor: None,
+        });
+    }
+    let mut builder = bucket
+        .list()
+        .limit(RECONCILE_PAGE)
+        .include(vec![Include::CustomMetadata]);
+    if let Some(cursor) = position.cursor.clone() {
+        builder = builder.cursor(cursor);
+    }
+    let listed = builder.execute().await?;
+
+    let mut objects = BTreeMap::new();
+    for obj in listed.objects() {
+        let failover = obj.custom_metadata()?.contains_key(FAILOVER);
+        objects.insert(obj.key(), (obj.etag(), failover));
+    }
+    Ok(Listed {
+        objects,
+        cursor: listed.cursor().filter(|_| listed.truncated()),
+    })
+}
+
+/// Where a bucket goes on from after reconciling up to `bound`, it stays put
+/// while its page has more past it.
+fn advance(position: &Position, listed: &Listed, bound: Option<&String>) -> Position {
+    if listed
+        .last()
+        .is_some_and(|last| bound.is_some_and(|b| last > b))
+    {
+        return position.clone();
+    }
+    Position {
+        done: listed.cursor.is_none(),
+        cursor: listed.cursor.clone(),
+    }
+}
+async fn copy(from: &Bucket, to: &Bucket, key: &str) -> Result<()> {
+    let obj = match from.get(key).execute().await? {
+        Some(obj) => obj,
+        None => return Ok(()),
+    };
+    let mut meta = obj.custom_metadata()?;
+    meta.remove(FAILOVER);
+    let expire_at = obj
+        .http_metadata()
+        .cache_expiry
+        .map(|at| UNIX_EPOCH + Duration::from_millis(at.as_millis()));
+    let body = match obj.body() {
+        Some(body) => body.bytes().await?,
+        None => vec![],
+    };
+    put_object(to, key, body, meta, Condition::Any, expire_at).await?;
+    Ok(())
+}
+
+/// Brings both buckets back in line, run by the cron when the replica is bound.
+///
+/// What was written to the replica during a failover is copied to the primary,
+/// otherwise the primary wins: what the replica is missing or has another etag
+/// for is copied over, and what the primary doesn't have is deleted. Deletes
+/// that only reached the replica come back this way, until cleanup drops them
+/// as expired.
+///
+/// Both buckets are walked a page at a time from where the last run stopped,
+/// starting over once they've been gone through.
+pub async fn reconcile(env: &Env) -> Result<()> {
+    let backend = env.var("STORAGE").map(|v| v.to_string()).ok();
+    if !matches!(backend.as_deref(), None | Some("r2")) {
+        return Ok(());
+    }
+    let (primary, replica) = match env.bucket(REPLICA_BINDING) {
+        Ok(replica) => (env.bucket("rtc")?, replica),
+        Err(_) => return Ok(()),
+    };
+    let progress = Progress::load(&primary).await?;
+    let listed = async {
+        let primary_objects = objects(&primary, &progress.primary).await?;
+        let replica_objects = objects(&replica, &progress.replica).await?;
+        Ok::<_, Error>((primary_objects, replica_objects))
+    };
+    let (primary_objects, replica_objects) = match listed.await {
+        Ok(listed) => listed,
+        Err(e) => {
+            // The cursors may have gone stale
+            Progress::default().save(&primary).await?;
+            return Err(e);
+        }
+    };
+
+    // Keys past the end of a page that has more can't be told missing yet
+    let bound = [&primary_objects, &replica_objects]
+        .into_iter()
+        .filter(|listed| listed.cursor.is_some())
+        .filter_map(Listed::last)
+        .min()
+        .cloned();
+    let covered = |key: &String| {
+        key != PROGRESS_KEY
+            && progress.after.as_ref().is_none_or(|after| key > after)
+            && bound.as_ref().is_none_or(|bound| key <= bound)
+    };
+
+    let mut copied = 0;
+    for (key, (etag, failover)) in replica_objects.objects.iter() {
+        if !covered(key) {
+            continue;
+        }
+        if *failover {
+            copy(&replica, &primary, key).await?;
+            // Unmarked, with the same etag once it's in the primary
+            copy(&primary, &replica, key).await?;
+            copied += 1;
+        } else if !primary_objects.objects.contains_key(key) {
+            // Missing from its page, or written since the primary was listed
+            if Storage::exists(&primary, key).await? {
+                continue;
+            }
+            Storage::delete(&replica, key).await?;
+            copied += 1;
+        } else if primary_objects.objects[key].0 != *etag {
+            copy(&primary, &replica, key).await?;
+            copied += 1;
+        }
+    }
+    for key in primary_objects.objects.keys() {
+        if covered(key) && !replica_objects.objects.contains_key(key) {
+            copy(&primary, &replica, key).await?;
+            copied += 1;
+        }
+    }
+    if copied > 0 {
+        console_log!("reconciled {} objects with the replica", copied);
+    }
+
+    let next = Progress {
+        primary: advance(&progress.primary, &primary_objects, bound.as_ref()),
+        replica: advance(&progress.replica, &replica_objects, bound.as_ref()),
+        after: bound,
+    };
+    if next.primary.done && next.replica.done {
+        // Gone through, the next run starts over
+        return Progress::default().save(&primary).await;
+    }
+    next.save(&primary).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{advance, Listed, Position};
+
+    fn page(keys: &[&str], cursor: Option<&str>) -> Listed {
+        Listed {
+            objects: keys
+                .iter()
+                .map(|key| (key.to_string(), (String::new(), false)))
+                .collect::<BTreeMap<_, _>>(),
+            cursor: cursor.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn advances_up_to_bound() {
+        let start = Position {
+            cursor: Some("0".to_owned()),
+            done: false,
+        };
+        let bound = "b".to_owned();
+        let ahead = advance(&start, &page(&["a", "b"], Some("1")), Some(&bound));
+        assert_eq!((ahead.cursor.as_deref(), ahead.done), (Some("1"), false));
+
+        // Has more past the bound, listed again
+        let behind = advance(&start, &page(&["a", "c"], Some("1")), Some(&bound));
+        assert_eq!((behind.cursor.as_deref(), behind.done), (Some("0"), false));
+        let last = advance(&start, &page(&["c"], None), Some(&bound));
+        assert!(!last.done);
+
+        let end = advance(&start, &page(&["a"], None), Some(&bound));
+        assert_eq!((end.cursor, end.done), (None, true));
+        let end = advance(&start, &page(&["c"], None), None);
+        assert!(end.done);
+    }
+}
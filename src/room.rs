@@ -1,10 +1,19 @@
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+
+use worker::Result;
 
 use crate::{
     auth::Auth,
-    db::{BucketInfo, Data, Metadata},
+    ban::token_hash,
+    clock::Clock,
+    config::Config,
+    db::{BucketInfo, Corrupted, Data, KeySpec, Metadata, Storage},
+    keys::{CryptoKeys, CROCKFORD},
+    proto::{IceServer, RoomStatus, Signal},
 };
 
 pub type Room = Data<RoomData, RoomMetadata, RoomInfo>;
@@ -13,65 +22,853 @@ pub struct RoomInfo {}
 impl BucketInfo for RoomInfo {
     const PREFIX: &'static str = "room";
     const KEY_LENGTH: u8 = 6;
+    // read out loud, so nothing that's easily mistaken
+    const ALPHABET: &'static str = CROCKFORD;
+}
+
+/// Left behind by deleted rooms for `TOMBSTONE_TTL`, so joining them tells
+/// an expired room apart from a wrong code.
+pub type Tombstone = Data<(), TombstoneMetadata, TombstoneInfo>;
+
+pub struct TombstoneInfo {}
+impl BucketInfo for TombstoneInfo {
+    const PREFIX: &'static str = "tomb";
+}
+
+pub struct TombstoneMetadata {
+    expire_at: SystemTime,
+}
+impl Default for TombstoneMetadata {
+    fn default() -> Self {
+        TombstoneMetadata {
+            expire_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Metadata for TombstoneMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        Some(self.expire_at)
+    }
+}
+impl TryFrom<HashMap<String, String>> for TombstoneMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let expire_at = value
+            .get("expire_at")
+            .and_then(|v| v.parse().ok())
+            .map(|v| UNIX_EPOCH + Duration::from_secs(v))
+            .unwrap_or(UNIX_EPOCH);
+        Ok(TombstoneMetadata { expire_at })
+    }
+}
+impl From<TombstoneMetadata> for HashMap<String, String> {
+    fn from(value: TombstoneMetadata) -> Self {
+        let expire_at = value
+            .expire_at
+            .duration_since(UNIX_EPOCH)
+            .expect("time travel?")
+            .as_secs();
+        HashMap::from([("expire_at".to_owned(), expire_at.to_string())])
+    }
+}
+
+impl Tombstone {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.meta.expire_at
+    }
+}
+
+/// Marks room `key` as recently deleted, an existing tombstone is kept as is.
+pub async fn bury(storage: &dyn Storage, config: &Config, key: &str) -> Result<()> {
+    let mut tombstone = Tombstone::unsaved(key.to_owned());
+    tombstone.meta.expire_at = SystemTime::now() + Duration::from_secs(config.tombstone_ttl);
+    tombstone.write(storage).await?;
+    Ok(())
+}
+
+/// Whether room `key` was deleted within `TOMBSTONE_TTL`.
+pub async fn is_buried(storage: &dyn Storage, key: &str) -> Result<bool> {
+    Ok(Tombstone::load(storage, key)
+        .await?
+        .is_some_and(|t| !t.is_expired()))
+}
+
+/// Writes the first room `build` makes whose code nobody has, drawing up to
+/// `attempts` of them. `None` once they were all taken.
+///
+/// `build` returns what the caller needs of the room once it's written.
+pub async fn create_unique<T>(
+    storage: &dyn Storage,
+    attempts: usize,
+    mut build: impl FnMut() -> Result<(Room, T)>,
+) -> Result<Option<T>> {
+    for _ in 0..attempts {
+        let (room, value) = build()?;
+        if room.write(storage).await? {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct RoomData {
     service: String,
-    offer: String,
-    answer: Option<String>,
+    // indexed by slot, the first one created the room
+    members: Vec<Option<String>>,
+    max_members: u8,
+    // may send `Signal::Kick`, the first member until it leaves
+    owner: Option<String>,
+    // removed by the owner, their peers are told they're gone
+    kicked: Vec<String>,
+    // identity provider subjects allowed in, anyone when empty
+    allowed: Vec<String>,
+    // JSON text of `Signal::SetRoomMeta`
+    metadata: Option<String>,
+    // watching without sending, indexed by slot from `FIRST_SPECTATOR_SLOT`
+    spectators: Vec<Option<String>>,
+    // JSON text of `Signal::SetIceServers`
+    ice_servers: Option<String>,
+    // see `cache_offer`
+    offer: Option<CachedOffer>,
+    // set by `Signal::ScreenJoins`
+    screening: Option<Screening>,
+}
+
+/// SDP a member sent while holding the code, copied for the first answerer.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CachedOffer {
+    from: String,
+    offer: Signal,
+    // member it went to, there's only the one
+    to: Option<String>,
+}
+
+/// Joiners of a screened room, waiting on the owner's `Signal::Accept`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Screening {
+    pending: Vec<JoinRequest>,
+    // let in by the owner, until it joins
+    accepted: Option<String>,
+    // waited along with the one accepted
+    turned_away: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JoinRequest {
+    key: String,
+    hint: String,
+    // the owner got its `Signal::JoinRequest`
+    told: bool,
+}
+
+/// Where a joiner of a screened room stands.
+pub enum Screened {
+    Accepted,
+    Pending(String),
+    TurnedAway,
 }
 
 #[derive(Default)]
-pub struct RoomMetadata {}
+pub struct RoomMetadata {
+    // hex encoded, salted with the room code
+    secret: Option<String>,
+    // set on reserved rooms, which outlive their members until then
+    expire_at: Option<SystemTime>,
+    // latest `kill_at` of the members that joined
+    kill_at: Option<SystemTime>,
+    // end of the room's maximum lifetime, members or not
+    close_at: Option<SystemTime>,
+    // display name of public rooms, listed in the lobby
+    name: Option<String>,
+    // when it was reserved or first joined, unknown for rooms from before
+    created_at: Option<SystemTime>,
+}
+
+impl Metadata for RoomMetadata {
+    fn expire_at(&self) -> Option<SystemTime> {
+        self.expire_at.max(self.kill_at)
+    }
+}
+impl TryFrom<HashMap<String, String>> for RoomMetadata {
+    type Error = Corrupted;
+
+    fn try_from(value: HashMap<String, String>) -> std::result::Result<Self, Corrupted> {
+        let secret = value.get("secret").filter(|v| !v.is_empty()).cloned();
+        let time = |name: &str| {
+            value
+                .get(name)
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    v.parse()
+                        .map(|v| UNIX_EPOCH + Duration::from_secs(v))
+                        .map_err(|_| Corrupted(format!("invalid {}", name)))
+                })
+                .transpose()
+        };
 
-impl Metadata for RoomMetadata {}
-impl From<HashMap<String, String>> for RoomMetadata {
-    fn from(_: HashMap<String, String>) -> Self {
-        RoomMetadata {}
+        Ok(RoomMetadata {
+            secret,
+            expire_at: time("expire_at")?,
+            kill_at: time("kill_at")?,
+            close_at: time("close_at")?,
+            name: value.get("name").filter(|v| !v.is_empty()).cloned(),
+            created_at: time("created_at")?,
+        })
     }
 }
 impl From<RoomMetadata> for HashMap<String, String> {
-    fn from(_: RoomMetadata) -> Self {
-        HashMap::new()
+    fn from(value: RoomMetadata) -> Self {
+        let mut map = HashMap::new();
+        let secret = value.secret.unwrap_or_default();
+        let time = |v: Option<SystemTime>| {
+            v.map(|v| {
+                v.duration_since(UNIX_EPOCH)
+                    .expect("time travel?")
+                    .as_secs()
+                    .to_string()
+            })
+            .unwrap_or_default()
+        };
+
+        map.insert("secret".to_owned(), secret);
+        map.insert("expire_at".to_owned(), time(value.expire_at));
+        map.insert("kill_at".to_owned(), time(value.kill_at));
+        map.insert("close_at".to_owned(), time(value.close_at));
+        map.insert("name".to_owned(), value.name.unwrap_or_default());
+        map.insert("created_at".to_owned(), time(value.created_at));
+        map
     }
 }
 
+/// What admins get to see of a room, without the members' tokens.
+#[derive(Serialize)]
+pub struct RoomSummary<'a> {
+    code: &'a str,
+    service: &'a str,
+    occupancy: usize,
+    max_members: u8,
+    protected: bool,
+    expire_at: Option<SystemTime>,
+    close_at: Option<SystemTime>,
+}
+
+pub enum JoinError {
+    Full,
+    WrongPassword,
+    NotAllowed,
+}
+
+/// Joiners that may wait on the owner of a screened room at once.
+pub const MAX_PENDING: usize = 16;
+
+/// Spectators take the slots from here up, above any member's.
+pub const FIRST_SPECTATOR_SLOT: u8 = 128;
+
+pub fn is_spectator_slot(slot: u8) -> bool {
+    slot >= FIRST_SPECTATOR_SLOT
+}
+
+/// Random codes tried when creating a room before giving up.
+pub const CREATE_ATTEMPTS: usize = 3;
+/// Chosen codes take letters, digits and `-`, e.g. `BLUE-TIGER-42`.
+const VANITY_CHARSET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-";
+const VANITY_LENGTH: std::ops::RangeInclusive<usize> = 4..=32;
+
+/// Normalizes a chosen room code, `None` if it breaks the charset or length policy.
+pub fn vanity_code(code: &str) -> Option<String> {
+    let code = code.trim().to_ascii_uppercase();
+    let valid = VANITY_LENGTH.contains(&code.len())
+        && code.chars().all(|c| VANITY_CHARSET.contains(c))
+        && !code.starts_with('-')
+        && !code.ends_with('-');
+    valid.then_some(code)
+}
+
+/// Storage key of room `code`, codes are only unique within a service.
+pub fn room_key(service: &str, code: &str) -> String {
+    format!("{}:{}", service, code)
+}
+
+/// The code clients know a room by, without its service.
+pub fn room_code(key: &str) -> &str {
+    key.split_once(':').map_or(key, |(_, code)| code)
+}
+
+fn hash_secret(code: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(code.as_bytes());
+    hasher.update(b":");
+    hasher.update(password.as_bytes());
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 impl Room {
-    pub fn get_peer(&self, peer: &Auth) -> Option<String> {
+    /// New room in `service` with a random code, `ROOM_ALPHABET` and `ROOM_CODE_LENGTH`
+    /// picking what it looks like.
+    ///
+    /// Codes are short enough to collide, writing the room fails then and it's up
+    /// to the caller to try another, up to `CREATE_ATTEMPTS` times.
+    pub fn create(config: &Config, service: &str) -> Result<Self> {
+        let spec = KeySpec {
+            alphabet: &config.room_alphabet,
+            length: config.room_code_length,
+        };
+        Self::create_with(&mut CryptoKeys, &spec, Some(service))
+    }
+
+    /// Returns the other members of the room as `(slot, token)` pairs, along
+    /// with its spectators unless `peer` is one of them.
+    pub fn get_peers(&self, peer: &Auth) -> Vec<(u8, String)> {
         let data = self.data.as_ref().expect("invalid state");
+        let spectators = if data.spectators.contains(&Some(peer.key.clone())) {
+            &[][..]
+        } else {
+            &data.spectators[..]
+        };
 
-        if peer.key == data.offer {
-            return data.answer.clone();
+        data.members
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, key)| Some((slot as u8, key.clone()?)))
+            .chain(
+                spectators
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, key)| Some((FIRST_SPECTATOR_SLOT + i as u8, key.clone()?))),
+            )
+            .filter(|(_, key)| *key != peer.key)
+            .collect()
+    }
+
+    pub fn get_members(&self) -> Vec<String> {
+        let data = self.data.as_ref().expect("invalid state");
+        data.members.iter().flatten().cloned().collect()
+    }
+
+    /// Members answering the offer, on every occupied slot but the lowest.
+    pub fn get_answerers(&self) -> Vec<String> {
+        let data = self.data.as_ref().expect("invalid state");
+        data.members.iter().flatten().skip(1).cloned().collect()
+    }
+
+    pub fn summary(&self) -> RoomSummary<'_> {
+        let data = self.data.as_ref().expect("invalid state");
+
+        RoomSummary {
+            code: room_code(&self.key),
+            service: &data.service,
+            occupancy: self.occupancy(),
+            max_members: data.max_members,
+            protected: self.meta.secret.is_some(),
+            expire_at: self.meta.expire_at,
+            close_at: self.meta.close_at,
         }
-        Some(data.offer.clone())
     }
 
-    pub fn join_room(&mut self, peer: &mut Auth) -> bool {
+    pub fn occupancy(&self) -> usize {
+        let data = self.data.as_ref().expect("invalid state");
+        data.members.iter().filter(|key| key.is_some()).count()
+    }
+
+    pub fn is_full(&self) -> bool {
+        let data = self.data.as_ref().expect("invalid state");
+        self.occupancy() >= data.max_members as usize
+    }
+
+    pub fn code(&self) -> &str {
+        room_code(&self.key)
+    }
+
+    pub fn max_members(&self) -> u8 {
+        self.data.as_ref().expect("invalid state").max_members
+    }
+
+    /// Only lets users with these identity provider subjects join.
+    pub fn allow(&mut self, subjects: Vec<String>) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.allowed = subjects;
+        self.modified = true;
+    }
+
+    /// Application info handed to everyone joining.
+    pub fn set_metadata(&mut self, metadata: &serde_json::Value) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.metadata = Some(metadata.to_string());
+        self.modified = true;
+    }
+
+    pub fn metadata(&self) -> Option<serde_json::Value> {
+        let data = self.data.as_ref().expect("invalid state");
+        serde_json::from_str(data.metadata.as_deref()?).ok()
+    }
+
+    /// ICE servers handed to everyone joining, instead of the ones from `/ident`.
+    pub fn set_ice_servers(&mut self, servers: &[IceServer]) {
         let data = self.data.as_mut().expect("invalid state");
-        let service = peer.get_service().expect("invalid state").clone();
+        data.ice_servers = serde_json::to_string(servers).ok();
+        self.modified = true;
+    }
+
+    pub fn ice_servers(&self) -> Option<Vec<IceServer>> {
+        let data = self.data.as_ref().expect("invalid state");
+        serde_json::from_str(data.ice_servers.as_deref()?).ok()
+    }
 
-        let is_offer = data.offer.is_empty();
-        let is_answer = data.answer.is_none();
-        if !is_offer && !is_answer {
-            return false;
+    /// Keeps the `Signal::SetSDP` that `peer` sent while alone, so the first
+    /// answerer gets it in the poll that joins.
+    ///
+    /// Only the first one is kept, it's handed out once.
+    pub fn cache_offer(&mut self, peer: &Auth, offer: &Signal) {
+        let data = self.data.as_mut().expect("invalid state");
+        if data.offer.is_none() {
+            data.offer = Some(CachedOffer {
+                from: peer.key.clone(),
+                offer: offer.clone(),
+                to: None,
+            });
+            self.modified = true;
         }
+    }
 
-        if is_offer {
-            // Creating room
-            data.service = service;
-            data.offer = peer.key.clone();
-        } else if service != data.service {
+    /// Hands the cached offer to `peer`, joining as its first answerer.
+    pub fn hand_offer(&mut self, peer: &Auth) {
+        let data = self.data.as_mut().expect("invalid state");
+        if let Some(ref mut cached) = data.offer {
+            if cached.to.is_none() && cached.from != peer.key {
+                cached.to = Some(peer.key.clone());
+                self.modified = true;
+            }
+        }
+    }
+
+    /// The offer `from` sent, if it was handed to `to` already.
+    pub fn handed_offer(&self, from: &str, to: &str) -> Option<&Signal> {
+        let data = self.data.as_ref().expect("invalid state");
+        data.offer
+            .as_ref()
+            .filter(|cached| cached.from == from && cached.to.as_deref() == Some(to))
+            .map(|cached| &cached.offer)
+    }
+
+    /// Has joiners wait for the owner's `Signal::Accept`, see `screen_join`.
+    pub fn screen(&mut self) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.screening = Some(Screening::default());
+        self.modified = true;
+    }
+
+    pub fn is_screened(&self) -> bool {
+        let data = self.data.as_ref().expect("invalid state");
+        data.screening.is_some()
+    }
+
+    /// Queues `peer` for the owner to accept unless it was, or someone else was.
+    ///
+    /// Its hint is the start of `token_hash`, which the owner can't join as.
+    pub fn screen_join(&mut self, peer: &Auth) -> Screened {
+        let is_full = self.is_full();
+        let data = self.data.as_mut().expect("invalid state");
+        let screening = data.screening.get_or_insert_with(Screening::default);
+        if screening.accepted.as_ref() == Some(&peer.key) {
+            return Screened::Accepted;
+        }
+        if is_full || screening.accepted.is_some() || screening.turned_away.contains(&peer.key) {
+            return Screened::TurnedAway;
+        }
+        if let Some(request) = screening.pending.iter().find(|r| r.key == peer.key) {
+            return Screened::Pending(request.hint.clone());
+        }
+        if screening.pending.len() >= MAX_PENDING {
+            return Screened::TurnedAway;
+        }
+        let hint = token_hash(&peer.key)[..12].to_owned();
+        screening.pending.push(JoinRequest {
+            key: peer.key.clone(),
+            hint: hint.clone(),
+            told: false,
+        });
+        self.modified = true;
+        Screened::Pending(hint)
+    }
+
+    /// Hints of the joiners the owner wasn't told about yet, marking them told.
+    pub fn untold_requests(&mut self) -> Vec<String> {
+        let data = self.data.as_mut().expect("invalid state");
+        let mut hints = vec![];
+        if let Some(ref mut screening) = data.screening {
+            for request in screening.pending.iter_mut().filter(|r| !r.told) {
+                request.told = true;
+                hints.push(request.hint.clone());
+            }
+        }
+        if !hints.is_empty() {
+            self.modified = true;
+        }
+        hints
+    }
+
+    /// Lets in the joiner with `hint`, turning away everyone else waiting.
+    /// Returns whether anyone had it.
+    pub fn accept(&mut self, hint: &str) -> bool {
+        let data = self.data.as_mut().expect("invalid state");
+        let screening = match data.screening {
+            Some(ref mut screening) if screening.accepted.is_none() => screening,
+            _ => return false,
+        };
+        let accepted = match screening.pending.iter().position(|r| r.hint == hint) {
+            Some(i) => screening.pending.remove(i).key,
+            None => return false,
+        };
+        screening.accepted = Some(accepted);
+        let others = screening.pending.drain(..).map(|r| r.key);
+        screening.turned_away.extend(others);
+        self.modified = true;
+        true
+    }
+
+    /// Lists the room in the lobby under `name`.
+    pub fn publish(&mut self, name: String) {
+        self.meta.name = Some(name);
+        self.modified = true;
+    }
+
+    pub fn public_name(&self) -> Option<&str> {
+        self.meta.name.as_deref()
+    }
+
+    /// Sets up an empty room to be joined until `expire_at`, it's closed at `close_at` either way.
+    pub fn reserve(
+        &mut self,
+        clock: &dyn Clock,
+        service: String,
+        max_members: u8,
+        password: Option<&str>,
+        expire_at: SystemTime,
+        close_at: SystemTime,
+    ) {
+        let data = self.data.as_mut().expect("invalid state");
+        data.service = service;
+        data.max_members = max_members.max(2);
+        self.meta.secret = password.map(|p| hash_secret(&self.key, p));
+        self.meta.expire_at = Some(expire_at);
+        self.meta.close_at = Some(close_at);
+        self.meta.created_at = Some(clock.now());
+        self.modified = true;
+    }
+
+    /// What `/room/{code}/status` tells of the room, nothing about its members.
+    pub fn status(&self) -> RoomStatus {
+        let data = self.data.as_ref().expect("invalid state");
+        RoomStatus {
+            exists: true,
+            full: self.is_full(),
+            service: data.service.clone(),
+            created_at: self.meta.created_at,
+            protected: self.meta.secret.is_some(),
+        }
+    }
+
+    /// Reserved rooms are kept around while empty, until they expire.
+    pub fn is_reserved(&self, clock: &dyn Clock) -> bool {
+        self.meta.expire_at.is_some_and(|t| clock.now() < t)
+    }
+
+    /// Whether the room was created before `at`, rooms from before that was
+    /// known count as older.
+    pub fn is_created_before(&self, at: SystemTime) -> bool {
+        self.meta.created_at.is_none_or(|t| t < at)
+    }
+
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        let now = clock.now();
+        self.meta.expire_at.is_some_and(|t| now >= t)
+            || self.meta.close_at.is_some_and(|t| now >= t)
+    }
+
+    /// Keeps the room around for as long as `peer` now lives.
+    pub fn extend(&mut self, peer: &Auth) {
+        self.meta.kill_at = self.meta.kill_at.max(Some(peer.kill_at()));
+        self.modified = true;
+    }
+
+    pub fn is_owner(&self, peer: &Auth) -> bool {
+        let data = self.data.as_ref().expect("invalid state");
+        data.owner.as_ref() == Some(&peer.key)
+    }
+
+    /// Puts `new` wherever `old` is in the room, for a session handed over.
+    pub fn rekey(&mut self, old: &str, new: &str) {
+        let data = self.data.as_mut().expect("invalid state");
+        let slots = data
+            .members
+            .iter_mut()
+            .chain(data.spectators.iter_mut())
+            .chain([&mut data.owner])
+            .flatten();
+        let offer = data
+            .offer
+            .iter_mut()
+            .flat_map(|offer| [&mut offer.from].into_iter().chain(offer.to.iter_mut()));
+        let screening = data.screening.iter_mut().flat_map(|screening| {
+            let pending = screening.pending.iter_mut().map(|request| &mut request.key);
+            pending
+                .chain(screening.accepted.iter_mut())
+                .chain(screening.turned_away.iter_mut())
+        });
+        let keys = slots
+            .chain(data.kicked.iter_mut())
+            .chain(offer)
+            .chain(screening);
+        for key in keys.filter(|key| *key == old) {
+            *key = new.to_owned();
+        }
+        self.modified = true;
+    }
+
+    pub fn is_kicked(&self, key: &str) -> bool {
+        let data = self.data.as_ref().expect("invalid state");
+        data.kicked.iter().any(|k| k == key)
+    }
+
+    /// Whether the service, password and allow-list let `peer` into the room,
+    /// full or not.
+    pub fn may_join(
+        &self,
+        peer: &Auth,
+        password: Option<&str>,
+    ) -> std::result::Result<(), JoinError> {
+        let secret = password.map(|p| hash_secret(&self.key, p));
+        let data = self.data.as_ref().expect("invalid state");
+        let service = peer.get_service().expect("invalid state");
+
+        if *service != data.service {
             // Can't join room with invalid service
-            return false;
+            Err(JoinError::Full)
+        } else if self.meta.secret.is_some() && secret != self.meta.secret {
+            Err(JoinError::WrongPassword)
+        } else if !data.allowed.is_empty()
+            && !peer.get_subject().is_some_and(|s| data.allowed.contains(s))
+        {
+            Err(JoinError::NotAllowed)
         } else {
-            // Valid service
-            data.answer = Some(peer.key.clone());
+            Ok(())
         }
+    }
 
-        peer.set_room(self);
+    /// Adds the peer to the room, creating it with `max_members` slots if empty.
+    ///
+    /// The creator's password protects the room, everyone else must match it.
+    /// New rooms close at `close_at`.
+    pub fn join_room(
+        &mut self,
+        clock: &dyn Clock,
+        peer: &mut Auth,
+        max_members: u8,
+        password: Option<&str>,
+        close_at: SystemTime,
+    ) -> std::result::Result<(), JoinError> {
+        let is_full = self.is_full();
+        // Reserved rooms are set up already
+        let is_new = self
+            .data
+            .as_ref()
+            .expect("invalid state")
+            .service
+            .is_empty();
+        if !is_new {
+            if is_full && !self.get_members().contains(&peer.key) {
+                return Err(JoinError::Full);
+            }
+            self.may_join(peer, password)?;
+        }
+        let secret = password.map(|p| hash_secret(&self.key, p));
+        let data = self.data.as_mut().expect("invalid state");
+
+        if is_new {
+            // Creating room
+            data.service = peer.get_service().expect("invalid state").clone();
+            data.max_members = max_members.max(2);
+            self.meta.secret = secret;
+            self.meta.close_at = Some(close_at);
+            self.meta.created_at = Some(clock.now());
+        }
+
+        // Retried join whose room got written but not the auth, keep its slot.
+        // Otherwise reuse slots freed by peers that left
+        let own = data
+            .members
+            .iter()
+            .position(|key| key.as_ref() == Some(&peer.key));
+        let slot = match own.or_else(|| data.members.iter().position(|key| key.is_none())) {
+            Some(slot) => slot,
+            None => {
+                data.members.push(None);
+                data.members.len() - 1
+            }
+        };
+        data.members[slot] = Some(peer.key.clone());
+        // Reserved rooms get their owner here
+        data.owner.get_or_insert_with(|| peer.key.clone());
+        if let Some(ref mut screening) = data.screening {
+            if screening.accepted.as_ref() == Some(&peer.key) {
+                // Someone else may be accepted once it leaves
+                screening.accepted = None;
+            }
+        }
+        self.meta.kill_at = self.meta.kill_at.max(Some(peer.kill_at()));
+        peer.add_room(self, slot as u8);
         self.modified = true;
 
-        true
+        Ok(())
+    }
+
+    /// Adds the peer as a spectator on the first free slot from `FIRST_SPECTATOR_SLOT`.
+    ///
+    /// The password and allow-list apply as they do to members, but spectators
+    /// take no member slot and can't create the room.
+    pub fn spectate(
+        &mut self,
+        peer: &mut Auth,
+        max_spectators: u8,
+        password: Option<&str>,
+    ) -> std::result::Result<(), JoinError> {
+        self.may_join(peer, password)?;
+        let data = self.data.as_mut().expect("invalid state");
+
+        let max = max_spectators.min(u8::MAX - FIRST_SPECTATOR_SLOT) as usize;
+        let own = data
+            .spectators
+            .iter()
+            .position(|key| key.as_ref() == Some(&peer.key));
+        let slot = match own.or_else(|| data.spectators.iter().position(|key| key.is_none())) {
+            Some(slot) => slot,
+            None if data.spectators.len() < max => {
+                data.spectators.push(None);
+                data.spectators.len() - 1
+            }
+            None => return Err(JoinError::Full),
+        };
+        data.spectators[slot] = Some(peer.key.clone());
+        peer.add_room(self, FIRST_SPECTATOR_SLOT + slot as u8);
+        self.modified = true;
+
+        Ok(())
+    }
+
+    /// Frees the peer's slot, returning whether the room is now empty.
+    pub fn leave_room(&mut self, peer: &Auth) -> bool {
+        self.free_slot(&peer.key);
+        self.occupancy() == 0
+    }
+
+    /// Frees the slot of `key`, e.g. for someone to take over from a member that died.
+    pub fn free_slot(&mut self, key: &str) {
+        let data = self.data.as_mut().expect("invalid state");
+
+        for slot in data.members.iter_mut().chain(data.spectators.iter_mut()) {
+            if slot.as_deref() == Some(key) {
+                *slot = None;
+                self.modified = true;
+            }
+        }
+        if data.owner.as_deref() == Some(key) {
+            // Handed to the lowest slot left
+            data.owner = data.members.iter().flatten().next().cloned();
+        }
+    }
+
+    /// Takes the member on `slot` out of the room, returning its token.
+    ///
+    /// Meant for the owner, which can't kick itself.
+    pub fn kick(&mut self, slot: u8) -> Option<String> {
+        let data = self.data.as_mut().expect("invalid state");
+        let slot = if is_spectator_slot(slot) {
+            data.spectators
+                .get_mut((slot - FIRST_SPECTATOR_SLOT) as usize)?
+        } else {
+            data.members.get_mut(slot as usize)?
+        };
+        let key = slot.clone()?;
+        if data.owner.as_ref() == Some(&key) {
+            return None;
+        }
+        *slot = None;
+        data.kicked.push(key.clone());
+        self.modified = true;
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_unique, room_key, Room, CREATE_ATTEMPTS};
+    use crate::{
+        db::Storage,
+        memory::MemoryStorage,
+        testing::{block_on, room, SeededKeys},
+    };
+
+    /// Takes the code `SeededKeys(seed)` draws first.
+    fn seed(storage: &MemoryStorage, seed: u64) {
+        let taken = room(&mut SeededKeys(seed));
+        assert!(block_on(taken.write(storage)).unwrap());
+    }
+
+    #[test]
+    fn retries_taken_code() {
+        let storage = MemoryStorage::default();
+        seed(&storage, 1);
+
+        let mut keys = SeededKeys(1);
+        let mut drawn = 0;
+        let created = block_on(create_unique(&storage, CREATE_ATTEMPTS, || {
+            drawn += 1;
+            let room = room(&mut keys);
+            let key = room.key.clone();
+            Ok((room, key))
+        }))
+        .unwrap();
+        assert_eq!(drawn, 2);
+        let key = created.unwrap();
+        assert!(block_on(Room::load(&storage, &key)).unwrap().is_some());
+        assert_eq!(block_on(storage.list("room:")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_attempts() {
+        let storage = MemoryStorage::default();
+        seed(&storage, 1);
+
+        // Drawing the same code every time
+        let created = block_on(create_unique(&storage, CREATE_ATTEMPTS, || {
+            Ok((room(&mut SeededKeys(1)), ()))
+        }))
+        .unwrap();
+        assert!(created.is_none());
+    }
+
+    #[test]
+    fn chosen_code_taken() {
+        let storage = MemoryStorage::default();
+        let chosen = || Room::unsaved(room_key("test", "BLUE-TIGER"));
+        assert!(block_on(chosen().write(&storage)).unwrap());
+
+        // Tried once, that's `CODE_TAKEN`
+        let mut drawn = 0;
+        let created = block_on(create_unique(&storage, 1, || {
+            drawn += 1;
+            Ok((chosen(), ()))
+        }))
+        .unwrap();
+        assert!(created.is_none());
+        assert_eq!(drawn, 1);
     }
 }
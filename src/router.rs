@@ -0,0 +1,299 @@
+use serde::Deserialize;
+use worker::{
+    console_log, event, Context, Cors, Env, Headers, MessageBatch, MessageExt, Method, Request,
+    Response, Result, ScheduleContext, ScheduledEvent,
+};
+
+use crate::{
+    admin::{admin, debug},
+    audit::{audit, Action},
+    ban::report_peer,
+    clock::SystemClock,
+    config::Config,
+    db::storage,
+    error::ApiError,
+    grpc::grpc,
+    health::health,
+    invite::invite,
+    limit::limit,
+    load::report,
+    lobby::{lobby, room_status},
+    log::token_prefix,
+    maintenance,
+    matcher::quick_match,
+    metrics::{flush, metrics},
+    openapi::openapi,
+    poll::{
+        cleanup, create_room, delete_now, heartbeat, host, ident, join, poll, poll_batch,
+        poll_query, refresh,
+    },
+    pool::refill,
+    replica::reconcile,
+    transfer::{transfer_claim, transfer_start},
+    ws::socket,
+};
+
+/// Seconds clients wait after a 503.
+const RETRY_AFTER: u64 = 5;
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+async fn handle(req: Request, env: Env, ctx: &Context) -> Result<Response> {
+    let path = req.path();
+    if path == "/health" {
+        if !matches!(req.method(), Method::Get) {
+            return ApiError::MethodNotAllowed.into_response();
+        }
+        return health(env).await;
+    }
+    if path == "/openapi.json" {
+        if !matches!(req.method(), Method::Get) {
+            return ApiError::MethodNotAllowed.into_response();
+        }
+        return openapi();
+    }
+    if path.starts_with("/admin/") {
+        return admin(req, env).await;
+    }
+    if path.starts_with("/debug/") {
+        return debug(req, env).await;
+    }
+    if path == "/metrics" {
+        if !matches!(req.method(), Method::Get) {
+            return ApiError::MethodNotAllowed.into_response();
+        }
+        return metrics(req, env).await;
+    }
+
+    // Polls can also be sent as a plain GET
+    let is_get = matches!(req.method(), Method::Get);
+    let status_code = path
+        .strip_prefix("/room/")
+        .and_then(|rest| rest.strip_suffix("/status"))
+        .filter(|code| !code.is_empty() && !code.contains('/'))
+        .map(str::to_owned);
+    let allowed = matches!(req.method(), Method::Post)
+        || (is_get && (path == "/poll" || path == "/rooms" || status_code.is_some()));
+    if !allowed {
+        return ApiError::MethodNotAllowed.into_response();
+    }
+
+    if let Some(res) = admit(&req, &env, &path).await? {
+        return Ok(res);
+    }
+
+    if path == "/ident" {
+        return ident(req, env, ctx).await;
+    } else if path == "/host" {
+        return host(req, env).await;
+    } else if path == "/join" {
+        return join(req, env).await;
+    } else if path == "/poll" && is_get {
+        return poll_query(req, env).await;
+    } else if path == "/poll" {
+        return poll(req, env, ctx).await;
+    } else if path == "/poll/batch" {
+        return poll_batch(req, env).await;
+    } else if path == "/heartbeat" {
+        return heartbeat(req, env).await;
+    } else if path == "/refresh" {
+        return refresh(req, env).await;
+    } else if path == "/rooms" && is_get {
+        return lobby(req, env).await;
+    } else if let Some(code) = status_code.filter(|_| is_get) {
+        return room_status(req, env, &code).await;
+    } else if path == "/room/create" {
+        return create_room(req, env).await;
+    } else if path == "/match" {
+        return quick_match(req, env).await;
+    } else if path == "/invite" {
+        return invite(req, env).await;
+    } else if path == "/transfer/start" {
+        return transfer_start(req, env).await;
+    } else if path == "/transfer/claim" {
+        return transfer_claim(req, env).await;
+    } else if path == "/report" {
+        return report_peer(req, env).await;
+    } else if path.starts_with("/grpc/") {
+        return grpc(req, env, ctx).await;
+    }
+
+    ApiError::NotFound.into_response()
+}
+
+/// The response turning the request away, if the maintenance flag or the
+/// caller's IP or token rate limit does.
+async fn admit(req: &Request, env: &Env, path: &str) -> Result<Option<Response>> {
+    // Sessions already going are left to finish
+    let creates = path == "/ident"
+        || path == "/host"
+        || path == "/join"
+        || path == "/room/create"
+        || path == "/match";
+    if creates && maintenance::is_on(env).await? {
+        return ApiError::Maintenance(maintenance::RETRY_AFTER)
+            .into_response()
+            .map(Some);
+    }
+
+    let ip = req.headers().get("CF-Connecting-IP")?;
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => Some(token),
+        None => req.query::<TokenQuery>().ok().map(|q| q.token),
+    };
+    let keys = [
+        ip.map(|ip| (format!("ip:{}", ip), ip)),
+        token.map(|token| (format!("token:{}", token), token_prefix(&token))),
+    ];
+    for (key, actor) in keys.iter().flatten() {
+        if let Some(limited) = limit(env, key).await? {
+            if limited.first {
+                // Once per run of rejections, not for every one of them
+                let config = Config::from_env(env);
+                audit(&*storage(env)?, &config, Action::RateLimited, actor, path).await;
+            }
+            return ApiError::RateLimited(limited.retry_after)
+                .into_response()
+                .map(Some);
+        }
+    }
+    Ok(None)
+}
+
+fn list_var(env: &Env, name: &str, default: &str) -> Vec<String> {
+    env.var(name)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| default.to_owned())
+        .split(';')
+        .filter(|v| !v.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// CORS policy from the `CORS_*` vars.
+///
+/// Browsers only take a single origin along with credentials, so the request's
+/// origin is echoed with them when it's listed. `*` allows any other origin
+/// without credentials.
+fn cors(req: &Request, env: &Env) -> Result<Cors> {
+    let origins = list_var(env, "CORS_ORIGINS", "*");
+    let headers = list_var(env, "CORS_HEADERS", "Authorization;*");
+    let max_age = env
+        .var("CORS_MAX_AGE")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(86400);
+
+    let cors = Cors::new()
+        .with_max_age(max_age)
+        .with_methods([Method::Options, Method::Get, Method::Post, Method::Delete])
+        .with_allowed_headers(headers)
+        .with_exposed_headers([
+            "X-Signal-Version",
+            "Retry-After",
+            "grpc-status",
+            "grpc-message",
+            "x-error-code",
+        ]);
+
+    let origin = req.headers().get("Origin")?;
+    Ok(match origin {
+        Some(origin) if origins.contains(&origin) => {
+            cors.with_origins([origin]).with_credentials(true)
+        }
+        _ if origins.iter().any(|o| o == "*") => cors.with_origins(["*"]),
+        _ => cors,
+    })
+}
+
+/// Turns a failed request into a 503, these are binding or storage failures
+/// since client errors are `ApiError`s.
+async fn unavailable(env: &Env, e: worker::Error) -> Result<Response> {
+    console_log!("request failed: {}", e);
+    // Clients slow down when there are many
+    report(env).await;
+    ApiError::Unavailable(RETRY_AFTER).into_response()
+}
+
+#[event(fetch)]
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    if req.path() == "/ws" {
+        // Upgrade responses can't carry CORS headers
+        if !matches!(req.method(), Method::Get) {
+            return ApiError::MethodNotAllowed.into_response();
+        }
+        let upgraded = match admit(&req, &env, "/ws").await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => socket(req, env.clone()).await,
+            Err(e) => Err(e),
+        };
+        return match upgraded {
+            Ok(res) => Ok(res),
+            Err(e) => unavailable(&env, e).await,
+        };
+    }
+
+    let cors = cors(&req, &env)?;
+
+    let mut res = if matches!(req.method(), Method::Options) {
+        let mut headers = Headers::new();
+        headers.set("Allow", "OPTIONS, GET, POST, DELETE")?;
+        Response::empty()?.with_headers(headers).with_cors(&cors)?
+    } else {
+        match handle(req, env.clone(), &ctx).await {
+            Ok(res) => res,
+            Err(e) => unavailable(&env, e).await?,
+        }
+        .with_cors(&cors)?
+    };
+    ctx.wait_until(flush(env));
+    // The allowed origin depends on the request's
+    res.headers_mut().append("Vary", "Origin")?;
+    Ok(res)
+}
+
+/// Consumes the deletions cleanup queued, retrying the messages with keys left.
+#[event(queue)]
+async fn do_deletions(batch: MessageBatch<Vec<String>>, env: Env, _ctx: Context) -> Result<()> {
+    let storage = storage(&env)?;
+    for message in batch.messages()?.into_iter() {
+        let keys = message.body();
+        if delete_now(&*storage, keys).await.len() == keys.len() {
+            message.ack();
+        } else {
+            message.retry();
+        }
+    }
+    Ok(())
+}
+
+#[event(scheduled)]
+async fn do_cleanup(_evt: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    let storage = match storage(&env) {
+        Ok(storage) => storage,
+        Err(e) => {
+            console_log!("storage unavailable, skipping cleanup: {}", e);
+            return;
+        }
+    };
+    let config = Config::from_env(&env);
+    if let Err(e) = refill(&*storage, &config).await {
+        console_log!("couldn't refill the pool: {}", e);
+    }
+    if !storage.expires() {
+        if let Err(e) = reconcile(&env).await {
+            console_log!("couldn't reconcile the replica: {}", e);
+        }
+    }
+    cleanup(&env, &SystemClock, &*storage, &config).await;
+    if storage.expires() {
+        // Nothing outlives its expiry
+        if let Err(e) = storage.purge_expired().await {
+            console_log!("couldn't purge expired objects: {}", e);
+        }
+    }
+    flush(env).await;
+}
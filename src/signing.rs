@@ -0,0 +1,113 @@
+use serde::Serialize;
+use worker::{Env, Result};
+
+/// KV namespace of runtime flags, its keys come after the ones from secrets.
+const BINDING: &str = "FLAGS";
+/// Kid of the single secret from before rotation, older than any listed key.
+const LEGACY_KID: &str = "default";
+
+/// What a set of keys signs.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Purpose {
+    /// Signed tokens, see `token`
+    Jwt,
+    /// TURN credentials, coturn takes every active one as a `static-auth-secret`
+    Turn,
+}
+
+impl Purpose {
+    pub const ALL: [Purpose; 2] = [Purpose::Jwt, Purpose::Turn];
+
+    /// Secret of `;` separated `kid=secret` pairs, oldest first.
+    fn secret(&self) -> &'static str {
+        match self {
+            Self::Jwt => "JWT_KEYS",
+            Self::Turn => "TURN_KEYS",
+        }
+    }
+
+    fn legacy_secret(&self) -> &'static str {
+        match self {
+            Self::Jwt => "JWT_SECRET",
+            Self::Turn => "TURN_SECRET",
+        }
+    }
+
+    /// Key of `FLAGS` with more pairs, added without a deploy.
+    fn flag(&self) -> &'static str {
+        match self {
+            Self::Jwt => "jwt_keys",
+            Self::Turn => "turn_keys",
+        }
+    }
+}
+
+pub struct SigningKey {
+    pub kid: String,
+    pub secret: String,
+}
+
+fn parse(pairs: &str) -> impl Iterator<Item = SigningKey> + '_ {
+    pairs
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(kid, secret)| !kid.is_empty() && !secret.is_empty())
+        .map(|(kid, secret)| SigningKey {
+            kid: kid.to_owned(),
+            secret: secret.to_owned(),
+        })
+}
+
+/// Active keys for `purpose`, newest last: the legacy secret, the secret's
+/// pairs and then the ones in `FLAGS`.
+///
+/// Everything listed is accepted, the newest signs. A key is retired by
+/// taking it out once what it signed has expired.
+pub async fn keys(env: &Env, purpose: Purpose) -> Result<Vec<SigningKey>> {
+    let mut keys = vec![];
+    if let Ok(secret) = env.secret(purpose.legacy_secret()) {
+        keys.push(SigningKey {
+            kid: LEGACY_KID.to_owned(),
+            secret: secret.to_string(),
+        });
+    }
+    if let Ok(pairs) = env.secret(purpose.secret()) {
+        keys.extend(parse(&pairs.to_string()));
+    }
+    if let Ok(store) = env.kv(BINDING) {
+        if let Some(pairs) = store.get(purpose.flag()).text().await? {
+            keys.extend(parse(&pairs));
+        }
+    }
+    Ok(keys)
+}
+
+/// Key new signatures for `purpose` are made with, `None` without any.
+pub async fn signing_key(env: &Env, purpose: Purpose) -> Result<Option<SigningKey>> {
+    Ok(keys(env, purpose).await?.pop())
+}
+
+/// Entry of `/admin/keys`, never with the secret.
+#[derive(Serialize)]
+pub struct KeyState {
+    purpose: Purpose,
+    kid: String,
+    /// New signatures are made with it, the others are only accepted
+    signing: bool,
+}
+
+/// Where every key stands, oldest first.
+pub async fn states(env: &Env) -> Result<Vec<KeyState>> {
+    let mut states = vec![];
+    for purpose in Purpose::ALL {
+        let keys = keys(env, purpose).await?;
+        let newest = keys.len().saturating_sub(1);
+        states.extend(keys.into_iter().enumerate().map(|(i, key)| KeyState {
+            purpose,
+            kid: key.kid,
+            signing: i == newest,
+        }));
+    }
+    Ok(states)
+}
@@ -0,0 +1,85 @@
+use std::{
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+use web_time::{Duration, SystemTime};
+use worker::Result;
+
+use crate::{auth::Auth, clock::Clock, config::Config, keys::KeyGenerator, room::Room};
+
+/// Runs a future that never waits on anything, like every `MemoryStorage` call.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// Same bytes for the same seed, splitmix64.
+pub struct SeededKeys(pub u64);
+
+impl KeyGenerator for SeededKeys {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<()> {
+        for chunk in buf.chunks_mut(8) {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// Stands still until moved, from the real time by default.
+pub struct FakeClock(pub SystemTime);
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        FakeClock(SystemTime::now())
+    }
+}
+
+impl FakeClock {
+    pub fn advance(&mut self, secs: u64) {
+        self.0 += Duration::from_secs(secs);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+/// Started auth of service `test`.
+pub fn auth(keys: &mut SeededKeys, clock: &dyn Clock, config: &Config) -> Auth {
+    let mut auth = Auth::create_with(keys, &Auth::key_spec(), None).unwrap();
+    auth.start(clock, config);
+    auth.set_service("test".to_owned());
+    auth
+}
+
+/// Empty room of service `test`.
+pub fn room(keys: &mut SeededKeys) -> Room {
+    Room::create_with(keys, &Room::key_spec(), Some("test")).unwrap()
+}
+
+/// Two auths that joined a room of two, `a` on the first slot and owning it.
+pub fn pair(keys: &mut SeededKeys, clock: &dyn Clock, config: &Config) -> (Auth, Auth, Room) {
+    let mut a = auth(keys, clock, config);
+    let mut b = auth(keys, clock, config);
+    let mut room = room(keys);
+    let close_at = clock.now() + Duration::from_secs(3600);
+    assert!(room.join_room(clock, &mut a, 2, None, close_at).is_ok());
+    assert!(room.join_room(clock, &mut b, 2, None, close_at).is_ok());
+    a.set_peers(clock, &room);
+    b.set_peers(clock, &room);
+    (a, b, room)
+}
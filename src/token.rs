@@ -0,0 +1,166 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{Env, Result};
+
+use crate::{
+    auth::Auth,
+    clock::{Clock, SystemClock},
+    config::Config,
+    db::Storage,
+    signing::{keys, signing_key, Purpose, SigningKey},
+};
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    // signing key, tokens from before rotation have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    // auth key
+    sub: String,
+    // kill_at, in seconds
+    exp: u64,
+    // service picked at ident
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    svc: Option<String>,
+    // country of the ident
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cty: Option<String>,
+    // ident time, in seconds, kept across refreshes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iat: Option<u64>,
+    // identity provider's subject
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uid: Option<String>,
+}
+
+/// Whether `TOKENS` is set to `"jwt"`, they're signed with the `Purpose::Jwt` keys.
+pub fn is_signed(env: &Env) -> bool {
+    env.var("TOKENS").is_ok_and(|v| v.to_string() == "jwt")
+}
+
+pub fn mac(secret: &str, message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length works");
+    mac.update(message.as_bytes());
+    mac
+}
+
+fn encode(key: &SigningKey, claims: &Claims) -> String {
+    let header = Header {
+        alg: "HS256".to_owned(),
+        typ: "JWT".to_owned(),
+        kid: Some(key.kid.clone()),
+    };
+    let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap());
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+    let message = format!("{}.{}", header, payload);
+    let signature = URL_SAFE_NO_PAD.encode(mac(&key.secret, &message).finalize().into_bytes());
+    format!("{}.{}", message, signature)
+}
+
+/// Claims of `token` if any of `keys` signed it, only the one its `kid` names
+/// when it has one.
+fn decode(keys: &[SigningKey], token: &str) -> Option<Claims> {
+    let (message, signature) = token.rsplit_once('.')?;
+    let (header, payload) = message.split_once('.')?;
+    let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    if header.alg != "HS256" || header.typ != "JWT" {
+        return None;
+    }
+
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    keys.iter()
+        .filter(|key| header.kid.as_ref().is_none_or(|kid| *kid == key.kid))
+        .find(|key| mac(&key.secret, message).verify_slice(&signature).is_ok())?;
+
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_secs();
+    (claims.exp > now).then_some(claims)
+}
+
+/// Signs a token for `user` with the newest key, `None` when JWTs aren't
+/// enabled or there's no key.
+pub async fn sign(env: &Env, user: &Auth) -> Result<Option<String>> {
+    if !is_signed(env) {
+        return Ok(None);
+    }
+    let key = match signing_key(env, Purpose::Jwt).await? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let exp = user
+        .kill_at()
+        .duration_since(UNIX_EPOCH)
+        .expect("time travel on kill_at?")
+        .as_secs();
+    let iat = user
+        .started_at()
+        .duration_since(UNIX_EPOCH)
+        .expect("time travel on started_at?")
+        .as_secs();
+    let claims = Claims {
+        sub: user.key.clone(),
+        exp,
+        svc: user.get_service().cloned(),
+        cty: user.get_country().cloned(),
+        iat: Some(iat),
+        uid: user.get_subject().cloned(),
+    };
+    Ok(Some(encode(&key, &claims)))
+}
+
+/// Looks up the auth behind `token`, a JWT once there are keys to sign them
+/// with and a plain auth key otherwise.
+///
+/// JWT sessions that aren't stored yet start from scratch, as long as they
+/// could have gone without their first poll. Later on a missing auth was
+/// deleted, by leaving, a kick or cleanup, and the token is refused.
+pub async fn session(
+    env: &Env,
+    storage: &dyn Storage,
+    config: &Config,
+    token: &str,
+) -> Result<Option<Auth>> {
+    if !is_signed(env) {
+        return Auth::load(storage, token).await;
+    }
+    let keys = keys(env, Purpose::Jwt).await?;
+    if keys.is_empty() {
+        return Auth::load(storage, token).await;
+    }
+    // Anything else, the `sub` of a token included, is refused
+    let claims = match decode(&keys, token) {
+        Some(claims) => claims,
+        None => return Ok(None),
+    };
+
+    match Auth::load(storage, &claims.sub).await? {
+        Some(user) => Ok(Some(user)),
+        None => {
+            let kill_at = UNIX_EPOCH + Duration::from_secs(claims.exp);
+            let started_at = claims.iat.map(|v| UNIX_EPOCH + Duration::from_secs(v));
+            let clock = SystemClock;
+            let mut user = Auth::unsaved(claims.sub);
+            user.start(&clock, config);
+            user.resume(
+                kill_at, started_at, claims.svc, claims.cty, claims.uid, config,
+            );
+            let window = Duration::from_secs(config.first_poll + config.grace_period);
+            if clock.now() >= user.started_at() + window {
+                return Ok(None);
+            }
+            Ok(Some(user))
+        }
+    }
+}
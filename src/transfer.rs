@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use web_time::{Duration, SystemTime};
+use worker::{Env, Request, Response, Result};
+
+use crate::{
+    auth::{expiry_bucket, Auth},
+    clock::SystemClock,
+    config::Config,
+    db::{storage, BucketInfo, Data, ExpiringMetadata},
+    error::ApiError,
+    keys::{CryptoKeys, CROCKFORD},
+    proto::{TransferClaimResponse, TransferStartResponse},
+    room::{Room, CREATE_ATTEMPTS},
+    token,
+    turn::ice_servers,
+};
+
+/// One-time code handing a session over to another device, keyed by the code.
+pub type Transfer = Data<TransferData, ExpiringMetadata, TransferInfo>;
+
+pub struct TransferInfo {}
+impl BucketInfo for TransferInfo {
+    const PREFIX: &'static str = "xfer";
+    const KEY_LENGTH: u8 = 8;
+    // typed in by hand on the other device
+    const ALPHABET: &'static str = CROCKFORD;
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct TransferData {
+    // key of the auth handed over
+    token: String,
+    claimed: bool,
+}
+
+impl Transfer {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.meta.expire_at
+    }
+}
+
+#[derive(Deserialize)]
+struct TransferClaim {
+    code: String,
+}
+
+/// Hands out a code for another device to take the session over with, valid
+/// for `TRANSFER_TTL` seconds and only once.
+pub async fn transfer_start(req: Request, env: Env) -> Result<Response> {
+    let token = match req.headers().get("Authorization")? {
+        Some(token) => token,
+        None => return ApiError::MissingToken.into_response(),
+    };
+
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&SystemClock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    let key = user.key.clone();
+    // Signed tokens may not be stored yet, the claim loads the auth
+    if !user.write(&*storage).await? {
+        return ApiError::Conflict.into_response();
+    }
+
+    let expire_at = SystemTime::now() + Duration::from_secs(config.transfer_ttl);
+    for _ in 0..CREATE_ATTEMPTS {
+        let mut transfer = Transfer::create_with(&mut CryptoKeys, &Transfer::key_spec(), None)?;
+        transfer.meta.expire_at = expire_at;
+        let data = transfer.data.as_mut().expect("invalid state");
+        data.token = key.clone();
+        let code = transfer.key.clone();
+        if transfer.write(&*storage).await? {
+            return Response::from_json(&TransferStartResponse { code, expire_at });
+        }
+    }
+    ApiError::Conflict.into_response()
+}
+
+/// Redeems a code from `transfer_start`, taking the session over under a
+/// new token.
+///
+/// Rooms and peers follow it to the new key, and everything the session got
+/// so far is replayed as after a `Signal::Resync`. The device that started
+/// the transfer gets `InvalidToken` from then on.
+pub async fn transfer_claim(mut req: Request, env: Env) -> Result<Response> {
+    let body = match req.json::<TransferClaim>().await {
+        Ok(b) => b,
+        Err(e) => return ApiError::Malformed(e.to_string()).into_response(),
+    };
+
+    let config = Config::from_env(&env);
+    let storage = storage(&env)?;
+    let code = body.code.trim().to_ascii_uppercase();
+    let mut transfer = match Transfer::load(&*storage, &code).await? {
+        Some(transfer) if !transfer.is_expired() => transfer,
+        _ => return ApiError::InvalidTransfer.into_response(),
+    };
+    let data = transfer.data.as_mut().expect("invalid state");
+    if data.claimed {
+        return ApiError::InvalidTransfer.into_response();
+    }
+    data.claimed = true;
+    let key = data.token.clone();
+    transfer.modified = true;
+
+    let mut user = match Auth::load(&*storage, &key).await? {
+        Some(user) if user.is_alive(&SystemClock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+    let config = Config::for_profile(&env, user.get_profile()).await?;
+    let mut heir = user.hand_over(
+        &SystemClock,
+        Auth::create_in(&expiry_bucket(user.kill_at()))?.key,
+    );
+    heir.resync();
+
+    // Written along with the transfer, a conflict leaves the code to retry with
+    let mut writes = vec![];
+    for room_key in heir.get_rooms().iter() {
+        if let Some(mut room) = Room::load(&*storage, room_key).await? {
+            room.rekey(&key, &heir.key);
+            writes.extend(room.into_put());
+        }
+    }
+    for peer_key in heir.get_peers().iter() {
+        if let Some(mut peer) = Auth::load(&*storage, peer_key).await? {
+            peer.rename_peer(&key, &heir.key);
+            writes.extend(peer.into_put());
+        }
+    }
+    let token = token::sign(&env, &heir)
+        .await?
+        .unwrap_or_else(|| heir.key.clone());
+    let (new_key, kill_at) = (heir.key.clone(), heir.kill_at());
+    writes.extend(
+        [transfer.into_put(), heir.into_put(), user.into_put()]
+            .into_iter()
+            .flatten(),
+    );
+    if !storage.put_all(writes).await? {
+        return ApiError::Conflict.into_response();
+    }
+    Response::from_json(&TransferClaimResponse {
+        ice_servers: ice_servers(&env, &config, &new_key).await?,
+        token,
+        kill_at,
+    })
+}
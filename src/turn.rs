@@ -0,0 +1,117 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use web_time::{Duration, SystemTime, UNIX_EPOCH};
+use worker::{Env, Result};
+
+use crate::{
+    ban::token_hash,
+    config::Config,
+    proto::IceServer,
+    signing::{signing_key, Purpose},
+};
+
+/// ICE servers a room can be given, and URLs per server.
+const MAX_ICE_SERVERS: usize = 4;
+const MAX_URLS: usize = 4;
+const MAX_URL_LENGTH: usize = 256;
+
+fn urls(env: &Env, var: &str) -> Vec<String> {
+    env.var(var)
+        .map(|v| v.to_string())
+        .unwrap_or_default()
+        .split(';')
+        .filter(|v| !v.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Mints credentials following coturn's REST API: the username is the
+/// expiry timestamp and the password its HMAC-SHA1 under the shared secret.
+///
+/// Usernames go in the clear and end up in TURN logs, so they carry the
+/// `token_hash` of `user` rather than the token.
+fn turn_credentials(config: &Config, secret: &str, user: &str) -> (String, String) {
+    let expiry = (SystemTime::now() + Duration::from_secs(config.max_connection))
+        .duration_since(UNIX_EPOCH)
+        .expect("time travel?")
+        .as_secs();
+    let username = format!("{}:{}", expiry, token_hash(user));
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("any key length works");
+    mac.update(username.as_bytes());
+    let credential = STANDARD.encode(mac.finalize().into_bytes());
+
+    (username, credential)
+}
+
+/// `scheme:host` of an ICE URL, e.g. `turns:eu.example.com` for
+/// `turns:eu.example.com:5349?transport=tcp`.
+fn ice_host(url: &str) -> &str {
+    let url = url.split('?').next().unwrap_or(url);
+    match url.rsplit_once(':') {
+        Some((host, port)) if host.contains(':') && port.chars().all(|c| c.is_ascii_digit()) => {
+            host
+        }
+        _ => url,
+    }
+}
+
+/// Whether a room may be given `servers`, every URL's `scheme:host` being
+/// listed in the `;` separated `ICE_ALLOWED` var. Nothing is allowed without it.
+pub fn is_ice_allowed(env: &Env, servers: &[IceServer]) -> bool {
+    let allowed = urls(env, "ICE_ALLOWED");
+    servers.len() <= MAX_ICE_SERVERS
+        && servers.iter().all(|server| {
+            !server.urls.is_empty()
+                && server.urls.len() <= MAX_URLS
+                && server.urls.iter().all(|url| {
+                    url.len() <= MAX_URL_LENGTH && allowed.iter().any(|a| a == ice_host(url))
+                })
+        })
+}
+
+/// Builds the ICE servers handed out with a new token, TURN credentials being
+/// signed with the newest `Purpose::Turn` key.
+pub async fn ice_servers(env: &Env, config: &Config, user: &str) -> Result<Vec<IceServer>> {
+    let mut servers = vec![];
+
+    let stun = urls(env, "STUN_URLS");
+    if !stun.is_empty() {
+        servers.push(IceServer {
+            urls: stun,
+            username: None,
+            credential: None,
+        });
+    }
+
+    let turn = urls(env, "TURN_URLS");
+    let key = if turn.is_empty() {
+        None
+    } else {
+        signing_key(env, Purpose::Turn).await?
+    };
+    if let Some(key) = key {
+        let (username, credential) = turn_credentials(config, &key.secret, user);
+        servers.push(IceServer {
+            urls: turn,
+            username: Some(username),
+            credential: Some(credential),
+        });
+    }
+
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::turn_credentials;
+    use crate::{ban::token_hash, config::Config};
+
+    #[test]
+    fn username_hides_token() {
+        let (username, _) = turn_credentials(&Config::default(), "secret", "TOKEN");
+        let (_, user) = username.split_once(':').unwrap();
+        assert_eq!(user, token_hash("TOKEN"));
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+use worker::{wasm_bindgen::JsValue, Env, Fetch, Headers, Method, Request, RequestInit, Result};
+
+pub const HEADER: &str = "X-Turnstile-Token";
+const SECRET: &str = "TURNSTILE_SECRET";
+const VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+#[derive(Serialize)]
+struct Verification<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Outcome {
+    success: bool,
+}
+
+/// Whether `/ident` needs a solved Turnstile challenge, on once `TURNSTILE_SECRET` is set.
+pub fn is_required(env: &Env) -> bool {
+    env.secret(SECRET).is_ok()
+}
+
+/// Asks Turnstile whether `token` is a challenge solved by `ip`, each token passes once.
+pub async fn verify(env: &Env, token: &str, ip: Option<&str>) -> Result<bool> {
+    let secret = match env.secret(SECRET) {
+        Ok(secret) => secret.to_string(),
+        Err(_) => return Ok(true),
+    };
+    let body = serde_json::to_string(&Verification {
+        secret: &secret,
+        response: token,
+        remoteip: ip,
+    })?;
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(JsValue::from_str(&body)));
+
+    let req = Request::new_with_init(VERIFY_URL, &init)?;
+    let outcome: Outcome = Fetch::Request(req).send().await?.json().await?;
+    Ok(outcome.success)
+}
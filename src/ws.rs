@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use worker::{
+    async_trait, console_log, durable_object, wasm_bindgen, wasm_bindgen_futures, Env, Request,
+    Response, Result, State, WebSocket, WebSocketIncomingMessage, WebSocketPair,
+};
+
+use crate::{
+    audit::{audit, Action},
+    auth::Auth,
+    clock::SystemClock,
+    config::Config,
+    db::storage,
+    error::ApiError,
+    limit::limit,
+    log::token_prefix,
+    metrics::flush,
+    poll::{exchange, protocol_version, Caller},
+    proto::{Signal, PROTOCOL_VERSIONS},
+    room::Room,
+    token,
+};
+
+const BINDING: &str = "SOCKETS";
+
+#[derive(Deserialize)]
+struct SocketQuery {
+    token: String,
+    // browsers can't set `X-Signal-Version` on websockets
+    version: Option<u32>,
+}
+
+/// Kept with each socket across hibernation.
+#[derive(Serialize, Deserialize)]
+struct Attachment {
+    token: String,
+    // of the upgrade, for the room quota and IP bans
+    ip: Option<String>,
+}
+
+pub fn is_enabled(env: &Env) -> bool {
+    env.durable_object(BINDING).is_ok()
+}
+
+/// Upgrades the request into a signalling websocket for the token in the query string.
+pub async fn socket(req: Request, env: Env) -> Result<Response> {
+    let upgrade = req.headers().get("Upgrade")?;
+    if !upgrade.is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+        return ApiError::ExpectedUpgrade.into_response();
+    }
+    let (token, version) = match req.query::<SocketQuery>() {
+        Ok(q) => (q.token, q.version),
+        Err(_) => return ApiError::MissingToken.into_response(),
+    };
+    let supported = match version {
+        Some(v) => PROTOCOL_VERSIONS.contains(&v),
+        None => protocol_version(&req)?.is_ok(),
+    };
+    if !supported {
+        return ApiError::UnsupportedVersion.into_response();
+    }
+
+    let storage = storage(&env)?;
+    let config = Config::from_env(&env);
+    let user = match token::session(&env, &*storage, &config, &token).await? {
+        Some(user) if user.is_alive(&SystemClock, &config) => user,
+        _ => return ApiError::InvalidToken.into_response(),
+    };
+
+    // Keyed by auth key so `notify` finds it
+    let namespace = env.durable_object(BINDING)?;
+    let stub = namespace.id_from_name(&user.key)?.get_stub()?;
+    stub.fetch_with_request(req).await
+}
+
+/// Asks the socket object of `token` to push its pending signals.
+///
+/// Does nothing when websockets aren't configured; the client will pick
+/// the signals up on its next poll instead.
+pub async fn notify(env: &Env, token: &str) {
+    let namespace = match env.durable_object(BINDING) {
+        Ok(namespace) => namespace,
+        Err(_) => return,
+    };
+
+    let url = format!("https://socket/notify?token={}", token);
+    let res = match namespace.id_from_name(token).and_then(|id| id.get_stub()) {
+        Ok(stub) => stub.fetch_with_str(&url).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        console_log!("couldn't notify {}: {}", token, e);
+    }
+}
+
+#[durable_object]
+pub struct SignalSocket {
+    state: State,
+    env: Env,
+}
+
+impl SignalSocket {
+    async fn push(&self, token: &str) -> Result<()> {
+        let sockets = self.state.get_websockets();
+        if sockets.is_empty() {
+            return Ok(());
+        }
+
+        let storage = storage(&self.env)?;
+        let mut user = match Auth::load(&*storage, token).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        for key in user.get_rooms().to_vec().iter() {
+            if let Some(room) = Room::load(&*storage, key).await? {
+                user.set_peers(&SystemClock, &room);
+            }
+        }
+        let peers = user.load_peers(&*storage).await?;
+
+        let config = Config::for_profile(&self.env, user.get_profile()).await?;
+        let signals = user.pull_signals(&SystemClock, &peers, &config);
+        if !user.write(&*storage).await? {
+            // The next poll will pick the signals up again
+            return Ok(());
+        }
+
+        for ws in sockets.iter() {
+            ws.send(&signals)?;
+        }
+        Ok(())
+    }
+}
+
+#[durable_object]
+impl DurableObject for SignalSocket {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, env }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let token = req.query::<SocketQuery>()?.token;
+
+        if req.path() == "/notify" {
+            self.push(&token).await?;
+            return Response::empty();
+        }
+
+        let ip = req.headers().get("CF-Connecting-IP")?;
+        let pair = WebSocketPair::new()?;
+        self.state.accept_web_socket(&pair.server);
+        pair.server
+            .serialize_attachment(&Attachment { token, ip })?;
+        Response::from_websocket(pair.client)
+    }
+
+    async fn websocket_message(
+        &mut self,
+        ws: WebSocket,
+        message: WebSocketIncomingMessage,
+    ) -> Result<()> {
+        let Attachment { token, ip } = match ws.deserialize_attachment::<Attachment>() {
+            Ok(Some(attachment)) => attachment,
+            // Sockets from before the IP was kept reconnect
+            _ => return ws.close(Some(1008), Some("Missing token.")),
+        };
+        let text = match message {
+            WebSocketIncomingMessage::String(text) => text,
+            WebSocketIncomingMessage::Binary(_) => {
+                return ws.send(&ApiError::Malformed("binary message".to_owned()))
+            }
+        };
+        let signals = match serde_json::from_str::<Vec<Signal>>(&text) {
+            Ok(s) => s,
+            Err(e) => return ws.send(&ApiError::Malformed(e.to_string())),
+        };
+        // Every message is a poll, limited like one
+        if let Some(limited) = limit(&self.env, &format!("token:{}", token)).await? {
+            if limited.first {
+                let config = Config::from_env(&self.env);
+                let storage = storage(&self.env)?;
+                let actor = token_prefix(&token);
+                audit(&*storage, &config, Action::RateLimited, &actor, "/ws").await;
+            }
+            return ws.send(&ApiError::RateLimited(limited.retry_after));
+        }
+
+        let res = match exchange(&self.env, &Caller::socket(ip), &token, signals, None).await? {
+            Ok(signals) => ws.send(&signals),
+            Err(e) => ws.send(&e),
+        };
+        self.state.wait_until(flush(self.env.clone()));
+        res
+    }
+
+    async fn websocket_close(
+        &mut self,
+        _ws: WebSocket,
+        _code: usize,
+        _reason: String,
+        _was_clean: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn websocket_error(&mut self, _ws: WebSocket, _error: worker::Error) -> Result<()> {
+        Ok(())
+    }
+}